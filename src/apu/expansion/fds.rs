@@ -0,0 +1,326 @@
+use nes::{VAddr};
+use nes::state::{StateWriter, StateReader};
+
+use apu::expansion::{ExpansionAudio};
+
+/// # Famicom Disk System audio
+///
+/// from http://wiki.nesdev.com/w/index.php/FDS_audio
+///
+/// One wavetable channel, 64 steps of 6 bit samples, with a volume envelope and a frequency
+/// modulation unit that has its own 64 step table of pitch adjustments.
+///
+/// - $4040-$407F - Wavetable, writable while $4089 bit 7 is set
+/// - $4080       - Volume envelope, MDVV VVVV, mode/direction/speed, or the gain when M is set
+/// - $4082       - Wave frequency low
+/// - $4083       - MEHH FFFF, halt the wave (H), halt the envelopes (E), frequency high
+/// - $4084       - Mod envelope, same as $4080
+/// - $4085       - Mod counter, 7 bit signed
+/// - $4086       - Mod frequency low
+/// - $4087       - H--- FFFF, halt mod (H), frequency high
+/// - $4088       - Mod table write, 3 bits, only while mod is halted
+/// - $4089       - W--- --VV, wavetable write enable (W) and master volume (V)
+/// - $408A       - Envelope speed
+/// - $4090       - Volume gain (read)
+/// - $4092       - Mod gain (read)
+
+static FDS_GAIN: f32 = 0.0003;
+
+//master volume 2/2, 2/3, 2/4, 2/5
+static MASTER_VOLUME: [f32, ..4] = [1.0, 2.0 / 3.0, 0.5, 0.4];
+
+//pitch adjustments applied to the mod counter, 4 resets it
+static MOD_ADJUST: [i32, ..8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+struct FdsEnvelope {
+    direct: bool, //envelope disabled, speed is the gain
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    counter: uint,
+}
+
+impl FdsEnvelope {
+    fn new() -> FdsEnvelope {
+        FdsEnvelope {
+            direct: true,
+            increase: false,
+            speed: 0,
+            gain: 0,
+            counter: 0,
+        }
+    }
+
+    fn write(&mut self, val: u8) {
+        self.direct = val & 0x80 != 0;
+        self.increase = val & 0x40 != 0;
+        self.speed = val & 0x3F;
+        self.counter = 0;
+        if self.direct { self.gain = self.speed; }
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.direct { return; }
+
+        self.counter += 1;
+        if self.counter < 8 * (master_speed as uint + 1) * (self.speed as uint + 1) { return; }
+        self.counter = 0;
+
+        if self.increase {
+            if self.gain < 32 { self.gain += 1; }
+        } else {
+            if self.gain > 0 { self.gain -= 1; }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.direct);
+        w.bool(self.increase);
+        w.u8(self.speed);
+        w.u8(self.gain);
+        w.uint(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.direct = r.bool();
+        self.increase = r.bool();
+        self.speed = r.u8();
+        self.gain = r.u8();
+        self.counter = r.uint();
+    }
+}
+
+pub struct FdsAudio {
+    wave: [u8, ..64],
+    wave_write: bool,
+    wave_halt: bool,
+    wave_frequency: u16,
+    wave_accumulator: u32,
+    wave_pos: uint,
+
+    envelope_halt: bool,
+    envelope_speed: u8,
+    volume: FdsEnvelope,
+    mod_envelope: FdsEnvelope,
+
+    mod_table: [u8, ..64],
+    mod_write_pos: uint,
+    mod_pos: uint,
+    mod_halt: bool,
+    mod_frequency: u16,
+    mod_accumulator: u32,
+    mod_counter: i32,
+
+    master_volume: uint,
+}
+
+impl FdsAudio {
+    pub fn new() -> FdsAudio {
+        FdsAudio {
+            wave: [0u8, ..64],
+            wave_write: false,
+            wave_halt: true,
+            wave_frequency: 0,
+            wave_accumulator: 0,
+            wave_pos: 0,
+
+            envelope_halt: false,
+            envelope_speed: 0xE8,
+            volume: FdsEnvelope::new(),
+            mod_envelope: FdsEnvelope::new(),
+
+            mod_table: [0u8, ..64],
+            mod_write_pos: 0,
+            mod_pos: 0,
+            mod_halt: true,
+            mod_frequency: 0,
+            mod_accumulator: 0,
+            mod_counter: 0,
+
+            master_volume: 0,
+        }
+    }
+
+    //the wave frequency bent by the mod counter, from the nesdev pseudocode
+    fn pitch(&self) -> i32 {
+        let mut temp = self.mod_counter * (self.mod_envelope.gain as i32);
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+
+        if temp >= 192 { temp -= 256; }
+        else if temp < -64 { temp += 256; }
+
+        temp = (self.wave_frequency as i32) * temp;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 { temp += 1; }
+
+        let pitch = (self.wave_frequency as i32) + temp;
+        if pitch < 0 { 0 } else { pitch }
+    }
+
+    fn clock_mod(&mut self) {
+        if self.mod_halt { return; }
+
+        self.mod_accumulator += self.mod_frequency as u32;
+        if self.mod_accumulator < 0x10000 { return; }
+        self.mod_accumulator &= 0xFFFF;
+
+        let step = self.mod_table[self.mod_pos] as uint;
+        if step == 4 {
+            self.mod_counter = 0;
+        } else {
+            self.mod_counter += MOD_ADJUST[step];
+            //7 bit signed wrap
+            if self.mod_counter > 63 { self.mod_counter -= 128; }
+            else if self.mod_counter < -64 { self.mod_counter += 128; }
+        }
+        self.mod_pos = (self.mod_pos + 1) & 0x3F;
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn name(&self) -> &'static str {
+        "fds"
+    }
+
+    fn write(&mut self, virtual_address: VAddr, val: u8) {
+        if virtual_address >= 0x4040 && virtual_address < 0x4080 {
+            if self.wave_write {
+                self.wave[(virtual_address - 0x4040) as uint] = val & 0x3F;
+            }
+            return;
+        }
+
+        match virtual_address {
+            0x4080 => { self.volume.write(val); }
+            0x4082 => {
+                self.wave_frequency = (self.wave_frequency & 0x0F00) | (val as u16);
+            }
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.wave_halt = val & 0x80 != 0;
+                self.envelope_halt = val & 0x40 != 0;
+                if self.wave_halt {
+                    self.wave_accumulator = 0;
+                    self.wave_pos = 0;
+                }
+            }
+            0x4084 => { self.mod_envelope.write(val); }
+            0x4085 => {
+                let counter = (val & 0x7F) as i32;
+                self.mod_counter = if counter > 63 { counter - 128 } else { counter };
+            }
+            0x4086 => {
+                self.mod_frequency = (self.mod_frequency & 0x0F00) | (val as u16);
+            }
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.mod_halt = val & 0x80 != 0;
+                if self.mod_halt { self.mod_accumulator = 0; }
+            }
+            0x4088 => {
+                //each write fills two consecutive entries
+                if self.mod_halt {
+                    self.mod_table[self.mod_write_pos] = val & 0x07;
+                    self.mod_table[self.mod_write_pos + 1] = val & 0x07;
+                    self.mod_write_pos = (self.mod_write_pos + 2) & 0x3F;
+                }
+            }
+            0x4089 => {
+                self.wave_write = val & 0x80 != 0;
+                self.master_volume = (val & 0x03) as uint;
+            }
+            0x408A => { self.envelope_speed = val; }
+            _ => { }
+        }
+    }
+
+    fn read(&mut self, virtual_address: VAddr) -> Option<u8> {
+        if virtual_address >= 0x4040 && virtual_address < 0x4080 {
+            Some(self.wave[(virtual_address - 0x4040) as uint] | 0x40)
+        } else if virtual_address == 0x4090 {
+            Some(self.volume.gain | 0x40)
+        } else if virtual_address == 0x4092 {
+            Some(self.mod_envelope.gain | 0x40)
+        } else {
+            None
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.envelope_halt && !self.wave_halt && self.envelope_speed != 0 {
+            let speed = self.envelope_speed;
+            self.volume.clock(speed);
+            self.mod_envelope.clock(speed);
+        }
+
+        self.clock_mod();
+
+        if !self.wave_halt {
+            self.wave_accumulator += self.pitch() as u32;
+            if self.wave_accumulator >= 0x10000 {
+                self.wave_accumulator &= 0xFFFF;
+                self.wave_pos = (self.wave_pos + 1) & 0x3F;
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        //the wave is held while it's writable
+        let gain = if self.volume.gain > 32 { 32 } else { self.volume.gain };
+        let level = (self.wave[self.wave_pos] as f32) * (gain as f32);
+        level * MASTER_VOLUME[self.master_volume] * FDS_GAIN
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(self.wave);
+        w.bool(self.wave_write);
+        w.bool(self.wave_halt);
+        w.u16(self.wave_frequency);
+        w.u32(self.wave_accumulator);
+        w.uint(self.wave_pos);
+
+        w.bool(self.envelope_halt);
+        w.u8(self.envelope_speed);
+        self.volume.save_state(w);
+        self.mod_envelope.save_state(w);
+
+        w.bytes(self.mod_table);
+        w.uint(self.mod_write_pos);
+        w.uint(self.mod_pos);
+        w.bool(self.mod_halt);
+        w.u16(self.mod_frequency);
+        w.u32(self.mod_accumulator);
+        w.u32(self.mod_counter as u32);
+
+        w.uint(self.master_volume);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.read_into(self.wave);
+        self.wave_write = r.bool();
+        self.wave_halt = r.bool();
+        self.wave_frequency = r.u16();
+        self.wave_accumulator = r.u32();
+        self.wave_pos = r.uint() & 0x3F;
+
+        self.envelope_halt = r.bool();
+        self.envelope_speed = r.u8();
+        self.volume.load_state(r);
+        self.mod_envelope.load_state(r);
+
+        r.read_into(self.mod_table);
+        self.mod_write_pos = r.uint() & 0x3E;
+        self.mod_pos = r.uint() & 0x3F;
+        self.mod_halt = r.bool();
+        self.mod_frequency = r.u16();
+        self.mod_accumulator = r.u32();
+        self.mod_counter = r.u32() as i32;
+
+        self.master_volume = r.uint() & 0x03;
+    }
+}
@@ -0,0 +1,196 @@
+use nes::{VAddr};
+use nes::state::{StateWriter, StateReader};
+
+use apu::{Envelope, LengthCounter, DUTY_TABLE, pulse_mix, tnd_mix};
+use apu::expansion::{ExpansionAudio};
+
+/// # MMC5 audio
+///
+/// from http://wiki.nesdev.com/w/index.php/MMC5_audio
+///
+/// Two pulse channels that work like the APU's without the sweep unit, and an 8 bit PCM
+/// channel. The MMC5 has its own frame counter that clocks the envelopes and length counters
+/// at a fixed 240 Hz.
+///
+/// - $5000-$5003 - Pulse 1, same as $4000-$4003 ($5001 is unused)
+/// - $5004-$5007 - Pulse 2, same as $4004-$4007 ($5005 is unused)
+/// - $5010       - PCM mode, I--- ---M, IRQ enable and read mode
+/// - $5011       - Raw PCM, writes of 0 are ignored
+/// - $5015       - Channel enable (write) and length counter status (read), ---- --21
+///
+/// PCM read mode and its IRQ are not emulated.
+
+static FRAME_PERIOD: uint = 7457; //240 Hz in CPU cycles
+
+struct Mmc5Pulse {
+    envelope: Envelope,
+    length: LengthCounter,
+    duty: u8,
+    duty_pos: u8,
+    timer_period: u16,
+    timer: u16,
+}
+
+impl Mmc5Pulse {
+    fn new() -> Mmc5Pulse {
+        Mmc5Pulse {
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            duty: 0,
+            duty_pos: 0,
+            timer_period: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, reg: uint, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.length.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | (val as u16);
+            }
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((val as u16 & 0x07) << 8);
+                self.length.load(val);
+                self.duty_pos = 0;
+                self.envelope.start = true;
+            }
+            _ => { }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.is_active() || DUTY_TABLE[self.duty as uint][self.duty_pos as uint] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.envelope.load_state(r);
+        self.length.load_state(r);
+        self.duty = r.u8() & 0x03;
+        self.duty_pos = r.u8() & 0x07;
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+    }
+}
+
+pub struct Mmc5Audio {
+    pulse_1: Mmc5Pulse,
+    pulse_2: Mmc5Pulse,
+    pcm: u8,
+    frame_cycle: uint,
+    cycle: u64,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Mmc5Audio {
+        Mmc5Audio {
+            pulse_1: Mmc5Pulse::new(),
+            pulse_2: Mmc5Pulse::new(),
+            pcm: 0,
+            frame_cycle: 0,
+            cycle: 0,
+        }
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn name(&self) -> &'static str {
+        "mmc5"
+    }
+
+    fn write(&mut self, virtual_address: VAddr, val: u8) {
+        match virtual_address {
+            0x5000 | 0x5001 | 0x5002 | 0x5003 => {
+                self.pulse_1.write((virtual_address - 0x5000) as uint, val);
+            }
+            0x5004 | 0x5005 | 0x5006 | 0x5007 => {
+                self.pulse_2.write((virtual_address - 0x5004) as uint, val);
+            }
+            0x5011 => {
+                if val != 0 { self.pcm = val; }
+            }
+            0x5015 => {
+                self.pulse_1.length.set_enabled(val & 0x01 != 0);
+                self.pulse_2.length.set_enabled(val & 0x02 != 0);
+            }
+            _ => { }
+        }
+    }
+
+    fn read(&mut self, virtual_address: VAddr) -> Option<u8> {
+        if virtual_address == 0x5015 {
+            let mut reg = 0u8;
+            if self.pulse_1.length.is_active() { reg |= 0x01; }
+            if self.pulse_2.length.is_active() { reg |= 0x02; }
+            Some(reg)
+        } else {
+            None
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.cycle & 0x01 == 1 {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.cycle += 1;
+
+        self.frame_cycle += 1;
+        if self.frame_cycle >= FRAME_PERIOD {
+            self.frame_cycle = 0;
+            self.pulse_1.envelope.clock();
+            self.pulse_2.envelope.clock();
+            self.pulse_1.length.clock();
+            self.pulse_2.length.clock();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let pulses = pulse_mix(self.pulse_1.output() as f32, self.pulse_2.output() as f32);
+        //the PCM channel is about as loud as the DMC, with one more bit
+        let pcm = tnd_mix(0.0, 0.0, (self.pcm as f32) / 2.0);
+        pulses + pcm
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        w.u8(self.pcm);
+        w.uint(self.frame_cycle);
+        w.u64(self.cycle);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.pulse_1.load_state(r);
+        self.pulse_2.load_state(r);
+        self.pcm = r.u8();
+        self.frame_cycle = r.uint();
+        self.cycle = r.u64();
+    }
+}
@@ -0,0 +1,55 @@
+use nes::{VAddr};
+use nes::state::{StateWriter, StateReader};
+
+pub use self::vrc6::Vrc6;
+pub use self::n163::Namco163;
+pub use self::vrc7::Vrc7;
+pub use self::s5b::Sunsoft5b;
+pub use self::mmc5::Mmc5Audio;
+pub use self::fds::FdsAudio;
+
+pub mod vrc6;
+pub mod n163;
+pub mod vrc7;
+pub mod s5b;
+pub mod mmc5;
+pub mod fds;
+
+#[cfg(test)]
+mod test;
+
+/// # Expansion audio
+///
+/// from http://wiki.nesdev.com/w/index.php/Expansion_audio
+///
+/// Famicom cartridges can mix their own sound into the console's audio. A mapper that has a
+/// sound chip hands it to the APU (see Mapper::audio_chips), and from then on the chip is
+/// clocked with the APU and its output is added to the mix.
+///
+/// Every chip sees all CPU writes from $4020 up and picks out its own registers, so the mapper
+/// doesn't have to forward them. Outputs are on the same scale as the APU mixer, a chip at full
+/// volume is roughly as loud as the hardware makes it next to the 2A03.
+pub trait ExpansionAudio {
+    fn name(&self) -> &'static str;
+
+    fn write(&mut self, virtual_address: VAddr, val: u8);
+
+    //for the few chips with readable registers
+    fn read(&mut self, _virtual_address: VAddr) -> Option<u8> {
+        None
+    }
+
+    //clocked once per CPU cycle
+    fn clock(&mut self);
+
+    fn output(&self) -> f32;
+
+    //registers and internal counters, in the APU's chunk after its own channels
+    fn save_state(&self, writer: &mut StateWriter);
+
+    fn load_state(&mut self, reader: &mut StateReader);
+}
+
+//level of one step of an APU pulse channel at full volume, the VRC6 and MMC5 pulses sit on the
+//same scale as the 2A03's
+pub static PULSE_STEP: f32 = 0.00996;
@@ -0,0 +1,168 @@
+use nes::{VAddr};
+use nes::state::{StateWriter, StateReader};
+
+use apu::expansion::{ExpansionAudio};
+
+/// # Namco 163
+///
+/// from http://wiki.nesdev.com/w/index.php/Namco_163_audio
+///
+/// Up to 8 wavetable channels that live in 128 bytes of internal RAM, shared between channel
+/// registers and 4 bit samples packed two to a byte.
+///
+/// - $F800-$FFFF - IAAA AAAA, RAM address (A) and auto increment (I)
+/// - $4800-$4FFF - RAM data port, read/write
+/// - $E000-$E7FF - -S-- ----, sound disable (S), the rest is the mapper's PRG bank
+///
+/// Channel n's registers start at $40 + n * 8, channel 7 is at $78:
+///
+/// - +0 - Frequency low
+/// - +1 - Phase low
+/// - +2 - Frequency mid
+/// - +3 - Phase mid
+/// - +4 - LLLL LLFF, wave length (256 - L * 4 samples) and frequency high
+/// - +5 - Phase high
+/// - +6 - Wave address, in samples
+/// - +7 - Volume, and for channel 7 the number of enabled channels - 1 in bits 4-6
+///
+/// The chip updates one channel every 15 CPU cycles, so the more channels are enabled the slower
+/// each one runs. The channels are time multiplexed on the real chip, here they are averaged.
+
+static RAM_SIZE: uint = 0x80;
+static CYCLES_PER_CHANNEL: uint = 15;
+
+//a full volume channel swings -8 to +7 times 15
+static N163_STEP: f32 = 0.0032;
+
+pub struct Namco163 {
+    ram: [u8, ..RAM_SIZE],
+    address: u8,
+    auto_increment: bool,
+    timer: uint,
+    channel: uint,
+    outputs: [i32, ..8],
+    disabled: bool,
+}
+
+impl Namco163 {
+    pub fn new() -> Namco163 {
+        Namco163 {
+            ram: [0u8, ..RAM_SIZE],
+            address: 0,
+            auto_increment: false,
+            timer: 0,
+            channel: 7,
+            outputs: [0, ..8],
+            disabled: false,
+        }
+    }
+
+    fn enabled_channels(&self) -> uint {
+        (((self.ram[0x7F] >> 4) & 0x07) + 1) as uint
+    }
+
+    fn sample(&self, index: uint) -> i32 {
+        let byte = self.ram[(index >> 1) & 0x7F];
+        let nibble = if index & 0x01 == 0 { byte & 0x0F } else { byte >> 4 };
+        nibble as i32
+    }
+
+    fn update_channel(&mut self, channel: uint) {
+        let base = 0x40 + channel * 8;
+
+        let frequency = (self.ram[base] as u32) | (self.ram[base + 2] as u32) << 8 |
+            ((self.ram[base + 4] & 0x03) as u32) << 16;
+        let mut phase = (self.ram[base + 1] as u32) | (self.ram[base + 3] as u32) << 8 |
+            (self.ram[base + 5] as u32) << 16;
+        let length = 256 - (self.ram[base + 4] & 0xFC) as u32;
+
+        phase = (phase + frequency) % (length << 16);
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        let index = ((phase >> 16) as uint + self.ram[base + 6] as uint) & 0xFF;
+        let volume = (self.ram[base + 7] & 0x0F) as i32;
+        self.outputs[channel] = (self.sample(index) - 8) * volume;
+    }
+}
+
+impl ExpansionAudio for Namco163 {
+    fn name(&self) -> &'static str {
+        "n163"
+    }
+
+    //the registers are mirrored across 2KB each
+    fn write(&mut self, virtual_address: VAddr, val: u8) {
+        match virtual_address & 0xF800 {
+            0xF800 => {
+                self.address = val & 0x7F;
+                self.auto_increment = val & 0x80 != 0;
+            }
+            0x4800 => {
+                self.ram[self.address as uint] = val;
+                if self.auto_increment { self.address = (self.address + 1) & 0x7F; }
+            }
+            0xE000 => {
+                self.disabled = val & 0x40 != 0;
+            }
+            _ => { }
+        }
+    }
+
+    fn read(&mut self, virtual_address: VAddr) -> Option<u8> {
+        if virtual_address & 0xF800 == 0x4800 {
+            let val = self.ram[self.address as uint];
+            if self.auto_increment { self.address = (self.address + 1) & 0x7F; }
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    //channels are updated from 7 down to 8 - the enabled count
+    fn clock(&mut self) {
+        self.timer += 1;
+        if self.timer < CYCLES_PER_CHANNEL { return; }
+        self.timer = 0;
+
+        let lowest = 8 - self.enabled_channels();
+        self.channel = if self.channel <= lowest { 7 } else { self.channel - 1 };
+
+        let channel = self.channel;
+        self.update_channel(channel);
+    }
+
+    fn output(&self) -> f32 {
+        if self.disabled { return 0.0; }
+
+        let enabled = self.enabled_channels();
+        let sum = self.outputs.slice_from(8 - enabled).iter().fold(0, |sum, &out| sum + out);
+        (sum as f32) / (enabled as f32) * N163_STEP
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(self.ram);
+        w.u8(self.address);
+        w.bool(self.auto_increment);
+        w.uint(self.timer);
+        w.uint(self.channel);
+        for &output in self.outputs.iter() {
+            w.u32(output as u32);
+        }
+        w.bool(self.disabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        r.read_into(self.ram);
+        self.address = r.u8() & 0x7F;
+        self.auto_increment = r.bool();
+        self.timer = r.uint();
+        self.channel = r.uint() & 0x07;
+        for output in self.outputs.mut_iter() {
+            *output = r.u32() as i32;
+        }
+        self.disabled = r.bool();
+    }
+}
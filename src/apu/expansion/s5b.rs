@@ -0,0 +1,254 @@
+use nes::{VAddr};
+use nes::state::{StateWriter, StateReader};
+
+use apu::expansion::{ExpansionAudio};
+
+/// # Sunsoft 5B
+///
+/// from http://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+///
+/// A YM2149F, the Yamaha clone of the AY-3-8910: three square wave channels, a noise generator
+/// and an envelope generator, each channel with its own tone/noise switches.
+///
+/// - $C000-$DFFF - Register select
+/// - $E000-$FFFF - Register write
+///
+/// - $00-$05 - Channel A, B and C period, 12 bit, low byte then high nibble
+/// - $06     - Noise period, 5 bit
+/// - $07     - --CB Acba, noise disable (CBA) and tone disable (cba), 1 disables
+/// - $08-$0A - Channel volume, ---E VVVV, E uses the envelope instead of V
+/// - $0B-$0C - Envelope period, 16 bit
+/// - $0D     - Envelope shape, ---- CAtH, continue, attack, alternate, hold
+///
+/// Volume steps are logarithmic, 3 dB apart.
+
+static PRESCALER: uint = 16;
+static ENVELOPE_PRESCALER: uint = 16; //on top of PRESCALER, the envelope has 16 steps
+
+static S5B_GAIN: f32 = 0.2;
+
+struct Tone {
+    period: u16,
+    counter: u16,
+    high: bool,
+}
+
+pub struct Sunsoft5b {
+    address: u8,
+    tones: [Tone, ..3],
+    volumes: [u8, ..3],
+    mixer: u8,
+
+    noise_period: u8,
+    noise_counter: u8,
+    noise_shift: u32,
+
+    envelope_period: u16,
+    envelope_counter: u32,
+    envelope_shape: u8,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+
+    prescaler: uint,
+}
+
+impl Sunsoft5b {
+    pub fn new() -> Sunsoft5b {
+        Sunsoft5b {
+            address: 0,
+            tones: [
+                Tone { period: 0, counter: 0, high: false },
+                Tone { period: 0, counter: 0, high: false },
+                Tone { period: 0, counter: 0, high: false },
+            ],
+            volumes: [0u8, ..3],
+            mixer: 0xFF,
+
+            noise_period: 0,
+            noise_counter: 0,
+            noise_shift: 1,
+
+            envelope_period: 0,
+            envelope_counter: 0,
+            envelope_shape: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: true,
+
+            prescaler: 0,
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x00 | 0x02 | 0x04 => {
+                let tone = &mut self.tones[(reg >> 1) as uint];
+                tone.period = (tone.period & 0x0F00) | (val as u16);
+            }
+            0x01 | 0x03 | 0x05 => {
+                let tone = &mut self.tones[(reg >> 1) as uint];
+                tone.period = (tone.period & 0x00FF) | ((val as u16 & 0x0F) << 8);
+            }
+            0x06 => { self.noise_period = val & 0x1F; }
+            0x07 => { self.mixer = val; }
+            0x08 | 0x09 | 0x0A => { self.volumes[(reg - 0x08) as uint] = val & 0x1F; }
+            0x0B => { self.envelope_period = (self.envelope_period & 0xFF00) | (val as u16); }
+            0x0C => { self.envelope_period = (self.envelope_period & 0x00FF) | ((val as u16) << 8); }
+            0x0D => {
+                self.envelope_shape = val & 0x0F;
+                self.envelope_step = 0;
+                self.envelope_counter = 0;
+                self.envelope_attack = val & 0x04 != 0;
+                self.envelope_holding = false;
+            }
+            _ => { } //$0E and $0F are I/O ports
+        }
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack { self.envelope_step } else { 15 - self.envelope_step }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding { return; }
+
+        self.envelope_counter += 1;
+        if self.envelope_counter < (self.envelope_period as u32) * (ENVELOPE_PRESCALER as u32) { return; }
+        self.envelope_counter = 0;
+
+        if self.envelope_step < 15 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        let continue_flag = self.envelope_shape & 0x08 != 0;
+        let alternate = self.envelope_shape & 0x02 != 0;
+        let hold = self.envelope_shape & 0x01 != 0;
+
+        if !continue_flag {
+            //one ramp, then silence
+            self.envelope_holding = true;
+            self.envelope_attack = false;
+            self.envelope_step = 15;
+        } else if hold {
+            //holds at the end of the ramp, or at its start when alternating
+            self.envelope_holding = true;
+            if alternate { self.envelope_attack = !self.envelope_attack; }
+            self.envelope_step = 15;
+        } else {
+            if alternate { self.envelope_attack = !self.envelope_attack; }
+            self.envelope_step = 0;
+        }
+    }
+}
+
+fn volume_level(volume: u8) -> f32 {
+    if volume == 0 { 0.0 } else { 2.0f32.powf(((volume as f32) - 15.0) / 2.0) }
+}
+
+impl ExpansionAudio for Sunsoft5b {
+    fn name(&self) -> &'static str {
+        "s5b"
+    }
+
+    fn write(&mut self, virtual_address: VAddr, val: u8) {
+        let register = virtual_address & 0xE000;
+        if register == 0xC000 {
+            self.address = val & 0x0F;
+        } else if register == 0xE000 {
+            let reg = self.address;
+            self.write_register(reg, val);
+        }
+    }
+
+    fn clock(&mut self) {
+        self.clock_envelope();
+
+        self.prescaler += 1;
+        if self.prescaler < PRESCALER { return; }
+        self.prescaler = 0;
+
+        for tone in self.tones.mut_iter() {
+            tone.counter += 1;
+            if tone.counter >= tone.period {
+                tone.counter = 0;
+                tone.high = !tone.high;
+            }
+        }
+
+        self.noise_counter += 1;
+        if self.noise_counter >= self.noise_period {
+            self.noise_counter = 0;
+            //17 bit LFSR, taps 0 and 3
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 0x01;
+            self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let noise_high = self.noise_shift & 0x01 != 0;
+        let mut sum = 0.0;
+
+        for i in range(0u, 3) {
+            let tone_disabled = self.mixer & (0x01 << i) != 0;
+            let noise_disabled = self.mixer & (0x08 << i) != 0;
+
+            if (tone_disabled || self.tones[i].high) && (noise_disabled || noise_high) {
+                let volume = self.volumes[i];
+                let level = if volume & 0x10 != 0 { self.envelope_level() } else { volume & 0x0F };
+                sum += volume_level(level);
+            }
+        }
+
+        sum * S5B_GAIN
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.address);
+        for tone in self.tones.iter() {
+            w.u16(tone.period);
+            w.u16(tone.counter);
+            w.bool(tone.high);
+        }
+        w.bytes(self.volumes);
+        w.u8(self.mixer);
+
+        w.u8(self.noise_period);
+        w.u8(self.noise_counter);
+        w.u32(self.noise_shift);
+
+        w.u16(self.envelope_period);
+        w.u32(self.envelope_counter);
+        w.u8(self.envelope_shape);
+        w.u8(self.envelope_step);
+        w.bool(self.envelope_attack);
+        w.bool(self.envelope_holding);
+
+        w.uint(self.prescaler);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.address = r.u8() & 0x0F;
+        for tone in self.tones.mut_iter() {
+            tone.period = r.u16();
+            tone.counter = r.u16();
+            tone.high = r.bool();
+        }
+        r.read_into(self.volumes);
+        self.mixer = r.u8();
+
+        self.noise_period = r.u8();
+        self.noise_counter = r.u8();
+        self.noise_shift = r.u32();
+
+        self.envelope_period = r.u16();
+        self.envelope_counter = r.u32();
+        self.envelope_shape = r.u8();
+        self.envelope_step = r.u8() & 0x0F;
+        self.envelope_attack = r.bool();
+        self.envelope_holding = r.bool();
+
+        self.prescaler = r.uint();
+    }
+}
@@ -0,0 +1,164 @@
+use apu::{Apu, CHANNEL_COUNT, pulse_mix};
+use apu::expansion::{ExpansionAudio, Vrc6, Vrc7, Namco163, Sunsoft5b, Mmc5Audio, FdsAudio, PULSE_STEP};
+
+#[test]
+fn expansion_vrc6_pulse_test() {
+    let mut vrc6 = Vrc6::new(false);
+    assert_eq!(vrc6.output(), 0.0);
+
+    //mode bit holds the pulse high at its volume
+    vrc6.write(0x9000, 0x8F);
+    vrc6.write(0x9002, 0x80);
+    vrc6.clock();
+    assert_eq!(vrc6.output(), 15.0 * PULSE_STEP);
+
+    //the halt bit stops every channel but keeps its level
+    vrc6.write(0x9003, 0x01);
+    vrc6.write(0xA000, 0x8F);
+    vrc6.write(0xA002, 0x80);
+    assert_eq!(vrc6.output(), 30.0 * PULSE_STEP);
+
+    //VRC6b swaps A0 and A1, $9001 is the enable register
+    let mut vrc6b = Vrc6::new(true);
+    vrc6b.write(0x9000, 0x8F);
+    vrc6b.write(0x9001, 0x80);
+    assert_eq!(vrc6b.output(), 15.0 * PULSE_STEP);
+}
+
+#[test]
+fn expansion_n163_ram_port_test() {
+    let mut n163 = Namco163::new();
+
+    n163.write(0xF800, 0x80 | 0x10);
+    n163.write(0x4800, 0xAA);
+    n163.write(0x4800, 0xBB);
+
+    n163.write(0xF800, 0x10);
+    assert_eq!(n163.read(0x4800), Some(0xAA));
+    assert_eq!(n163.read(0x4800), Some(0xAA));
+
+    n163.write(0xF800, 0x80 | 0x10);
+    assert_eq!(n163.read(0x4800), Some(0xAA));
+    assert_eq!(n163.read(0x4800), Some(0xBB));
+
+    assert_eq!(n163.read(0x5000), None);
+
+    //the ports are mirrored, and $E000 bit 6 silences the chip
+    n163.write(0xFFFF, 0x00);
+    n163.write(0x4FFF, 0xCC);
+    n163.write(0xF800, 0x00);
+    assert_eq!(n163.read(0x4C00), Some(0xCC));
+
+    n163.write(0xE7FF, 0x40);
+    assert_eq!(n163.output(), 0.0);
+}
+
+#[test]
+fn expansion_vrc7_key_on_test() {
+    let mut vrc7 = Vrc7::new();
+
+    //custom instrument with instant attack and no decay, modulator turned down
+    let custom = [0x21, 0x21, 0x3F, 0x00, 0xF0, 0xF0, 0x00, 0x00];
+    for (reg, &val) in custom.iter().enumerate() {
+        vrc7.write(0x9010, reg as u8);
+        vrc7.write(0x9030, val);
+    }
+
+    vrc7.write(0x9010, 0x10);
+    vrc7.write(0x9030, 0x80);
+    vrc7.write(0x9010, 0x30);
+    vrc7.write(0x9030, 0x00);
+
+    for _ in range(0u, 36 * 64) { vrc7.clock(); }
+    assert_eq!(vrc7.output(), 0.0);
+
+    //key on, block 2
+    vrc7.write(0x9010, 0x20);
+    vrc7.write(0x9030, 0x10 | 0x04);
+    for _ in range(0u, 36 * 64) { vrc7.clock(); }
+    assert!(vrc7.output() > 0.0);
+}
+
+#[test]
+fn expansion_s5b_mixer_test() {
+    let mut s5b = Sunsoft5b::new();
+    assert_eq!(s5b.output(), 0.0);
+
+    //tone and noise are both off at power up, so channel A holds at its volume
+    s5b.write(0xC000, 0x08);
+    s5b.write(0xE000, 0x0F);
+    assert_eq!(s5b.output(), 0.2);
+
+    //the registers are mirrored up to $DFFF and $FFFF
+    s5b.write(0xDFFF, 0x08);
+    s5b.write(0xFFFF, 0x00);
+    assert_eq!(s5b.output(), 0.0);
+
+    //turning channel A's tone on starts it low
+    s5b.write(0xC000, 0x08);
+    s5b.write(0xE000, 0x0F);
+    s5b.write(0xC000, 0x07);
+    s5b.write(0xE000, 0xFE);
+    assert_eq!(s5b.output(), 0.0);
+}
+
+#[test]
+fn expansion_mmc5_pulse_test() {
+    let mut mmc5 = Mmc5Audio::new();
+    assert_eq!(mmc5.output(), 0.0);
+
+    //the length counter only loads while the channel is enabled
+    mmc5.write(0x5000, 0xC0 | 0x30 | 0x0F); //25% negated, halt, constant volume 15
+    mmc5.write(0x5003, 0x08);
+    assert_eq!(mmc5.read(0x5015), Some(0x00));
+    assert_eq!(mmc5.output(), 0.0);
+
+    mmc5.write(0x5015, 0x01);
+    mmc5.write(0x5003, 0x08);
+    assert_eq!(mmc5.read(0x5015), Some(0x01));
+    assert_eq!(mmc5.output(), pulse_mix(15.0, 0.0));
+
+    //PCM writes of 0 are ignored
+    mmc5.write(0x5011, 0x00);
+    assert_eq!(mmc5.output(), pulse_mix(15.0, 0.0));
+    mmc5.write(0x5011, 0x80);
+    assert!(mmc5.output() > pulse_mix(15.0, 0.0));
+
+    mmc5.write(0x5015, 0x00);
+    assert_eq!(mmc5.read(0x5015), Some(0x00));
+}
+
+#[test]
+fn expansion_fds_wavetable_test() {
+    let mut fds = FdsAudio::new();
+
+    //the wavetable is only writable while $4089 bit 7 is set
+    fds.write(0x4040, 0x3F);
+    assert_eq!(fds.read(0x4040), Some(0x40));
+
+    fds.write(0x4089, 0x80);
+    fds.write(0x4040, 0x3F);
+    assert_eq!(fds.read(0x4040), Some(0x7F));
+
+    fds.write(0x4080, 0x80 | 0x20); //direct gain 32
+    assert_eq!(fds.read(0x4090), Some(0x60));
+    assert!(fds.output() > 0.0);
+}
+
+#[test]
+fn expansion_apu_register_test() {
+    let mut apu = Apu::new();
+    apu.register_expansion(box Vrc6::new(false) as Box<ExpansionAudio>);
+    apu.register_expansion(box Namco163::new() as Box<ExpansionAudio>);
+
+    let names = apu.stem_names();
+    assert_eq!(names.len(), CHANNEL_COUNT + 2);
+    assert_eq!(names[CHANNEL_COUNT], "vrc6");
+    assert_eq!(names[CHANNEL_COUNT + 1], "n163");
+
+    //readable registers go through the APU
+    apu.expansion_write(0xF800, 0x00);
+    apu.expansion_write(0x4800, 0x42);
+    assert_eq!(apu.expansion_read(0x4800), Some(0x42));
+    assert_eq!(apu.expansion_read(0x6000), None);
+}
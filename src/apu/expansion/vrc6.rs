@@ -0,0 +1,257 @@
+use nes::{VAddr};
+use nes::state::{StateWriter, StateReader};
+
+use apu::expansion::{ExpansionAudio, PULSE_STEP};
+
+/// # Konami VRC6
+///
+/// from http://wiki.nesdev.com/w/index.php/VRC6_audio
+///
+/// Two pulse channels with 8 duty cycles and a sawtooth.
+///
+/// - $9000 - Pulse 1 MDDD VVVV (mode, duty, volume)
+/// - $9001 - Pulse 1 period low
+/// - $9002 - Pulse 1 E--- PPPP (enable, period high)
+/// - $9003 - Frequency control ---- -ABH (halt all channels with H, A/B scale the periods)
+/// - $A000-$A002 - Pulse 2, same as pulse 1
+/// - $B000 - Saw --AA AAAA (accumulator rate)
+/// - $B001 - Saw period low
+/// - $B002 - Saw E--- PPPP (enable, period high)
+///
+/// Mapper 26 (VRC6b) swaps the A0 and A1 address lines.
+
+struct Vrc6Pulse {
+    mode: bool,
+    duty: u8,
+    volume: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            mode: false,
+            duty: 0,
+            volume: 0,
+            enabled: false,
+            period: 0,
+            timer: 0,
+            step: 15,
+        }
+    }
+
+    fn write(&mut self, reg: uint, val: u8) {
+        match reg {
+            0 => {
+                self.mode = val & 0x80 != 0;
+                self.duty = (val >> 4) & 0x07;
+                self.volume = val & 0x0F;
+            }
+            1 => {
+                self.period = (self.period & 0x0F00) | (val as u16);
+            }
+            2 => {
+                self.period = (self.period & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled { self.step = 15; }
+            }
+            _ => { }
+        }
+    }
+
+    fn clock(&mut self, shift: uint) {
+        if !self.enabled { return; }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = if self.step == 0 { 15 } else { self.step - 1 };
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.mode || self.step <= self.duty) { self.volume } else { 0 }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.mode);
+        w.u8(self.duty);
+        w.u8(self.volume);
+        w.bool(self.enabled);
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u8(self.step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.mode = r.bool();
+        self.duty = r.u8();
+        self.volume = r.u8();
+        self.enabled = r.bool();
+        self.period = r.u16();
+        self.timer = r.u16();
+        self.step = r.u8();
+    }
+}
+
+struct Vrc6Saw {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn new() -> Vrc6Saw {
+        Vrc6Saw {
+            rate: 0,
+            enabled: false,
+            period: 0,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write(&mut self, reg: uint, val: u8) {
+        match reg {
+            0 => {
+                self.rate = val & 0x3F;
+            }
+            1 => {
+                self.period = (self.period & 0x0F00) | (val as u16);
+            }
+            2 => {
+                self.period = (self.period & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => { }
+        }
+    }
+
+    //the accumulator takes the rate every other timer clock, the 7th of those resets it instead
+    fn clock(&mut self, shift: uint) {
+        if !self.enabled { return; }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step & 0x01 == 0 {
+                self.accumulator += self.rate;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.rate);
+        w.bool(self.enabled);
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u8(self.step);
+        w.u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.rate = r.u8();
+        self.enabled = r.bool();
+        self.period = r.u16();
+        self.timer = r.u16();
+        self.step = r.u8();
+        self.accumulator = r.u8();
+    }
+}
+
+pub struct Vrc6 {
+    swap_lines: bool,
+    halt: bool,
+    shift: uint,
+    pulse_1: Vrc6Pulse,
+    pulse_2: Vrc6Pulse,
+    saw: Vrc6Saw,
+}
+
+impl Vrc6 {
+    pub fn new(swap_lines: bool) -> Vrc6 {
+        Vrc6 {
+            swap_lines: swap_lines,
+            halt: false,
+            shift: 0,
+            pulse_1: Vrc6Pulse::new(),
+            pulse_2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6 {
+    fn name(&self) -> &'static str {
+        "vrc6"
+    }
+
+    fn write(&mut self, virtual_address: VAddr, val: u8) {
+        let mut reg = (virtual_address & 0x0003) as uint;
+        if self.swap_lines {
+            reg = ((reg & 0x01) << 1) | ((reg & 0x02) >> 1);
+        }
+
+        match (virtual_address & 0xFFFC, reg) {
+            (0x9000, 3) => {
+                self.halt = val & 0x01 != 0;
+                self.shift = if val & 0x04 != 0 { 8 } else if val & 0x02 != 0 { 4 } else { 0 };
+            }
+            (0x9000, _) => { self.pulse_1.write(reg, val); }
+            (0xA000, _) => { self.pulse_2.write(reg, val); }
+            (0xB000, _) => { self.saw.write(reg, val); }
+            _ => { }
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt { return; }
+
+        self.pulse_1.clock(self.shift);
+        self.pulse_2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    fn output(&self) -> f32 {
+        let level = self.pulse_1.output() as f32 + self.pulse_2.output() as f32 + self.saw.output() as f32;
+        level * PULSE_STEP
+    }
+
+    //the line swap comes from the board, it isn't saved
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.halt);
+        w.uint(self.shift);
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        self.saw.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.halt = r.bool();
+        self.shift = r.uint();
+        self.pulse_1.load_state(r);
+        self.pulse_2.load_state(r);
+        self.saw.load_state(r);
+    }
+}
@@ -0,0 +1,358 @@
+use std::f32::consts::PI;
+
+use nes::{VAddr};
+use nes::state::{StateWriter, StateReader};
+
+use apu::expansion::{ExpansionAudio};
+
+/// # Konami VRC7
+///
+/// from http://wiki.nesdev.com/w/index.php/VRC7_audio
+///
+/// A cut down Yamaha YM2413 (OPLL): six 2-operator FM channels, 15 built in instruments and one
+/// custom instrument.
+///
+/// - $9010 - Register select
+/// - $9030 - Register write
+///
+/// - $00-$07 - Custom instrument, same layout as the PATCHES entries below
+/// - $10-$15 - F-number low 8 bits
+/// - $20-$25 - --ST BBBF, sustain (S), key on (T), block/octave (B), F-number high bit (F)
+/// - $30-$35 - IIII VVVV, instrument (0 is custom) and volume attenuation in 3 dB steps
+///
+/// ## Instrument layout
+///
+/// - 0 - Modulator AM VIB EG KSR MMMM (multiplier)
+/// - 1 - Carrier, same as 0
+/// - 2 - Modulator KSL(2) total level(6)
+/// - 3 - Carrier KSL(2), -, carrier rectify, modulator rectify, feedback(3)
+/// - 4 - Modulator attack(4) decay(4)
+/// - 5 - Carrier attack(4) decay(4)
+/// - 6 - Modulator sustain level(4) release(4)
+/// - 7 - Carrier sustain level(4) release(4)
+///
+/// This is a simplified model of the OPLL. Operators are sine oscillators with modulator
+/// feedback, half wave rectification and an ADSR envelope in dB. Vibrato, tremolo and key
+/// scaling are not emulated, and envelope timing is an approximation of the real rate tables.
+
+//the OPLL makes one sample every 72 of its 3.58 MHz clocks, or every 36 CPU cycles
+static CYCLES_PER_SAMPLE: uint = 36;
+
+static CHANNEL_COUNT: uint = 6;
+
+static PATCHES: [[u8, ..8], ..15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27], //1 Buzzy bell
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12], //2 Guitar
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12], //3 Wurly
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27], //4 Flute
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28], //5 Clarinet
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4], //6 Synth
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07], //7 Trumpet
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17], //8 Organ
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01], //9 Bells
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02], //A Vibes
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12], //B Vibraphone
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16], //C Tutti
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02], //D Fretless
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6], //E Synth bass
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06], //F Sweep
+];
+
+static MULTIPLIERS: [f32, ..16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+static FEEDBACK: [f32, ..8] = [
+    0.0, PI / 16.0, PI / 8.0, PI / 4.0, PI / 2.0, PI, PI * 2.0, PI * 4.0,
+];
+
+static SILENCE_DB: f32 = 48.0;
+static RATE_SCALE: f32 = 0.000015; //dB per sample at rate 0, doubles per rate step
+static KEY_OFF_SUSTAIN_RATE: uint = 5;
+
+static VRC7_GAIN: f32 = 0.12;
+
+#[deriving(PartialEq, Show)]
+enum EnvelopePhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+struct Operator {
+    phase: f32, //0.0 - 1.0
+    envelope: EnvelopePhase,
+    attenuation: f32, //in dB
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0.0,
+            envelope: Release,
+            attenuation: SILENCE_DB,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.envelope = Attack;
+    }
+
+    fn key_off(&mut self) {
+        self.envelope = Release;
+    }
+
+    //regs are this operator's multiplier byte, attack/decay byte and sustain/release byte
+    fn clock_envelope(&mut self, multiplier: u8, attack_decay: u8, sustain_release: u8, sustain: bool) {
+        let sustained_tone = multiplier & 0x20 != 0;
+        let attack = (attack_decay >> 4) as uint;
+        let decay = (attack_decay & 0x0F) as uint;
+        let sustain_level = (sustain_release >> 4) as f32 * 3.0;
+        let release = (sustain_release & 0x0F) as uint;
+
+        match self.envelope {
+            Attack => {
+                if attack == 15 {
+                    self.attenuation = 0.0;
+                } else {
+                    self.attenuation -= rate_step(attack) * 8.0;
+                }
+                if self.attenuation <= 0.0 {
+                    self.attenuation = 0.0;
+                    self.envelope = Decay;
+                }
+            }
+            Decay => {
+                self.attenuation += rate_step(decay);
+                if self.attenuation >= sustain_level {
+                    self.attenuation = sustain_level;
+                    self.envelope = Sustain;
+                }
+            }
+            Sustain => {
+                //percussive tones keep decaying at the release rate
+                if !sustained_tone {
+                    self.attenuation += rate_step(release);
+                }
+            }
+            Release => {
+                let rate = if sustain { KEY_OFF_SUSTAIN_RATE } else { release };
+                self.attenuation += rate_step(rate);
+            }
+        }
+
+        if self.attenuation > SILENCE_DB { self.attenuation = SILENCE_DB; }
+    }
+
+    fn amplitude(&self, extra_attenuation: f32) -> f32 {
+        let db = self.attenuation + extra_attenuation;
+        if db >= SILENCE_DB { 0.0 } else { 10.0f32.powf(-db / 20.0) }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.f32(self.phase);
+        w.u8(match self.envelope { Attack => 0, Decay => 1, Sustain => 2, Release => 3 });
+        w.f32(self.attenuation);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.phase = r.f32();
+        self.envelope = match r.u8() { 0 => Attack, 1 => Decay, 2 => Sustain, _ => Release };
+        self.attenuation = r.f32();
+    }
+}
+
+fn rate_step(rate: uint) -> f32 {
+    if rate == 0 { 0.0 } else { RATE_SCALE * ((1u << rate) as f32) }
+}
+
+struct Channel {
+    fnum: u16,
+    block: u8,
+    key: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    feedback: [f32, ..2], //last two modulator outputs
+    output: f32,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            fnum: 0,
+            block: 0,
+            key: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0, ..2],
+            output: 0.0,
+        }
+    }
+
+    fn update(&mut self, patch: &[u8, ..8]) {
+        self.modulator.clock_envelope(patch[0], patch[4], patch[6], self.sustain);
+        self.carrier.clock_envelope(patch[1], patch[5], patch[7], self.sustain);
+
+        //phase increment per sample is fnum * 2^block / 2^19, times the operator's multiplier
+        let base = (self.fnum as f32) * ((1u << self.block as uint) as f32) / 524288.0;
+        self.modulator.phase = (self.modulator.phase + base * MULTIPLIERS[(patch[0] & 0x0F) as uint]) % 1.0;
+        self.carrier.phase = (self.carrier.phase + base * MULTIPLIERS[(patch[1] & 0x0F) as uint]) % 1.0;
+
+        let feedback = (self.feedback[0] + self.feedback[1]) / 2.0 * FEEDBACK[(patch[3] & 0x07) as uint];
+        let total_level = (patch[2] & 0x3F) as f32 * 0.75;
+        let mut modulator = (2.0 * PI * self.modulator.phase + feedback).sin() * self.modulator.amplitude(total_level);
+        if patch[3] & 0x08 != 0 && modulator < 0.0 { modulator = 0.0; }
+        self.feedback[1] = self.feedback[0];
+        self.feedback[0] = modulator;
+
+        let volume = (self.volume as f32) * 3.0;
+        let mut carrier = (2.0 * PI * (self.carrier.phase + modulator)).sin() * self.carrier.amplitude(volume);
+        if patch[3] & 0x10 != 0 && carrier < 0.0 { carrier = 0.0; }
+
+        self.output = carrier;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.fnum);
+        w.u8(self.block);
+        w.bool(self.key);
+        w.bool(self.sustain);
+        w.u8(self.instrument);
+        w.u8(self.volume);
+        self.modulator.save_state(w);
+        self.carrier.save_state(w);
+        w.f32(self.feedback[0]);
+        w.f32(self.feedback[1]);
+        w.f32(self.output);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.fnum = r.u16() & 0x1FF;
+        self.block = r.u8() & 0x07;
+        self.key = r.bool();
+        self.sustain = r.bool();
+        self.instrument = r.u8() & 0x0F;
+        self.volume = r.u8() & 0x0F;
+        self.modulator.load_state(r);
+        self.carrier.load_state(r);
+        self.feedback[0] = r.f32();
+        self.feedback[1] = r.f32();
+        self.output = r.f32();
+    }
+}
+
+pub struct Vrc7 {
+    address: u8,
+    custom: [u8, ..8],
+    channels: [Channel, ..CHANNEL_COUNT],
+    timer: uint,
+}
+
+impl Vrc7 {
+    pub fn new() -> Vrc7 {
+        Vrc7 {
+            address: 0,
+            custom: [0u8, ..8],
+            channels: [
+                Channel::new(), Channel::new(), Channel::new(),
+                Channel::new(), Channel::new(), Channel::new(),
+            ],
+            timer: 0,
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, val: u8) {
+        let index = (reg & 0x0F) as uint;
+
+        match reg & 0xF0 {
+            0x00 => {
+                if index < 8 { self.custom[index] = val; }
+            }
+            0x10 if index < CHANNEL_COUNT => {
+                let channel = &mut self.channels[index];
+                channel.fnum = (channel.fnum & 0x100) | (val as u16);
+            }
+            0x20 if index < CHANNEL_COUNT => {
+                let channel = &mut self.channels[index];
+                channel.fnum = (channel.fnum & 0x0FF) | ((val as u16 & 0x01) << 8);
+                channel.block = (val >> 1) & 0x07;
+                channel.sustain = val & 0x20 != 0;
+
+                let key = val & 0x10 != 0;
+                if key && !channel.key {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                } else if !key && channel.key {
+                    channel.modulator.key_off();
+                    channel.carrier.key_off();
+                }
+                channel.key = key;
+            }
+            0x30 if index < CHANNEL_COUNT => {
+                let channel = &mut self.channels[index];
+                channel.instrument = val >> 4;
+                channel.volume = val & 0x0F;
+            }
+            _ => { }
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc7 {
+    fn name(&self) -> &'static str {
+        "vrc7"
+    }
+
+    fn write(&mut self, virtual_address: VAddr, val: u8) {
+        if virtual_address == 0x9010 {
+            self.address = val;
+        } else if virtual_address == 0x9030 {
+            let reg = self.address;
+            self.write_register(reg, val);
+        }
+    }
+
+    fn clock(&mut self) {
+        self.timer += 1;
+        if self.timer < CYCLES_PER_SAMPLE { return; }
+        self.timer = 0;
+
+        for channel in self.channels.mut_iter() {
+            let patch =
+                if channel.instrument == 0 { self.custom }
+                else { PATCHES[(channel.instrument - 1) as uint] };
+            channel.update(&patch);
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let sum = self.channels.iter().fold(0.0, |sum, channel| sum + channel.output);
+        sum * VRC7_GAIN
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.address);
+        w.bytes(self.custom);
+        for channel in self.channels.iter() {
+            channel.save_state(w);
+        }
+        w.uint(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.address = r.u8();
+        r.read_into(self.custom);
+        for channel in self.channels.mut_iter() {
+            channel.load_state(r);
+        }
+        self.timer = r.uint();
+    }
+}
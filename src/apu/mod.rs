@@ -0,0 +1,1119 @@
+use std::mem;
+
+use nes::{VAddr};
+use nes::{Region};
+use nes::state::{StateWriter, StateReader};
+
+pub use self::wav::{PcmWriter, PcmFormat, Wav, RawPcm};
+pub use self::expansion::{ExpansionAudio};
+
+pub mod wav;
+pub mod expansion;
+
+#[cfg(test)]
+mod test;
+
+/// # APU
+///
+/// This is from http://wiki.nesdev.com/w/index.php/APU
+///
+/// The APU has five channels, two pulse waves, a triangle wave, noise and a delta modulation
+/// channel (DMC). Every channel is driven off the CPU clock, and a frame counter clocks the
+/// envelopes, sweeps, length counters and the triangle's linear counter roughly 240 times a
+/// second.
+///
+/// # Registers
+///
+/// - $4000-$4003 - Pulse 1
+/// - $4004-$4007 - Pulse 2
+/// - $4008-$400B - Triangle
+/// - $400C-$400F - Noise
+/// - $4010-$4013 - DMC
+/// - $4015       - Channel enable (write), channel status (read)
+/// - $4017       - Frame counter (write only, reads go to controller 2)
+///
+/// Cartridges can add their own sound chips, see the expansion module. Those are registered
+/// with register_expansion and mixed in after the APU's own channels.
+
+pub static CPU_CLOCK_RATE: f64 = 1789773.0; //NTSC, in Hz
+pub static DEFAULT_SAMPLE_RATE: uint = 44100;
+
+pub static CHANNEL_COUNT: uint = 5;
+pub static CHANNEL_NAMES: [&'static str, ..CHANNEL_COUNT] = [
+    "pulse1",
+    "pulse2",
+    "triangle",
+    "noise",
+    "dmc",
+];
+
+static LENGTH_TABLE: [u8, ..32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+static DUTY_TABLE: [[u8, ..8], ..4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], //12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], //25%
+    [0, 1, 1, 1, 1, 0, 0, 0], //50%
+    [1, 0, 0, 1, 1, 1, 1, 1], //25% negated
+];
+
+static TRIANGLE_SEQUENCE: [u8, ..32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+
+//in CPU cycles
+static NOISE_PERIOD_TABLE: [u16, ..16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+static PAL_NOISE_PERIOD_TABLE: [u16, ..16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+//in CPU cycles
+static DMC_RATE_TABLE: [u16, ..16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+static PAL_DMC_RATE_TABLE: [u16, ..16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+//CPU cycles at which the frame counter steps, the last entry also resets the sequence
+static FRAME_STEPS_4: [uint, ..4] = [7457, 14913, 22371, 29829];
+static FRAME_STEPS_5: [uint, ..4] = [7457, 14913, 22371, 37281];
+static PAL_FRAME_STEPS_4: [uint, ..4] = [8313, 16627, 24939, 33253];
+static PAL_FRAME_STEPS_5: [uint, ..4] = [8313, 16627, 24939, 41565];
+
+struct Envelope {
+    start: bool,
+    loop_flag: bool, //shared with the length counter halt flag
+    constant: bool,
+    volume: u8, //also the divider period
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            start: false,
+            loop_flag: false,
+            constant: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // --LC VVVV
+    pub fn write(&mut self, val: u8) {
+        self.loop_flag = val & 0x20 != 0;
+        self.constant = val & 0x10 != 0;
+        self.volume = val & 0x0F;
+    }
+
+    //clocked by the frame counter every quarter frame
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.loop_flag {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
+
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    count: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            count: 0,
+        }
+    }
+
+    //the top five bits of the channel's fourth register index into LENGTH_TABLE
+    pub fn load(&mut self, val: u8) {
+        if self.enabled {
+            self.count = LENGTH_TABLE[(val >> 3) as uint];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled { self.count = 0; }
+    }
+
+    //clocked by the frame counter every half frame
+    pub fn clock(&mut self) {
+        if !self.halt && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.count > 0
+    }
+}
+
+struct Pulse {
+    ones_complement: bool, //pulse 1 negates its sweep with one's complement, pulse 2 with two's
+    envelope: Envelope,
+    length: LengthCounter,
+    duty: u8,
+    duty_pos: u8,
+    timer_period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement: ones_complement,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            duty: 0,
+            duty_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    pub fn write(&mut self, reg: uint, val: u8) {
+        match reg {
+            // DDLC VVVV
+            0 => {
+                self.duty = val >> 6;
+                self.length.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 0x07;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 0x07;
+                self.sweep_reload = true;
+            }
+            // TTTT TTTT
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | (val as u16);
+            }
+            // LLLL LTTT
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((val as u16 & 0x07) << 8);
+                self.length.load(val);
+                self.duty_pos = 0;
+                self.envelope.start = true;
+            }
+            _ => { error!("Impossible pulse register"); }
+        }
+    }
+
+    //clocked every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let period = self.timer_period as int;
+        let change = period >> (self.sweep_shift as uint);
+        let target =
+            if self.sweep_negate {
+                period - change - if self.ones_complement { 1 } else { 0 }
+            } else {
+                period + change
+            };
+
+        if target < 0 { 0 } else { target as u16 }
+    }
+
+    fn is_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x07FF
+    }
+
+    //clocked by the frame counter every half frame
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted() {
+            self.timer_period = self.sweep_target();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.is_muted() ||
+            DUTY_TABLE[self.duty as uint][self.duty_pos as uint] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+struct Triangle {
+    length: LengthCounter,
+    control: bool, //shared with the length counter halt flag
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    timer_period: u16,
+    timer: u16,
+    seq_pos: u8,
+}
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle {
+            length: LengthCounter::new(),
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            timer_period: 0,
+            timer: 0,
+            seq_pos: 0,
+        }
+    }
+
+    pub fn write(&mut self, reg: uint, val: u8) {
+        match reg {
+            // CRRR RRRR
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = val & 0x7F;
+            }
+            1 => { } //unused
+            // TTTT TTTT
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | (val as u16);
+            }
+            // LLLL LTTT
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((val as u16 & 0x07) << 8);
+                self.length.load(val);
+                self.linear_reload = true;
+            }
+            _ => { error!("Impossible triangle register"); }
+        }
+    }
+
+    //clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.is_active() && self.linear_counter > 0 {
+                self.seq_pos = (self.seq_pos + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    //clocked by the frame counter every quarter frame
+    pub fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control { self.linear_reload = false; }
+    }
+
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.seq_pos as uint]
+    }
+}
+
+struct Noise {
+    envelope: Envelope,
+    length: LengthCounter,
+    mode: bool,
+    period_table: &'static [u16, ..16],
+    timer_period: u16,
+    timer: u16,
+    shift: u16,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            mode: false,
+            period_table: &NOISE_PERIOD_TABLE,
+            timer_period: NOISE_PERIOD_TABLE[0],
+            timer: 0,
+            shift: 1,
+        }
+    }
+
+    pub fn write(&mut self, reg: uint, val: u8) {
+        match reg {
+            // --LC VVVV
+            0 => {
+                self.length.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            1 => { } //unused
+            // M--- PPPP
+            2 => {
+                self.mode = val & 0x80 != 0;
+                self.timer_period = self.period_table[(val & 0x0F) as uint];
+            }
+            // LLLL L---
+            3 => {
+                self.length.load(val);
+                self.envelope.start = true;
+            }
+            _ => { error!("Impossible noise register"); }
+        }
+    }
+
+    //clocked every CPU cycle, the period table is already in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.shift & 0x01 != 0 || !self.length.is_active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+struct Dmc {
+    enabled: bool,
+    irq_enabled: bool,
+    irq: bool,
+    loop_flag: bool,
+    rate_table: &'static [u16, ..16],
+    rate: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: VAddr,
+    sample_length: u16,
+    current_address: VAddr,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            enabled: false,
+            irq_enabled: false,
+            irq: false,
+            loop_flag: false,
+            rate_table: &DMC_RATE_TABLE,
+            rate: DMC_RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    pub fn write(&mut self, reg: uint, val: u8) {
+        match reg {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                if !self.irq_enabled { self.irq = false; }
+                self.loop_flag = val & 0x40 != 0;
+                self.rate = self.rate_table[(val & 0x0F) as uint];
+            }
+            // -DDD DDDD
+            1 => {
+                self.output_level = val & 0x7F;
+            }
+            // AAAA AAAA, address = %11AAAAAA.AA000000
+            2 => {
+                self.sample_address = 0xC000 | ((val as VAddr) << 6);
+            }
+            // LLLL LLLL, length = %LLLL.LLLL0001
+            3 => {
+                self.sample_length = ((val as u16) << 4) | 0x0001;
+            }
+            _ => { error!("Impossible dmc register"); }
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    //the memory reader wants a byte from the CPU bus when the sample buffer is empty
+    pub fn fetch_address(&self) -> Option<VAddr> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill(&mut self, val: u8) {
+        self.sample_buffer = Some(val);
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    //clocked every CPU cycle, the rate table is already in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.rate - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift & 0x01 != 0 {
+                if self.output_level <= 125 { self.output_level += 2; }
+            } else {
+                if self.output_level >= 2 { self.output_level -= 2; }
+            }
+        }
+
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(val) => {
+                    self.silence = false;
+                    self.shift = val;
+                }
+                None => { self.silence = true; }
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+//approximation of the APU's nonlinear mixer, outputs in the range 0.0 - 1.0
+//from http://wiki.nesdev.com/w/index.php/APU_Mixer
+fn pulse_mix(pulse_1: f32, pulse_2: f32) -> f32 {
+    if pulse_1 + pulse_2 == 0.0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / (pulse_1 + pulse_2) + 100.0)
+    }
+}
+
+fn tnd_mix(triangle: f32, noise: f32, dmc: f32) -> f32 {
+    let sum = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+    if sum == 0.0 {
+        0.0
+    } else {
+        159.79 / (1.0 / sum + 100.0)
+    }
+}
+
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    expansion: Vec<Box<ExpansionAudio>>,
+
+    //frame counter
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: uint,
+    frame_steps_4: &'static [uint, ..4],
+    frame_steps_5: &'static [uint, ..4],
+
+    cycle: u64,
+
+    //output
+    clock_rate: f64,
+    sample_rate: uint,
+    sample_clock: f64,
+    sample_sum: f32,
+    stem_sums: Vec<f32>,
+    sum_count: uint,
+    record_stems: bool,
+    samples: Vec<f32>,
+    stems: Vec<Vec<f32>>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+
+            expansion: Vec::new(),
+
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_steps_4: &FRAME_STEPS_4,
+            frame_steps_5: &FRAME_STEPS_5,
+
+            cycle: 0,
+
+            clock_rate: CPU_CLOCK_RATE,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0.0,
+            sample_sum: 0.0,
+            stem_sums: Vec::from_elem(CHANNEL_COUNT, 0.0),
+            sum_count: 0,
+            record_stems: false,
+            samples: Vec::new(),
+            stems: Vec::from_fn(CHANNEL_COUNT, |_| Vec::new()),
+        }
+    }
+
+    /// PAL has its own noise, DMC and frame counter timing, and every region has its own CPU
+    /// clock to resample from.
+    pub fn set_region(&mut self, region: Region) {
+        if region.pal_apu() {
+            self.noise.period_table = &PAL_NOISE_PERIOD_TABLE;
+            self.dmc.rate_table = &PAL_DMC_RATE_TABLE;
+            self.frame_steps_4 = &PAL_FRAME_STEPS_4;
+            self.frame_steps_5 = &PAL_FRAME_STEPS_5;
+        } else {
+            self.noise.period_table = &NOISE_PERIOD_TABLE;
+            self.dmc.rate_table = &DMC_RATE_TABLE;
+            self.frame_steps_4 = &FRAME_STEPS_4;
+            self.frame_steps_5 = &FRAME_STEPS_5;
+        }
+        self.clock_rate = region.cpu_clock_rate();
+    }
+
+    pub fn sample_rate(&self) -> uint {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: uint) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Adds a cartridge sound chip to the mix. It gets its own stem after the APU channels.
+    pub fn register_expansion(&mut self, chip: Box<ExpansionAudio>) {
+        self.expansion.push(chip);
+        self.stems.push(Vec::new());
+        self.stem_sums.push(0.0);
+    }
+
+    //the names of the buffers returned by take_stems
+    pub fn stem_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = CHANNEL_NAMES.iter().map(|&name| name).collect();
+        for chip in self.expansion.iter() {
+            names.push(chip.name());
+        }
+        names
+    }
+
+    //when set, every channel's output is also kept on its own, see take_stems
+    pub fn set_record_stems(&mut self, record_stems: bool) {
+        self.record_stems = record_stems;
+        for stem in self.stems.mut_iter() {
+            stem.clear();
+        }
+    }
+
+    //returns the mixed samples generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        let mut samples = Vec::new();
+        mem::swap(&mut samples, &mut self.samples);
+        samples
+    }
+
+    //returns one buffer per channel, in stem_names order, generated since the last call
+    pub fn take_stems(&mut self) -> Vec<Vec<f32>> {
+        let mut stems = Vec::from_fn(self.stems.len(), |_| Vec::new());
+        mem::swap(&mut stems, &mut self.stems);
+        stems
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    pub fn write_register(&mut self, virtual_address: VAddr, val: u8) {
+        match virtual_address {
+            0x4000 | 0x4001 | 0x4002 | 0x4003 => {
+                self.pulse_1.write((virtual_address - 0x4000) as uint, val);
+            }
+            0x4004 | 0x4005 | 0x4006 | 0x4007 => {
+                self.pulse_2.write((virtual_address - 0x4004) as uint, val);
+            }
+            0x4008 | 0x4009 | 0x400A | 0x400B => {
+                self.triangle.write((virtual_address - 0x4008) as uint, val);
+            }
+            0x400C | 0x400D | 0x400E | 0x400F => {
+                self.noise.write((virtual_address - 0x400C) as uint, val);
+            }
+            0x4010 | 0x4011 | 0x4012 | 0x4013 => {
+                self.dmc.write((virtual_address - 0x4010) as uint, val);
+            }
+            // ---D NT21
+            0x4015 => {
+                self.pulse_1.length.set_enabled(val & 0x01 != 0);
+                self.pulse_2.length.set_enabled(val & 0x02 != 0);
+                self.triangle.length.set_enabled(val & 0x04 != 0);
+                self.noise.length.set_enabled(val & 0x08 != 0);
+                self.dmc.set_enabled(val & 0x10 != 0);
+            }
+            // MI-- ----
+            0x4017 => {
+                self.five_step = val & 0x80 != 0;
+                self.irq_inhibit = val & 0x40 != 0;
+                if self.irq_inhibit { self.frame_irq = false; }
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => { error!("Not an APU register: {:X}", virtual_address); }
+        }
+    }
+
+    // IF-D NT21
+    pub fn read_status(&mut self) -> u8 {
+        let reg = self.peek_status();
+        self.frame_irq = false;
+
+        reg
+    }
+
+    //$4015 without acknowledging the frame IRQ
+    pub fn peek_status(&self) -> u8 {
+        let mut reg: u8 = 0;
+        if self.pulse_1.length.is_active() { reg |= 0x01; }
+        if self.pulse_2.length.is_active() { reg |= 0x02; }
+        if self.triangle.length.is_active() { reg |= 0x04; }
+        if self.noise.length.is_active() { reg |= 0x08; }
+        if self.dmc.bytes_remaining > 0 { reg |= 0x10; }
+        if self.frame_irq { reg |= 0x40; }
+        if self.dmc.irq { reg |= 0x80; }
+        reg
+    }
+
+    //expansion chips see every write to cartridge space and decode their own registers
+    pub fn expansion_write(&mut self, virtual_address: VAddr, val: u8) {
+        for chip in self.expansion.mut_iter() {
+            chip.write(virtual_address, val);
+        }
+    }
+
+    pub fn expansion_read(&mut self, virtual_address: VAddr) -> Option<u8> {
+        for chip in self.expansion.mut_iter() {
+            match chip.read(virtual_address) {
+                Some(val) => { return Some(val); }
+                None => { }
+            }
+        }
+        None
+    }
+
+    pub fn dmc_fetch_address(&self) -> Option<VAddr> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.fill(val);
+    }
+
+    //advances the APU by one CPU cycle. The DMC may want a byte from memory afterwards, the CPU
+    //checks dmc_fetch_address and hands it over with dmc_fill
+    pub fn clock(&mut self) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle & 0x01 == 1 {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        for chip in self.expansion.mut_iter() {
+            chip.clock();
+        }
+
+        self.clock_frame_counter();
+        self.clock_sample();
+
+        self.cycle += 1;
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+
+        let steps = if self.five_step { self.frame_steps_5 } else { self.frame_steps_4 };
+
+        if self.frame_cycle == steps[0] || self.frame_cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if self.frame_cycle == steps[1] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        } else if self.frame_cycle == steps[3] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+            if !self.five_step && !self.irq_inhibit { self.frame_irq = true; }
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.length.clock();
+        self.pulse_2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    //box filters the channel outputs down to the sample rate
+    fn clock_sample(&mut self) {
+        let pulse_1 = self.pulse_1.output() as f32;
+        let pulse_2 = self.pulse_2.output() as f32;
+        let triangle = self.triangle.output() as f32;
+        let noise = self.noise.output() as f32;
+        let dmc = self.dmc.output() as f32;
+
+        self.sample_sum += pulse_mix(pulse_1, pulse_2) + tnd_mix(triangle, noise, dmc);
+        for chip in self.expansion.iter() {
+            self.sample_sum += chip.output();
+        }
+
+        if self.record_stems {
+            let stem_sums = self.stem_sums.as_mut_slice();
+            stem_sums[0] += pulse_mix(pulse_1, 0.0);
+            stem_sums[1] += pulse_mix(0.0, pulse_2);
+            stem_sums[2] += tnd_mix(triangle, 0.0, 0.0);
+            stem_sums[3] += tnd_mix(0.0, noise, 0.0);
+            stem_sums[4] += tnd_mix(0.0, 0.0, dmc);
+            for (i, chip) in self.expansion.iter().enumerate() {
+                stem_sums[CHANNEL_COUNT + i] += chip.output();
+            }
+        }
+        self.sum_count += 1;
+
+        self.sample_clock += 1.0;
+        let cycles_per_sample = self.clock_rate / (self.sample_rate as f64);
+        if self.sample_clock >= cycles_per_sample {
+            self.sample_clock -= cycles_per_sample;
+
+            let count = self.sum_count as f32;
+            self.samples.push(self.sample_sum / count);
+            self.sample_sum = 0.0;
+
+            if self.record_stems {
+                for (stem, sum) in self.stems.mut_iter().zip(self.stem_sums.mut_iter()) {
+                    stem.push(*sum / count);
+                    *sum = 0.0;
+                }
+            }
+
+            self.sum_count = 0;
+        }
+    }
+}
+
+// save states, see nes::state. The region tables and the output settings aren't saved, they
+// come from the console the state is loaded into
+
+impl Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.start);
+        w.bool(self.loop_flag);
+        w.bool(self.constant);
+        w.u8(self.volume);
+        w.u8(self.divider);
+        w.u8(self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.start = r.bool();
+        self.loop_flag = r.bool();
+        self.constant = r.bool();
+        self.volume = r.u8();
+        self.divider = r.u8();
+        self.decay = r.u8();
+    }
+}
+
+impl LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.halt);
+        w.u8(self.count);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.enabled = r.bool();
+        self.halt = r.bool();
+        self.count = r.u8();
+    }
+}
+
+impl Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_period);
+        w.bool(self.sweep_negate);
+        w.u8(self.sweep_shift);
+        w.bool(self.sweep_reload);
+        w.u8(self.sweep_divider);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.envelope.load_state(r);
+        self.length.load_state(r);
+        self.duty = r.u8();
+        self.duty_pos = r.u8();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+        self.sweep_enabled = r.bool();
+        self.sweep_period = r.u8();
+        self.sweep_negate = r.bool();
+        self.sweep_shift = r.u8();
+        self.sweep_reload = r.bool();
+        self.sweep_divider = r.u8();
+    }
+}
+
+impl Triangle {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.length.save_state(w);
+        w.bool(self.control);
+        w.u8(self.linear_reload_value);
+        w.u8(self.linear_counter);
+        w.bool(self.linear_reload);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u8(self.seq_pos);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.length.load_state(r);
+        self.control = r.bool();
+        self.linear_reload_value = r.u8();
+        self.linear_counter = r.u8();
+        self.linear_reload = r.bool();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+        self.seq_pos = r.u8();
+    }
+}
+
+impl Noise {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.bool(self.mode);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u16(self.shift);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.envelope.load_state(r);
+        self.length.load_state(r);
+        self.mode = r.bool();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+        self.shift = r.u16();
+    }
+}
+
+impl Dmc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq);
+        w.bool(self.loop_flag);
+        w.u16(self.rate);
+        w.u16(self.timer);
+        w.u8(self.output_level);
+        w.u16(self.sample_address);
+        w.u16(self.sample_length);
+        w.u16(self.current_address);
+        w.u16(self.bytes_remaining);
+        w.bool(self.sample_buffer.is_some());
+        w.u8(self.sample_buffer.unwrap_or(0));
+        w.u8(self.shift);
+        w.u8(self.bits_remaining);
+        w.bool(self.silence);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.enabled = r.bool();
+        self.irq_enabled = r.bool();
+        self.irq = r.bool();
+        self.loop_flag = r.bool();
+        self.rate = r.u16();
+        self.timer = r.u16();
+        self.output_level = r.u8();
+        self.sample_address = r.u16();
+        self.sample_length = r.u16();
+        self.current_address = r.u16();
+        self.bytes_remaining = r.u16();
+        let has_sample = r.bool();
+        let sample = r.u8();
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.shift = r.u8();
+        self.bits_remaining = r.u8();
+        self.silence = r.bool();
+    }
+}
+
+impl Apu {
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+
+        w.bool(self.five_step);
+        w.bool(self.irq_inhibit);
+        w.bool(self.frame_irq);
+        w.uint(self.frame_cycle);
+        w.u64(self.cycle);
+
+        //keeps the resampler in phase, so a loaded state produces the same samples
+        w.f64(self.sample_clock);
+        w.f32(self.sample_sum);
+        w.uint(self.sum_count);
+
+        w.u32(self.expansion.len() as u32);
+        for chip in self.expansion.iter() {
+            chip.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.pulse_1.load_state(r);
+        self.pulse_2.load_state(r);
+        self.triangle.load_state(r);
+        self.noise.load_state(r);
+        self.dmc.load_state(r);
+
+        self.five_step = r.bool();
+        self.irq_inhibit = r.bool();
+        self.frame_irq = r.bool();
+        self.frame_cycle = r.uint();
+        self.cycle = r.u64();
+
+        self.sample_clock = r.f64();
+        self.sample_sum = r.f32();
+        self.sum_count = r.uint();
+        self.samples.clear();
+
+        let chips = r.u32() as uint;
+        for chip in self.expansion.mut_iter().take(chips) {
+            chip.load_state(r);
+        }
+    }
+}
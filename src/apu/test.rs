@@ -0,0 +1,109 @@
+use apu::{Apu, DEFAULT_SAMPLE_RATE, CPU_CLOCK_RATE, CHANNEL_COUNT};
+use apu::{pulse_mix, tnd_mix};
+use apu::wav::to_i16;
+
+#[test]
+fn apu_length_counter_status_test() {
+    let mut apu = Apu::new();
+
+    //length counters ignore loads while the channel is disabled
+    apu.write_register(0x4003, 0x08);
+    assert_eq!(apu.read_status() & 0x01, 0x00);
+
+    apu.write_register(0x4015, 0x0F);
+    apu.write_register(0x4003, 0x08); //LENGTH_TABLE[1] = 254
+    apu.write_register(0x400B, 0x08);
+    assert_eq!(apu.read_status() & 0x0F, 0x05);
+    assert_eq!(apu.pulse_1.length.count, 254);
+
+    apu.write_register(0x4015, 0x00);
+    assert_eq!(apu.read_status() & 0x0F, 0x00);
+}
+
+#[test]
+fn apu_frame_counter_test() {
+    let mut apu = Apu::new();
+    apu.write_register(0x4015, 0x01);
+    apu.write_register(0x4003, 0x18); //LENGTH_TABLE[3] = 2
+
+    //two half frames per 4-step sequence
+    for _ in range(0, 14913u) { apu.clock(); }
+    assert_eq!(apu.pulse_1.length.count, 1);
+
+    for _ in range(0, 29829u - 14913) { apu.clock(); }
+    assert_eq!(apu.pulse_1.length.count, 0);
+    assert_eq!(apu.read_status() & 0x40, 0x40);
+    assert_eq!(apu.read_status() & 0x40, 0x00);
+
+    //5-step mode never raises the frame IRQ
+    apu.write_register(0x4017, 0x80);
+    for _ in range(0, 37281u) { apu.clock(); }
+    assert_eq!(apu.read_status() & 0x40, 0x00);
+}
+
+#[test]
+fn apu_pulse_sweep_mute_test() {
+    let mut apu = Apu::new();
+    apu.write_register(0x4015, 0x01);
+    apu.write_register(0x4000, 0xBF); //50% duty, halt, constant volume 15
+
+    //periods below 8 are muted
+    apu.write_register(0x4002, 0x07);
+    apu.write_register(0x4003, 0x08);
+    assert!(apu.pulse_1.is_muted());
+
+    //so are sweep targets above $7FF, even with the sweep disabled
+    apu.write_register(0x4001, 0x01);
+    apu.write_register(0x4002, 0xFF);
+    apu.write_register(0x4003, 0x0F);
+    assert!(apu.pulse_1.is_muted());
+
+    apu.write_register(0x4003, 0x0B);
+    assert!(!apu.pulse_1.is_muted());
+}
+
+#[test]
+fn apu_dmc_fetch_test() {
+    let mut apu = Apu::new();
+    apu.write_register(0x4012, 0x01); //$C040
+    apu.write_register(0x4013, 0x00); //1 byte
+    assert_eq!(apu.dmc_fetch_address(), None);
+
+    apu.write_register(0x4015, 0x10);
+    assert_eq!(apu.dmc_fetch_address(), Some(0xC040));
+    assert_eq!(apu.read_status() & 0x10, 0x10);
+
+    apu.dmc_fill(0xFF);
+    assert_eq!(apu.dmc_fetch_address(), None);
+    assert_eq!(apu.read_status() & 0x10, 0x00);
+}
+
+#[test]
+fn apu_sample_rate_test() {
+    let mut apu = Apu::new();
+    apu.set_record_stems(true);
+
+    for _ in range(0, CPU_CLOCK_RATE as uint) { apu.clock(); }
+
+    let samples = apu.take_samples();
+    assert!(samples.len() >= DEFAULT_SAMPLE_RATE - 1 && samples.len() <= DEFAULT_SAMPLE_RATE);
+    assert!(apu.take_samples().is_empty());
+
+    let stems = apu.take_stems();
+    assert_eq!(stems.len(), CHANNEL_COUNT);
+    for stem in stems.iter() {
+        assert_eq!(stem.len(), samples.len());
+    }
+}
+
+#[test]
+fn apu_mixer_test() {
+    assert_eq!(pulse_mix(0.0, 0.0), 0.0);
+    assert_eq!(tnd_mix(0.0, 0.0, 0.0), 0.0);
+    assert!(pulse_mix(15.0, 15.0) + tnd_mix(15.0, 15.0, 127.0) <= 1.0);
+
+    assert_eq!(to_i16(0.0), 0);
+    assert_eq!(to_i16(1.0), 32767);
+    assert_eq!(to_i16(2.0), 32767);
+    assert_eq!(to_i16(-2.0), -32767);
+}
@@ -0,0 +1,106 @@
+use std::io::{File, IoResult, SeekSet};
+
+/// # PCM output
+///
+/// Writes 16 bit signed little endian samples, either as a canonical WAV file or as headerless
+/// raw PCM. WAV layout from http://soundfile.sapp.org/doc/WaveFormat/
+///
+///  offset  size  field
+///  0       4     "RIFF"
+///  4       4     36 + data size
+///  8       4     "WAVE"
+///  12      4     "fmt "
+///  16      4     16 (PCM fmt chunk size)
+///  20      2     1 (PCM)
+///  22      2     channel count
+///  24      4     sample rate
+///  28      4     byte rate
+///  32      2     block align
+///  34      2     bits per sample
+///  36      4     "data"
+///  40      4     data size
+///  44            samples
+
+static WAV_HEADER_SIZE: u32 = 44;
+static BITS_PER_SAMPLE: u16 = 16;
+
+#[deriving(PartialEq, Show)]
+pub enum PcmFormat {
+    Wav,
+    RawPcm,
+}
+
+pub struct PcmWriter {
+    file: File,
+    format: PcmFormat,
+    channels: u16,
+    data_size: u32,
+}
+
+impl PcmWriter {
+    pub fn create(path: &Path, format: PcmFormat, sample_rate: uint, channels: uint) -> IoResult<PcmWriter> {
+        let mut writer = PcmWriter {
+            file: try!(File::create(path)),
+            format: format,
+            channels: channels as u16,
+            data_size: 0,
+        };
+
+        if writer.format == Wav {
+            try!(writer.write_header(sample_rate as u32));
+        }
+
+        Ok(writer)
+    }
+
+    //samples are interleaved when there is more than one channel, and expected in -1.0 - 1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> IoResult<()> {
+        for &sample in samples.iter() {
+            try!(self.file.write_le_i16(to_i16(sample)));
+        }
+        self.data_size += (samples.len() * 2) as u32;
+
+        Ok(())
+    }
+
+    //patches the WAV header with the final sizes
+    pub fn finish(mut self) -> IoResult<()> {
+        if self.format == Wav {
+            try!(self.file.seek(4, SeekSet));
+            try!(self.file.write_le_u32(WAV_HEADER_SIZE - 8 + self.data_size));
+            try!(self.file.seek(40, SeekSet));
+            try!(self.file.write_le_u32(self.data_size));
+        }
+
+        self.file.flush()
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> IoResult<()> {
+        let block_align = self.channels * (BITS_PER_SAMPLE / 8);
+
+        try!(self.file.write(b"RIFF"));
+        try!(self.file.write_le_u32(WAV_HEADER_SIZE - 8)); //patched in finish
+        try!(self.file.write(b"WAVE"));
+
+        try!(self.file.write(b"fmt "));
+        try!(self.file.write_le_u32(16));
+        try!(self.file.write_le_u16(1));
+        try!(self.file.write_le_u16(self.channels));
+        try!(self.file.write_le_u32(sample_rate));
+        try!(self.file.write_le_u32(sample_rate * block_align as u32));
+        try!(self.file.write_le_u16(block_align));
+        try!(self.file.write_le_u16(BITS_PER_SAMPLE));
+
+        try!(self.file.write(b"data"));
+        self.file.write_le_u32(0) //patched in finish
+    }
+}
+
+pub fn to_i16(sample: f32) -> i16 {
+    let clamped =
+        if sample > 1.0 { 1.0 }
+        else if sample < -1.0 { -1.0 }
+        else { sample };
+
+    (clamped * 32767.0) as i16
+}
@@ -0,0 +1,318 @@
+extern crate getopts;
+extern crate time;
+extern crate rustnes;
+
+use rustnes::{Nes, Region, Movie, InputScript};
+use rustnes::{TraceLogger, CompactFormat, CodeDataLog};
+use rustnes::{Ca65Style, Asm6Style};
+
+use getopts::{optopt, optflag, optmulti, getopts, usage};
+
+use std::io::{File, BufferedWriter, stderr};
+use std::num::{from_str_radix};
+use std::os;
+use std::uint;
+
+/// # rustnes-headless
+///
+/// Runs a ROM without a window or audio device and reports on where it ended up, for CI and
+/// scripted tests.
+///
+/// Exit status:
+///
+/// - 0 - ran every frame, and the framebuffer matched --expect-hash if given
+/// - 1 - bad arguments, or a file couldn't be read or written
+/// - 2 - the timeout ran out before the frames did. With a timeout and no frame count or
+///       movie it runs until the timeout, and that's a success
+/// - 3 - the framebuffer didn't match --expect-hash
+
+static EXIT_ERROR: int = 1;
+static EXIT_TIMEOUT: int = 2;
+static EXIT_MISMATCH: int = 3;
+
+static DEFAULT_FRAMES: uint = 60 * 10;
+
+fn main() {
+    let args: Vec<String> = os::args();
+
+    let opts = [
+        optopt("", "frames", "number of frames to run (default 600, or the movie's length)", "N"),
+        optopt("", "timeout", "stop after this many seconds of wall clock time", "SECONDS"),
+        optopt("", "input", "input script, see movie::script", "FILE"),
+        optopt("", "movie", "FM2 movie to play", "FILE"),
+        optopt("", "region", "override the ROM's region: ntsc, pal or dendy", "REGION"),
+        optopt("", "screenshot", "write the final frame to a PNG", "FILE"),
+        optopt("", "ram-dump", "write the 2 KB of CPU RAM to a file", "FILE"),
+        optopt("", "expect-hash", "exit with 3 unless the framebuffer hash matches", "HEX"),
+        optopt("", "trace", "log every instruction to a file, see debugger::trace", "FILE"),
+        optopt("", "trace-format", "nestest (default) or compact", "FORMAT"),
+        optopt("", "trace-pc", "only log instructions in a hex address range", "START-END"),
+        optopt("", "trace-last", "only write the last N instructions, when the run ends", "N"),
+        optopt("", "cdl", "record code/data usage to an FCEUX .cdl file, adding to it if it exists", "FILE"),
+        optflag("", "cdl-new", "only write what the existing .cdl file didn't cover"),
+        optopt("", "export-asm", "write the PRG-ROM banks as source, PREFIX.bank0.s and on", "PREFIX"),
+        optopt("", "asm-style", "ca65 (default) or asm6", "STYLE"),
+        optflag("", "profile", "print the cycles spent in every subroutine at the end, see debugger::profiler"),
+        optmulti("", "symbols", "names for the trace and the export, an ld65 .dbg or FCEUX .nl file", "FILE"),
+        optflag("q", "quiet", "don't print the frame count and framebuffer hash"),
+        optflag("h", "help", "print this help"),
+    ];
+
+    let program = args[0].clone();
+    let matches = match getopts(args.tail(), opts) {
+        Ok(m) => { m }
+        Err(f) => { return exit_error(f.to_string()); }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("{}", usage(format!("Usage: {} [options] ROM", program).as_slice(), opts));
+        if matches.free.is_empty() && !matches.opt_present("h") { os::set_exit_status(EXIT_ERROR); }
+        return;
+    }
+
+    let path = Path::new(matches.free[0].as_slice());
+    if !path.exists() { return exit_error(format!("No such ROM: {}", path.display())); }
+
+    let mut nes = match Nes::open(path) {
+        Ok(nes) => nes,
+        Err(e) => { return exit_error(format!("Couldn't load the ROM: {}", e)); }
+    };
+    match matches.opt_str("region") {
+        Some(name) => {
+            match Region::from_name(name.as_slice()) {
+                Some(region) => { nes.set_region(region); }
+                None => { return exit_error("--region must be ntsc, pal or dendy".to_string()); }
+            }
+        }
+        None => { }
+    }
+    nes.reset();
+
+    let mut frames = DEFAULT_FRAMES;
+    let mut frames_given = false;
+
+    match matches.opt_str("movie") {
+        Some(file) => {
+            let movie = match Movie::open(&Path::new(file.as_slice())) {
+                Some(movie) => movie,
+                None => { return exit_error(format!("Couldn't load the movie {}", file)); }
+            };
+            frames = movie.len();
+            frames_given = true;
+            match nes.play_movie(movie) {
+                Ok(()) => { }
+                Err(e) => { return exit_error(format!("Couldn't start the movie: {}", e)); }
+            }
+        }
+        None => { }
+    }
+
+    let script = match matches.opt_str("input") {
+        Some(file) => {
+            match InputScript::open(&Path::new(file.as_slice())) {
+                Some(script) => Some(script),
+                None => { return exit_error(format!("Couldn't load the input script {}", file)); }
+            }
+        }
+        None => None,
+    };
+
+    match matches.opt_str("frames") {
+        Some(n) => {
+            match from_str::<uint>(n.as_slice()) {
+                Some(n) => {
+                    frames = n;
+                    frames_given = true;
+                }
+                None => { return exit_error("--frames must be a number".to_string()); }
+            }
+        }
+        None => { }
+    }
+
+    let timeout = match matches.opt_str("timeout") {
+        Some(n) => {
+            match from_str::<f64>(n.as_slice()) {
+                Some(seconds) => Some(time::precise_time_ns() + (seconds * 1e9) as u64),
+                None => { return exit_error("--timeout must be a number".to_string()); }
+            }
+        }
+        None => None,
+    };
+
+    for file in matches.opt_strs("symbols").iter() {
+        match nes.load_symbols(&Path::new(file.as_slice())) {
+            Ok(_) => { }
+            Err(e) => { return exit_error(format!("Couldn't load {}: {}", file, e)); }
+        }
+    }
+
+    match matches.opt_str("trace") {
+        Some(file) => {
+            let out = match File::create(&Path::new(file.as_slice())) {
+                Ok(out) => out,
+                Err(e) => { return exit_error(format!("Couldn't write {}: {}", file, e)); }
+            };
+            let mut trace = TraceLogger::new(box BufferedWriter::new(out) as Box<Writer>);
+
+            match matches.opt_str("trace-format").as_ref().map(|format| format.as_slice()) {
+                Some("nestest") | None => { }
+                Some("compact") => { trace.set_format(CompactFormat); }
+                Some(_) => { return exit_error("--trace-format must be nestest or compact".to_string()); }
+            }
+            match matches.opt_str("trace-pc") {
+                Some(range) => {
+                    let bounds: Vec<Option<u16>> = range.as_slice().split('-').map(|addr| {
+                        from_str_radix::<u16>(addr.trim_left_chars('$'), 16)
+                    }).collect();
+                    match (bounds.len(), bounds[0], bounds.get(1).and_then(|end| *end)) {
+                        (2, Some(start), Some(end)) if start <= end => { trace.set_pc_range(Some((start, end))); }
+                        _ => { return exit_error("--trace-pc must be a hex range like 8000-80FF".to_string()); }
+                    }
+                }
+                None => { }
+            }
+            match matches.opt_str("trace-last") {
+                Some(n) => {
+                    match from_str::<uint>(n.as_slice()) {
+                        Some(n) => { trace.set_ring(Some(n)); }
+                        None => { return exit_error("--trace-last must be a number".to_string()); }
+                    }
+                }
+                None => { }
+            }
+
+            nes.start_trace(trace);
+        }
+        None => { }
+    }
+
+    match matches.opt_str("cdl") {
+        Some(file) => {
+            let path = Path::new(file.as_slice());
+            let empty = nes.new_cdl();
+            let mut cdl =
+                if path.exists() {
+                    match CodeDataLog::open(&path, empty.prg_len(), empty.chr_len()) {
+                        Ok(cdl) => cdl,
+                        Err(e) => { return exit_error(format!("Couldn't load {}: {}", file, e)); }
+                    }
+                } else {
+                    empty
+                };
+            if matches.opt_present("cdl-new") { cdl.start_new_coverage(); }
+            nes.start_cdl(cdl);
+        }
+        None => { }
+    }
+
+    if matches.opt_present("profile") { nes.start_profiler(); }
+
+    if timeout.is_some() && !frames_given { frames = uint::MAX; }
+
+    let mut timed_out = false;
+    for frame in range(0, frames) {
+        match timeout {
+            Some(deadline) if time::precise_time_ns() >= deadline => {
+                timed_out = true;
+                break;
+            }
+            _ => { }
+        }
+
+        match script {
+            Some(ref script) => { script.apply(&mut nes, frame); }
+            None => { }
+        }
+        nes.run_frame();
+    }
+
+    //writes out what --trace-last kept
+    nes.stop_trace();
+
+    let hash = nes.framebuffer_hash();
+    if !matches.opt_present("q") {
+        println!("frames: {}", nes.frame_count());
+        println!("framebuffer: {:016X}", hash);
+    }
+
+    match matches.opt_str("screenshot") {
+        Some(file) => {
+            match nes.screenshot(&Path::new(file.as_slice())) {
+                Ok(()) => { }
+                Err(e) => { return exit_error(format!("Couldn't write {}: {}", file, e)); }
+            }
+        }
+        None => { }
+    }
+
+    match nes.profile_report() {
+        Some(report) => { println!("{}", report); }
+        None => { }
+    }
+
+    match matches.opt_str("export-asm") {
+        Some(prefix) => {
+            let style = match matches.opt_str("asm-style").as_ref().map(|style| style.as_slice()) {
+                Some("ca65") | None => Ca65Style,
+                Some("asm6") => Asm6Style,
+                Some(_) => { return exit_error("--asm-style must be ca65 or asm6".to_string()); }
+            };
+
+            for (bank, text) in nes.export_disassembly(nes.symbols(), style).iter().enumerate() {
+                let file = format!("{}.bank{}.s", prefix, bank);
+                match File::create(&Path::new(file.as_slice())).write_str(text.as_slice()) {
+                    Ok(()) => { }
+                    Err(e) => { return exit_error(format!("Couldn't write {}: {}", file, e)); }
+                }
+            }
+        }
+        None => { }
+    }
+
+    match (matches.opt_str("cdl"), nes.stop_cdl()) {
+        (Some(file), Some(cdl)) => {
+            match cdl.save(&Path::new(file.as_slice())) {
+                Ok(()) => { }
+                Err(e) => { return exit_error(format!("Couldn't write {}: {}", file, e)); }
+            }
+            if !matches.opt_present("q") {
+                println!("cdl: {} PRG bytes, {} CHR bytes", cdl.prg_covered(), cdl.chr_covered());
+            }
+        }
+        _ => { }
+    }
+
+    match matches.opt_str("ram-dump") {
+        Some(file) => {
+            match File::create(&Path::new(file.as_slice())).write(nes.ram()) {
+                Ok(()) => { }
+                Err(e) => { return exit_error(format!("Couldn't write {}: {}", file, e)); }
+            }
+        }
+        None => { }
+    }
+
+    if timed_out && frames_given {
+        os::set_exit_status(EXIT_TIMEOUT);
+        return;
+    }
+
+    match matches.opt_str("expect-hash") {
+        Some(expected) => {
+            let expected = expected.as_slice().trim_left_chars('#');
+            if from_str_radix::<u64>(expected, 16) != Some(hash) {
+                if !matches.opt_present("q") {
+                    println!("expected framebuffer: {}", expected);
+                }
+                os::set_exit_status(EXIT_MISMATCH);
+            }
+        }
+        None => { }
+    }
+}
+
+fn exit_error(message: String) {
+    let _ = stderr().write_line(message.as_slice());
+    os::set_exit_status(EXIT_ERROR);
+}
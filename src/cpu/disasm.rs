@@ -0,0 +1,234 @@
+use nes::VAddr;
+
+use cpu::Cpu;
+use cpu::isa;
+use cpu::isa::{Instruction, decode};
+
+use debugger::symbols::Symbols;
+
+/// # Disassembler
+///
+/// Turns machine code back into assembly, one instruction at a time, with operands written
+/// the way most 6502 assemblers take them:
+///
+///  IMM  LDA #$12      ZP   LDA $12       ZPX  LDA $12,X     ZPY  LDX $12,Y
+///  ABS  LDA $1234     ABSX LDA $1234,X   ABSY LDA $1234,Y   IND  JMP ($1234)
+///  INDX LDA ($12,X)   INDY LDA ($12),Y   ACC  ASL A         IMP  CLD
+///
+/// Branches show the address they go to instead of the offset. Opcodes isa::decode doesn't
+/// know come out as `.byte $xx`. With symbols loaded the addresses can be names instead, see
+/// Cpu::symbolic_text.
+///
+/// Cpu::annotate adds what the instruction is about to touch like nestest.log does, using the
+/// registers and memory as they are right now:
+///
+///  STX $00 = 00                  the value at the address
+///  LDA $0300,X @ 0301 = 89       the indexed address, then its value
+///  LDA ($80,X) @ 80 = 0200 = 5A  the pointer's address, the pointer, the value
+///  LDA ($89),Y = 0300 @ 0302 = 89
+///  JMP ($0200) = DB7E            the jump target
+
+pub struct Disassembly {
+    pub addr: VAddr,
+
+    /// The opcode and its operand
+    pub bytes: Vec<u8>,
+
+    /// None for an unknown opcode or one cut off by the end of the bytes
+    pub instruction: Option<Instruction>,
+}
+
+impl Disassembly {
+    pub fn len(&self) -> uint {
+        self.bytes.len()
+    }
+
+    /// The operand as a little endian number, 0 for implied and accumulator instructions
+    pub fn operand(&self) -> VAddr {
+        match self.bytes.len() {
+            2 => self.bytes[1] as VAddr,
+            3 => (self.bytes[2] as VAddr) << 8 | (self.bytes[1] as VAddr),
+            _ => 0,
+        }
+    }
+
+    /// Where a branch, JMP or JSR goes, when it's known without running it
+    pub fn target(&self) -> Option<VAddr> {
+        match self.instruction {
+            Some(instruction) => match instruction.address_mode {
+                isa::REL => Some(self.branch_target()),
+                isa::ABS if instruction.instr == isa::JMP || instruction.instr == isa::JSR => Some(self.operand()),
+                _ => None,
+            },
+            None => None,
+        }
+    }
+
+    fn branch_target(&self) -> VAddr {
+        //relative to the instruction after the branch, see Cpu::add_pc_rel
+        let next = self.addr + self.len() as VAddr;
+        (next as i16 + (self.bytes[1] as i8) as i16) as VAddr
+    }
+
+    /// "4C F5 C5"
+    pub fn bytes_text(&self) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", *byte)).collect();
+        bytes.as_slice().connect(" ")
+    }
+
+    /// "LDA ($12),Y"
+    pub fn text(&self) -> String {
+        self.text_with(|_| None)
+    }
+
+    /// The text with names from `name` for the addresses operands use, "LDA (pointer),Y".
+    /// Immediate operands stay numbers.
+    pub fn text_with(&self, name: |VAddr| -> Option<String>) -> String {
+        let instruction = match self.instruction {
+            Some(instruction) => instruction,
+            None => { return format!(".byte ${:02X}", self.bytes[0]); }
+        };
+
+        let operand = self.operand();
+        let addr = match instruction.address_mode {
+            isa::ZP | isa::ZPX | isa::ZPY | isa::INDX | isa::INDY => {
+                name(operand).unwrap_or_else(|| format!("${:02X}", operand))
+            }
+            isa::ABS | isa::ABSX | isa::ABSY | isa::IND => {
+                name(operand).unwrap_or_else(|| format!("${:04X}", operand))
+            }
+            isa::REL => {
+                let target = self.branch_target();
+                name(target).unwrap_or_else(|| format!("${:04X}", target))
+            }
+            isa::IMM | isa::ACC | isa::IMP | isa::ADDRESS_MODE_NONE => String::new(),
+        };
+
+        let operand = match instruction.address_mode {
+            isa::IMM => format!("#${:02X}", operand),
+            isa::ZP | isa::ABS | isa::REL => addr,
+            isa::ZPX | isa::ABSX => format!("{},X", addr),
+            isa::ZPY | isa::ABSY => format!("{},Y", addr),
+            isa::IND => format!("({})", addr),
+            isa::INDX => format!("({},X)", addr),
+            isa::INDY => format!("({}),Y", addr),
+            isa::ACC => "A".to_string(),
+            isa::IMP | isa::ADDRESS_MODE_NONE => String::new(),
+        };
+
+        if operand.is_empty() {
+            format!("{}", instruction.instr)
+        } else {
+            format!("{} {}", instruction.instr, operand)
+        }
+    }
+}
+
+/// Disassembles the instruction at the start of `bytes`, which was loaded at `addr`.
+pub fn disassemble(addr: VAddr, bytes: &[u8]) -> Disassembly {
+    assert!(!bytes.is_empty());
+
+    let instruction = decode(bytes[0]).and_then(|instruction| {
+        if instruction.len() <= bytes.len() { Some(instruction) } else { None }
+    });
+
+    let len = instruction.map_or(1, |instruction| instruction.len());
+    Disassembly {
+        addr: addr,
+        bytes: bytes.slice_to(len).to_vec(),
+        instruction: instruction,
+    }
+}
+
+/// Disassembles everything in `bytes`, treating it all as code.
+pub fn disassemble_all(addr: VAddr, bytes: &[u8]) -> Vec<Disassembly> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let line = disassemble(addr + pos as VAddr, bytes.slice_from(pos));
+        pos += line.len();
+        lines.push(line);
+    }
+
+    lines
+}
+
+impl Cpu {
+    /// Disassembles the instruction at `addr` in the CPU address space without side effects.
+    pub fn disassemble(&self, addr: VAddr) -> Disassembly {
+        let bytes = [self.peek_byte(addr), self.peek_byte(addr + 1), self.peek_byte(addr + 2)];
+        disassemble(addr, bytes.as_slice())
+    }
+
+    /// The disassembly with the addresses and values it uses, see the nestest examples above.
+    pub fn annotate(&self, line: &Disassembly) -> String {
+        self.annotate_text(line, line.text())
+    }
+
+    /// Annotated with names from `symbols` for the operand, `LDA frame_count = 05`.
+    pub fn annotate_symbols(&self, line: &Disassembly, symbols: &Symbols) -> String {
+        self.annotate_text(line, self.symbolic_text(line, symbols))
+    }
+
+    /// The disassembly with names from `symbols` for the operand. ROM addresses are looked up
+    /// in the bank that's mapped in there now.
+    pub fn symbolic_text(&self, line: &Disassembly, symbols: &Symbols) -> String {
+        line.text_with(|addr| symbols.lookup(addr, self.prg_bank(addr)).map(|symbol| symbol.name.clone()))
+    }
+
+    fn annotate_text(&self, line: &Disassembly, text: String) -> String {
+        let instruction = match line.instruction {
+            Some(instruction) => instruction,
+            None => { return text; }
+        };
+
+        let operand = line.operand();
+        let x = self.state.X;
+        let y = self.state.Y;
+
+        //addresses are worked out the same way as Cpu::instr_mem_addr
+        match instruction.address_mode {
+            isa::ZP => {
+                format!("{} = {:02X}", text, self.peek_byte(operand))
+            }
+            isa::ZPX | isa::ZPY => {
+                let index = if instruction.address_mode == isa::ZPX { x } else { y };
+                let addr = (operand as u8 + index) as VAddr;
+                format!("{} @ {:02X} = {:02X}", text, addr, self.peek_byte(addr))
+            }
+            isa::ABS => {
+                if instruction.instr == isa::JMP || instruction.instr == isa::JSR {
+                    text
+                } else {
+                    format!("{} = {:02X}", text, self.peek_byte(operand))
+                }
+            }
+            isa::ABSX | isa::ABSY => {
+                let index = if instruction.address_mode == isa::ABSX { x } else { y };
+                let addr = operand + index as VAddr;
+                format!("{} @ {:04X} = {:02X}", text, addr, self.peek_byte(addr))
+            }
+            isa::IND => {
+                format!("{} = {:04X}", text, self.peek_addr(operand))
+            }
+            isa::INDX => {
+                let pointer = (operand as u8 + x) as VAddr;
+                let addr = self.peek_addr(pointer);
+                format!("{} @ {:02X} = {:04X} = {:02X}", text, pointer, addr, self.peek_byte(addr))
+            }
+            isa::INDY => {
+                let base = self.peek_addr(operand);
+                let addr = base + y as VAddr;
+                format!("{} = {:04X} @ {:04X} = {:02X}", text, base, addr, self.peek_byte(addr))
+            }
+            _ => text,
+        }
+    }
+
+    fn peek_addr(&self, virtual_address: VAddr) -> VAddr {
+        let lo = self.peek_byte(virtual_address);
+        let hi = self.peek_byte(virtual_address + 1);
+        (hi as VAddr) << 8 | (lo as VAddr)
+    }
+}
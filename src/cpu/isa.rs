@@ -14,9 +14,14 @@ impl Instruction {
             None => { println!("Decode failed. Op Code: {:X}", opcode); fail!("FAIL"); }
         }
     }
+
+    //opcode and operand bytes
+    pub fn len(&self) -> uint {
+        1 + self.address_mode.operand_len()
+    }
 }
 
-fn decode(opcode: u8) -> Option<Instruction>
+pub fn decode(opcode: u8) -> Option<Instruction>
 {
     let (instr, mode, cycles) =
         match opcode {
@@ -308,3 +313,14 @@ pub enum AddressMode {
 
     ADDRESS_MODE_NONE,
 }
+
+impl AddressMode {
+    //bytes that follow the opcode
+    pub fn operand_len(&self) -> uint {
+        match *self {
+            ZP | ZPX | ZPY | IMM | REL | INDX | INDY => 1,
+            ABS | ABSX | ABSY | IND => 2,
+            IMP | ACC | ADDRESS_MODE_NONE => 0,
+        }
+    }
+}
@@ -1,20 +1,34 @@
 #[macro_escape]
 
 use std::fmt;
-use std::io;
 
-use nes::{PrgRom};
-use nes::{VAddr};
+use nes::{VAddr, PRG_ROM_BANK_SIZE};
+use nes::state;
+use nes::state::{StateWriter, StateReader, StateHashes};
+use nes::clock::MasterClock;
 
 use ppu::{Ppu};
 
+use apu::{Apu};
+
+use input::{InputPorts, OPEN_BUS};
+
+use mapper::{Mapper};
+
+use debugger::{AccessLog, CpuBus, ReadAccess, WriteAccess, ExecAccess};
+use debugger::trace::TraceLogger;
+use debugger::cdl::{CodeDataLog, CDL_CODE, CDL_DATA, CDL_PCM};
+use debugger::callstack::{CallStack, CallFrame, CallKind, JsrCall, NmiCall, IrqCall, BrkCall};
+use debugger::profiler::Profiler;
+
 use self::isa::{
     Instruction, 
     Instr, 
     AddressMode,
 };
 
-mod isa;
+pub mod isa;
+pub mod disasm;
 
 #[cfg(test)] 
 mod test;
@@ -87,7 +101,7 @@ impl fmt::Show for CpuFlags {
 }
 
 #[allow(uppercase_variables)]
-struct CpuState {
+pub struct CpuState {
     pub PC: VAddr,  //Program Counter
     pub A:  u8,     //Accumulator
     pub X:  u8,     //Index Register X
@@ -110,24 +124,69 @@ impl CpuState {
 }
 
 static RAM_SIZE: uint = 0x0800; //2 KB
+
+static NMI_VECTOR: VAddr = 0xFFFA;
+static IRQ_VECTOR: VAddr = 0xFFFE;
+static INTERRUPT_CYCLES: uint = 7;
+
+//call_subroutine returns here, nothing ever executes out of the APU/IO register space
+static SUBROUTINE_RETURN_ADDR: VAddr = 0x4018;
 type Ram = [u8, ..RAM_SIZE];
 
 pub struct Cpu {
     state: CpuState,
-    prg_rom: PrgRom,
+    mapper: Box<Mapper>,
     ram: Ram,
     pub ppu: Ppu,
+    pub apu: Apu,
+    pub input: InputPorts,
+
+    /// Keeps the PPU in step with the CPU, see nes::clock
+    pub clock: MasterClock,
+
+    //cycles of the running instruction before the one it reads or writes its operand on
+    access_cycles: uint,
+
+    /// Memory accesses for the debugger's watchpoints, off unless it's attached
+    pub access_log: AccessLog,
+
+    /// Logs every instruction before it runs, see Nes::start_trace
+    pub trace: Option<TraceLogger>,
+
+    /// Marks PRG-ROM as code or data as it's read, see Nes::start_cdl
+    pub cdl: Option<CodeDataLog>,
+
+    /// Follows JSRs, interrupts and returns, see Nes::call_stack
+    pub call_stack: Option<CallStack>,
+
+    /// Counts cycles per subroutine, see Nes::start_profiler
+    pub profiler: Option<Profiler>,
 }
 
 impl Cpu {
-    pub fn new(prg_rom: PrgRom, ppu: Ppu) -> Cpu {
+    pub fn new(mapper: Box<Mapper>, ppu: Ppu) -> Cpu {
         let cpu_state = CpuState::new();
 
+        //cartridge sound chips are mixed in by the APU
+        let mut apu = Apu::new();
+        for chip in mapper.audio_chips().move_iter() {
+            apu.register_expansion(chip);
+        }
+
         Cpu { 
             state: cpu_state,
-            prg_rom: prg_rom,
+            mapper: mapper,
             ram: [0u8, ..RAM_SIZE],
             ppu: ppu,
+            apu: apu,
+            input: InputPorts::new(),
+            clock: MasterClock::ntsc(),
+            access_cycles: 0,
+            access_log: AccessLog::new(),
+            trace: None,
+            cdl: None,
+            call_stack: None,
+            profiler: None,
         }
     }
 
@@ -141,13 +200,220 @@ impl Cpu {
 
     }
 
-    pub fn run_cycles(&mut self, cycles: &mut int) {
-        let mut reader = io::stdin();
-        loop {
-            *cycles -= self.instr_run() as int;
-            info!("Remaining cycles: {}", *cycles);
-            //reader.read_line();
-            if *cycles <= 0 { break; }
+    pub fn pc(&self) -> VAddr {
+        self.state.PC
+    }
+
+    /// The registers, for debuggers
+    pub fn state(&self) -> CpuState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: CpuState) {
+        self.state = state;
+    }
+
+    pub fn ram<'a>(&'a self) -> &'a [u8] {
+        self.ram.as_slice()
+    }
+
+    //the 16 KB PRG-ROM bank mapped in at an address, counting from the start of PRG-ROM
+    pub fn prg_bank(&self, virtual_address: VAddr) -> Option<uint> {
+        self.mapper.prg_rom_offset(virtual_address).map(|offset| offset / PRG_ROM_BANK_SIZE)
+    }
+
+    //registers and RAM, the mapper has its own chunk
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.save_registers(w);
+        w.bytes(self.ram);
+    }
+
+    fn save_registers(&self, w: &mut StateWriter) {
+        w.u16(self.state.PC);
+        w.u8(self.state.A);
+        w.u8(self.state.X);
+        w.u8(self.state.Y);
+        w.u8(self.state.S);
+        w.u8(self.state.P.bits);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.state.PC = r.u16();
+        self.state.A = r.u8();
+        self.state.X = r.u8();
+        self.state.Y = r.u8();
+        self.state.S = r.u8();
+        self.state.P = CpuFlags::from_bits_truncate(r.u8());
+        r.read_into(self.ram);
+    }
+
+    pub fn save_mapper_state(&self, w: &mut StateWriter) {
+        self.mapper.save_state(w);
+    }
+
+    pub fn load_mapper_state(&mut self, r: &mut StateReader) {
+        self.mapper.load_state(r);
+    }
+
+    //see Nes::state_hashes
+    pub fn state_hashes(&self, hashes: &mut StateHashes) {
+        let mut registers = StateWriter::new();
+        self.save_registers(&mut registers);
+        hashes.push(("CpuState", state::hash(registers.into_bytes().as_slice())));
+        hashes.push(("ram", state::hash(self.ram)));
+
+        self.ppu.state_hashes(hashes);
+
+        let mut apu = StateWriter::new();
+        self.apu.save_state(&mut apu);
+        hashes.push(("Apu", state::hash(apu.into_bytes().as_slice())));
+
+        let mut mapper = StateWriter::new();
+        self.mapper.save_state(&mut mapper);
+        hashes.push(("mapper", state::hash(mapper.into_bytes().as_slice())));
+    }
+
+    //runs one instruction, or enters a pending interrupt, and keeps the APU in step with it.
+    //Returns the cycles it took.
+    pub fn step(&mut self) -> uint {
+        let cycles =
+            if self.ppu.take_nmi() {
+                self.interrupt(NMI_VECTOR)
+            } else if self.apu.irq_pending() && !self.state.P.contains(I_FLAG) {
+                self.interrupt(IRQ_VECTOR)
+            } else {
+                self.instr_run()
+            };
+
+        self.count_cycles(cycles);
+        self.step_apu(cycles);
+        cycles
+    }
+
+    //the trace logger and profiler keep their own count
+    fn count_cycles(&mut self, cycles: uint) {
+        match self.trace {
+            Some(ref mut trace) => { trace.add_cycles(cycles); }
+            None => { }
+        }
+        match self.profiler {
+            Some(ref mut profiler) => { profiler.add_cycles(cycles); }
+            None => { }
+        }
+    }
+
+    //a JSR, interrupt or BRK went to `entry` and comes back to `return_addr`, S was `sp`
+    //before it pushed anything
+    fn track_call(&mut self, kind: CallKind, entry: VAddr, return_addr: VAddr, sp: u8) {
+        if self.call_stack.is_none() && self.profiler.is_none() { return; }
+
+        let frame = CallFrame {
+            kind: kind,
+            entry: entry,
+            bank: self.prg_bank(entry),
+            return_addr: return_addr,
+            sp: sp,
+        };
+        match self.call_stack {
+            Some(ref mut call_stack) => { call_stack.call(frame.clone()); }
+            None => { }
+        }
+        match self.profiler {
+            Some(ref mut profiler) => { profiler.call(frame); }
+            None => { }
+        }
+    }
+
+    //an RTS or RTI just went to PC
+    fn track_return(&mut self, rti: bool) {
+        if self.call_stack.is_none() && self.profiler.is_none() { return; }
+
+        //a real return from a call tracking missed goes to just after a JSR
+        let s = self.state.S;
+        let after_jsr = rti || isa::decode(self.peek_byte(self.state.PC - 3)).map_or(false, |instruction| {
+            instruction.instr == isa::JSR
+        });
+        match self.call_stack {
+            Some(ref mut call_stack) => { call_stack.ret(s, after_jsr); }
+            None => { }
+        }
+        match self.profiler {
+            Some(ref mut profiler) => { profiler.ret(s, after_jsr); }
+            None => { }
+        }
+    }
+
+    //TXS
+    fn track_stack(&mut self) {
+        let s = self.state.S;
+        match self.call_stack {
+            Some(ref mut call_stack) => { call_stack.unwind(s); }
+            None => { }
+        }
+        match self.profiler {
+            Some(ref mut profiler) => { profiler.unwind(s); }
+            None => { }
+        }
+    }
+
+    //NMI and IRQ push the status without B, unlike BRK
+    fn interrupt(&mut self, vector: VAddr) -> uint {
+        let pc = self.state.PC;
+        let sp = self.state.S;
+        self.push_addr(pc);
+        let p = self.state.P;
+        self.push(p.bits & !B_FLAG.bits);
+        self.state.P.insert(I_FLAG);
+        self.state.PC = self.read_addr(vector);
+
+        let kind = if vector == NMI_VECTOR { NmiCall } else { IrqCall };
+        let entry = self.state.PC;
+        self.track_call(kind, entry, pc, sp);
+        INTERRUPT_CYCLES
+    }
+
+    //runs the subroutine at addr as if it was entered with JSR and with the given A and X, until
+    //it returns or max_cycles have passed. Returns the number of cycles run.
+    pub fn call_subroutine(&mut self, addr: VAddr, a: u8, x: u8, max_cycles: uint) -> uint {
+        let sp = self.state.S;
+        self.push_addr(SUBROUTINE_RETURN_ADDR - 1);
+        self.track_call(JsrCall, addr, SUBROUTINE_RETURN_ADDR, sp);
+        self.state.PC = addr;
+        self.state.A = a;
+        self.state.X = x;
+
+        self.resume_subroutine(max_cycles)
+    }
+
+    //carries on with a subroutine call_subroutine ran out of cycles in
+    pub fn resume_subroutine(&mut self, max_cycles: uint) -> uint {
+        let mut cycles: uint = 0;
+        while self.state.PC != SUBROUTINE_RETURN_ADDR && cycles < max_cycles {
+            let instr_cycles = self.instr_run();
+            self.count_cycles(instr_cycles);
+            self.step_apu(instr_cycles);
+            cycles += instr_cycles;
+        }
+
+        cycles
+    }
+
+    //whether the last call_subroutine got back to its caller
+    pub fn subroutine_returned(&self) -> bool {
+        self.state.PC == SUBROUTINE_RETURN_ADDR
+    }
+
+    //clocks the APU once per CPU cycle, servicing the DMC's sample fetches from the memory bus
+    pub fn step_apu(&mut self, cycles: uint) {
+        for _ in range(0, cycles) {
+            self.apu.clock();
+            match self.apu.dmc_fetch_address() {
+                Some(addr) => {
+                    let val = self.read_logged(addr, CDL_PCM);
+                    self.apu.dmc_fill(val);
+                }
+                None => { }
+            }
         }
     }
 
@@ -156,17 +422,24 @@ impl Cpu {
     pub fn instr_run(&mut self) -> uint {
         let mut extra_cycles: uint = 0;
 
-        info!("PC: {:x}", self.state.PC);
+        if self.trace.is_some() {
+            let mut trace = self.trace.take().unwrap();
+            trace.log(self);
+            self.trace = Some(trace);
+        }
 
         let instr = self.instr_decode();
 
-        info!("Instruction: {} {}", instr.instr, instr.address_mode);
+        match self.cdl {
+            Some(ref mut cdl) => {
+                cdl.set_indirect_data(instr.address_mode == isa::INDX || instr.address_mode == isa::INDY);
+            }
+            None => { }
+        }
 
         //get the memory address referenced by this instr
         let (mem_addr, page_boundary_crossed) = self.instr_mem_addr(instr.address_mode);
 
-        info!("mem_addr: {:x}", mem_addr);
-
         if page_boundary_crossed {
             extra_cycles += match instr.instr {
                 isa::ADC | isa::AND | isa::CMP | isa::EOR |
@@ -176,12 +449,25 @@ impl Cpu {
             }
         }
 
+        //the operand is read or written on the last cycle, the other cycles are for the
+        //opcode, the address and dummy accesses
+        let last_cycle = instr.cycles + extra_cycles - 1;
+        self.access_cycles = last_cycle;
+
         match instr.instr {
-            isa::JMP => self.state.PC = mem_addr,
+            isa::JMP => {
+                self.state.PC = mem_addr;
+                match self.cdl {
+                    Some(ref mut cdl) if instr.address_mode == isa::IND => { cdl.indirect_jump(); }
+                    _ => { }
+                }
+            }
             isa::JSR => {
                 let pc = self.state.PC - 1;
+                let sp = self.state.S;
                 self.push_addr(pc);
                 self.state.PC = mem_addr;
+                self.track_call(JsrCall, mem_addr, pc + 1, sp);
             }
             isa::BCC | isa::BCS | isa::BEQ | isa::BMI | 
             isa::BNE | isa::BPL | isa::BVC | isa::BVS => {
@@ -189,8 +475,16 @@ impl Cpu {
                 extra_cycles += self.instr_do_branch(instr.instr, mem);
             }
             _ => {
+                //read-modify-write instructions read 2 cycles before they write
+                let rmw = instr.address_mode != isa::ACC && match instr.instr {
+                    isa::ASL | isa::DEC | isa::INC | isa::LSR | isa::ROL | isa::ROR => true,
+                    _ => false,
+                };
+                if rmw { self.access_cycles = last_cycle - 2; }
+
                 //get the value referenced by the memory addr
                 let mem = self.instr_mem_read(mem_addr, instr);
+                self.access_cycles = last_cycle;
 
                 info!("mem: {:x}", mem);
 
@@ -202,6 +496,12 @@ impl Cpu {
             }
         }
 
+        match self.cdl {
+            Some(ref mut cdl) => { cdl.set_indirect_data(false); }
+            None => { }
+        }
+        self.access_cycles = 0;
+
         instr.cycles + extra_cycles
     }
 
@@ -346,7 +646,7 @@ impl Cpu {
             isa::TAY => { self.state.Y = a; self.state.P.set_zn(a); }
             isa::TYA => { self.state.A = y; self.state.P.set_zn(y); }
             isa::TSX => { self.state.X = s; self.state.P.set_zn(s); }
-            isa::TXS => { self.state.S = x; self.state.P.set_zn(x); }
+            isa::TXS => { self.state.S = x; self.state.P.set_zn(x); self.track_stack(); }
 
             //Stack
             isa::PHA => { self.push(a); }
@@ -358,10 +658,12 @@ impl Cpu {
             //Note: JMP and JSR are implemented in instr_run because they need access to m_addr
             isa::RTS => {
                 self.state.PC = self.pop_addr() + 1;
+                self.track_return(false);
             }
             isa::RTI => {
                 self.state.P.bits = self.pop();
                 self.state.PC = self.pop_addr();
+                self.track_return(true);
             }
 
             //Set and Clear
@@ -377,10 +679,14 @@ impl Cpu {
             isa::NOP => { }
             isa::BRK => {
                 let pc = self.state.PC + 1;
+                let sp = self.state.S;
                 self.push_addr(pc);
                 self.push(p.bits | B_FLAG.bits);
                 self.state.P.insert(I_FLAG);
                 self.state.PC = self.read_addr(0xFFFE);
+
+                let entry = self.state.PC;
+                self.track_call(BrkCall, entry, pc, sp);
             }
 
             _ => { error!("Unimplemented instruction"); }
@@ -483,7 +789,9 @@ impl Cpu {
     //this function will read the byte at PC and increment PC by 1
     fn read_pc_byte(&mut self) -> u8 {
         let pc = self.state.PC;
-        let byte = self.read_byte(pc);
+        let byte = self.read_bus(pc);
+        self.access_log.record(CpuBus, pc, byte, ExecAccess);
+        self.log_prg(pc, CDL_CODE);
         self.state.PC += 1;
         byte
     }
@@ -574,73 +882,123 @@ impl Cpu {
 /// | Zero Page     |       |               |
 /// |_______________| $0000 |_______________|
 
+    //Read a byte from the memory bus without side effects, for debuggers, cheats and scripts.
+    //Reading $2002 this way leaves vblank set, and nothing is logged
+    //TODO controllers and expansion audio registers, they read as 0 for now
+    pub fn peek_byte(&self, virtual_address: VAddr) -> u8 {
+        if virtual_address < 0x2000 {
+            self.ram[(virtual_address & 0x07FF) as uint]
+        } else if virtual_address < 0x4000 {
+            self.ppu.peek_register((virtual_address & 0x0007) as uint)
+        } else if virtual_address == 0x4015 {
+            self.apu.peek_status()
+        } else if virtual_address < 0x4020 {
+            0x00
+        } else {
+            self.mapper.prg_peek(virtual_address)
+        }
+    }
+
+    //Write a byte without side effects, returns false where that isn't possible. PRG-ROM can
+    //be changed this way, and bank registers aren't written
+    pub fn poke_byte(&mut self, virtual_address: VAddr, val: u8) -> bool {
+        if virtual_address < 0x2000 {
+            self.ram[(virtual_address & 0x07FF) as uint] = val;
+            true
+        } else if virtual_address < 0x4000 {
+            self.ppu.poke_register((virtual_address & 0x0007) as uint, val)
+        } else if virtual_address < 0x4020 {
+            false
+        } else {
+            self.mapper.prg_poke(virtual_address, val)
+        }
+    }
+
+    pub fn mapper_registers(&self) -> Vec<(String, u8)> {
+        self.mapper.registers()
+    }
+
     //Read a byte from the memory bus
     fn read_byte(&mut self, virtual_address: VAddr) -> u8 {
+        self.read_logged(virtual_address, CDL_DATA)
+    }
+
+    //a read for the access log and code/data logger, which marks PRG-ROM with cdl_flags
+    fn read_logged(&mut self, virtual_address: VAddr, cdl_flags: u8) -> u8 {
+        let val = self.read_bus(virtual_address);
+        self.access_log.record(CpuBus, virtual_address, val, ReadAccess);
+        self.log_prg(virtual_address, cdl_flags);
+        val
+    }
+
+    //marks where an address is in PRG-ROM, if it's there and the logger is on
+    fn log_prg(&mut self, virtual_address: VAddr, cdl_flags: u8) {
+        match self.cdl {
+            Some(ref mut cdl) => {
+                match self.mapper.prg_rom_offset(virtual_address) {
+                    Some(offset) => { cdl.log_prg(offset, virtual_address, cdl_flags); }
+                    None => { }
+                }
+            }
+            None => { }
+        }
+    }
+
+    fn read_bus(&mut self, virtual_address: VAddr) -> u8 {
         if virtual_address < 0x2000 {
             let address: uint = (virtual_address & 0x07FF) as uint; //Mirrored after 0x0800
             self.ram[address]
         } else if virtual_address < 0x4000 {
             let address: uint = (virtual_address & 0x0007) as uint; //Mirrored after 0x2008
-            //TODO calls into PPU at this point
-            //TODO several of these registers are read only
-            match address {
-                0 => { 0x11 } //PPU Control Register 1
-                1 => { 0x22 } //PPU Control Register 2
-                2 => { self.ppu.read_ppu_status() } //PPU Status Register
-                3 => { 0x44 } //SPR-RAM Address Register
-                4 => { 0x55 } //SPR-RAM I/O Register
-                5 => { 0x66 } //VRAM Address Register 1
-                6 => { 0x77 } //VRAM Address Register 2
-                7 => { 0x88 } //VRAM I/O Register
-                _ => { error!("Impossible"); 0x00 }
-            }
+            self.sync_ppu();
+            self.ppu.read_register(address)
         } else if virtual_address < 0x4020 {
-            //TODO APU Registers and I/O devices
-            0x00
-        } else if virtual_address < 0x6000 {
-            //TODO Expansion ROM
-            0x00
-        } else if virtual_address < 0x8000 {
-            //TODO SRAM
-            0x00
-        } 
-        
-        //TODO I need to implement mapping at some point
-        else if virtual_address < 0xC000 {
-            let address = (virtual_address & 0x3FFF) as uint;
-            self.prg_rom[0][address]
+            //TODO remaining I/O devices
+            match virtual_address {
+                0x4015 => { self.apu.read_status() }
+                0x4016 => { OPEN_BUS | self.input.read(0, &self.ppu) }
+                0x4017 => { OPEN_BUS | self.input.read(1, &self.ppu) }
+                _ => { 0x00 }
+            }
         } else { // if virtual_address <= 0xFFFF
-            let address = (virtual_address & 0x3FFF) as uint;
-            self.prg_rom[1][address]
+            //Expansion ROM, SRAM and PRG-ROM all live on the cartridge, along with the
+            //readable registers of its sound chip
+            match self.apu.expansion_read(virtual_address) {
+                Some(val) => { val }
+                None => { self.mapper.prg_read(virtual_address) }
+            }
         }
     }
 
-    fn write_byte(&mut self, virtual_address: VAddr, val: u8) {
+    //runs the PPU up to the cycle an instruction accesses one of its registers on, the rest
+    //of the instruction's dots are run by Nes::step
+    fn sync_ppu(&mut self) {
+        let dots = self.clock.sync_ppu(self.access_cycles);
+        for _ in range(0, dots) {
+            self.ppu.tick();
+        }
+    }
+
+    pub fn write_byte(&mut self, virtual_address: VAddr, val: u8) {
+        self.access_log.record(CpuBus, virtual_address, val, WriteAccess);
+
         if virtual_address < 0x2000 {
             let address: uint = (virtual_address as uint) & 0x07FF; //Mirrored after 0x0800
             self.ram[address] = val;
         } else if virtual_address < 0x4000 {
             let address: uint = (virtual_address as uint) & 0x0007; //Mirrorer after 0x2008
-            //TODO ppu
-            match address {
-                0 => { }
-                1 => { }
-                2 => { error!("PPU Status Register ($2002) is Read Only"); }
-                3 => { }
-                4 => { }
-                5 => { }
-                6 => { }
-                7 => { }
-                _ => { }
-            }
+            self.sync_ppu();
+            self.ppu.write_register(address, val);
         } else if virtual_address < 0x4020 {
-            //TODO APU Registers and I/O devices
-        } else if virtual_address < 0x6000 {
-            //TODO Expansion ROM
-        } else if virtual_address < 0x8000 {
-            //TODO SRAM
+            //TODO remaining I/O devices
+            if virtual_address <= 0x4013 || virtual_address == 0x4015 || virtual_address == 0x4017 {
+                self.apu.write_register(virtual_address, val);
+            } else if virtual_address == 0x4016 {
+                self.input.write(val);
+            }
         } else {
-            error!("Can't write to PRG-ROM");
+            self.apu.expansion_write(virtual_address, val);
+            self.mapper.prg_write(virtual_address, val);
         }
     }
 }
@@ -4,12 +4,17 @@ use nes::{PrgRom, PRG_ROM_BANK_SIZE};
 use nes::{ChrRom, CHR_ROM_BANK_SIZE};
 use nes::test;
 
-use cpu::{Cpu, CpuState, CpuFlags, Ram, RAM_SIZE};
+use cpu::{Cpu, CpuFlags, Ram, RAM_SIZE};
 use cpu::{C_FLAG, Z_FLAG, I_FLAG, D_FLAG, B_FLAG, X_FLAG, V_FLAG, N_FLAG};
 use cpu::isa;
+use cpu::disasm::{disassemble, disassemble_all};
 
 use ppu::Ppu;
 
+use input::BUTTON_B;
+
+use mapper::{Mapper, Nrom};
+
 /// # Macros
 ///
 ///
@@ -38,10 +43,6 @@ macro_rules! ram(
 ///     
 ///
 
-fn get_empty_cpu_state() -> CpuState {
-    CpuState::new()
-}
-
 fn get_empty_ram() -> Ram {
     [0u8, ..RAM_SIZE]
 }
@@ -51,39 +52,18 @@ fn get_initialized_ram(init: u8) -> Ram {
 }
 
 fn get_empty_cpu() -> Cpu {
-    let state = get_empty_cpu_state();
-    let prg_rom = prg_rom!();
-    let ram = [0u8, ..RAM_SIZE];
-
-    Cpu {
-        state: state,
-        prg_rom: prg_rom,
-        ram: ram,
-        ppu: Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]),
-    }
+    get_cpu_with_prg_rom(prg_rom!())
 }
 
 fn get_cpu_with_prg_rom(prg_rom: PrgRom) -> Cpu {
-    let state = get_empty_cpu_state();
-    let ram = [0u8, ..RAM_SIZE];
-
-    Cpu {
-        state: state,
-        prg_rom: prg_rom,
-        ram: ram,
-        ppu: Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]),
-    }
+    get_cpu_with_prg_rom_and_ram(prg_rom, get_empty_ram())
 }
 
+//through Cpu::new, so the fixtures don't need every field the Cpu has
 fn get_cpu_with_prg_rom_and_ram(prg_rom: PrgRom, ram: Ram) -> Cpu {
-    let state = get_empty_cpu_state();
-
-    Cpu {
-        state: state,
-        prg_rom: prg_rom,
-        ram: ram,
-        ppu: Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]),
-    }
+    let mut cpu = Cpu::new(box Nrom::new(prg_rom) as Box<Mapper>, Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]));
+    cpu.ram = ram;
+    cpu
 }
 
 /// # Sanity Test
@@ -2285,3 +2265,175 @@ fn cpu_instr_exec_brk_test() {
     assert_eq!(cpu.state.P, CpuFlags::none() | I_FLAG);
     assert_eq!(cpu.state.PC, 0xBBAA);
 }
+
+#[test]
+fn cpu_call_subroutine_test() {
+    let mut prg_rom_bank = prg_rom_bank!(0xC5);
+    //STA $10
+    prg_rom_bank[0x0000] = 0x85;
+    prg_rom_bank[0x0001] = 0x10;
+    //STX $11
+    prg_rom_bank[0x0002] = 0x86;
+    prg_rom_bank[0x0003] = 0x11;
+    //RTS
+    prg_rom_bank[0x0004] = 0x60;
+
+    let mut cpu = cpu!(prg_rom!(prg_rom_bank, prg_rom_bank!()));
+
+    assert_eq!(cpu.call_subroutine(0x8000, 0xAA, 0xBB, 100), 12);
+    assert_eq!(cpu.ram[0x10], 0xAA);
+    assert_eq!(cpu.ram[0x11], 0xBB);
+    assert_eq!(cpu.state.S, 0xFF);
+
+    //routines that run past max_cycles are abandoned
+    assert_eq!(cpu.call_subroutine(0x8000, 0xAA, 0xBB, 1), 3);
+    assert_eq!(cpu.state.PC, 0x8002);
+}
+
+#[test]
+fn cpu_controller_port_test() {
+    let mut cpu = get_empty_cpu();
+    cpu.input.set_player_buttons(1, BUTTON_B);
+
+    cpu.write_byte(0x4016, 0x01);
+    cpu.write_byte(0x4016, 0x00);
+
+    //open bus in the upper bits, serial data in bit 0
+    assert_eq!(cpu.read_byte(0x4016), 0x40);
+    assert_eq!(cpu.read_byte(0x4017), 0x40);
+    assert_eq!(cpu.read_byte(0x4017), 0x41);
+    assert_eq!(cpu.read_byte(0x4016), 0x40);
+}
+
+#[test]
+fn cpu_interrupt_test() {
+    let mut prg_rom_bank = prg_rom_bank!(0xEA);
+    //NMI $9000, IRQ $A000
+    prg_rom_bank[0x3FFA] = 0x00;
+    prg_rom_bank[0x3FFB] = 0x90;
+    prg_rom_bank[0x3FFE] = 0x00;
+    prg_rom_bank[0x3FFF] = 0xA0;
+
+    let mut cpu = cpu!(prg_rom!(prg_rom_bank!(0xEA), prg_rom_bank));
+    cpu.state.PC = 0x8000;
+    cpu.state.P = CpuFlags::none() | C_FLAG;
+
+    //NMI on the next vblank
+    cpu.ppu.write_ppu_ctrl(0x80);
+    while cpu.ppu.scanline() != 241 || cpu.ppu.dot() != 2 {
+        cpu.ppu.tick();
+    }
+
+    assert_eq!(cpu.step(), 7);
+    assert_eq!(cpu.state.PC, 0x9000);
+    assert_eq!(cpu.ram[0x01FF], 0x80);
+    assert_eq!(cpu.ram[0x01FE], 0x00);
+    assert_eq!(cpu.ram[0x01FD], (CpuFlags::none() | C_FLAG).bits);
+    assert_eq!(cpu.state.P, CpuFlags::none() | C_FLAG | I_FLAG);
+
+    //IRQs wait for the I flag to clear
+    cpu.apu.write_register(0x4017, 0x00);
+    for _ in range(0, 29830u) { cpu.apu.clock(); }
+    assert!(cpu.apu.irq_pending());
+    assert_eq!(cpu.step(), 2);
+    assert_eq!(cpu.state.PC, 0x9001);
+
+    cpu.state.P.remove(I_FLAG);
+    assert_eq!(cpu.step(), 7);
+    assert_eq!(cpu.state.PC, 0xA000);
+}
+
+/// # Disassembler Tests
+///
+///
+
+#[test]
+fn cpu_disassemble_test() {
+    let code = [
+        0xA9, 0x12,         //$8000 LDA #$12
+        0xB1, 0x12,         //$8002 LDA ($12),Y
+        0x6C, 0x34, 0x12,   //$8004 JMP ($1234)
+        0x9D, 0x00, 0x03,   //$8007 STA $0300,X
+        0xB6, 0x80,         //$800A LDX $80,Y
+        0x0A,               //$800C ASL A
+        0xD0, 0xF0,         //$800D BNE $7FFF
+        0x20, 0x00, 0x90,   //$800F JSR $9000
+        0x02,               //$8012 unknown
+        0x60,               //$8013 RTS
+        0xAD, 0x00,         //$8014 cut off, then BRK
+    ];
+
+    let lines = disassemble_all(0x8000, code.as_slice());
+    let text: Vec<String> = lines.iter().map(|line| line.text()).collect();
+    assert_eq!(text, vec!["LDA #$12", "LDA ($12),Y", "JMP ($1234)", "STA $0300,X", "LDX $80,Y",
+                          "ASL A", "BNE $7FFF", "JSR $9000", ".byte $02", "RTS", ".byte $AD", "BRK"]
+                    .iter().map(|s| s.to_string()).collect());
+
+    let addrs: Vec<u16> = lines.iter().map(|line| line.addr).collect();
+    assert_eq!(addrs, vec![0x8000, 0x8002, 0x8004, 0x8007, 0x800A, 0x800C, 0x800D, 0x800F, 0x8012,
+                           0x8013, 0x8014, 0x8015]);
+
+    assert_eq!(lines[6].target(), Some(0x7FFF));
+    assert_eq!(lines[7].target(), Some(0x9000));
+    assert_eq!(lines[2].target(), None);
+    assert_eq!(lines[3].bytes_text(), "9D 00 03".to_string());
+    assert_eq!(lines[3].operand(), 0x0300);
+
+    //forward branch across a page
+    let line = disassemble(0x80F0, &[0x10, 0x7F]);
+    assert_eq!(line.text(), "BPL $8171".to_string());
+
+    assert_eq!(isa::decode(0xA1).unwrap().len(), 2);
+    assert_eq!(isa::decode(0x4C).unwrap().len(), 3);
+    assert_eq!(isa::decode(0x60).unwrap().len(), 1);
+    assert_eq!(isa::INDY.operand_len(), 1);
+    assert_eq!(isa::ACC.operand_len(), 0);
+}
+
+#[test]
+fn cpu_disassemble_annotate_test() {
+    let mut prg_rom_bank = prg_rom_bank!(0xEA);
+    prg_rom_bank[0x0000] = 0xB1; //LDA ($89),Y
+    prg_rom_bank[0x0001] = 0x89;
+    prg_rom_bank[0x0002] = 0xA1; //LDA ($80,X)
+    prg_rom_bank[0x0003] = 0x80;
+    prg_rom_bank[0x0004] = 0xB5; //LDA $33,X
+    prg_rom_bank[0x0005] = 0x33;
+    prg_rom_bank[0x0006] = 0x6C; //JMP ($0200)
+    prg_rom_bank[0x0007] = 0x00;
+    prg_rom_bank[0x0008] = 0x02;
+    prg_rom_bank[0x0009] = 0x8E; //STX $0300
+    prg_rom_bank[0x000A] = 0x00;
+    prg_rom_bank[0x000B] = 0x03;
+    prg_rom_bank[0x000C] = 0x4C; //JMP $8000
+    prg_rom_bank[0x000D] = 0x00;
+    prg_rom_bank[0x000E] = 0x80;
+
+    let mut ram = ram!();
+    ram[0x0089] = 0x00;
+    ram[0x008A] = 0x03;
+    ram[0x0082] = 0x00;
+    ram[0x0083] = 0x02;
+    ram[0x0035] = 0x5A;
+    ram[0x0200] = 0x7E;
+    ram[0x0201] = 0xDB;
+    ram[0x0302] = 0x89;
+
+    let mut cpu = cpu!(prg_rom!(prg_rom_bank, prg_rom_bank!(0xEA)), ram);
+    cpu.state.X = 0x02;
+    cpu.state.Y = 0x02;
+
+    let annotated: Vec<String> = [0x8000, 0x8002, 0x8004, 0x8006, 0x8009, 0x800C].iter()
+        .map(|&addr| cpu.annotate(&cpu.disassemble(addr))).collect();
+    assert_eq!(annotated, vec![
+        "LDA ($89),Y = 0300 @ 0302 = 89",
+        "LDA ($80,X) @ 82 = 0200 = 7E",
+        "LDA $33,X @ 35 = 5A",
+        "JMP ($0200) = DB7E",
+        "STX $0300 = 00",
+        "JMP $8000",
+    ].iter().map(|s| s.to_string()).collect());
+
+    //annotating doesn't run anything
+    assert_eq!(cpu.state.PC, 0x0000);
+}
@@ -0,0 +1,124 @@
+use nes::VAddr;
+
+/// # Shadow call stack
+///
+/// Keeps its own list of the subroutines the CPU is in, from the JSRs, interrupts and BRKs
+/// it sees, rather than reading return addresses off the stack page. Games play tricks with
+/// the stack that make those useless:
+///
+/// - pushing an address and running RTS to jump there, usually out of a jump table
+/// - pulling their own return address to read data that follows the JSR, and pushing it back
+///   adjusted
+/// - pulling a return address to return straight to the caller's caller
+/// - TXS to start over, on reset or after an error
+///
+/// So a return is matched by the stack pointer instead of by the address. Every frame has S
+/// as it was before the call pushed anything, and an RTS or RTI that puts S back there returns
+/// from that frame, wherever it goes, and from any frames called after it. An RTS that doesn't
+/// get back to any frame's S is a jump, it's counted and the stack is left alone except for
+/// frames whose return address it pulled.
+///
+/// Tracking has to start before the calls do to know about them. An RTS that doesn't match a
+/// frame but lands right after a JSR is taken for a return from a call made before tracking
+/// started, not a jump.
+
+#[deriving(PartialEq, Show, Clone)]
+pub enum CallKind {
+    JsrCall,
+    NmiCall,
+    IrqCall,
+    BrkCall,
+}
+
+#[deriving(PartialEq, Show, Clone)]
+pub struct CallFrame {
+    pub kind: CallKind,
+
+    /// Where the subroutine or handler starts, and the 16 KB PRG-ROM bank that was there
+    pub entry: VAddr,
+    pub bank: Option<uint>,
+
+    /// The instruction after the JSR, or the one the interrupt or BRK goes back to
+    pub return_addr: VAddr,
+
+    /// S before the call pushed anything, returning puts it back
+    pub sp: u8,
+}
+
+/// What an RTS or RTI turned out to be.
+#[deriving(PartialEq, Show, Clone)]
+pub enum Return {
+    /// Returned from this many frames, more than one when the stack was unwound by hand
+    Returned(uint),
+    /// Went somewhere with an address it pushed itself
+    RtsJump,
+    /// Returned from a call tracking didn't see
+    Untracked,
+}
+
+#[deriving(PartialEq, Show, Clone)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+    rts_jumps: uint,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack {
+            frames: Vec::new(),
+            rts_jumps: 0,
+        }
+    }
+
+    /// Outermost first, the one running now is last.
+    pub fn frames<'a>(&'a self) -> &'a [CallFrame] {
+        self.frames.as_slice()
+    }
+
+    pub fn depth(&self) -> uint {
+        self.frames.len()
+    }
+
+    /// RTS jumps seen since tracking started.
+    pub fn rts_jumps(&self) -> uint {
+        self.rts_jumps
+    }
+
+    pub fn call(&mut self, frame: CallFrame) {
+        self.frames.push(frame);
+    }
+
+    /// An RTS or RTI left S at `s`. `after_jsr` is whether it went to just past a JSR.
+    pub fn ret(&mut self, s: u8, after_jsr: bool) -> Return {
+        match self.frames.iter().rposition(|frame| frame.sp == s) {
+            Some(pos) => {
+                let returned = self.frames.len() - pos;
+                self.frames.truncate(pos);
+                Returned(returned)
+            }
+            None => {
+                self.unwind(s);
+                if after_jsr {
+                    Untracked
+                } else {
+                    self.rts_jumps += 1;
+                    RtsJump
+                }
+            }
+        }
+    }
+
+    /// S moved up to `s`, past the return addresses of any frames that were called with it
+    /// lower. Returns how many frames that dropped.
+    pub fn unwind(&mut self, s: u8) -> uint {
+        //a frame's return address is at sp and sp - 1, pulled once S gets to sp - 1
+        let keep = self.frames.iter().take_while(|frame| (frame.sp as uint) >= (s as uint) + 2).count();
+        let dropped = self.frames.len() - keep;
+        self.frames.truncate(keep);
+        dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
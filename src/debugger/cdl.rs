@@ -0,0 +1,190 @@
+use std::io::{File, IoResult, IoError, InvalidInput};
+use std::mem;
+
+use nes::VAddr;
+
+/// # Code/Data Logger
+///
+/// from http://www.fceux.com/web/help/fceux.html?CodeDataLogger.html
+///
+/// Records how every byte of PRG-ROM and CHR-ROM was used, so a disassembler can tell code
+/// from data. The file is FCEUX's .cdl, one byte of flags for every PRG-ROM byte followed by
+/// one for every CHR-ROM byte.
+///
+/// PRG-ROM:
+///
+///  7 6 5 4 3 2 1 0
+///  _ P I J B B D C
+///    | | | +-+ | +- Code, fetched as an opcode or operand
+///    | | |  |  +--- Data, read by an instruction
+///    | | |  +------ Which 8 KB of $8000-$FFFF it was last accessed through
+///    | | +--------- Code reached through JMP ($xxxx), usually a jump table
+///    | +----------- Data read through a ($xx,X) or ($xx),Y pointer
+///    +------------- PCM, fetched by the DMC
+///
+/// CHR-ROM:
+///
+///  7 6 5 4 3 2 1 0
+///  _ _ _ _ _ _ R D
+///              | +- Drawn by the PPU
+///              +--- Read through $2007
+///
+/// The CPU marks PRG-ROM as it reads through the mapper, the PPU marks CHR-ROM. Flags only
+/// ever get added, so a log loaded from an earlier session keeps growing.
+///
+/// In new coverage mode the log remembers what was known when the mode started, and only
+/// what's been covered since is saved, so playing through one level gives a file with just
+/// the code and data that level used.
+
+pub static CDL_CODE: u8 = 0x01;
+pub static CDL_DATA: u8 = 0x02;
+pub static CDL_BANK_MASK: u8 = 0x0C;
+pub static CDL_INDIRECT_CODE: u8 = 0x10;
+pub static CDL_INDIRECT_DATA: u8 = 0x20;
+pub static CDL_PCM: u8 = 0x40;
+
+pub static CDL_CHR_RENDERED: u8 = 0x01;
+pub static CDL_CHR_READ: u8 = 0x02;
+
+#[deriving(PartialEq, Show, Clone)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+
+    //what was known when new coverage mode started
+    known: Option<(Vec<u8>, Vec<u8>)>,
+
+    //the instruction running reads through a pointer, and the next opcode was jumped to
+    //through one
+    indirect_data: bool,
+    indirect_jump: bool,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_rom_len: uint, chr_rom_len: uint) -> CodeDataLog {
+        CodeDataLog {
+            prg: Vec::from_elem(prg_rom_len, 0u8),
+            chr: Vec::from_elem(chr_rom_len, 0u8),
+            known: None,
+            indirect_data: false,
+            indirect_jump: false,
+        }
+    }
+
+    /// Reads a .cdl file, which has to be for a ROM of the same size.
+    pub fn open(path: &Path, prg_rom_len: uint, chr_rom_len: uint) -> IoResult<CodeDataLog> {
+        let bytes = try!(File::open(path).read_to_end());
+        if bytes.len() != prg_rom_len + chr_rom_len {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "CDL file is for a different sized ROM",
+                detail: Some(format!("{} bytes, expected {}", bytes.len(), prg_rom_len + chr_rom_len)),
+            });
+        }
+
+        let mut cdl = CodeDataLog::new(0, 0);
+        cdl.prg = bytes.slice_to(prg_rom_len).to_vec();
+        cdl.chr = bytes.slice_from(prg_rom_len).to_vec();
+        Ok(cdl)
+    }
+
+    /// Writes the .cdl file, only the new coverage in new coverage mode.
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut file = try!(File::create(path));
+        try!(file.write(self.prg_flags().as_slice()));
+        file.write(self.chr_flags().as_slice())
+    }
+
+    /// Only what's covered from now on gets saved.
+    pub fn start_new_coverage(&mut self) {
+        self.known = Some((self.prg.clone(), self.chr.clone()));
+    }
+
+    pub fn stop_new_coverage(&mut self) {
+        self.known = None;
+    }
+
+    /// The PRG-ROM flags as they'd be saved.
+    pub fn prg_flags(&self) -> Vec<u8> {
+        match self.known {
+            Some((ref prg, _)) => CodeDataLog::new_flags(self.prg.as_slice(), prg.as_slice()),
+            None => self.prg.clone(),
+        }
+    }
+
+    pub fn chr_flags(&self) -> Vec<u8> {
+        match self.known {
+            Some((_, ref chr)) => CodeDataLog::new_flags(self.chr.as_slice(), chr.as_slice()),
+            None => self.chr.clone(),
+        }
+    }
+
+    /// Everything logged for PRG-ROM, whether it's new or not.
+    pub fn prg_usage<'a>(&'a self) -> &'a [u8] {
+        self.prg.as_slice()
+    }
+
+    //flags that weren't set before, the bank bits only count along with new usage
+    fn new_flags(now: &[u8], known: &[u8]) -> Vec<u8> {
+        now.iter().zip(known.iter()).map(|(&now, &known)| {
+            let new = now & !known & !CDL_BANK_MASK;
+            if new != 0 { new | (now & CDL_BANK_MASK) } else { 0 }
+        }).collect()
+    }
+
+    /// PRG-ROM bytes with any flags set, only new ones in new coverage mode.
+    pub fn prg_covered(&self) -> uint {
+        self.prg_flags().iter().filter(|&&flags| flags != 0).count()
+    }
+
+    pub fn chr_covered(&self) -> uint {
+        self.chr_flags().iter().filter(|&&flags| flags != 0).count()
+    }
+
+    pub fn prg_len(&self) -> uint {
+        self.prg.len()
+    }
+
+    pub fn chr_len(&self) -> uint {
+        self.chr.len()
+    }
+
+    /// Marks a PRG-ROM byte read at `virtual_address`. Code and data reads pick up the
+    /// indirect flags of the instruction that's running.
+    pub fn log_prg(&mut self, offset: uint, virtual_address: VAddr, flags: u8) {
+        if offset >= self.prg.len() { return; }
+
+        let mut flags = flags | ((virtual_address >> 13) & 0x03) as u8 << 2;
+        if flags & CDL_CODE != 0 && self.indirect_jump {
+            flags |= CDL_INDIRECT_CODE;
+            self.indirect_jump = false;
+        }
+        if flags & CDL_DATA != 0 && self.indirect_data {
+            flags |= CDL_INDIRECT_DATA;
+        }
+
+        let byte = self.prg.get_mut(offset);
+        *byte = (*byte & !CDL_BANK_MASK) | flags;
+    }
+
+    /// Whether the instruction about to run reads its data through a pointer.
+    pub fn set_indirect_data(&mut self, indirect: bool) {
+        self.indirect_data = indirect;
+    }
+
+    /// A JMP ($xxxx) just ran, the next opcode is indirect code.
+    pub fn indirect_jump(&mut self) {
+        self.indirect_jump = true;
+    }
+
+    //the PPU keeps the CHR side while the log is recording, see Nes::start_cdl
+    pub fn take_chr(&mut self) -> Vec<u8> {
+        let mut chr = Vec::new();
+        mem::swap(&mut chr, &mut self.chr);
+        chr
+    }
+
+    pub fn put_chr(&mut self, chr: Vec<u8>) {
+        self.chr = chr;
+    }
+}
@@ -0,0 +1,423 @@
+use std::cmp;
+use std::num::from_str_radix;
+
+use nes::{Nes, VAddr};
+
+use debugger::{Debugger, BreakpointKind, ExecBreak, WatchBreak, OpcodeBreak, CpuBus, PpuBus};
+use debugger::{StopReason, BreakpointHit, WatchpointHit, StepDone, StepLimit, STEP_LIMIT_FRAMES};
+use debugger::{ReadAccess, WriteAccess, ExecAccess};
+use debugger::condition::Condition;
+use debugger::symbols::Symbols;
+use debugger::callstack::{NmiCall, IrqCall, BrkCall};
+
+/// # Debugger commands
+///
+/// What the frontend's debugger prompt takes. Addresses and opcodes are hex, with or without
+/// a $ in front:
+///
+///  c, continue [frames]          run until something stops it
+///  s, step                       run one instruction
+///  n, next                       step over a JSR
+///  o, out                        run until the subroutine returns
+///  b, break ADDR[-END] [if COND] break when PC gets there
+///  bo, breakop OP [if COND]      break before running an opcode
+///  w, watch [r|w|rw] [ppu] ADDR[-END] [if COND]
+///                                break after a read or write, writes by default
+///  d, delete ID                  remove a breakpoint or watchpoint
+///  enable ID, disable ID
+///  l, list                       breakpoints and watchpoints
+///  r, regs                       registers
+///  m, mem ADDR [LEN]             memory dump, LEN is decimal, 64 bytes by default
+///  dis [ADDR] [COUNT]            disassembly, from PC by default
+///  bt, backtrace                 the subroutines and interrupts PC is in
+///  prof [off]                    start profiling, or show the profile so far, off stops it
+///  h, help
+///  q, quit
+///
+/// See the condition module for COND.
+///
+/// With symbols, see Nes::load_symbols, an address can also be a name or a name and a decimal
+/// offset, `b main_loop`, `m buffer+16`. Disassembly and where it stopped show the names and
+/// source lines.
+///
+/// The backtrace only knows about calls made since the debugger was attached, see
+/// debugger::callstack.
+
+static DEFAULT_DUMP_LEN: uint = 64;
+static DEFAULT_DISASSEMBLY_LINES: uint = 10;
+static DUMP_ROW_LEN: uint = 16;
+
+#[deriving(PartialEq, Show, Clone)]
+pub enum Command {
+    Continue(Option<uint>),
+    Step,
+    Next,
+    Out,
+
+    /// Conditions are kept as text so commands can be compared, parse checked them already
+    AddBreakpoint(BreakpointKind, Option<String>),
+
+    Delete(uint),
+    Enable(uint, bool),
+    List,
+    Registers,
+    Memory(VAddr, uint),
+    Disassemble(Option<VAddr>, uint),
+    Backtrace,
+
+    /// Start or show the profile, or stop it
+    Profile(bool),
+
+    Help,
+    Quit,
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    parse_command_with(line, &Symbols::new())
+}
+
+/// Parses a command that can use the names in `symbols` for addresses.
+pub fn parse_command_with(line: &str, symbols: &Symbols) -> Result<Command, String> {
+    //everything after "if" is the condition
+    let (line, condition) = match line.find_str(" if ") {
+        Some(pos) => (line.slice_to(pos), Some(line.slice_from(pos + 4).trim().to_string())),
+        None => (line, None),
+    };
+
+    match condition {
+        Some(ref text) if Condition::parse(text.as_slice()).is_none() => {
+            return Err(format!("Bad condition: {}", text));
+        }
+        _ => { }
+    }
+
+    let words: Vec<&str> = line.words().collect();
+    if words.is_empty() { return Err("No command".to_string()); }
+    let args = words.slice_from(1);
+
+    let command = match words[0] {
+        "c" | "continue" => {
+            match args.get(0) {
+                Some(n) => match from_str::<uint>(*n) {
+                    Some(frames) => Continue(Some(frames)),
+                    None => { return Err(format!("Bad frame count: {}", n)); }
+                },
+                None => Continue(None),
+            }
+        }
+        "s" | "step" => Step,
+        "n" | "next" => Next,
+        "o" | "out" => Out,
+        "b" | "break" => {
+            let (start, end) = try!(parse_range(args.get(0), symbols));
+            AddBreakpoint(ExecBreak(start, end), condition.clone())
+        }
+        "bo" | "breakop" => {
+            let opcode = try!(parse_addr(args.get(0), symbols));
+            if opcode > 0xFF { return Err(format!("Bad opcode: ${:X}", opcode)); }
+            AddBreakpoint(OpcodeBreak(opcode as u8), condition.clone())
+        }
+        "w" | "watch" => {
+            let mut args = args;
+            let mode = match args.get(0).map(|arg| *arg) {
+                Some("r") => Some((true, false)),
+                Some("w") => Some((false, true)),
+                Some("rw") => Some((true, true)),
+                _ => None,
+            };
+            let (read, write) = match mode {
+                Some(mode) => {
+                    args = args.slice_from(1);
+                    mode
+                }
+                None => (false, true),
+            };
+
+            let bus = if args.get(0) == Some(&"ppu") {
+                args = args.slice_from(1);
+                PpuBus
+            } else {
+                CpuBus
+            };
+
+            let (start, end) = try!(parse_range(args.get(0), symbols));
+            AddBreakpoint(WatchBreak(bus, start, end, read, write), condition.clone())
+        }
+        "d" | "delete" => Delete(try!(parse_id(args.get(0)))),
+        "enable" => Enable(try!(parse_id(args.get(0))), true),
+        "disable" => Enable(try!(parse_id(args.get(0))), false),
+        "l" | "list" => List,
+        "r" | "regs" => Registers,
+        "m" | "mem" => {
+            let addr = try!(parse_addr(args.get(0), symbols));
+            let len = match args.get(1) {
+                Some(n) => match from_str::<uint>(*n) {
+                    Some(len) => len,
+                    None => { return Err(format!("Bad length: {}", n)); }
+                },
+                None => DEFAULT_DUMP_LEN,
+            };
+            Memory(addr, len)
+        }
+        "dis" => {
+            let addr = match args.get(0) {
+                Some(addr) => Some(try!(parse_addr(Some(addr), symbols))),
+                None => None,
+            };
+            let count = match args.get(1) {
+                Some(n) => match from_str::<uint>(*n) {
+                    Some(count) => count,
+                    None => { return Err(format!("Bad line count: {}", n)); }
+                },
+                None => DEFAULT_DISASSEMBLY_LINES,
+            };
+            Disassemble(addr, count)
+        }
+        "bt" | "backtrace" => Backtrace,
+        "prof" => {
+            match args.get(0).map(|arg| *arg) {
+                None => Profile(true),
+                Some("off") => Profile(false),
+                Some(arg) => { return Err(format!("Bad profile option: {}", arg)); }
+            }
+        }
+        "h" | "help" => Help,
+        "q" | "quit" => Quit,
+        _ => { return Err(format!("Unknown command: {}", words[0])); }
+    };
+
+    Ok(command)
+}
+
+fn parse_addr(arg: Option<&&str>, symbols: &Symbols) -> Result<VAddr, String> {
+    let arg = match arg {
+        Some(arg) => *arg,
+        None => { return Err("Missing address".to_string()); }
+    };
+
+    //names win over hex that happens to spell one, like "add"
+    let (name, offset) = match arg.find('+') {
+        Some(pos) => (arg.slice_to(pos), from_str::<uint>(arg.slice_from(pos + 1))),
+        None => (arg, Some(0)),
+    };
+    match (symbols.find(name), offset) {
+        (Some(symbol), Some(offset)) => { return Ok(symbol.addr + offset as VAddr); }
+        (Some(_), None) => { return Err(format!("Bad offset: {}", arg)); }
+        (None, _) => { }
+    }
+
+    let digits = if arg.starts_with("$") {
+        arg.slice_from(1)
+    } else if arg.starts_with("0x") {
+        arg.slice_from(2)
+    } else {
+        arg
+    };
+
+    match from_str_radix::<VAddr>(digits, 16) {
+        Some(addr) => Ok(addr),
+        None => Err(format!("Bad address: {}", arg)),
+    }
+}
+
+fn parse_range(arg: Option<&&str>, symbols: &Symbols) -> Result<(VAddr, VAddr), String> {
+    let arg = match arg {
+        Some(arg) => *arg,
+        None => { return Err("Missing address".to_string()); }
+    };
+
+    match arg.find('-') {
+        Some(pos) => {
+            let start = try!(parse_addr(Some(&arg.slice_to(pos)), symbols));
+            let end = try!(parse_addr(Some(&arg.slice_from(pos + 1)), symbols));
+            if end < start { return Err(format!("Bad range: {}", arg)); }
+            Ok((start, end))
+        }
+        None => {
+            let addr = try!(parse_addr(Some(&arg), symbols));
+            Ok((addr, addr))
+        }
+    }
+}
+
+fn parse_id(arg: Option<&&str>) -> Result<uint, String> {
+    match arg.and_then(|arg| from_str::<uint>(*arg)) {
+        Some(id) => Ok(id),
+        None => Err("Missing breakpoint number".to_string()),
+    }
+}
+
+/// Runs a command and returns what to print. Attaches a debugger first if there isn't one.
+/// Quit does nothing, it's for the frontend.
+pub fn execute_command(nes: &mut Nes, command: Command) -> String {
+    if nes.debugger().is_none() {
+        nes.attach_debugger(Debugger::new());
+    }
+
+    match command {
+        Continue(frames) => {
+            let stop = nes.debug_continue(frames);
+            stopped(nes, stop)
+        }
+        Step => {
+            let stop = nes.debug_step_into();
+            stopped(nes, stop)
+        }
+        Next => {
+            let stop = nes.debug_step_over();
+            stopped(nes, stop)
+        }
+        Out => {
+            let stop = nes.debug_step_out();
+            stopped(nes, stop)
+        }
+        AddBreakpoint(kind, condition) => {
+            let condition = condition.and_then(|text| Condition::parse(text.as_slice()));
+            let debugger = nes.debugger().unwrap();
+            let id = debugger.add(kind, condition);
+            let breakpoint = debugger.breakpoints().iter().find(|breakpoint| breakpoint.id == id).unwrap();
+            breakpoint.describe()
+        }
+        Delete(id) => {
+            if nes.debugger().unwrap().remove(id) { format!("Deleted #{}", id) } else { format!("No #{}", id) }
+        }
+        Enable(id, enabled) => {
+            if nes.debugger().unwrap().set_enabled(id, enabled) { String::new() } else { format!("No #{}", id) }
+        }
+        List => {
+            let debugger = nes.debugger().unwrap();
+            if debugger.breakpoints().is_empty() {
+                "No breakpoints".to_string()
+            } else {
+                let lines: Vec<String> = debugger.breakpoints().iter().map(|breakpoint| breakpoint.describe()).collect();
+                lines.as_slice().connect("\n")
+            }
+        }
+        Registers => registers(nes),
+        Memory(addr, len) => {
+            let mut lines = Vec::new();
+            let mut row = addr as uint;
+            while row < addr as uint + len && row <= 0xFFFF {
+                let end = cmp::min(row + DUMP_ROW_LEN, addr as uint + len);
+                let bytes: Vec<String> = range(row, end).map(|a| format!("{:02X}", nes.peek(a as VAddr))).collect();
+                lines.push(format!("{:04X}  {}", row, bytes.as_slice().connect(" ")));
+                row = end;
+            }
+            lines.as_slice().connect("\n")
+        }
+        Disassemble(addr, count) => {
+            let mut addr = addr.unwrap_or(nes.cpu_state().PC);
+            let mut lines = Vec::new();
+            for _ in range(0, count) {
+                let bank = nes.prg_bank(addr);
+                match nes.symbols().lookup(addr, bank) {
+                    Some(symbol) => { lines.push(format!("{}:", symbol.name)); }
+                    None => { }
+                }
+
+                let line = nes.disassemble(addr);
+                let text = format!("{:04X}  {:<8}  {}", addr, line.bytes_text(), nes.symbolic_text(&line));
+                match nes.symbols().source_line(addr, bank) {
+                    Some(source) if source.addr == addr => {
+                        lines.push(format!("{:<32}; {}:{}", text, source.file, source.line));
+                    }
+                    _ => { lines.push(text); }
+                }
+                addr += line.len() as VAddr;
+            }
+            lines.as_slice().connect("\n")
+        }
+        Backtrace => backtrace(nes),
+        Profile(true) => {
+            if nes.profiler().is_none() {
+                nes.start_profiler();
+                return "Profiling".to_string();
+            }
+            nes.profile_report().unwrap()
+        }
+        Profile(false) => {
+            let report = nes.profile_report();
+            nes.stop_profiler();
+            report.unwrap_or("Not profiling".to_string())
+        }
+        Help => HELP.to_string(),
+        Quit => String::new(),
+    }
+}
+
+fn stopped(nes: &mut Nes, stop: Option<StopReason>) -> String {
+    let reason = match stop {
+        Some(BreakpointHit(id)) => format!("Breakpoint #{}", id),
+        Some(WatchpointHit(id, access)) => {
+            let bus = if access.bus == PpuBus { "PPU " } else { "" };
+            let kind = match access.kind { ReadAccess => "read", WriteAccess => "write", ExecAccess => "fetch" };
+            format!("Watchpoint #{}, {}{} ${:04X} = {:02X}", id, bus, kind, access.addr, access.val)
+        }
+        Some(StepDone) => String::new(),
+        Some(StepLimit) => format!("Still going after {} frames", STEP_LIMIT_FRAMES),
+        None => format!("Frame {}", nes.frame_count()),
+    };
+
+    let pc = nes.cpu_state().PC;
+    let line = format!("{:04X}  {}\n{}", pc, nes.disassemble_pc(), registers(nes));
+    let line = match nes.describe_addr(pc) {
+        Some(location) => format!("{}\n{}", location, line),
+        None => line,
+    };
+    if reason.is_empty() { line } else { format!("{}\n{}", reason, line) }
+}
+
+//innermost first, #0 is where PC is and every frame after is where a call returns to
+fn backtrace(nes: &Nes) -> String {
+    let mut lines = vec![backtrace_line(nes, 0, nes.cpu_state().PC, "")];
+    match nes.call_stack() {
+        Some(call_stack) => {
+            //an interrupt or BRK stopped the code at the address it returns to
+            for (n, frame) in call_stack.frames().iter().rev().enumerate() {
+                let kind = match frame.kind {
+                    NmiCall => " [NMI]",
+                    IrqCall => " [IRQ]",
+                    BrkCall => " [BRK]",
+                    _ => "",
+                };
+                lines.push(backtrace_line(nes, n + 1, frame.return_addr, kind));
+            }
+        }
+        None => { }
+    }
+    lines.as_slice().connect("\n")
+}
+
+fn backtrace_line(nes: &Nes, n: uint, addr: VAddr, kind: &str) -> String {
+    match nes.describe_addr(addr) {
+        Some(location) => format!("#{:<2} ${:04X} {}{}", n, addr, location, kind),
+        None => format!("#{:<2} ${:04X}{}", n, addr, kind),
+    }
+}
+
+fn registers(nes: &Nes) -> String {
+    let state = nes.cpu_state();
+    format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+            state.A, state.X, state.Y, state.P.bits(), state.S, state.PC)
+}
+
+static HELP: &'static str = "\
+c, continue [frames]          run until something stops it
+s, step                       run one instruction
+n, next                       step over a JSR
+o, out                        run until the subroutine returns
+b, break ADDR[-END] [if COND] break when PC gets there
+bo, breakop OP [if COND]      break before running an opcode
+w, watch [r|w|rw] [ppu] ADDR[-END] [if COND]
+                              break after a read or write, writes by default
+d, delete ID                  remove a breakpoint or watchpoint
+enable ID, disable ID
+l, list                       breakpoints and watchpoints
+r, regs                       registers
+m, mem ADDR [LEN]             memory dump
+dis [ADDR] [COUNT]            disassembly, from PC by default
+bt, backtrace                 the subroutines and interrupts PC is in
+prof [off]                    start profiling, or show the profile so far, off stops it
+q, quit
+ADDR can be a symbol name, or a name and a decimal offset like main_loop+3
+COND compares registers A X Y S P PC, flags C Z I D V N and memory [ADDR], e.g. A == $40 && X > 3";
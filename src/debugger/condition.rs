@@ -0,0 +1,284 @@
+use std::ascii::StrAsciiExt;
+use std::num::from_str_radix;
+
+use nes::VAddr;
+
+use cpu::{Cpu, CpuState};
+use cpu::{C_FLAG, Z_FLAG, I_FLAG, D_FLAG, V_FLAG, N_FLAG};
+
+/// # Breakpoint conditions
+///
+/// C-like comparisons on the registers and memory:
+///
+///  A == $40 && X > 3
+///  [$0300] != 0 || (PC >= $C000 && S < $F0)
+///
+/// - registers - A, X, Y, S, P and PC, flags C, Z, I, D, V and N are 0 or 1
+/// - numbers   - $hex, %binary or decimal
+/// - memory    - [address] is the byte there, read without side effects. The address is a
+///               number, a register or another memory read, there's no arithmetic
+/// - operators - == != < <= > >=, then &&, then ||, and parentheses
+///
+/// A value on its own is true when it isn't 0.
+
+#[deriving(PartialEq, Show, Clone)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Operator(String),
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+}
+
+#[deriving(PartialEq, Show, Clone)]
+enum Register {
+    RegA,
+    RegX,
+    RegY,
+    RegS,
+    RegP,
+    RegPC,
+    RegFlag(u8),
+}
+
+#[deriving(PartialEq, Show, Clone)]
+enum Value {
+    Constant(u16),
+    Reg(Register),
+    Memory(Box<Value>),
+}
+
+#[deriving(PartialEq, Show, Clone)]
+enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[deriving(PartialEq, Show, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Comparison(Value, Compare, Value),
+    NonZero(Value),
+}
+
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Option<Condition> {
+        let tokens = match tokenize(text) {
+            Some(tokens) => tokens,
+            None => { return None; }
+        };
+
+        let mut parser = Parser { tokens: tokens, pos: 0 };
+        let expr = match parser.or() {
+            Some(expr) => expr,
+            None => { return None; }
+        };
+        if parser.pos != parser.tokens.len() { return None; }
+
+        Some(Condition {
+            text: text.trim().to_string(),
+            expr: expr,
+        })
+    }
+
+    /// The condition as it was typed
+    pub fn text<'a>(&'a self) -> &'a str {
+        self.text.as_slice()
+    }
+
+    pub fn eval(&self, state: &CpuState, cpu: &Cpu) -> bool {
+        eval_expr(&self.expr, state, cpu)
+    }
+}
+
+fn eval_expr(expr: &Expr, state: &CpuState, cpu: &Cpu) -> bool {
+    match *expr {
+        Or(ref a, ref b) => eval_expr(&**a, state, cpu) || eval_expr(&**b, state, cpu),
+        And(ref a, ref b) => eval_expr(&**a, state, cpu) && eval_expr(&**b, state, cpu),
+        Comparison(ref a, compare, ref b) => {
+            let a = eval_value(a, state, cpu);
+            let b = eval_value(b, state, cpu);
+            match compare {
+                Equal => a == b,
+                NotEqual => a != b,
+                Less => a < b,
+                LessEqual => a <= b,
+                Greater => a > b,
+                GreaterEqual => a >= b,
+            }
+        }
+        NonZero(ref a) => eval_value(a, state, cpu) != 0,
+    }
+}
+
+fn eval_value(value: &Value, state: &CpuState, cpu: &Cpu) -> u16 {
+    match *value {
+        Constant(n) => n,
+        Reg(register) => match register {
+            RegA => state.A as u16,
+            RegX => state.X as u16,
+            RegY => state.Y as u16,
+            RegS => state.S as u16,
+            RegP => state.P.bits() as u16,
+            RegPC => state.PC,
+            RegFlag(bits) => if state.P.bits() & bits != 0 { 1 } else { 0 },
+        },
+        Memory(ref addr) => cpu.peek_byte(eval_value(&**addr, state, cpu) as VAddr) as u16,
+    }
+}
+
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        let start = pos;
+        pos += 1;
+
+        if c.is_whitespace() {
+            continue;
+        } else if c == '(' {
+            tokens.push(Open);
+        } else if c == ')' {
+            tokens.push(Close);
+        } else if c == '[' {
+            tokens.push(OpenBracket);
+        } else if c == ']' {
+            tokens.push(CloseBracket);
+        } else if c == '$' || c == '%' || c.is_digit() {
+            let radix = match c { '$' => 16, '%' => 2, _ => 10 };
+            let digits_start = if c.is_digit() { start } else { pos };
+            while pos < chars.len() && chars[pos].is_alphanumeric() { pos += 1; }
+
+            let digits = String::from_chars(chars.slice(digits_start, pos));
+            match from_str_radix::<u16>(digits.as_slice(), radix) {
+                Some(n) => { tokens.push(Number(n)); }
+                None => { return None; }
+            }
+        } else if c.is_alphabetic() {
+            while pos < chars.len() && chars[pos].is_alphanumeric() { pos += 1; }
+            tokens.push(Name(String::from_chars(chars.slice(start, pos)).as_slice().to_ascii_upper()));
+        } else if "=!<>&|".contains_char(c) {
+            while pos < chars.len() && "=&|".contains_char(chars[pos]) && pos - start < 2 { pos += 1; }
+            tokens.push(Operator(String::from_chars(chars.slice(start, pos))));
+        } else {
+            return None;
+        }
+    }
+
+    Some(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: uint,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<Token> {
+        if self.pos < self.tokens.len() { Some(self.tokens[self.pos].clone()) } else { None }
+    }
+
+    fn next_is(&mut self, token: Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Option<Expr> {
+        let mut expr = match self.and() { Some(expr) => expr, None => { return None; } };
+        while self.next_is(Operator("||".to_string())) {
+            let rhs = match self.and() { Some(expr) => expr, None => { return None; } };
+            expr = Or(box expr, box rhs);
+        }
+        Some(expr)
+    }
+
+    fn and(&mut self) -> Option<Expr> {
+        let mut expr = match self.comparison() { Some(expr) => expr, None => { return None; } };
+        while self.next_is(Operator("&&".to_string())) {
+            let rhs = match self.comparison() { Some(expr) => expr, None => { return None; } };
+            expr = And(box expr, box rhs);
+        }
+        Some(expr)
+    }
+
+    fn comparison(&mut self) -> Option<Expr> {
+        if self.next_is(Open) {
+            let expr = self.or();
+            if !self.next_is(Close) { return None; }
+            return expr;
+        }
+
+        let lhs = match self.value() { Some(value) => value, None => { return None; } };
+
+        let compare = match self.peek() {
+            Some(Operator(op)) => match op.as_slice() {
+                "==" => Equal,
+                "!=" => NotEqual,
+                "<" => Less,
+                "<=" => LessEqual,
+                ">" => Greater,
+                ">=" => GreaterEqual,
+                _ => { return Some(NonZero(lhs)); }
+            },
+            _ => { return Some(NonZero(lhs)); }
+        };
+        self.pos += 1;
+
+        match self.value() {
+            Some(rhs) => Some(Comparison(lhs, compare, rhs)),
+            None => None,
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        let token = match self.peek() { Some(token) => token, None => { return None; } };
+        self.pos += 1;
+
+        match token {
+            Number(n) => Some(Constant(n)),
+            Name(name) => {
+                let register = match name.as_slice() {
+                    "A" => RegA,
+                    "X" => RegX,
+                    "Y" => RegY,
+                    "S" | "SP" => RegS,
+                    "P" => RegP,
+                    "PC" => RegPC,
+                    "C" => RegFlag(C_FLAG.bits()),
+                    "Z" => RegFlag(Z_FLAG.bits()),
+                    "I" => RegFlag(I_FLAG.bits()),
+                    "D" => RegFlag(D_FLAG.bits()),
+                    "V" => RegFlag(V_FLAG.bits()),
+                    "N" => RegFlag(N_FLAG.bits()),
+                    _ => { return None; }
+                };
+                Some(Reg(register))
+            }
+            OpenBracket => {
+                let addr = self.value();
+                if !self.next_is(CloseBracket) { return None; }
+                addr.map(|addr| Memory(box addr))
+            }
+            _ => None,
+        }
+    }
+}
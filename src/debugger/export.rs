@@ -0,0 +1,367 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet, TreeMap};
+
+use nes::{VAddr, PRG_ROM_BANK_SIZE};
+
+use cpu::isa;
+use cpu::isa::Instruction;
+use cpu::disasm::{Disassembly, disassemble};
+
+use debugger::cdl::{CDL_CODE, CDL_DATA, CDL_PCM, CDL_BANK_MASK};
+use debugger::symbols::Symbols;
+
+/// # Disassembly export
+///
+/// Writes PRG-ROM out as source that ca65 or asm6 assembles back into the same bytes, one file
+/// for every 16 KB bank:
+///
+///  ; Bank 0, PRG-ROM $0000-$3FFF at $8000-$BFFF
+///  ; 1022 bytes of code, 210 of data, 15152 unused
+///
+///  PPUSTATUS = $2002
+///
+///  .org $8000
+///
+///  Reset:
+///      SEI                         ; $8000
+///  L8001:
+///      BIT PPUSTATUS               ; $8001
+///      BPL L8001                   ; $8004
+///      .byte $00,$10,$20,$30       ; $8006
+///
+/// With a code/data log only what it saw run is decoded as code, everything else comes out as
+/// .byte. Without one anything isa::decode knows is taken for code.
+///
+/// JSR, JMP and branch targets in the bank get labels, named from the symbols where there is
+/// one and `L<addr>` where there isn't. Symbols for operands outside the bank become equates
+/// at the top. Names that aren't plain identifiers, or that are already taken by another
+/// address, are left as addresses.
+///
+/// A bank goes wherever the log says it was used, or at $C000 for the last bank and $8000
+/// for the others without one, which is right for NROM and most fixed-bank mappers.
+///
+/// Absolute operands below $100 would be assembled as zero page, ca65 gets them with an a:
+/// in front and asm6, which can't do that, gets the instruction as .byte.
+
+#[deriving(PartialEq, Show, Clone)]
+pub enum AsmStyle {
+    Ca65Style,
+    Asm6Style,
+}
+
+static DATA_ROW_LEN: uint = 8;
+
+enum Item {
+    CodeItem(Disassembly),
+    DataItem(VAddr, u8),
+}
+
+impl Item {
+    fn addr(&self) -> VAddr {
+        match *self {
+            CodeItem(ref line) => line.addr,
+            DataItem(addr, _) => addr,
+        }
+    }
+}
+
+/// Every bank of `prg`, see above. `usage` is the PRG-ROM part of a code/data log.
+pub fn export(prg: &[u8], usage: Option<&[u8]>, symbols: &Symbols, style: AsmStyle) -> Vec<String> {
+    let banks = (prg.len() + PRG_ROM_BANK_SIZE - 1) / PRG_ROM_BANK_SIZE;
+    range(0, banks).map(|bank| export_bank(prg, usage, bank, symbols, style)).collect()
+}
+
+pub fn export_bank(prg: &[u8], usage: Option<&[u8]>, bank: uint, symbols: &Symbols, style: AsmStyle) -> String {
+    let start = bank * PRG_ROM_BANK_SIZE;
+    let end = cmp::min(start + PRG_ROM_BANK_SIZE, prg.len());
+    let bytes = prg.slice(start, end);
+    let usage = usage.map(|usage| usage.slice(start, end));
+    let base = bank_base(usage, end == prg.len());
+
+    let items = split_items(bytes, usage, base, style);
+
+    let mut bank_export = BankExport {
+        symbols: symbols,
+        style: style,
+        bank: bank,
+        base: base,
+        len: bytes.len(),
+        labels: HashMap::new(),
+        names: HashMap::new(),
+        equates: TreeMap::new(),
+    };
+    bank_export.find_labels(&items);
+
+    let body = bank_export.body(&items);
+    let mut text = bank_export.header(start, usage);
+    text.push_str(body.as_slice());
+    text
+}
+
+/// Where in $8000-$FFFF a bank goes, see above.
+pub fn bank_base(usage: Option<&[u8]>, last_bank: bool) -> VAddr {
+    //bytes used through $8000-$BFFF and through $C000-$FFFF
+    let mut windows = [0u, 0u];
+    match usage {
+        Some(usage) => {
+            for &flags in usage.iter() {
+                if flags & (CDL_CODE | CDL_DATA | CDL_PCM) != 0 {
+                    windows[((flags & CDL_BANK_MASK) >> 3) as uint] += 1;
+                }
+            }
+        }
+        None => { }
+    }
+
+    if windows[0] == 0 && windows[1] == 0 {
+        if last_bank { 0xC000 } else { 0x8000 }
+    } else if windows[1] > windows[0] {
+        0xC000
+    } else {
+        0x8000
+    }
+}
+
+fn split_items(bytes: &[u8], usage: Option<&[u8]>, base: VAddr, style: AsmStyle) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let line = disassemble(base + pos as VAddr, bytes.slice_from(pos));
+        let logged_code = match usage {
+            Some(usage) => usage.slice(pos, pos + line.len()).iter().all(|&flags| flags & CDL_CODE != 0),
+            None => true,
+        };
+
+        if line.instruction.is_some() && logged_code && reassembles(&line, style) {
+            pos += line.len();
+            items.push(CodeItem(line));
+        } else {
+            items.push(DataItem(line.addr, bytes[pos]));
+            pos += 1;
+        }
+    }
+
+    items
+}
+
+//an absolute operand an assembler would turn into zero page
+fn forced_absolute(line: &Disassembly, instruction: Instruction) -> bool {
+    let absolute = match instruction.address_mode {
+        isa::ABS | isa::ABSX | isa::ABSY => instruction.instr != isa::JMP && instruction.instr != isa::JSR,
+        _ => false,
+    };
+    absolute && line.operand() < 0x100
+}
+
+fn reassembles(line: &Disassembly, style: AsmStyle) -> bool {
+    match line.instruction {
+        Some(instruction) => style != Asm6Style || !forced_absolute(line, instruction),
+        None => false,
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    let reserved = ["A", "X", "Y", "a", "x", "y"];
+    !name.is_empty()
+        && !reserved.iter().any(|&word| word == name)
+        && !name.char_at(0).is_digit()
+        && name.chars().all(|c| c.is_alphanumeric() && c.is_ascii() || c == '_')
+}
+
+struct BankExport<'a> {
+    symbols: &'a Symbols,
+    style: AsmStyle,
+    bank: uint,
+    base: VAddr,
+    len: uint,
+
+    labels: HashMap<VAddr, String>,
+
+    //every name used so far and its address, labels and equates
+    names: HashMap<String, VAddr>,
+    equates: TreeMap<VAddr, String>,
+}
+
+impl<'a> BankExport<'a> {
+    fn in_bank(&self, addr: VAddr) -> bool {
+        addr >= self.base && ((addr - self.base) as uint) < self.len
+    }
+
+    fn add_name(&mut self, addr: VAddr, name: &str) -> bool {
+        if !valid_name(name) { return false; }
+        match self.names.find_equiv(&name) {
+            Some(&other) => { return other == addr; }
+            None => { }
+        }
+        self.names.insert(name.to_string(), addr);
+        true
+    }
+
+    fn find_labels(&mut self, items: &Vec<Item>) {
+        let starts: HashSet<VAddr> = items.iter().map(|item| item.addr()).collect();
+
+        //the symbols first, so they win over made up names
+        for item in items.iter() {
+            let addr = item.addr();
+            let name = match self.symbols.lookup(addr, Some(self.bank)) {
+                Some(symbol) => symbol.name.clone(),
+                None => { continue; }
+            };
+            if self.add_name(addr, name.as_slice()) {
+                self.labels.insert(addr, name);
+            }
+        }
+
+        for item in items.iter() {
+            let target = match *item {
+                CodeItem(ref line) => line.target(),
+                DataItem(..) => None,
+            };
+            match target {
+                Some(target) if self.in_bank(target) && starts.contains(&target) && !self.labels.contains_key(&target) => {
+                    let name = format!("L{:04X}", target);
+                    if self.add_name(target, name.as_slice()) {
+                        self.labels.insert(target, name);
+                    }
+                }
+                _ => { }
+            }
+        }
+    }
+
+    //the label or symbol for an operand, symbols outside the labels become equates
+    fn name(&mut self, addr: VAddr) -> Option<String> {
+        match self.labels.find(&addr) {
+            Some(name) => { return Some(name.clone()); }
+            None => { }
+        }
+
+        let bank = if self.in_bank(addr) { Some(self.bank) } else { None };
+        let name = match self.symbols.lookup(addr, bank) {
+            Some(symbol) => symbol.name.clone(),
+            None => { return None; }
+        };
+        if !self.add_name(addr, name.as_slice()) { return None; }
+
+        self.equates.insert(addr, name.clone());
+        Some(name)
+    }
+
+    fn instruction_text(&mut self, line: &Disassembly, instruction: Instruction) -> String {
+        let mode = instruction.address_mode;
+        let addr = match line.target() {
+            Some(target) if mode == isa::REL => target,
+            _ => line.operand(),
+        };
+
+        let name = match mode {
+            isa::IMM | isa::IMP | isa::ACC | isa::ADDRESS_MODE_NONE => String::new(),
+            _ => match self.name(addr) {
+                Some(name) => name,
+                None if mode.operand_len() == 1 && mode != isa::REL => format!("${:02X}", addr),
+                None => format!("${:04X}", addr),
+            },
+        };
+        let name = if forced_absolute(line, instruction) { format!("a:{}", name) } else { name };
+
+        let operand = match mode {
+            isa::IMM => format!("#${:02X}", addr),
+            isa::ZP | isa::ABS | isa::REL => name,
+            isa::ZPX | isa::ABSX => format!("{},X", name),
+            isa::ZPY | isa::ABSY => format!("{},Y", name),
+            isa::IND => format!("({})", name),
+            isa::INDX => format!("({},X)", name),
+            isa::INDY => format!("({}),Y", name),
+            isa::ACC => "A".to_string(),
+            isa::IMP | isa::ADDRESS_MODE_NONE => String::new(),
+        };
+
+        if operand.is_empty() {
+            format!("{}", instruction.instr)
+        } else {
+            format!("{} {}", instruction.instr, operand)
+        }
+    }
+
+    fn body(&mut self, items: &Vec<Item>) -> String {
+        let mut text = String::new();
+        let mut row: Vec<u8> = Vec::new();
+        let mut row_addr = self.base;
+
+        for item in items.iter() {
+            let addr = item.addr();
+            let label = self.labels.find(&addr).map(|label| label.clone());
+
+            let is_code = match *item { CodeItem(..) => true, DataItem(..) => false };
+            if !row.is_empty() && (label.is_some() || is_code || row.len() == DATA_ROW_LEN) {
+                text.push_str(data_row(row_addr, row.as_slice()).as_slice());
+                row.truncate(0);
+            }
+
+            match label {
+                Some(label) => { text.push_str(format!("{}:\n", label).as_slice()); }
+                None => { }
+            }
+
+            match *item {
+                CodeItem(ref line) => {
+                    let code = self.instruction_text(line, line.instruction.unwrap());
+                    text.push_str(source_line(code, addr).as_slice());
+                }
+                DataItem(addr, byte) => {
+                    if row.is_empty() { row_addr = addr; }
+                    row.push(byte);
+                }
+            }
+        }
+
+        if !row.is_empty() {
+            text.push_str(data_row(row_addr, row.as_slice()).as_slice());
+        }
+        text
+    }
+
+    fn header(&self, start: uint, usage: Option<&[u8]>) -> String {
+        let mut text = format!("; Bank {}, PRG-ROM ${:04X}-${:04X} at ${:04X}-${:04X}\n",
+                               self.bank, start, start + self.len - 1,
+                               self.base, self.base as uint + self.len - 1);
+        match usage {
+            Some(usage) => {
+                let code = usage.iter().filter(|&&flags| flags & CDL_CODE != 0).count();
+                let data = usage.iter().filter(|&&flags| flags & CDL_CODE == 0 && flags & (CDL_DATA | CDL_PCM) != 0).count();
+                text.push_str(format!("; {} bytes of code, {} of data, {} unused\n",
+                                      code, data, usage.len() - code - data).as_slice());
+            }
+            None => { }
+        }
+        text.push_str("\n");
+
+        if self.style == Ca65Style {
+            text.push_str(".setcpu \"6502\"\n\n");
+        }
+
+        if !self.equates.is_empty() {
+            for (&addr, name) in self.equates.iter() {
+                if addr < 0x100 {
+                    text.push_str(format!("{} = ${:02X}\n", name, addr).as_slice());
+                } else {
+                    text.push_str(format!("{} = ${:04X}\n", name, addr).as_slice());
+                }
+            }
+            text.push_str("\n");
+        }
+
+        text.push_str(format!(".org ${:04X}\n\n", self.base).as_slice());
+        text
+    }
+}
+
+fn source_line(code: String, addr: VAddr) -> String {
+    format!("    {:<27} ; ${:04X}\n", code, addr)
+}
+
+fn data_row(addr: VAddr, bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", *byte)).collect();
+    source_line(format!(".byte {}", bytes.as_slice().connect(",")), addr)
+}
@@ -0,0 +1,400 @@
+use std::io::{IoResult, IoError, TimedOut};
+use std::io::{Listener, Acceptor};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::num::from_str_radix;
+
+use nes::{Nes, VAddr};
+
+use cpu::CpuState;
+use cpu::CpuFlags;
+
+use debugger::{Debugger, BreakpointKind, ExecBreak, WatchBreak, CpuBus};
+use debugger::{StopReason, BreakpointHit, WatchpointHit, StepDone, StepLimit};
+
+/// # GDB remote serial protocol
+///
+/// from https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+///
+/// Lets gdb, or anything else that speaks the protocol, debug the running emulator over TCP.
+/// Packets are `$data#cc`, where cc is the sum of the data bytes mod 256 in hex, and each one
+/// is acknowledged with + (or - to ask for it again). A lone 0x03 byte interrupts the target.
+///
+/// gdb has no 6502 target, so the register layout is ours, 7 bytes in this order:
+///
+///  0 A, 1 X, 2 Y, 3 S, 4 P, 5 PC (2 bytes, little endian)
+///
+/// Supported packets:
+///
+/// - ?                  - why the target stopped
+/// - g, G               - read and write all the registers
+/// - p n, P n=v         - read and write one register
+/// - m addr,len         - read memory, without side effects, see Nes::peek
+/// - M addr,len:bytes   - write memory, see Nes::poke
+/// - c, s               - continue and step, the reply comes when the target stops
+/// - Z0/z0 addr         - execution breakpoints (Z1 hardware breakpoints are the same thing)
+/// - Z2/Z3/Z4 addr,len  - write, read and access watchpoints on the CPU bus, they stop with
+///                        watch, rwatch and awatch replies
+/// - D, k               - detach and kill, both just end the session
+///
+/// Anything else gets the empty reply that means unsupported.
+
+//signals in stop replies
+static SIGINT: u8 = 2;
+static SIGTRAP: u8 = 5;
+
+//how long to look for an interrupt between frames while running
+static INTERRUPT_POLL_MS: u64 = 1;
+
+static REGISTER_COUNT: uint = 6;
+
+/// What the stub wants done after a packet.
+#[deriving(PartialEq, Show)]
+pub enum GdbAction {
+    GdbReply(String),
+
+    /// Run until a breakpoint or an interrupt, then reply with the stop
+    GdbContinue,
+
+    /// Reply and end the session
+    GdbDetach(String),
+}
+
+pub struct GdbStub {
+    //the debugger's ids for gdb's breakpoints
+    breakpoints: Vec<(BreakpointKind, uint)>,
+}
+
+impl GdbStub {
+    /// Attaches a debugger to the NES if it doesn't have one.
+    pub fn new(nes: &mut Nes) -> GdbStub {
+        if nes.debugger().is_none() {
+            nes.attach_debugger(Debugger::new());
+        }
+
+        GdbStub {
+            breakpoints: Vec::new(),
+        }
+    }
+
+    /// Handles the data of one packet.
+    pub fn handle(&mut self, nes: &mut Nes, packet: &str) -> GdbAction {
+        if packet.is_empty() { return GdbReply(String::new()); }
+
+        let args = packet.slice_from(1);
+        let reply = match packet.char_at(0) {
+            '?' => stop_reply(SIGTRAP),
+            'g' => {
+                let registers = read_registers(&nes.cpu_state());
+                to_hex(registers.as_slice())
+            }
+            'G' => {
+                match from_hex(args) {
+                    Some(ref bytes) if bytes.len() == 7 => {
+                        let state = write_registers(nes.cpu_state(), bytes.as_slice());
+                        nes.set_cpu_state(state);
+                        "OK".to_string()
+                    }
+                    _ => error(1),
+                }
+            }
+            'p' => {
+                match from_str_radix::<uint>(args, 16) {
+                    Some(n) if n < REGISTER_COUNT => {
+                        let registers = read_registers(&nes.cpu_state());
+                        let start = register_start(n);
+                        to_hex(registers.slice(start, start + register_len(n)))
+                    }
+                    _ => error(1),
+                }
+            }
+            'P' => {
+                let parts: Vec<&str> = args.splitn('=', 1).collect();
+                let n = from_str_radix::<uint>(parts[0], 16);
+                let bytes = if parts.len() == 2 { from_hex(parts[1]) } else { None };
+                match (n, bytes) {
+                    (Some(n), Some(bytes)) if n < REGISTER_COUNT && bytes.len() == register_len(n) => {
+                        let mut registers = read_registers(&nes.cpu_state());
+                        let start = register_start(n);
+                        for (i, &byte) in bytes.iter().enumerate() {
+                            *registers.get_mut(start + i) = byte;
+                        }
+                        let state = write_registers(nes.cpu_state(), registers.as_slice());
+                        nes.set_cpu_state(state);
+                        "OK".to_string()
+                    }
+                    _ => error(1),
+                }
+            }
+            'm' => {
+                match parse_addr_len(args) {
+                    Some((addr, len)) => {
+                        let bytes: Vec<u8> = range(0, len).map(|i| nes.peek(addr + i as VAddr)).collect();
+                        to_hex(bytes.as_slice())
+                    }
+                    None => error(1),
+                }
+            }
+            'M' => {
+                let parts: Vec<&str> = args.splitn(':', 1).collect();
+                let target = parse_addr_len(parts[0]);
+                let bytes = if parts.len() == 2 { from_hex(parts[1]) } else { None };
+                match (target, bytes) {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+                        let mut ok = true;
+                        for (i, &byte) in bytes.iter().enumerate() {
+                            ok = nes.poke(addr + i as VAddr, byte) && ok;
+                        }
+                        if ok { "OK".to_string() } else { error(14) } //EFAULT
+                    }
+                    _ => error(1),
+                }
+            }
+            'c' => {
+                if !args.is_empty() { return GdbReply(error(1)); } //resuming at an address isn't supported
+                return GdbContinue;
+            }
+            's' => {
+                if !args.is_empty() { return GdbReply(error(1)); }
+                let stop = nes.debug_step_into();
+                stop_reason_reply(nes, stop)
+            }
+            'Z' | 'z' => self.breakpoint(nes, packet.char_at(0) == 'Z', args),
+            'D' => { return GdbDetach("OK".to_string()); }
+            'k' => { return GdbDetach(String::new()); }
+            'q' => {
+                if args == "Attached" {
+                    "1".to_string()
+                } else if args.starts_with("Supported") {
+                    "PacketSize=1000".to_string()
+                } else {
+                    String::new()
+                }
+            }
+            _ => String::new(),
+        };
+
+        GdbReply(reply)
+    }
+
+    //Z/z type,addr,kind
+    fn breakpoint(&mut self, nes: &mut Nes, insert: bool, args: &str) -> String {
+        let parts: Vec<&str> = args.split(',').collect();
+        if parts.len() < 3 { return error(1); }
+
+        let addr = from_str_radix::<VAddr>(parts[1], 16);
+        let len = from_str_radix::<VAddr>(parts[2], 16);
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr, if len == 0 { 1 } else { len }),
+            _ => { return error(1); }
+        };
+        let end = addr + (len - 1);
+
+        let kind = match parts[0] {
+            "0" | "1" => ExecBreak(addr, addr),
+            "2" => WatchBreak(CpuBus, addr, end, false, true),
+            "3" => WatchBreak(CpuBus, addr, end, true, false),
+            "4" => WatchBreak(CpuBus, addr, end, true, true),
+            _ => { return String::new(); }
+        };
+
+        let debugger = nes.debugger().unwrap();
+        if insert {
+            if !self.breakpoints.iter().any(|&(existing, _)| existing == kind) {
+                let id = debugger.add(kind, None);
+                self.breakpoints.push((kind, id));
+            }
+            "OK".to_string()
+        } else {
+            match self.breakpoints.iter().position(|&(existing, _)| existing == kind) {
+                Some(i) => {
+                    let (_, id) = self.breakpoints.remove(i).unwrap();
+                    debugger.remove(id);
+                    "OK".to_string()
+                }
+                None => error(1),
+            }
+        }
+    }
+
+    /// Talks to one client until it detaches or disconnects.
+    pub fn session(&mut self, nes: &mut Nes, stream: &mut TcpStream) -> IoResult<()> {
+        loop {
+            let packet = match try!(read_packet(stream)) {
+                Some(packet) => packet,
+                None => {
+                    //interrupted while already stopped
+                    try!(write_packet(stream, stop_reply(SIGINT).as_slice()));
+                    continue;
+                }
+            };
+
+            match self.handle(nes, packet.as_slice()) {
+                GdbReply(reply) => { try!(write_packet(stream, reply.as_slice())); }
+                GdbContinue => {
+                    let reply = try!(GdbStub::run(nes, stream));
+                    try!(write_packet(stream, reply.as_slice()));
+                }
+                GdbDetach(reply) => {
+                    if !reply.is_empty() { try!(write_packet(stream, reply.as_slice())); }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    //runs a frame at a time, looking for an interrupt from the client in between
+    fn run(nes: &mut Nes, stream: &mut TcpStream) -> IoResult<String> {
+        loop {
+            let stop = nes.debug_continue(Some(1));
+            if stop.is_some() { return Ok(stop_reason_reply(nes, stop)); }
+
+            stream.set_read_timeout(Some(INTERRUPT_POLL_MS));
+            let byte = stream.read_byte();
+            stream.set_read_timeout(None);
+
+            match byte {
+                Ok(0x03) => { return Ok(stop_reply(SIGINT)); }
+                Ok(_) => { }
+                Err(IoError { kind: TimedOut, .. }) => { }
+                Err(e) => { return Err(e); }
+            }
+        }
+    }
+}
+
+/// Waits for a client on localhost and debugs until it's done.
+pub fn serve_gdb(nes: &mut Nes, port: u16) -> IoResult<()> {
+    let listener = try!(TcpListener::bind("127.0.0.1", port));
+    let mut acceptor = try!(listener.listen());
+    info!("Waiting for gdb on port {}", port);
+
+    let mut stream = try!(acceptor.accept());
+    let mut stub = GdbStub::new(nes);
+    stub.session(nes, &mut stream)
+}
+
+/// Reads and acknowledges the next packet. None for an interrupt.
+pub fn read_packet<R: Reader + Writer>(stream: &mut R) -> IoResult<Option<String>> {
+    loop {
+        match try!(stream.read_byte()) {
+            0x03 => { return Ok(None); }
+            b'$' => { }
+            _ => { continue; } //acks and noise
+        }
+
+        let mut data = Vec::new();
+        loop {
+            let byte = try!(stream.read_byte());
+            if byte == b'#' { break; }
+            data.push(byte);
+        }
+
+        let digits = [try!(stream.read_byte()), try!(stream.read_byte())];
+        let expected = from_str_radix::<u8>(String::from_utf8_lossy(digits.as_slice()).as_slice(), 16);
+        if expected == Some(checksum(data.as_slice())) {
+            try!(stream.write(b"+"));
+            return Ok(Some(String::from_utf8_lossy(data.as_slice()).into_string()));
+        } else {
+            try!(stream.write(b"-"));
+        }
+    }
+}
+
+pub fn write_packet<W: Writer>(stream: &mut W, data: &str) -> IoResult<()> {
+    stream.write_str(encode_packet(data).as_slice())
+}
+
+/// `$data#cc`
+pub fn encode_packet(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data.as_bytes()))
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum + byte)
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+//gdb tells write, read and access watchpoints apart by the stop reason
+fn stop_reason_reply(nes: &mut Nes, stop: Option<StopReason>) -> String {
+    match stop {
+        Some(WatchpointHit(id, access)) => {
+            let kind = match nes.debugger() {
+                Some(debugger) => {
+                    let breakpoint = debugger.breakpoints().iter().find(|breakpoint| breakpoint.id == id);
+                    breakpoint.map(|breakpoint| breakpoint.kind)
+                }
+                None => None,
+            };
+            let reason = match kind {
+                Some(WatchBreak(_, _, _, true, true)) => "awatch",
+                Some(WatchBreak(_, _, _, true, false)) => "rwatch",
+                _ => "watch",
+            };
+            format!("T{:02x}{}:{:04x};", SIGTRAP, reason, access.addr)
+        }
+        Some(BreakpointHit(..)) | Some(StepDone) => stop_reply(SIGTRAP),
+        Some(StepLimit) | None => stop_reply(SIGINT),
+    }
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+//A X Y S P PCL PCH
+fn read_registers(state: &CpuState) -> Vec<u8> {
+    vec![state.A, state.X, state.Y, state.S, state.P.bits(), state.PC as u8, (state.PC >> 8) as u8]
+}
+
+fn write_registers(mut state: CpuState, bytes: &[u8]) -> CpuState {
+    state.A = bytes[0];
+    state.X = bytes[1];
+    state.Y = bytes[2];
+    state.S = bytes[3];
+    state.P = CpuFlags::from_bits_truncate(bytes[4]);
+    state.PC = (bytes[6] as VAddr) << 8 | bytes[5] as VAddr;
+    state
+}
+
+//where register n is in the 7 bytes, they're all one byte but PC
+fn register_start(n: uint) -> uint {
+    n
+}
+
+fn register_len(n: uint) -> uint {
+    if n == 5 { 2 } else { 1 }
+}
+
+fn parse_addr_len(args: &str) -> Option<(VAddr, uint)> {
+    let parts: Vec<&str> = args.split(',').collect();
+    if parts.len() != 2 { return None; }
+
+    match (from_str_radix::<VAddr>(parts[0], 16), from_str_radix::<uint>(parts[1], 16)) {
+        (Some(addr), Some(len)) => Some((addr, len)),
+        _ => None,
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes.iter() {
+        hex.push_str(format!("{:02x}", *byte).as_slice());
+    }
+    hex
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 { return None; }
+
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for i in range(0, hex.len() / 2) {
+        match from_str_radix::<u8>(hex.slice(i * 2, i * 2 + 2), 16) {
+            Some(byte) => { bytes.push(byte); }
+            None => { return None; }
+        }
+    }
+    Some(bytes)
+}
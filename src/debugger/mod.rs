@@ -0,0 +1,399 @@
+use std::mem;
+
+use nes::VAddr;
+
+use cpu::{Cpu, CpuState};
+use cpu::isa;
+use cpu::isa::{Instruction, decode};
+
+use self::condition::Condition;
+
+pub mod condition;
+pub mod command;
+pub mod gdb;
+pub mod trace;
+pub mod cdl;
+pub mod symbols;
+pub mod export;
+pub mod callstack;
+pub mod profiler;
+
+#[cfg(test)]
+mod test;
+
+/// # Debugger
+///
+/// Stops emulation when something interesting happens so it can be looked at:
+///
+/// - breakpoints - PC reaches an address, or anywhere in a range
+/// - watchpoints - an address range is read or written, on the CPU or PPU bus
+/// - opcode breakpoints - an instruction with the opcode is about to run, good for catching
+///   BRK or a jump into garbage
+///
+/// Any of them can have a condition on the registers and memory, see the condition module,
+/// `A == $40 && X > 3`. A breakpoint with a false condition is skipped.
+///
+/// Stepping runs one instruction (into), runs through a JSR until it returns (over), or runs
+/// until the current subroutine returns with RTS or RTI (out). Over and out compare the stack
+/// pointer with where it started, so they work with recursion and interrupts in between.
+///
+/// The debugger doesn't run anything itself, Nes::run_frame checks it around every
+/// instruction once one is attached with Nes::attach_debugger. Execution breakpoints are
+/// checked before the instruction at PC runs, watchpoints after the instruction that did the
+/// access, so PC has already moved past it.
+
+/// Frames step over and step out give up after, in case the subroutine never returns
+pub static STEP_LIMIT_FRAMES: uint = 60;
+
+#[deriving(PartialEq, Show, Clone)]
+pub enum Bus {
+    CpuBus,
+    PpuBus,
+}
+
+#[deriving(PartialEq, Show, Clone)]
+pub enum AccessKind {
+    ReadAccess,
+    WriteAccess,
+
+    /// Opcode and operand fetches
+    ExecAccess,
+}
+
+#[deriving(PartialEq, Show, Clone)]
+pub struct Access {
+    pub bus: Bus,
+    pub addr: VAddr,
+    pub val: u8,
+    pub kind: AccessKind,
+}
+
+/// The memory accesses since the last take, only recorded while enabled.
+pub struct AccessLog {
+    enabled: bool,
+    accesses: Vec<Access>,
+}
+
+impl AccessLog {
+    pub fn new() -> AccessLog {
+        AccessLog {
+            enabled: false,
+            accesses: Vec::new(),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.accesses.clear();
+    }
+
+    pub fn record(&mut self, bus: Bus, addr: VAddr, val: u8, kind: AccessKind) {
+        if self.enabled {
+            self.accesses.push(Access { bus: bus, addr: addr, val: val, kind: kind });
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<Access> {
+        let mut accesses = Vec::new();
+        mem::swap(&mut accesses, &mut self.accesses);
+        accesses
+    }
+}
+
+#[deriving(PartialEq, Show, Clone)]
+pub enum BreakpointKind {
+    /// PC in the range, inclusive
+    ExecBreak(VAddr, VAddr),
+
+    /// An access of one of the kinds to an address in the range, inclusive
+    WatchBreak(Bus, VAddr, VAddr, bool, bool),
+
+    OpcodeBreak(u8),
+}
+
+pub struct Breakpoint {
+    pub id: uint,
+    pub kind: BreakpointKind,
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+
+    /// Times it stopped emulation
+    pub hits: uint,
+}
+
+impl Breakpoint {
+    pub fn describe(&self) -> String {
+        let kind = match self.kind {
+            ExecBreak(start, end) if start == end => format!("break ${:04X}", start),
+            ExecBreak(start, end) => format!("break ${:04X}-${:04X}", start, end),
+            WatchBreak(bus, start, end, read, write) => {
+                let kind = match (read, write) { (true, true) => "rw", (true, false) => "r", _ => "w" };
+                let bus = if bus == PpuBus { " ppu" } else { "" };
+                if start == end {
+                    format!("watch{}{} ${:04X}", kind, bus, start)
+                } else {
+                    format!("watch{}{} ${:04X}-${:04X}", kind, bus, start, end)
+                }
+            }
+            OpcodeBreak(opcode) => format!("break opcode ${:02X}", opcode),
+        };
+
+        let condition = match self.condition {
+            Some(ref condition) => format!(" if {}", condition.text()),
+            None => String::new(),
+        };
+        let disabled = if self.enabled { "" } else { " (disabled)" };
+
+        format!("#{} {}{}{}, {} hits", self.id, kind, condition, disabled, self.hits)
+    }
+}
+
+#[deriving(PartialEq, Show, Clone)]
+pub enum StopReason {
+    BreakpointHit(uint),
+
+    /// The watchpoint and the access that set it off
+    WatchpointHit(uint, Access),
+
+    StepDone,
+
+    /// Step over or out ran for STEP_LIMIT_FRAMES without getting there
+    StepLimit,
+}
+
+#[deriving(PartialEq, Show)]
+enum StepMode {
+    NoStep,
+    StepInto,
+
+    //stop when PC gets to the return address with the stack back where it was
+    StepOver(VAddr, u8),
+
+    //stop after an RTS or RTI pulls the stack above where it was
+    StepOut(u8),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: uint,
+
+    step: StepMode,
+    step_frames: uint,
+
+    //don't stop at this PC again before running it, so continuing from a breakpoint works
+    resume_pc: Option<VAddr>,
+
+    //the instruction about to run, for step out
+    instruction: Option<Instruction>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            next_id: 1,
+            step: NoStep,
+            step_frames: 0,
+            resume_pc: None,
+            instruction: None,
+        }
+    }
+
+    /// Adds a breakpoint and returns its id.
+    pub fn add(&mut self, kind: BreakpointKind, condition: Option<Condition>) -> uint {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.breakpoints.push(Breakpoint {
+            id: id,
+            kind: kind,
+            condition: condition,
+            enabled: true,
+            hits: 0,
+        });
+        id
+    }
+
+    pub fn remove(&mut self, id: uint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() != len
+    }
+
+    pub fn set_enabled(&mut self, id: uint, enabled: bool) -> bool {
+        match self.breakpoints.mut_iter().find(|breakpoint| breakpoint.id == id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn breakpoints<'a>(&'a self) -> &'a [Breakpoint] {
+        self.breakpoints.as_slice()
+    }
+
+    /// Whether the CPU and PPU need to log their memory accesses
+    pub fn has_watchpoints(&self) -> bool {
+        self.breakpoints.iter().any(|breakpoint| match breakpoint.kind {
+            WatchBreak(..) => true,
+            _ => false,
+        })
+    }
+
+    pub fn step_into(&mut self) {
+        self.start_step(StepInto);
+    }
+
+    /// Steps over a JSR at PC, or steps into anything else
+    pub fn step_over(&mut self, cpu: &Cpu) {
+        let pc = cpu.pc();
+        let line = cpu.disassemble(pc);
+        let step = match line.instruction {
+            Some(instruction) if instruction.instr == isa::JSR => {
+                StepOver(pc + line.len() as VAddr, cpu.state().S)
+            }
+            _ => StepInto,
+        };
+        self.start_step(step);
+    }
+
+    pub fn step_out(&mut self, cpu: &Cpu) {
+        self.start_step(StepOut(cpu.state().S));
+    }
+
+    fn start_step(&mut self, step: StepMode) {
+        self.step = step;
+        self.step_frames = 0;
+    }
+
+    /// Called when a frame finishes while stepping.
+    pub fn frame_done(&mut self) -> Option<StopReason> {
+        match self.step {
+            StepOver(..) | StepOut(..) => {
+                self.step_frames += 1;
+                if self.step_frames >= STEP_LIMIT_FRAMES {
+                    self.step = NoStep;
+                    return Some(StepLimit);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks the breakpoints on the instruction at PC before it runs.
+    pub fn before_instruction(&mut self, cpu: &Cpu) -> Option<StopReason> {
+        let pc = cpu.pc();
+        let opcode = cpu.peek_byte(pc);
+        self.instruction = decode(opcode);
+
+        if self.resume_pc == Some(pc) {
+            self.resume_pc = None;
+            return None;
+        }
+        self.resume_pc = None;
+
+        match self.step {
+            StepOver(addr, s) if pc == addr && cpu.state().S >= s => {
+                return self.stop(pc, StepDone);
+            }
+            _ => { }
+        }
+
+        let state = cpu.state();
+        let mut hit = None;
+        for breakpoint in self.breakpoints.mut_iter() {
+            if !breakpoint.enabled { continue; }
+
+            let matches = match breakpoint.kind {
+                ExecBreak(start, end) => pc >= start && pc <= end,
+                OpcodeBreak(op) => op == opcode,
+                WatchBreak(..) => false,
+            };
+
+            if matches && Debugger::condition_holds(&breakpoint.condition, &state, cpu) {
+                breakpoint.hits += 1;
+                hit = Some(breakpoint.id);
+                break;
+            }
+        }
+
+        match hit {
+            Some(id) => self.stop(pc, BreakpointHit(id)),
+            None => None,
+        }
+    }
+
+    /// Checks the watchpoints against what the instruction accessed, and finishes steps.
+    pub fn after_instruction(&mut self, cpu: &Cpu, accesses: &[Access]) -> Option<StopReason> {
+        let state = cpu.state();
+
+        let mut hit = None;
+        for breakpoint in self.breakpoints.mut_iter() {
+            if !breakpoint.enabled { continue; }
+
+            let (bus, start, end, read, write) = match breakpoint.kind {
+                WatchBreak(bus, start, end, read, write) => (bus, start, end, read, write),
+                _ => { continue; }
+            };
+
+            let access = accesses.iter().find(|access| {
+                access.bus == bus && access.addr >= start && access.addr <= end &&
+                    match access.kind {
+                        ReadAccess => read,
+                        WriteAccess => write,
+                        ExecAccess => false,
+                    }
+            });
+
+            match access {
+                Some(access) if Debugger::condition_holds(&breakpoint.condition, &state, cpu) => {
+                    breakpoint.hits += 1;
+                    hit = Some(WatchpointHit(breakpoint.id, access.clone()));
+                    break;
+                }
+                _ => { }
+            }
+        }
+
+        match hit {
+            Some(reason) => { return self.stop(cpu.pc(), reason); }
+            None => { }
+        }
+
+        let done = match self.step {
+            StepInto => true,
+            StepOut(s) => {
+                let returned = match self.instruction {
+                    Some(instruction) => instruction.instr == isa::RTS || instruction.instr == isa::RTI,
+                    None => false,
+                };
+                returned && state.S > s
+            }
+            _ => false,
+        };
+
+        if done { self.stop(cpu.pc(), StepDone) } else { None }
+    }
+
+    fn stop(&mut self, pc: VAddr, reason: StopReason) -> Option<StopReason> {
+        self.step = NoStep;
+
+        //a breakpoint at PC has been seen already, let it run next time
+        match reason {
+            BreakpointHit(..) | StepDone => { self.resume_pc = Some(pc); }
+            _ => { }
+        }
+
+        Some(reason)
+    }
+
+    fn condition_holds(condition: &Option<Condition>, state: &CpuState, cpu: &Cpu) -> bool {
+        match *condition {
+            Some(ref condition) => condition.eval(state, cpu),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::cmp::Equal;
+use std::collections::HashMap;
+
+use nes::VAddr;
+
+use debugger::callstack::{CallStack, CallFrame, CallKind, NmiCall, IrqCall, BrkCall};
+
+/// # Subroutine profiler
+///
+/// Counts the CPU cycles spent in every subroutine and interrupt handler, frame by frame,
+/// using a shadow call stack (see the callstack module) so RTS jumps and other stack tricks
+/// don't throw it off.
+///
+/// - exclusive cycles are spent in the routine itself
+/// - inclusive cycles also count the routines it called, and the interrupts that came in
+///   while it ran. A routine that calls itself is only counted once
+///
+/// The JSR and the RTS count towards the routine they call and return from, and so do the 7
+/// cycles of entering an interrupt. Everything outside the calls the profiler saw, usually
+/// the main loop, is the top level.
+///
+/// Frames end when the PPU starts a new one. The budget is the CPU cycles in a frame for the
+/// region, 29780.67 on NTSC, so a routine at 25% of it takes a quarter of the frame.
+///
+///  frames: 60, 29781 cycles in the last, 100.0% of the budget
+///  routine                   calls  inclusive  exclusive  budget  avg incl  max incl
+///  (top level)                   0      29781       9402  100.0%   29780.9     29782
+///  nmi_handler [NMI]             1       2274       1903    7.6%    2270.2      2290
+
+#[deriving(PartialEq, Show, Clone)]
+pub struct RoutineProfile {
+    pub entry: VAddr,
+    pub bank: Option<uint>,
+
+    /// How it was first called, None for the top level, which has 0 for the entry
+    pub kind: Option<CallKind>,
+
+    /// In the last frame
+    pub calls: uint,
+    pub inclusive: u64,
+    pub exclusive: u64,
+
+    /// Over every frame
+    pub total_calls: uint,
+    pub total_inclusive: u64,
+    pub total_exclusive: u64,
+    pub max_inclusive: u64,
+
+    //the frame that's running
+    current_calls: uint,
+    current_inclusive: u64,
+    current_exclusive: u64,
+}
+
+impl RoutineProfile {
+    fn new(entry: VAddr, bank: Option<uint>, kind: Option<CallKind>) -> RoutineProfile {
+        RoutineProfile {
+            entry: entry,
+            bank: bank,
+            kind: kind,
+            calls: 0,
+            inclusive: 0,
+            exclusive: 0,
+            total_calls: 0,
+            total_inclusive: 0,
+            total_exclusive: 0,
+            max_inclusive: 0,
+            current_calls: 0,
+            current_inclusive: 0,
+            current_exclusive: 0,
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.calls = self.current_calls;
+        self.inclusive = self.current_inclusive;
+        self.exclusive = self.current_exclusive;
+
+        self.total_calls += self.calls;
+        self.total_inclusive += self.inclusive;
+        self.total_exclusive += self.exclusive;
+        if self.inclusive > self.max_inclusive { self.max_inclusive = self.inclusive; }
+
+        self.current_calls = 0;
+        self.current_inclusive = 0;
+        self.current_exclusive = 0;
+    }
+}
+
+static TOP_LEVEL: uint = 0;
+
+pub struct Profiler {
+    stack: CallStack,
+
+    //top level first
+    routines: Vec<RoutineProfile>,
+    index: HashMap<(VAddr, Option<uint>), uint>,
+
+    //the routine of every frame on the stack, and how deep it'll be once the cycles of a
+    //return have been counted
+    active: Vec<uint>,
+    returned_depth: Option<uint>,
+
+    budget: f64,
+    frames: uint,
+    frame_cycles: u64,
+    last_frame_cycles: u64,
+}
+
+impl Profiler {
+    /// `budget` is the CPU cycles in a frame, see Region::cpu_cycles_per_frame.
+    pub fn new(budget: f64) -> Profiler {
+        Profiler {
+            stack: CallStack::new(),
+            routines: vec![RoutineProfile::new(0, None, None)],
+            index: HashMap::new(),
+            active: Vec::new(),
+            returned_depth: None,
+            budget: budget,
+            frames: 0,
+            frame_cycles: 0,
+            last_frame_cycles: 0,
+        }
+    }
+
+    pub fn call(&mut self, frame: CallFrame) {
+        self.finish_return();
+
+        let key = (frame.entry, frame.bank);
+        let existing = self.index.find(&key).map(|&routine| routine);
+        let routine = match existing {
+            Some(routine) => routine,
+            None => {
+                self.routines.push(RoutineProfile::new(frame.entry, frame.bank, Some(frame.kind)));
+                self.index.insert(key, self.routines.len() - 1);
+                self.routines.len() - 1
+            }
+        };
+
+        self.routines.get_mut(routine).current_calls += 1;
+        self.active.push(routine);
+        self.stack.call(frame);
+    }
+
+    /// See CallStack::ret, the routines returned from get the cycles of the RTS or RTI.
+    pub fn ret(&mut self, s: u8, after_jsr: bool) {
+        self.finish_return();
+        self.stack.ret(s, after_jsr);
+        self.returned_depth = Some(self.stack.depth());
+    }
+
+    pub fn unwind(&mut self, s: u8) {
+        self.finish_return();
+        self.stack.unwind(s);
+        self.active.truncate(self.stack.depth());
+    }
+
+    /// Forgets the calls in progress, after a power cycle or loading a state.
+    pub fn clear_stack(&mut self) {
+        self.stack.clear();
+        self.active.clear();
+        self.returned_depth = None;
+    }
+
+    pub fn add_cycles(&mut self, cycles: uint) {
+        let cycles = cycles as u64;
+        self.frame_cycles += cycles;
+
+        let current = self.active.last().map_or(TOP_LEVEL, |&routine| routine);
+        self.routines.get_mut(current).current_exclusive += cycles;
+        self.routines.get_mut(TOP_LEVEL).current_inclusive += cycles;
+
+        for (i, &routine) in self.active.iter().enumerate() {
+            //once for recursion
+            if self.active.slice_to(i).contains(&routine) { continue; }
+            self.routines.get_mut(routine).current_inclusive += cycles;
+        }
+
+        self.finish_return();
+    }
+
+    fn finish_return(&mut self) {
+        match self.returned_depth.take() {
+            Some(depth) => { self.active.truncate(depth); }
+            None => { }
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        for routine in self.routines.mut_iter() {
+            routine.end_frame();
+        }
+        self.frames += 1;
+        self.last_frame_cycles = self.frame_cycles;
+        self.frame_cycles = 0;
+    }
+
+    /// Finished frames.
+    pub fn frames(&self) -> uint {
+        self.frames
+    }
+
+    pub fn last_frame_cycles(&self) -> u64 {
+        self.last_frame_cycles
+    }
+
+    /// How much of a frame's cycles `cycles` is, 1.0 for all of them.
+    pub fn budget_used(&self, cycles: u64) -> f64 {
+        cycles as f64 / self.budget
+    }
+
+    /// Every routine seen, the most inclusive cycles in the last frame first.
+    pub fn routines(&self) -> Vec<RoutineProfile> {
+        let mut routines = self.routines.clone();
+        routines.sort_by(|a, b| {
+            match b.inclusive.cmp(&a.inclusive) {
+                Equal => b.total_inclusive.cmp(&a.total_inclusive),
+                order => order,
+            }
+        });
+        routines
+    }
+
+    /// The table above, with `name` naming routines by their entry and bank.
+    pub fn report(&self, name: |VAddr, Option<uint>| -> Option<String>) -> String {
+        let frames = if self.frames == 0 { 1 } else { self.frames };
+
+        let mut lines = vec![
+            format!("frames: {}, {} cycles in the last, {:.1f}% of the budget",
+                    self.frames, self.last_frame_cycles, 100.0 * self.budget_used(self.last_frame_cycles)),
+            format!("{:<24} {:>6} {:>10} {:>10} {:>7} {:>9} {:>9}",
+                    "routine", "calls", "inclusive", "exclusive", "budget", "avg incl", "max incl"),
+        ];
+
+        for routine in self.routines().iter() {
+            let label = match routine.kind {
+                None => "(top level)".to_string(),
+                Some(kind) => {
+                    let label = name(routine.entry, routine.bank).unwrap_or_else(|| format!("${:04X}", routine.entry));
+                    match kind {
+                        NmiCall => format!("{} [NMI]", label),
+                        IrqCall => format!("{} [IRQ]", label),
+                        BrkCall => format!("{} [BRK]", label),
+                        _ => label,
+                    }
+                }
+            };
+
+            lines.push(format!("{:<24} {:>6} {:>10} {:>10} {:>6.1f}% {:>9.1f} {:>9}",
+                               label, routine.calls, routine.inclusive, routine.exclusive,
+                               100.0 * self.budget_used(routine.inclusive),
+                               routine.total_inclusive as f64 / frames as f64, routine.max_inclusive));
+        }
+
+        lines.as_slice().connect("\n")
+    }
+}
@@ -0,0 +1,325 @@
+use std::io::{File, IoResult, IoError, InvalidInput};
+use std::num::from_str_radix;
+
+use nes::{VAddr, PRG_ROM_BANK_SIZE, INES_HEADER_SIZE};
+
+/// # Symbols
+///
+/// Names for addresses, from the files assemblers and other emulators write:
+///
+/// - FCEUX .nl, one file per 16 KB PRG-ROM bank, `game.nes.0.nl`, `game.nes.1.nl`, and one
+///   for RAM, `game.nes.ram.nl`. Every line is `$ADDR#name#comment`, `$ADDR/LEN#name#` for an
+///   array
+/// - ld65 .dbg, from `ld65 --dbgfile`. Its sym lines have the value, and the segment they're
+///   in says where in the ROM file a label ended up, which gives the bank. Its line and span
+///   lines say which source line made which bytes
+///
+/// Symbols in PRG-ROM have the 16 KB bank they're in, counted from the start of PRG-ROM, the
+/// same as the trace logger's bank filter and Cpu::prg_bank. RAM, registers and other equates
+/// have none. Looking one up with the bank that's mapped in at the address tells apart labels
+/// that share an address in different banks.
+///
+/// ld65 equates below $0800 are left out, they're usually constants rather than addresses and
+/// would turn every LDA #8 into a name.
+
+static DBG_EQUATE_MIN: VAddr = 0x0800;
+static DBG_MACRO_LINE: uint = 2;
+
+#[deriving(PartialEq, Show, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: VAddr,
+    pub bank: Option<uint>,
+}
+
+/// The bytes an assembler made for a line of source.
+#[deriving(PartialEq, Show, Clone)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: uint,
+    pub addr: VAddr,
+    pub len: uint,
+    pub bank: Option<uint>,
+}
+
+#[deriving(PartialEq, Show, Clone)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+    lines: Vec<SourceLine>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            symbols: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, addr: VAddr, bank: Option<uint>) {
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            addr: addr,
+            bank: bank,
+        });
+    }
+
+    pub fn len(&self) -> uint {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty() && self.lines.is_empty()
+    }
+
+    pub fn symbols<'a>(&'a self) -> &'a [Symbol] {
+        self.symbols.as_slice()
+    }
+
+    /// The first symbol for `addr`. A bank only matches symbols in that bank or in none, with
+    /// no bank any symbol does.
+    pub fn lookup<'a>(&'a self, addr: VAddr, bank: Option<uint>) -> Option<&'a Symbol> {
+        self.symbols.iter().find(|symbol| {
+            symbol.addr == addr && (symbol.bank.is_none() || bank.is_none() || symbol.bank == bank)
+        })
+    }
+
+    pub fn find<'a>(&'a self, name: &str) -> Option<&'a Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name.as_slice() == name)
+    }
+
+    /// The symbol for `addr`, or the closest one before it in the same bank plus how far past
+    /// it `addr` is, `main_loop+3`. Only PRG-ROM addresses with a known bank get the offset.
+    pub fn describe(&self, addr: VAddr, bank: Option<uint>) -> Option<String> {
+        match self.lookup(addr, bank) {
+            Some(symbol) => { return Some(symbol.name.clone()); }
+            None => { }
+        }
+        if bank.is_none() { return None; }
+
+        let closest = self.symbols.iter().filter(|symbol| {
+            symbol.bank == bank && symbol.addr < addr
+        }).max_by(|symbol| symbol.addr);
+        closest.map(|symbol| format!("{}+{}", symbol.name, addr - symbol.addr))
+    }
+
+    /// The source line that made the byte at `addr`, banks match the same way as lookup.
+    pub fn source_line<'a>(&'a self, addr: VAddr, bank: Option<uint>) -> Option<&'a SourceLine> {
+        self.lines.iter().find(|line| {
+            addr >= line.addr && ((addr - line.addr) as uint) < line.len
+                && (line.bank.is_none() || bank.is_none() || line.bank == bank)
+        })
+    }
+
+    /// Reads a .dbg or .nl file, going by the extension, and returns how many symbols it had.
+    /// The bank of a .nl file comes from its name, see above.
+    pub fn load(&mut self, path: &Path) -> IoResult<uint> {
+        let text = try!(File::open(path).read_to_string());
+        let (before, lines_before) = (self.symbols.len(), self.lines.len());
+
+        let ok = match path.extension_str() {
+            Some("dbg") => self.add_dbg(text.as_slice()),
+            Some("nl") => {
+                let bank = path.filestem_str().and_then(|stem| {
+                    stem.rsplitn(1, '.').next().and_then(|bank| from_str_radix::<uint>(bank, 16))
+                });
+                self.add_nl(text.as_slice(), bank)
+            }
+            _ => {
+                return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "symbol files have to be .dbg or .nl",
+                    detail: Some(path.display().to_string()),
+                });
+            }
+        };
+
+        if !ok {
+            self.symbols.truncate(before);
+            self.lines.truncate(lines_before);
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "bad symbol file",
+                detail: Some(path.display().to_string()),
+            });
+        }
+        Ok(self.len() - before)
+    }
+
+    /// Adds the labels of an FCEUX .nl file, false if an address doesn't parse. Lines that
+    /// don't start with $ carry on the comment before them.
+    pub fn add_nl(&mut self, text: &str, bank: Option<uint>) -> bool {
+        for line in text.lines() {
+            let line = line.trim();
+            if !line.starts_with("$") { continue; }
+
+            let fields: Vec<&str> = line.splitn(2, '#').collect();
+            let addr = fields[0].slice_from(1).splitn(1, '/').next().unwrap();
+            let addr = match from_str_radix::<VAddr>(addr.trim(), 16) {
+                Some(addr) => addr,
+                None => { return false; }
+            };
+
+            match fields.get(1) {
+                Some(name) if !name.trim().is_empty() => { self.add(name.trim(), addr, bank); }
+                _ => { }
+            }
+        }
+        true
+    }
+
+    /// Adds the labels, equates and source lines of an ld65 .dbg file, false if it isn't one.
+    pub fn add_dbg(&mut self, text: &str) -> bool {
+        //id -> where the segment starts in the CPU address space and in the ROM file
+        let mut segments: Vec<(uint, VAddr, Option<uint>)> = Vec::new();
+        let mut files: Vec<(uint, String)> = Vec::new();
+        //id -> segment, offset in the segment, size
+        let mut spans: Vec<(uint, uint, uint, uint)> = Vec::new();
+        let mut syms = Vec::new();
+        let mut lines = Vec::new();
+        let mut version = false;
+
+        for line in text.lines() {
+            let (kind, fields) = match dbg_record(line) {
+                Some(record) => record,
+                None => { continue; }
+            };
+
+            match kind.as_slice() {
+                "version" => { version = true; }
+                "seg" => {
+                    let id = dbg_number(&fields, "id");
+                    let start = dbg_number(&fields, "start");
+                    match (id, start) {
+                        (Some(id), Some(start)) => {
+                            segments.push((id, start as VAddr, dbg_number(&fields, "ooffs")));
+                        }
+                        _ => { return false; }
+                    }
+                }
+                "file" => {
+                    match (dbg_number(&fields, "id"), dbg_field(&fields, "name")) {
+                        (Some(id), Some(name)) => { files.push((id, name.to_string())); }
+                        _ => { return false; }
+                    }
+                }
+                "span" => {
+                    let span = (dbg_number(&fields, "id"), dbg_number(&fields, "seg"),
+                                dbg_number(&fields, "start"), dbg_number(&fields, "size"));
+                    match span {
+                        (Some(id), Some(seg), Some(start), Some(size)) => { spans.push((id, seg, start, size)); }
+                        _ => { return false; }
+                    }
+                }
+                "sym" => { syms.push(fields); }
+                "line" => { lines.push(fields); }
+                _ => { }
+            }
+        }
+
+        if !version { return false; }
+
+        for fields in syms.iter() {
+            let name = match dbg_field(fields, "name") { Some(name) => name, None => { return false; } };
+            let addr = match dbg_number(fields, "val") { Some(val) => val as VAddr, None => { return false; } };
+
+            match dbg_field(fields, "type") {
+                Some("lab") => { }
+                Some("equ") if addr >= DBG_EQUATE_MIN => { }
+                _ => { continue; }
+            }
+
+            let bank = dbg_number(fields, "seg").and_then(|seg| segment_bank(segments.as_slice(), seg, addr));
+            self.add(name, addr, bank);
+        }
+
+        for fields in lines.iter() {
+            //lines inside a macro, the line that used the macro covers the same bytes
+            if dbg_number(fields, "type") == Some(DBG_MACRO_LINE) { continue; }
+
+            let file = dbg_number(fields, "file").and_then(|file| files.iter().find(|&&(id, _)| id == file));
+            let (file, line) = match (file, dbg_number(fields, "line")) {
+                (Some(&(_, ref file)), Some(line)) => (file, line),
+                _ => { return false; }
+            };
+
+            //only the lines that made bytes have spans, "span=3+4" for more than one
+            let span_ids = match dbg_field(fields, "span") { Some(ids) => ids, None => { continue; } };
+            for id in span_ids.split('+').filter_map(|id| from_str::<uint>(id)) {
+                let span = spans.iter().find(|&&(span, _, _, _)| span == id);
+                let segment = span.and_then(|&(_, seg, _, _)| segments.iter().find(|&&(id, _, _)| id == seg));
+                match (span, segment) {
+                    (Some(&(_, seg, offset, size)), Some(&(_, start, _))) => {
+                        let addr = start + offset as VAddr;
+                        self.lines.push(SourceLine {
+                            file: file.clone(),
+                            line: line,
+                            addr: addr,
+                            len: size,
+                            bank: segment_bank(segments.as_slice(), seg, addr),
+                        });
+                    }
+                    _ => { return false; }
+                }
+            }
+        }
+        true
+    }
+}
+
+//the bank of an address in a segment that went into the ROM file, past the header
+fn segment_bank(segments: &[(uint, VAddr, Option<uint>)], seg: uint, addr: VAddr) -> Option<uint> {
+    match segments.iter().find(|&&(id, _, _)| id == seg) {
+        Some(&(_, start, Some(offset))) if offset >= INES_HEADER_SIZE && addr >= start => {
+            Some((offset - INES_HEADER_SIZE + (addr - start) as uint) / PRG_ROM_BANK_SIZE)
+        }
+        _ => None,
+    }
+}
+
+//"sym\tid=0,name=\"main\",val=0x8000" -> ("sym", [("id", "0"), ("name", "main"), ...]),
+//commas inside quotes don't split
+fn dbg_record(line: &str) -> Option<(String, Vec<(String, String)>)> {
+    let line = line.trim();
+    let split = match line.find(|c: char| c.is_whitespace()) {
+        Some(split) => split,
+        None => { return None; }
+    };
+    let kind = line.slice_to(split).to_string();
+
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    for c in line.slice_from(split).trim().chars().chain(Some(',').move_iter()) {
+        match c {
+            '"' => { quoted = !quoted; }
+            ',' if !quoted => {
+                {
+                    let pair: Vec<&str> = field.as_slice().splitn(1, '=').collect();
+                    if pair.len() == 2 {
+                        fields.push((pair[0].to_string(), pair[1].to_string()));
+                    }
+                }
+                field.truncate(0);
+            }
+            c => { field.push_char(c); }
+        }
+    }
+
+    Some((kind, fields))
+}
+
+fn dbg_field<'a>(fields: &'a Vec<(String, String)>, key: &str) -> Option<&'a str> {
+    fields.iter().find(|&&(ref name, _)| name.as_slice() == key).map(|&(_, ref value)| value.as_slice())
+}
+
+//decimal, or hex with 0x
+fn dbg_number(fields: &Vec<(String, String)>, key: &str) -> Option<uint> {
+    dbg_field(fields, key).and_then(|value| {
+        if value.starts_with("0x") {
+            from_str_radix::<uint>(value.slice_from(2), 16)
+        } else {
+            from_str::<uint>(value)
+        }
+    })
+}
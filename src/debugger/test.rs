@@ -0,0 +1,757 @@
+use nes::{Nes, VAddr, PRG_ROM_BANK_SIZE, CHR_ROM_BANK_SIZE};
+use nes::test::get_test_rom;
+
+use cpu::Cpu;
+use cpu::{CpuFlags, C_FLAG};
+
+use ppu::Ppu;
+
+use mapper::{Mapper, Nrom};
+
+use debugger::{Debugger, ExecBreak, WatchBreak, OpcodeBreak, CpuBus, PpuBus, Access, ReadAccess, WriteAccess};
+use debugger::{BreakpointHit, WatchpointHit, StepDone};
+use debugger::condition::Condition;
+use debugger::command::{parse_command, parse_command_with, execute_command, Continue, Step, AddBreakpoint, Memory, Disassemble};
+use debugger::command::{Backtrace, Profile};
+use debugger::gdb::{GdbStub, GdbReply, GdbContinue, GdbDetach, encode_packet, read_packet, write_packet};
+use debugger::trace::{TraceLogger, CompactFormat};
+use debugger::cdl::{CodeDataLog, CDL_CODE, CDL_DATA, CDL_INDIRECT_CODE, CDL_INDIRECT_DATA};
+use debugger::cdl::{CDL_CHR_RENDERED, CDL_CHR_READ};
+use debugger::symbols::{Symbols, Symbol, SourceLine};
+use debugger::export::{Ca65Style, Asm6Style, export_bank};
+use debugger::callstack::{CallStack, CallFrame, JsrCall, NmiCall, Returned, RtsJump, Untracked};
+
+use std::io::{Listener, Acceptor, MemWriter, TempDir, File};
+use std::io::net::tcp::{TcpListener, TcpStream};
+
+static DEBUGGER_TEST_PROGRAM: [u8, ..16] = [
+    0x78,               //$8000 SEI
+    0xA2, 0x00,         //$8001 LDX #$00
+    0x20, 0x0D, 0x80,   //$8003 JSR $800D
+    0xE8,               //$8006 INX
+    0x8D, 0x00, 0x03,   //$8007 STA $0300
+    0x4C, 0x06, 0x80,   //$800A JMP $8006
+    0xA9, 0x40,         //$800D LDA #$40
+    0x60,               //$800F RTS
+];
+
+fn get_debugger_nes() -> Nes {
+    let mut nes = Nes::from_bytes(get_test_rom(&DEBUGGER_TEST_PROGRAM).as_slice());
+    nes.reset();
+    nes.attach_debugger(Debugger::new());
+    nes
+}
+
+#[test]
+fn debugger_condition_test() {
+    let mut cpu = Cpu::new(box Nrom::new(prg_rom!()) as Box<Mapper>, Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]));
+    let mut state = cpu.state();
+    state.A = 0x40;
+    state.X = 0x05;
+    state.P.insert(C_FLAG);
+    cpu.set_state(state);
+
+    let holds = |text: &str| Condition::parse(text).unwrap().eval(&cpu.state(), &cpu);
+    assert!(holds("A == $40 && X > 3"));
+    assert!(!holds("A == $40 && X > 5"));
+    assert!(holds("a != 64 || x >= %101"));
+    assert!(holds("(A < $40 || X <= 5) && C == 1"));
+    assert!(!holds("Z"));
+    assert!(holds("[$0000] == 0"));
+    assert!(holds("PC == 0"));
+
+    assert!(Condition::parse("A ==").is_none());
+    assert!(Condition::parse("(A == 1").is_none());
+    assert!(Condition::parse("Q == 1").is_none());
+    assert!(Condition::parse("A == $GG").is_none());
+    assert_eq!(Condition::parse(" X > 3 ").unwrap().text(), "X > 3");
+}
+
+#[test]
+fn debugger_breakpoint_test() {
+    let mut nes = get_debugger_nes();
+    let id = nes.debugger().unwrap().add(ExecBreak(0x800D, 0x800D), None);
+
+    assert_eq!(nes.debug_continue(Some(1)), Some(BreakpointHit(id)));
+    assert_eq!(nes.cpu_state().PC, 0x800D);
+    assert_eq!(nes.cpu_state().X, 0x00);
+
+    //the subroutine is only called once
+    assert_eq!(nes.debug_continue(Some(2)), None);
+    assert_eq!(nes.debugger().unwrap().breakpoints()[0].hits, 1);
+
+    //stopping part way through a frame doesn't lose it
+    assert_eq!(nes.frame_count(), 2);
+}
+
+#[test]
+fn debugger_conditional_breakpoint_test() {
+    let mut nes = get_debugger_nes();
+    let condition = Condition::parse("X == 3").unwrap();
+    let id = nes.debugger().unwrap().add(ExecBreak(0x8006, 0x8007), Some(condition));
+
+    assert_eq!(nes.debug_continue(Some(1)), Some(BreakpointHit(id)));
+    assert_eq!(nes.cpu_state().PC, 0x8007);
+    assert_eq!(nes.cpu_state().X, 0x03);
+
+    assert!(nes.debugger().unwrap().set_enabled(id, false));
+    assert_eq!(nes.debug_continue(Some(1)), None);
+    assert!(nes.debugger().unwrap().remove(id));
+    assert!(!nes.debugger().unwrap().remove(id));
+}
+
+#[test]
+fn debugger_watchpoint_test() {
+    let mut nes = get_debugger_nes();
+    let id = nes.debugger().unwrap().add(WatchBreak(CpuBus, 0x0300, 0x03FF, false, true), None);
+
+    //reads don't count for write watchpoints, and the PPU bus is separate
+    nes.debugger().unwrap().add(WatchBreak(CpuBus, 0x0300, 0x0300, true, false), None);
+    nes.debugger().unwrap().add(WatchBreak(PpuBus, 0x0300, 0x0300, true, true), None);
+
+    let access = Access { bus: CpuBus, addr: 0x0300, val: 0x40, kind: WriteAccess };
+    assert_eq!(nes.debug_continue(Some(1)), Some(WatchpointHit(id, access)));
+    assert_eq!(nes.cpu_state().PC, 0x800A);
+}
+
+#[test]
+fn debugger_ppu_watchpoint_test() {
+    let program = [
+        0xA9, 0x21,         //$8000 LDA #$21
+        0x8D, 0x06, 0x20,   //$8002 STA $2006
+        0xA9, 0x08,         //$8005 LDA #$08
+        0x8D, 0x06, 0x20,   //$8007 STA $2006
+        0xA9, 0x5A,         //$800A LDA #$5A
+        0x8D, 0x07, 0x20,   //$800C STA $2007
+        0xA9, 0x21,         //$800F LDA #$21
+        0x8D, 0x06, 0x20,   //$8011 STA $2006
+        0xA9, 0x08,         //$8014 LDA #$08
+        0x8D, 0x06, 0x20,   //$8016 STA $2006
+        0xAD, 0x07, 0x20,   //$8019 LDA $2007
+        0xAD, 0x07, 0x20,   //$801C LDA $2007
+        0x4C, 0x1F, 0x80,   //$801F JMP $801F
+    ];
+    let mut nes = Nes::from_bytes(get_test_rom(&program).as_slice());
+    nes.reset();
+    nes.attach_debugger(Debugger::new());
+    let write_id = nes.debugger().unwrap().add(WatchBreak(PpuBus, 0x2108, 0x2108, false, true), None);
+
+    let access = Access { bus: PpuBus, addr: 0x2108, val: 0x5A, kind: WriteAccess };
+    assert_eq!(nes.debug_continue(Some(1)), Some(WatchpointHit(write_id, access)));
+    assert_eq!(nes.cpu_state().PC, 0x800F);
+
+    //the first read only fills the read buffer, it's still a read of $2108 on the PPU bus
+    let read_id = nes.debugger().unwrap().add(WatchBreak(PpuBus, 0x2108, 0x2108, true, false), None);
+    let access = Access { bus: PpuBus, addr: 0x2108, val: 0x5A, kind: ReadAccess };
+    assert_eq!(nes.debug_continue(Some(1)), Some(WatchpointHit(read_id, access)));
+    assert_eq!(nes.cpu_state().PC, 0x801C);
+
+    nes.step_instruction();
+    assert_eq!(nes.cpu_state().A, 0x5A);
+}
+
+#[test]
+fn debugger_step_test() {
+    let mut nes = get_debugger_nes();
+
+    assert_eq!(nes.debug_step_into(), Some(StepDone));
+    assert_eq!(nes.cpu_state().PC, 0x8001);
+    assert_eq!(nes.debug_step_into(), Some(StepDone));
+    assert_eq!(nes.cpu_state().PC, 0x8003);
+
+    //over the JSR
+    assert_eq!(nes.debug_step_over(), Some(StepDone));
+    assert_eq!(nes.cpu_state().PC, 0x8006);
+    assert_eq!(nes.cpu_state().A, 0x40);
+
+    //over anything else is into
+    assert_eq!(nes.debug_step_over(), Some(StepDone));
+    assert_eq!(nes.cpu_state().PC, 0x8007);
+
+    let mut nes = get_debugger_nes();
+    for _ in range(0u, 3) { nes.debug_step_into(); }
+    assert_eq!(nes.cpu_state().PC, 0x800D);
+    assert_eq!(nes.debug_step_out(), Some(StepDone));
+    assert_eq!(nes.cpu_state().PC, 0x8006);
+
+    //stepping onto a breakpoint and continuing runs the instruction there first
+    let id = nes.debugger().unwrap().add(ExecBreak(0x8007, 0x8007), None);
+    assert_eq!(nes.debug_step_into(), Some(StepDone));
+    assert_eq!(nes.debug_continue(Some(1)), Some(BreakpointHit(id)));
+    assert_eq!(nes.cpu_state().PC, 0x8007);
+}
+
+#[test]
+fn debugger_opcode_break_test() {
+    let mut nes = get_debugger_nes();
+    let id = nes.debugger().unwrap().add(OpcodeBreak(0x60), None);
+
+    assert_eq!(nes.debug_continue(Some(1)), Some(BreakpointHit(id)));
+    assert_eq!(nes.cpu_state().PC, 0x800F);
+}
+
+#[test]
+fn debugger_command_test() {
+    assert_eq!(parse_command("c"), Ok(Continue(None)));
+    assert_eq!(parse_command("continue 10"), Ok(Continue(Some(10))));
+    assert_eq!(parse_command("s"), Ok(Step));
+    assert_eq!(parse_command("b $800D"), Ok(AddBreakpoint(ExecBreak(0x800D, 0x800D), None)));
+    assert_eq!(parse_command("b 8000-80ff if A == $40"),
+               Ok(AddBreakpoint(ExecBreak(0x8000, 0x80FF), Some("A == $40".to_string()))));
+    assert_eq!(parse_command("bo 00"), Ok(AddBreakpoint(OpcodeBreak(0x00), None)));
+    assert_eq!(parse_command("w 0300"), Ok(AddBreakpoint(WatchBreak(CpuBus, 0x0300, 0x0300, false, true), None)));
+    assert_eq!(parse_command("watch rw ppu $2000-$23FF"),
+               Ok(AddBreakpoint(WatchBreak(PpuBus, 0x2000, 0x23FF, true, true), None)));
+    assert_eq!(parse_command("m 0x300 32"), Ok(Memory(0x0300, 32)));
+    assert_eq!(parse_command("dis"), Ok(Disassemble(None, 10)));
+
+    assert!(parse_command("b").is_err());
+    assert!(parse_command("b 9000-8000").is_err());
+    assert!(parse_command("b 8000 if A =").is_err());
+    assert!(parse_command("bo 100").is_err());
+    assert!(parse_command("frobnicate").is_err());
+
+    let mut nes = Nes::from_bytes(get_test_rom(&DEBUGGER_TEST_PROGRAM).as_slice());
+    nes.reset();
+
+    assert_eq!(execute_command(&mut nes, parse_command("b 800D").unwrap()), "#1 break $800D, 0 hits".to_string());
+    let output = execute_command(&mut nes, Continue(None));
+    assert!(output.as_slice().starts_with("Breakpoint #1\n800D  LDA #$40\n"));
+    assert!(output.as_slice().ends_with("PC:800D"));
+
+    assert_eq!(execute_command(&mut nes, Memory(0x8000, 3)), "8000  78 A2 00".to_string());
+    assert_eq!(execute_command(&mut nes, Disassemble(Some(0x800D), 2)),
+               "800D  A9 40     LDA #$40\n800F  60        RTS".to_string());
+}
+
+#[test]
+fn debugger_gdb_packet_test() {
+    let mut nes = get_debugger_nes();
+    let mut stub = GdbStub::new(&mut nes);
+    let reply = |text: &str| GdbReply(text.to_string());
+
+    assert_eq!(encode_packet("OK"), "$OK#9a".to_string());
+    assert_eq!(encode_packet(""), "$#00".to_string());
+
+    let mut state = nes.cpu_state();
+    state.A = 0x12;
+    state.X = 0x34;
+    state.Y = 0x56;
+    state.S = 0xFD;
+    state.P = CpuFlags::from_bits_truncate(0x24);
+    nes.set_cpu_state(state);
+
+    assert_eq!(stub.handle(&mut nes, "?"), reply("S05"));
+    assert_eq!(stub.handle(&mut nes, "g"), reply("123456fd240080"));
+    assert_eq!(stub.handle(&mut nes, "p5"), reply("0080"));
+    assert_eq!(stub.handle(&mut nes, "P0=40"), reply("OK"));
+    assert_eq!(nes.cpu_state().A, 0x40);
+    assert_eq!(stub.handle(&mut nes, "G0000000000000000"), reply("E01"));
+    assert_eq!(stub.handle(&mut nes, "p9"), reply("E01"));
+
+    assert_eq!(stub.handle(&mut nes, "m8000,3"), reply("78a200"));
+    assert_eq!(stub.handle(&mut nes, "M0300,2:abcd"), reply("OK"));
+    assert_eq!(stub.handle(&mut nes, "m300,2"), reply("abcd"));
+    assert_eq!(stub.handle(&mut nes, "M0300,2:ab"), reply("E01"));
+
+    assert_eq!(stub.handle(&mut nes, "s"), reply("S05"));
+    assert_eq!(nes.cpu_state().PC, 0x8001);
+
+    assert_eq!(stub.handle(&mut nes, "Z0,800d,1"), reply("OK"));
+    assert_eq!(stub.handle(&mut nes, "c"), GdbContinue);
+    assert_eq!(stub.handle(&mut nes, "z0,800d,1"), reply("OK"));
+    assert_eq!(stub.handle(&mut nes, "z0,800d,1"), reply("E01"));
+    assert_eq!(stub.handle(&mut nes, "Z2,0300,1"), reply("OK"));
+    assert_eq!(nes.debugger().unwrap().breakpoints()[0].kind, WatchBreak(CpuBus, 0x0300, 0x0300, false, true));
+
+    //JSR pushes to $01FD and RTS pulls from it, read and access watchpoints say which they are
+    assert_eq!(stub.handle(&mut nes, "Z4,01fd,1"), reply("OK"));
+    assert_eq!(stub.handle(&mut nes, "s"), reply("S05"));
+    assert_eq!(stub.handle(&mut nes, "s"), reply("T05awatch:01fd;"));
+    assert_eq!(stub.handle(&mut nes, "z4,01fd,1"), reply("OK"));
+    assert_eq!(stub.handle(&mut nes, "Z3,01fd,1"), reply("OK"));
+    assert_eq!(stub.handle(&mut nes, "s"), reply("S05"));
+    assert_eq!(stub.handle(&mut nes, "s"), reply("T05rwatch:01fd;"));
+
+    assert_eq!(stub.handle(&mut nes, "vMustReplyEmpty"), reply(""));
+    assert_eq!(stub.handle(&mut nes, "D"), GdbDetach("OK".to_string()));
+}
+
+//a scripted client, the way gdb would talk to the stub
+#[test]
+fn debugger_gdb_session_test() {
+    let listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+    let mut acceptor = listener.listen().unwrap();
+    let port = acceptor.socket_name().unwrap().port;
+
+    let (tx, rx) = channel();
+    spawn(proc() {
+        let mut stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        let mut request = |data: &str| {
+            write_packet(&mut stream, data).unwrap();
+            assert_eq!(stream.read_byte().unwrap(), b'+');
+            read_packet(&mut stream).unwrap().unwrap()
+        };
+
+        let replies = vec![
+            request("qSupported:multiprocess+"),
+            request("Z0,800d,1"),
+            request("c"),
+            request("p5"),
+            request("m800d,2"),
+            request("D"),
+        ];
+        tx.send(replies);
+    });
+
+    let mut nes = get_debugger_nes();
+    let mut stream = acceptor.accept().unwrap();
+    let mut stub = GdbStub::new(&mut nes);
+    stub.session(&mut nes, &mut stream).unwrap();
+
+    let replies: Vec<String> = rx.recv();
+    assert_eq!(replies, vec!["PacketSize=1000".to_string(), "OK".to_string(), "S05".to_string(),
+                             "0d80".to_string(), "a940".to_string(), "OK".to_string()]);
+    assert_eq!(nes.cpu_state().PC, 0x800D);
+}
+
+fn get_trace_logger(ring: uint) -> TraceLogger {
+    let mut trace = TraceLogger::new(box MemWriter::new() as Box<Writer>);
+    trace.set_ring(Some(ring));
+    trace
+}
+
+#[test]
+fn debugger_trace_test() {
+    let mut nes = get_debugger_nes();
+    let mut trace = get_trace_logger(3);
+    trace.set_format(CompactFormat);
+    trace.show_ppu(false);
+    trace.show_cycles(false);
+    nes.start_trace(trace);
+
+    for _ in range(0u, 4) { nes.step_instruction(); }
+    assert_eq!(nes.trace().unwrap().lines(), vec![
+        "8001  LDX #$00       A:00 X:00 Y:00 S:FF P:nvubdIzc".to_string(),
+        "8003  JSR $800D      A:00 X:00 Y:00 S:FF P:nvubdIZc".to_string(),
+        "800D  LDA #$40       A:00 X:00 Y:00 S:FD P:nvubdIZc".to_string(),
+    ]);
+
+    //nestest format, only from the trigger on
+    let mut nes = get_debugger_nes();
+    let mut trace = get_trace_logger(10);
+    trace.set_trigger(Some(0x800D));
+    nes.start_trace(trace);
+
+    for _ in range(0u, 5) { nes.step_instruction(); }
+    assert_eq!(nes.trace().unwrap().lines(), vec![
+        "800D  A9 40     LDA #$40                        A:00 X:00 Y:00 P:06 SP:FD PPU:  0, 30 CYC:10".to_string(),
+        "800F  60        RTS                             A:40 X:00 Y:00 P:04 SP:FD PPU:  0, 36 CYC:12".to_string(),
+    ]);
+
+    //hitting a breakpoint writes out the ring
+    nes.debugger().unwrap().add(ExecBreak(0x8007, 0x8007), None);
+    nes.debug_continue(Some(1));
+    assert!(nes.trace().unwrap().lines().is_empty());
+
+    //filters
+    let mut nes = get_debugger_nes();
+    let mut trace = get_trace_logger(10);
+    trace.set_pc_range(Some((0x8006, 0x8007)));
+    nes.start_trace(trace);
+    for _ in range(0u, 7) { nes.step_instruction(); }
+    assert_eq!(nes.trace().unwrap().lines().len(), 2);
+
+    nes.trace().unwrap().set_pc_range(None);
+    nes.trace().unwrap().set_bank(Some(1));
+    for _ in range(0u, 7) { nes.step_instruction(); }
+    assert_eq!(nes.trace().unwrap().lines().len(), 2);
+
+    assert!(nes.stop_trace().is_some());
+    assert!(nes.trace().is_none());
+}
+
+#[test]
+fn debugger_cdl_test() {
+    let mut program = Vec::from_elem(0x33, 0x00u8);
+    let code = [
+        0x78,               //$8000 SEI
+        0xAD, 0x20, 0x80,   //$8001 LDA $8020
+        0xA9, 0x21,         //$8004 LDA #$21
+        0x85, 0x00,         //$8006 STA $00
+        0xA9, 0x80,         //$8008 LDA #$80
+        0x85, 0x01,         //$800A STA $01
+        0xA0, 0x00,         //$800C LDY #$00
+        0xB1, 0x00,         //$800E LDA ($00),Y
+        0x6C, 0x22, 0x80,   //$8010 JMP ($8022)
+    ];
+    for (i, &byte) in code.iter().enumerate() { *program.get_mut(i) = byte; }
+    *program.get_mut(0x22) = 0x30;
+    *program.get_mut(0x23) = 0x80;
+    *program.get_mut(0x30) = 0x4C; //$8030 JMP $8030
+    *program.get_mut(0x31) = 0x30;
+    *program.get_mut(0x32) = 0x80;
+
+    let mut nes = Nes::from_bytes(get_test_rom(program.as_slice()).as_slice());
+    nes.reset();
+    let cdl = nes.new_cdl();
+    assert_eq!((cdl.prg_len(), cdl.chr_len()), (2 * PRG_ROM_BANK_SIZE, CHR_ROM_BANK_SIZE));
+    assert!(!nes.start_cdl(CodeDataLog::new(PRG_ROM_BANK_SIZE, CHR_ROM_BANK_SIZE)));
+    assert!(nes.start_cdl(cdl));
+    nes.run_frame();
+
+    let cdl = nes.cdl().unwrap();
+    let prg = cdl.prg_flags();
+    assert_eq!(prg[0x00], CDL_CODE);
+    assert_eq!(prg[0x12], CDL_CODE);
+    assert_eq!(prg[0x20], CDL_DATA);
+    assert_eq!(prg[0x21], CDL_DATA | CDL_INDIRECT_DATA);
+    assert_eq!(prg[0x22], CDL_DATA);
+    assert_eq!(prg[0x30], CDL_CODE | CDL_INDIRECT_CODE);
+    assert_eq!(prg[0x31], CDL_CODE);
+    assert_eq!(prg[0x1F], 0x00);
+    assert_eq!(cdl.prg_covered(), 0x13 + 4 + 3);
+    assert_eq!(cdl.chr_covered(), 0);
+
+    //the bank bits say which 8 KB it was read through
+    let mut cdl = CodeDataLog::new(PRG_ROM_BANK_SIZE, 0);
+    cdl.log_prg(0x3FFC, 0xFFFC, CDL_DATA);
+    assert_eq!(cdl.prg_flags()[0x3FFC], CDL_DATA | 0x0C);
+
+    //new coverage only counts what wasn't covered before
+    let mut cdl = nes.stop_cdl().unwrap();
+    cdl.start_new_coverage();
+    assert!(nes.start_cdl(cdl));
+    nes.run_frame();
+    let mut cdl = nes.stop_cdl().unwrap();
+    assert_eq!(cdl.prg_covered(), 0);
+    cdl.log_prg(0x40, 0x8040, CDL_CODE);
+    assert_eq!(cdl.prg_covered(), 1);
+    cdl.stop_new_coverage();
+    assert_eq!(cdl.prg_covered(), 0x13 + 4 + 3 + 1);
+
+    let dir = TempDir::new("rustnes_cdl").unwrap();
+    let path = dir.path().join("test.cdl");
+    cdl.save(&path).unwrap();
+    let loaded = CodeDataLog::open(&path, 2 * PRG_ROM_BANK_SIZE, CHR_ROM_BANK_SIZE).unwrap();
+    assert_eq!(loaded.prg_flags(), cdl.prg_flags());
+    assert!(CodeDataLog::open(&path, PRG_ROM_BANK_SIZE, CHR_ROM_BANK_SIZE).is_err());
+}
+
+#[test]
+fn debugger_cdl_chr_test() {
+    let program = [
+        0xA9, 0x00,         //$8000 LDA #$00
+        0x8D, 0x06, 0x20,   //$8002 STA $2006
+        0xA9, 0x10,         //$8005 LDA #$10
+        0x8D, 0x06, 0x20,   //$8007 STA $2006
+        0xAD, 0x07, 0x20,   //$800A LDA $2007
+        0xAD, 0x07, 0x20,   //$800D LDA $2007
+        0xA9, 0x08,         //$8010 LDA #$08
+        0x8D, 0x01, 0x20,   //$8012 STA $2001
+        0x4C, 0x15, 0x80,   //$8015 JMP $8015
+    ];
+    let mut nes = Nes::from_bytes(get_test_rom(&program).as_slice());
+    nes.reset();
+    let cdl = nes.new_cdl();
+    assert!(nes.start_cdl(cdl));
+    nes.run_frame();
+    nes.run_frame();
+
+    //the name tables are empty, so tile 0 is drawn for the background and every sprite
+    let cdl = nes.cdl().unwrap();
+    let chr = cdl.chr_flags();
+    assert_eq!(chr[0x00], CDL_CHR_RENDERED);
+    assert_eq!(chr[0x0F], CDL_CHR_RENDERED);
+    assert_eq!(chr[0x10], CDL_CHR_READ);
+    assert_eq!(chr[0x11], CDL_CHR_READ);
+    assert_eq!(cdl.chr_covered(), 0x12);
+}
+
+#[test]
+fn debugger_symbols_test() {
+    let mut symbols = Symbols::new();
+    assert!(symbols.add_nl("$8000#Reset#Starts here\n\\more of the comment\n$8010##no name\n", Some(0)));
+    assert!(symbols.add_nl("$0300/10#buffer#\n$0010#player_x#\n", None));
+    assert!(!symbols.add_nl("$80G0#bad#\n", None));
+    assert_eq!(symbols.len(), 3);
+    assert_eq!(symbols.symbols()[1], Symbol { name: "buffer".to_string(), addr: 0x0300, bank: None });
+
+    //ROM labels only match their own bank, RAM ones any
+    assert_eq!(symbols.lookup(0x8000, Some(0)).map(|symbol| symbol.name.as_slice()), Some("Reset"));
+    assert_eq!(symbols.lookup(0x8000, None).map(|symbol| symbol.name.as_slice()), Some("Reset"));
+    assert!(symbols.lookup(0x8000, Some(1)).is_none());
+    assert_eq!(symbols.lookup(0x0010, Some(1)).map(|symbol| symbol.name.as_slice()), Some("player_x"));
+
+    let dbg = "version\tmajor=2,minor=0\n\
+               file\tid=0,name=\"main, game.s\",size=100,mtime=0x5F000000,mod=0\n\
+               seg\tid=0,name=\"CODE\",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400\n\
+               seg\tid=1,name=\"BSS\",start=0x000300,size=0x0010,addrsize=absolute,type=rw\n\
+               sym\tid=0,name=\"main_loop\",addrsize=absolute,scope=0,def=1,val=0xC010,seg=0,type=lab\n\
+               sym\tid=1,name=\"frame_count\",addrsize=absolute,scope=0,def=2,val=0x300,seg=1,type=lab\n\
+               sym\tid=2,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=3,val=0x2000,type=equ\n\
+               sym\tid=3,name=\"SPRITES\",addrsize=zeropage,scope=0,def=4,val=0x8,type=equ\n\
+               sym\tid=4,name=\"init\",addrsize=absolute,scope=0,def=5,val=0xC000,type=imp\n";
+    let mut symbols = Symbols::new();
+    assert!(symbols.add_dbg(dbg));
+    assert!(!symbols.add_dbg("sym\tid=0,name=\"main\",val=0x8000,type=lab\n"));
+    assert_eq!(symbols.symbols(), [
+        Symbol { name: "main_loop".to_string(), addr: 0xC010, bank: Some(1) },
+        Symbol { name: "frame_count".to_string(), addr: 0x0300, bank: None },
+        Symbol { name: "PPUCTRL".to_string(), addr: 0x2000, bank: None },
+    ].as_slice());
+
+    //.nl files say their bank in the name
+    let dir = TempDir::new("rustnes_symbols").unwrap();
+    let path = dir.path().join("game.nes.1.nl");
+    File::create(&path).write_str("$C000#Reset#\n").unwrap();
+    let mut symbols = Symbols::new();
+    assert_eq!(symbols.load(&path).unwrap(), 1);
+    assert_eq!(symbols.symbols()[0].bank, Some(1));
+    assert!(symbols.load(&dir.path().join("game.sym")).is_err());
+}
+
+#[test]
+fn debugger_export_test() {
+    let mut nes = Nes::from_bytes(get_test_rom(&DEBUGGER_TEST_PROGRAM).as_slice());
+    nes.reset();
+    let cdl = nes.new_cdl();
+    nes.start_cdl(cdl);
+    nes.run_frame();
+
+    let mut symbols = Symbols::new();
+    symbols.add("update", 0x800D, Some(0));
+    symbols.add("counter", 0x0300, None);
+    symbols.add("elsewhere", 0x800D, Some(1));
+
+    let banks = nes.export_disassembly(&symbols, Ca65Style);
+    assert_eq!(banks.len(), 2);
+
+    //without the address comments
+    let lines: Vec<&str> = banks[0].as_slice().lines().map(|line| {
+        line.splitn(1, ';').next().unwrap().trim_right()
+    }).filter(|line| !line.is_empty()).collect();
+    assert_eq!(lines.slice_to(16), [
+        ".setcpu \"6502\"",
+        "counter = $0300",
+        ".org $8000",
+        "    SEI",
+        "    LDX #$00",
+        "    JSR update",
+        "L8006:",
+        "    INX",
+        "    STA counter",
+        "    JMP L8006",
+        "update:",
+        "    LDA #$40",
+        "    RTS",
+        "    .byte $EA,$EA,$EA,$EA,$EA,$EA,$EA,$EA",
+        "    .byte $EA,$EA,$EA,$EA,$EA,$EA,$EA,$EA",
+        "    .byte $EA,$EA,$EA,$EA,$EA,$EA,$EA,$EA",
+    ].as_slice());
+    assert!(banks[0].as_slice().starts_with("; Bank 0, PRG-ROM $0000-$3FFF at $8000-$BFFF\n; 16 bytes of code, 0 of data, 16368 unused\n"));
+    assert!(banks[0].as_slice().contains("    SEI                         ; $8000\n"));
+
+    //the vectors were read through $FFFA-$FFFF
+    assert!(banks[1].as_slice().starts_with("; Bank 1, PRG-ROM $4000-$7FFF at $C000-$FFFF\n"));
+    assert!(banks[1].as_slice().contains("    .byte $EA,$EA,$00,$80,$00,$80,$00,$80 ; $FFF8\n"));
+
+    //absolute addressing that would assemble as zero page
+    let prg = [0xADu8, 0x12, 0x00, 0xD0, 0xFB];
+    let ca65 = export_bank(prg.as_slice(), None, 0, &Symbols::new(), Ca65Style);
+    assert!(ca65.as_slice().contains("\nLC000:\n    LDA a:$0012"));
+    assert!(ca65.as_slice().contains("    BNE LC000"));
+    let asm6 = export_bank(prg.as_slice(), None, 0, &Symbols::new(), Asm6Style);
+    assert!(!asm6.as_slice().contains(".setcpu"));
+    assert!(asm6.as_slice().contains("\nLC000:\n    .byte $AD,$12,$00"));
+    assert!(asm6.as_slice().contains("    BNE LC000"));
+}
+
+//what ld65 --dbgfile would write for DEBUGGER_TEST_PROGRAM
+static DEBUGGER_TEST_DBG: &'static str = "\
+version\tmajor=2,minor=0
+file\tid=0,name=\"main.s\",size=400,mtime=0x5F000000,mod=0
+line\tid=0,file=0,line=10,span=0
+line\tid=1,file=0,line=11,span=1
+line\tid=2,file=0,line=99,type=2,span=1
+line\tid=3,file=0,line=15,span=2
+line\tid=4,file=0,line=1
+seg\tid=0,name=\"CODE\",start=0x008000,size=0x0010,addrsize=absolute,type=ro,oname=\"test.nes\",ooffs=16
+seg\tid=1,name=\"BSS\",start=0x000300,size=0x0001,addrsize=absolute,type=rw
+span\tid=0,seg=0,start=6,size=1
+span\tid=1,seg=0,start=7,size=3
+span\tid=2,seg=0,start=13,size=2
+sym\tid=0,name=\"main_loop\",addrsize=absolute,scope=0,def=0,val=0x8006,seg=0,type=lab
+sym\tid=1,name=\"update\",addrsize=absolute,scope=0,def=3,val=0x800D,seg=0,type=lab
+sym\tid=2,name=\"counter\",addrsize=absolute,scope=0,def=5,val=0x300,seg=1,type=lab
+";
+
+#[test]
+fn debugger_symbols_source_test() {
+    let mut symbols = Symbols::new();
+    assert!(symbols.add_dbg(DEBUGGER_TEST_DBG));
+    assert_eq!(symbols.find("update").map(|symbol| symbol.addr), Some(0x800D));
+    assert!(symbols.find("nothing").is_none());
+
+    //the macro line covers the same bytes as line 11 and is left out
+    assert_eq!(symbols.source_line(0x8009, Some(0)), Some(&SourceLine {
+        file: "main.s".to_string(), line: 11, addr: 0x8007, len: 3, bank: Some(0),
+    }));
+    assert!(symbols.source_line(0x800A, Some(0)).is_none());
+    assert!(symbols.source_line(0x8007, Some(1)).is_none());
+
+    assert_eq!(symbols.describe(0x8006, Some(0)), Some("main_loop".to_string()));
+    assert_eq!(symbols.describe(0x8009, Some(0)), Some("main_loop+3".to_string()));
+    assert_eq!(symbols.describe(0x0301, None), None);
+
+    //names for addresses in commands
+    assert_eq!(parse_command_with("b main_loop", &symbols), Ok(AddBreakpoint(ExecBreak(0x8006, 0x8006), None)));
+    assert_eq!(parse_command_with("b main_loop-update", &symbols), Ok(AddBreakpoint(ExecBreak(0x8006, 0x800D), None)));
+    assert_eq!(parse_command_with("m counter+2 4", &symbols), Ok(Memory(0x0302, 4)));
+    assert_eq!(parse_command_with("dis 8000", &symbols), Ok(Disassemble(Some(0x8000), 10)));
+    assert!(parse_command_with("m counter+x", &symbols).is_err());
+    assert!(parse_command("b main_loop").is_err());
+
+    let mut nes = Nes::from_bytes(get_test_rom(&DEBUGGER_TEST_PROGRAM).as_slice());
+    nes.reset();
+    nes.set_symbols(symbols.clone());
+    assert_eq!(nes.describe_addr(0x8008), Some("main_loop+2 at main.s:11".to_string()));
+    assert_eq!(nes.describe_addr(0x0300), Some("counter".to_string()));
+    assert_eq!(nes.describe_addr(0x0400), None);
+
+    assert_eq!(execute_command(&mut nes, Disassemble(Some(0x8003), 3)), [
+        "8003  20 0D 80  JSR update".to_string(),
+        "main_loop:".to_string(),
+        format!("{:<32}; main.s:10", "8006  E8        INX"),
+        format!("{:<32}; main.s:11", "8007  8D 00 03  STA counter"),
+    ].as_slice().connect("\n"));
+
+    let command = parse_command_with("b main_loop", nes.symbols()).unwrap();
+    execute_command(&mut nes, command);
+    let output = execute_command(&mut nes, Continue(None));
+    assert!(output.as_slice().starts_with("Breakpoint #1\nmain_loop at main.s:10\n8006  INX\n"));
+
+    //the trace logger picks them up when it starts
+    let mut nes = get_debugger_nes();
+    nes.set_symbols(symbols);
+    let mut trace = get_trace_logger(3);
+    trace.set_format(CompactFormat);
+    trace.show_ppu(false);
+    trace.show_cycles(false);
+    nes.start_trace(trace);
+
+    for _ in range(0u, 7) { nes.step_instruction(); }
+    let lines = nes.trace().unwrap().lines();
+    assert!(lines[0].as_slice().starts_with("800F  RTS "));
+    assert!(lines[1].as_slice().starts_with("main_loop:\n8006  INX "));
+    assert!(lines[2].as_slice().starts_with("8007  STA counter    A:40 X:01"));
+}
+
+fn get_call_frame(entry: VAddr, return_addr: VAddr, sp: u8) -> CallFrame {
+    CallFrame { kind: JsrCall, entry: entry, bank: Some(0), return_addr: return_addr, sp: sp }
+}
+
+static DEBUGGER_RTS_JUMP_PROGRAM: [u8, ..35] = [
+    0x78,               //$8000 SEI
+    0xA2, 0xFF,         //$8001 LDX #$FF
+    0x9A,               //$8003 TXS
+    0x20, 0x10, 0x80,   //$8004 JSR $8010
+    0x4C, 0x07, 0x80,   //$8007 JMP $8007
+    0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA,
+    0xA9, 0x80,         //$8010 LDA #$80
+    0x48,               //$8012 PHA
+    0xA9, 0x1F,         //$8013 LDA #$1F
+    0x48,               //$8015 PHA
+    0x60,               //$8016 RTS, to $8020
+    0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA,
+    0xE6, 0x00,         //$8020 INC $00
+    0x60,               //$8022 RTS
+];
+
+#[test]
+fn debugger_callstack_test() {
+    let mut stack = CallStack::new();
+    stack.call(get_call_frame(0x8100, 0x8006, 0xFF));
+    stack.call(get_call_frame(0x8200, 0x8103, 0xFD));
+    assert_eq!(stack.depth(), 2);
+
+    //returning to the outer frame's S returns from both
+    assert_eq!(stack.ret(0xFF, true), Returned(2));
+    assert_eq!(stack.depth(), 0);
+
+    //an RTS that leaves S short of any frame is a jump
+    stack.call(get_call_frame(0x8100, 0x8006, 0xFF));
+    assert_eq!(stack.ret(0xFD, false), RtsJump);
+    assert_eq!((stack.depth(), stack.rts_jumps()), (1, 1));
+    assert_eq!(stack.ret(0xF0, true), Untracked);
+    assert_eq!(stack.depth(), 1);
+
+    //S pulled past a frame's return address drops it
+    stack.call(CallFrame { kind: NmiCall, entry: 0x8300, bank: Some(0), return_addr: 0x8100, sp: 0xFD });
+    assert_eq!(stack.unwind(0xFC), 1);
+    assert_eq!(stack.unwind(0xFF), 1);
+    assert_eq!(stack.depth(), 0);
+
+    let mut nes = Nes::from_bytes(get_test_rom(&DEBUGGER_RTS_JUMP_PROGRAM).as_slice());
+    nes.reset();
+    nes.attach_debugger(Debugger::new());
+    let mut symbols = Symbols::new();
+    symbols.add("start", 0x8000, Some(0));
+    symbols.add("jump_table", 0x8010, Some(0));
+    nes.set_symbols(symbols);
+
+    for _ in range(0u, 4) { nes.step_instruction(); }
+    assert_eq!(nes.cpu_state().PC, 0x8010);
+    assert_eq!(nes.call_stack().unwrap().depth(), 1);
+    assert_eq!(execute_command(&mut nes, Backtrace), "#0  $8010 jump_table\n#1  $8007 start+7".to_string());
+
+    //the pushed address takes it to $8020 and the subroutine carries on there
+    for _ in range(0u, 5) { nes.step_instruction(); }
+    assert_eq!(nes.cpu_state().PC, 0x8020);
+    assert_eq!(nes.call_stack().unwrap().depth(), 1);
+    assert_eq!(nes.call_stack().unwrap().rts_jumps(), 1);
+
+    for _ in range(0u, 2) { nes.step_instruction(); }
+    assert_eq!(nes.cpu_state().PC, 0x8007);
+    assert_eq!(nes.call_stack().unwrap().depth(), 0);
+    assert_eq!(parse_command("bt"), Ok(Backtrace));
+
+    nes.detach_debugger();
+    assert!(nes.call_stack().is_none());
+}
+
+#[test]
+fn debugger_profiler_test() {
+    let mut nes = get_debugger_nes();
+    nes.start_profiler();
+    nes.debug_continue(Some(2));
+
+    let profiler = nes.profiler().unwrap();
+    assert_eq!(profiler.frames(), 2);
+
+    //JSR, LDA and RTS, all in the first frame
+    let routines = profiler.routines();
+    let update = routines.iter().find(|routine| routine.entry == 0x800D).unwrap();
+    assert_eq!(update.kind, Some(JsrCall));
+    assert_eq!((update.total_calls, update.total_inclusive, update.total_exclusive), (1, 14, 14));
+    assert_eq!((update.calls, update.inclusive), (0, 0));
+
+    //the top level gets every cycle, about a frame's worth
+    let top = &routines[0];
+    assert_eq!(top.kind, None);
+    assert_eq!(top.inclusive, profiler.last_frame_cycles());
+    assert!((profiler.budget_used(top.inclusive) - 1.0).abs() < 0.01);
+
+    let report = nes.profile_report().unwrap();
+    assert!(report.as_slice().starts_with("frames: 2, "));
+    assert!(report.as_slice().contains("(top level)"));
+    assert!(report.as_slice().contains("$800D"));
+
+    assert_eq!(parse_command("prof"), Ok(Profile(true)));
+    assert_eq!(parse_command("prof off"), Ok(Profile(false)));
+    assert!(parse_command("prof on").is_err());
+    assert!(execute_command(&mut nes, Profile(false)).as_slice().starts_with("frames: 2, "));
+    assert!(nes.profiler().is_none());
+    assert_eq!(execute_command(&mut nes, Profile(true)), "Profiling".to_string());
+    assert!(nes.profiler().is_some());
+}
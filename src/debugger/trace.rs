@@ -0,0 +1,247 @@
+use std::collections::{RingBuf, Deque};
+use std::task;
+
+use nes::VAddr;
+
+use cpu::Cpu;
+use cpu::{C_FLAG, Z_FLAG, I_FLAG, D_FLAG, B_FLAG, X_FLAG, V_FLAG, N_FLAG};
+
+use debugger::symbols::Symbols;
+
+/// # Trace logger
+///
+/// Writes a line for every instruction the CPU runs, before it runs, in one of two formats.
+/// Nestest is the layout of nestest.log, so traces can be diffed against it or against other
+/// emulators:
+///
+///  C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+///
+/// Compact leaves out the bytes and the values the instruction is about to use, and spells
+/// out the flags, set ones in capitals:
+///
+///  C000  JMP $C5F5      A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0 H:21 C:7
+///
+/// The PPU position (scanline, then dot) and the CPU cycle count are optional in both.
+///
+/// Filters pick which instructions get logged:
+///
+/// - a PC range, inclusive
+/// - a 16 KB PRG-ROM bank, counted from the start of PRG-ROM, for code that gets banked in
+/// - a trigger address, nothing is logged until PC gets there the first time
+///
+/// With symbols, operands are written as names and a label line goes before an instruction
+/// that has one:
+///
+///  main_loop:
+///  C010  AD 02 20  LDA PPUSTATUS = 80              A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+///
+/// In ring mode only the last N lines are kept, and dump writes them out. The NES dumps it
+/// when a breakpoint or watchpoint stops emulation, and the logger dumps itself when it's
+/// dropped, which includes the emulator failing with it attached. That leaves a record of
+/// what led up to a crash without logging millions of lines.
+
+#[deriving(PartialEq, Show, Clone)]
+pub enum TraceFormat {
+    NestestFormat,
+    CompactFormat,
+}
+
+//the flags from bit 7 down, U is the unused bit 5
+static FLAG_NAMES: [char, ..8] = ['n', 'v', 'u', 'b', 'd', 'i', 'z', 'c'];
+
+pub struct TraceLogger {
+    format: TraceFormat,
+    show_ppu: bool,
+    show_cycles: bool,
+
+    pc_range: Option<(VAddr, VAddr)>,
+    bank: Option<uint>,
+    trigger: Option<VAddr>,
+    triggered: bool,
+
+    //the last lines when in ring mode, and how many to keep
+    ring: Option<RingBuf<String>>,
+    ring_len: uint,
+
+    //CPU cycles since power on, the Cpu adds them as it goes
+    cycles: u64,
+
+    symbols: Option<Symbols>,
+
+    out: Box<Writer>,
+}
+
+impl TraceLogger {
+    /// Nestest format with the PPU position and cycles, no filters.
+    pub fn new(out: Box<Writer>) -> TraceLogger {
+        TraceLogger {
+            format: NestestFormat,
+            show_ppu: true,
+            show_cycles: true,
+            pc_range: None,
+            bank: None,
+            trigger: None,
+            triggered: true,
+            ring: None,
+            ring_len: 0,
+            cycles: 0,
+            symbols: None,
+            out: out,
+        }
+    }
+
+    pub fn set_format(&mut self, format: TraceFormat) {
+        self.format = format;
+    }
+
+    pub fn show_ppu(&mut self, show: bool) {
+        self.show_ppu = show;
+    }
+
+    pub fn show_cycles(&mut self, show: bool) {
+        self.show_cycles = show;
+    }
+
+    pub fn set_pc_range(&mut self, range: Option<(VAddr, VAddr)>) {
+        self.pc_range = range;
+    }
+
+    pub fn set_bank(&mut self, bank: Option<uint>) {
+        self.bank = bank;
+    }
+
+    /// Logs nothing until PC reaches `trigger`.
+    pub fn set_trigger(&mut self, trigger: Option<VAddr>) {
+        self.trigger = trigger;
+        self.triggered = trigger.is_none();
+    }
+
+    /// Keeps only the last `len` lines until dump, or writes every line as it goes with None.
+    pub fn set_ring(&mut self, len: Option<uint>) {
+        self.dump();
+        self.ring = len.map(|len| RingBuf::with_capacity(len));
+        self.ring_len = len.unwrap_or(0);
+    }
+
+    pub fn set_symbols(&mut self, symbols: Option<Symbols>) {
+        self.symbols = symbols;
+    }
+
+    pub fn set_cycles(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    pub fn add_cycles(&mut self, cycles: uint) {
+        self.cycles += cycles as u64;
+    }
+
+    /// What ring mode is holding on to, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        match self.ring {
+            Some(ref ring) => ring.iter().map(|line| line.clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Logs the instruction at PC if it gets through the filters.
+    pub fn log(&mut self, cpu: &Cpu) {
+        let pc = cpu.pc();
+
+        if !self.triggered {
+            if self.trigger != Some(pc) { return; }
+            self.triggered = true;
+        }
+
+        match self.pc_range {
+            Some((start, end)) if pc < start || pc > end => { return; }
+            _ => { }
+        }
+
+        if self.bank.is_some() && self.bank != cpu.prg_bank(pc) { return; }
+
+        let mut line = self.format_line(cpu);
+        match self.symbols.as_ref().and_then(|symbols| symbols.lookup(pc, cpu.prg_bank(pc))) {
+            Some(symbol) => { line = format!("{}:\n{}", symbol.name, line); }
+            None => { }
+        }
+
+        match self.ring {
+            Some(ref mut ring) => {
+                if ring.len() == self.ring_len { ring.pop_front(); }
+                if self.ring_len > 0 { ring.push_back(line); }
+            }
+            None => { let _ = self.out.write_line(line.as_slice()); }
+        }
+    }
+
+    pub fn format_line(&self, cpu: &Cpu) -> String {
+        let state = cpu.state();
+        let line = cpu.disassemble(state.PC);
+
+        let mut text = match self.format {
+            NestestFormat => {
+                let annotated = match self.symbols {
+                    Some(ref symbols) => cpu.annotate_symbols(&line, symbols),
+                    None => cpu.annotate(&line),
+                };
+                format!("{:04X}  {:<8}  {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+                        state.PC, line.bytes_text(), annotated,
+                        state.A, state.X, state.Y, state.P.bits(), state.S)
+            }
+            CompactFormat => {
+                let flags = [N_FLAG, V_FLAG, X_FLAG, B_FLAG, D_FLAG, I_FLAG, Z_FLAG, C_FLAG];
+                let flags: String = flags.iter().zip(FLAG_NAMES.iter()).map(|(&flag, &name)| {
+                    if state.P.contains(flag) { name.to_uppercase() } else { name }
+                }).collect();
+
+                let text = match self.symbols {
+                    Some(ref symbols) => cpu.symbolic_text(&line, symbols),
+                    None => line.text(),
+                };
+                format!("{:04X}  {:<14} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}",
+                        state.PC, text,
+                        state.A, state.X, state.Y, state.S, flags)
+            }
+        };
+
+        if self.show_ppu {
+            let (scanline, dot) = (cpu.ppu.scanline(), cpu.ppu.dot());
+            text.push_str(match self.format {
+                NestestFormat => format!(" PPU:{:3},{:3}", scanline, dot),
+                CompactFormat => format!(" V:{} H:{}", scanline, dot),
+            }.as_slice());
+        }
+
+        if self.show_cycles {
+            text.push_str(match self.format {
+                NestestFormat => format!(" CYC:{}", self.cycles),
+                CompactFormat => format!(" C:{}", self.cycles),
+            }.as_slice());
+        }
+
+        text
+    }
+
+    /// Writes out and forgets the lines ring mode kept.
+    pub fn dump(&mut self) {
+        match self.ring {
+            Some(ref mut ring) => {
+                for line in ring.iter() {
+                    let _ = self.out.write_line(line.as_slice());
+                }
+                ring.clear();
+            }
+            None => { }
+        }
+        let _ = self.out.flush();
+    }
+}
+
+impl Drop for TraceLogger {
+    fn drop(&mut self) {
+        if task::failing() && !self.lines().is_empty() {
+            let _ = self.out.write_line("Trace up to the failure:");
+        }
+        self.dump();
+    }
+}
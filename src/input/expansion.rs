@@ -0,0 +1,132 @@
+use nes::state::{StateWriter, StateReader};
+
+use input::{ButtonState, signature_bits};
+
+/// # Famicom expansion port
+///
+/// from http://wiki.nesdev.com/w/index.php/Expansion_port
+///
+/// On the Famicom the controllers are hardwired and only drive bit 0 of $4016/$4017, and
+/// everything else plugs into the 15 pin expansion port. Devices there see all three output
+/// bits of $4016 and can drive bit 1 of $4016 and bits 1-4 of $4017.
+///
+/// ## Output ($4016 write)
+///
+/// 7  bit  0
+/// ---- ----
+/// xxxx xCBA
+///       |||
+///       ||+- OUT0, the controller strobe
+///       |+-- OUT1
+///       +--- OUT2
+pub trait ExpansionDevice {
+    fn name(&self) -> &'static str;
+
+    fn write(&mut self, val: u8);
+
+    //bits 1-4 of $4016 (port 0) or $4017 (port 1)
+    fn read(&mut self, port: uint) -> u8;
+
+    //index 0 is player 3, 1 is player 4
+    fn set_buttons(&mut self, _index: uint, _buttons: ButtonState) {
+    }
+
+    //see keyboard::KEY_NAMES for the key numbers
+    fn set_key(&mut self, _key: uint, _pressed: bool) {
+    }
+
+    fn save_state(&self, writer: &mut StateWriter);
+
+    fn load_state(&mut self, reader: &mut StateReader);
+}
+
+/// # Famicom 4 player adapter
+///
+/// from http://wiki.nesdev.com/w/index.php/Four_player_adapters
+///
+/// Players 3 and 4 are read on bit 1, player 3 on $4016 and player 4 on $4017. Simple
+/// expansion controllers stop after their 8 buttons, the Hori adapter in 4 player mode pads
+/// them with 8 zeros and a signature, $20 on $4016 and $10 on $4017 (the reverse of the Four
+/// Score's) read high bit first, then returns 1.
+
+static HORI_SIGNATURES: [u8, ..2] = [0x20, 0x10];
+
+pub struct HoriAdapter {
+    buttons: [ButtonState, ..2],
+    strobe: bool,
+    shift: [u32, ..2],
+    reads: [uint, ..2],
+}
+
+impl HoriAdapter {
+    pub fn new() -> HoriAdapter {
+        HoriAdapter {
+            buttons: [ButtonState::empty(), ButtonState::empty()],
+            strobe: false,
+            shift: [0, 0],
+            reads: [0, 0],
+        }
+    }
+
+    fn reload(&mut self) {
+        for port in range(0u, 2) {
+            self.shift[port] = (self.buttons[port].bits() as u32) |
+                signature_bits(HORI_SIGNATURES[port]) << 16;
+            self.reads[port] = 0;
+        }
+    }
+}
+
+impl ExpansionDevice for HoriAdapter {
+    fn name(&self) -> &'static str {
+        "hori"
+    }
+
+    fn write(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe { self.reload(); }
+    }
+
+    fn read(&mut self, port: uint) -> u8 {
+        let port = port & 0x01;
+
+        let bit =
+            if self.strobe {
+                self.buttons[port].bits() & 0x01
+            } else if self.reads[port] >= 24 {
+                0x01
+            } else {
+                let bit = (self.shift[port] & 0x01) as u8;
+                self.shift[port] >>= 1;
+                self.reads[port] += 1;
+                bit
+            };
+
+        bit << 1
+    }
+
+    fn set_buttons(&mut self, index: uint, buttons: ButtonState) {
+        if index < 2 {
+            self.buttons[index] = buttons;
+            if self.strobe { self.reload(); }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.strobe);
+        for port in range(0u, 2) {
+            w.u8(self.buttons[port].bits());
+            w.u32(self.shift[port]);
+            w.uint(self.reads[port]);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.strobe = r.bool();
+        for port in range(0u, 2) {
+            self.buttons[port] = ButtonState::from_bits_truncate(r.u8());
+            self.shift[port] = r.u32();
+            self.reads[port] = r.uint();
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use ppu::Ppu;
+use nes::state::{StateWriter, StateReader};
+
+use input::{InputDevice, ButtonState, signature_bits};
+
+/// # Four Score
+///
+/// from http://wiki.nesdev.com/w/index.php/Four_Score
+///
+/// Each port reads out 24 bits: two controllers and a signature, so games can tell the
+/// adapter from a plain controller.
+///
+/// - $4016 - Player 1, player 3, signature $10 (0001 0000)
+/// - $4017 - Player 2, player 4, signature $20 (0010 0000)
+///
+/// The signature reads out high bit first, reads 17-24 on $4016 are 0,0,0,1,0,0,0,0. Reads
+/// past the 24th return 1. FourScore::new(port) is one half, the adapter needs
+/// both ports.
+
+static SIGNATURES: [u8, ..2] = [0x10, 0x20];
+
+pub struct FourScore {
+    signature: u8,
+    buttons: [ButtonState, ..2],
+    strobe: bool,
+    shift: u32,
+    reads: uint,
+}
+
+impl FourScore {
+    pub fn new(port: uint) -> FourScore {
+        FourScore {
+            signature: SIGNATURES[port & 0x01],
+            buttons: [ButtonState::empty(), ButtonState::empty()],
+            strobe: false,
+            shift: 0,
+            reads: 0,
+        }
+    }
+
+    fn reload(&mut self) {
+        self.shift = (self.buttons[0].bits() as u32) | (self.buttons[1].bits() as u32) << 8 |
+            signature_bits(self.signature) << 16;
+        self.reads = 0;
+    }
+}
+
+impl InputDevice for FourScore {
+    fn name(&self) -> &'static str {
+        "fourscore"
+    }
+
+    fn write_strobe(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe { self.reload(); }
+    }
+
+    fn read(&mut self, _ppu: &Ppu) -> u8 {
+        if self.strobe {
+            return self.buttons[0].bits() & 0x01;
+        }
+
+        if self.reads >= 24 {
+            return 0x01;
+        }
+
+        let bit = (self.shift & 0x01) as u8;
+        self.shift >>= 1;
+        self.reads += 1;
+        bit
+    }
+
+    fn set_buttons(&mut self, index: uint, buttons: ButtonState) {
+        if index < 2 {
+            self.buttons[index] = buttons;
+            if self.strobe { self.reload(); }
+        }
+    }
+
+    //the signature comes from the port, it isn't saved
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.buttons[0].bits());
+        w.u8(self.buttons[1].bits());
+        w.bool(self.strobe);
+        w.u32(self.shift);
+        w.uint(self.reads);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.buttons[0] = ButtonState::from_bits_truncate(r.u8());
+        self.buttons[1] = ButtonState::from_bits_truncate(r.u8());
+        self.strobe = r.bool();
+        self.shift = r.u32();
+        self.reads = r.uint();
+    }
+}
@@ -0,0 +1,118 @@
+use nes::state::{StateWriter, StateReader};
+
+use input::expansion::{ExpansionDevice};
+
+/// # Family BASIC keyboard
+///
+/// from http://wiki.nesdev.com/w/index.php/Family_BASIC_Keyboard
+///
+/// ## Output ($4016 write)
+///
+/// 7  bit  0
+/// ---- ----
+/// xxxx xKCR
+///       |||
+///       ||+- 1: Reset to row 0, column 0
+///       |+-- Column select, going from 1 to 0 moves on to the next row
+///       +--- 1: Enable keyboard matrix
+///
+/// ## Input ($4017 read)
+///
+/// 7  bit  0
+/// ---- ----
+/// xxxK KKKx
+///    | |||
+///    +-+++-- The four keys of the selected row and column, 0 when pressed
+///
+/// Nine rows of two columns of four keys, listed in KEY_NAMES in that order. Reading past the
+/// last row returns no keys, and the whole register reads 0 while the matrix is disabled.
+
+pub static KEY_COUNT: uint = 72;
+static ROW_COUNT: uint = 9;
+
+pub static KEY_NAMES: [&'static str, ..KEY_COUNT] = [
+    "]", "[", "RETURN", "F8", "STOP", "YEN", "RSHIFT", "KANA",
+    ";", ":", "@", "F7", "^", "-", "/", "_",
+    "K", "L", "O", "F6", "0", "P", ",", ".",
+    "J", "U", "I", "F5", "8", "9", "N", "M",
+    "H", "G", "Y", "F4", "6", "7", "V", "B",
+    "D", "R", "T", "F3", "4", "5", "C", "F",
+    "A", "S", "W", "F2", "3", "E", "Z", "X",
+    "CTR", "Q", "ESC", "F1", "2", "1", "GRPH", "LSHIFT",
+    "LEFT", "RIGHT", "UP", "CLR", "INS", "DEL", "SPACE", "DOWN",
+];
+
+pub fn key_index(name: &str) -> Option<uint> {
+    KEY_NAMES.iter().position(|&key| key == name)
+}
+
+pub struct FamilyKeyboard {
+    keys: [bool, ..KEY_COUNT],
+    enabled: bool,
+    row: uint,
+    column: uint,
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> FamilyKeyboard {
+        FamilyKeyboard {
+            keys: [false, ..KEY_COUNT],
+            enabled: false,
+            row: 0,
+            column: 0,
+        }
+    }
+}
+
+impl ExpansionDevice for FamilyKeyboard {
+    fn name(&self) -> &'static str {
+        "keyboard"
+    }
+
+    fn write(&mut self, val: u8) {
+        let column = ((val >> 1) & 0x01) as uint;
+
+        if val & 0x01 != 0 {
+            self.row = 0;
+        } else if self.column == 1 && column == 0 && self.row < ROW_COUNT {
+            self.row += 1;
+        }
+
+        self.column = column;
+        self.enabled = val & 0x04 != 0;
+    }
+
+    fn read(&mut self, port: uint) -> u8 {
+        if port == 0 || !self.enabled { return 0x00; }
+        if self.row >= ROW_COUNT { return 0x1E; }
+
+        let first = self.row * 8 + self.column * 4;
+        let mut val = 0x1E;
+        for i in range(0u, 4) {
+            if self.keys[first + i] { val &= !(0x02 << i); }
+        }
+        val
+    }
+
+    fn set_key(&mut self, key: uint, pressed: bool) {
+        if key < KEY_COUNT { self.keys[key] = pressed; }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        for &key in self.keys.iter() {
+            w.bool(key);
+        }
+        w.bool(self.enabled);
+        w.uint(self.row);
+        w.uint(self.column);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        for key in self.keys.mut_iter() {
+            *key = r.bool();
+        }
+        self.enabled = r.bool();
+        self.row = r.uint();
+        self.column = r.uint() & 0x01;
+    }
+}
@@ -0,0 +1,398 @@
+use ppu::Ppu;
+use nes::state::{StateWriter, StateReader, StateResult, BadValue};
+
+pub use self::zapper::Zapper;
+pub use self::four_score::FourScore;
+pub use self::paddle::VausPaddle;
+pub use self::power_pad::PowerPad;
+pub use self::expansion::{ExpansionDevice, HoriAdapter};
+pub use self::keyboard::FamilyKeyboard;
+
+pub mod zapper;
+pub mod four_score;
+pub mod paddle;
+pub mod power_pad;
+pub mod expansion;
+pub mod keyboard;
+
+#[cfg(test)]
+mod test;
+
+/// # Input devices
+///
+/// from http://wiki.nesdev.com/w/index.php/Input_devices
+///
+/// Whatever is plugged into a controller port sees the strobe written to $4016 and drives
+/// bits 0-4 of its port's register. The setters are how a frontend hands the device its
+/// state, a device ignores the ones that don't apply to it.
+pub trait InputDevice {
+    fn name(&self) -> &'static str;
+
+    fn write_strobe(&mut self, val: u8);
+
+    //bits 0-4, the PPU is there for light guns
+    fn read(&mut self, ppu: &Ppu) -> u8;
+
+    //index is the controller within the device, only the Four Score has more than one
+    fn set_buttons(&mut self, _index: uint, _buttons: ButtonState) {
+    }
+
+    //screen coordinates, negative when aimed off screen
+    fn set_pointer(&mut self, _x: int, _y: int) {
+    }
+
+    fn set_trigger(&mut self, _pressed: bool) {
+    }
+
+    //bit n is Power Pad button n + 1
+    fn set_pad(&mut self, _pressed: u16) {
+    }
+
+    //shift registers along with what the frontend last set, for save states
+    fn save_state(&self, writer: &mut StateWriter);
+
+    fn load_state(&mut self, reader: &mut StateReader);
+}
+
+#[deriving(PartialEq, Show)]
+pub enum ConsoleType {
+    NesConsole,
+    FamicomConsole,
+}
+
+/// # Controller ports
+///
+/// Everything behind $4016 and $4017. In NesConsole mode the devices in the two ports drive
+/// bits 0-4 of their register. In FamicomConsole mode the ports hold the hardwired
+/// controllers and only drive bit 0, an expansion port device drives bits 1-4, and bit 2 of
+/// $4016 is the microphone on controller 2.
+pub struct InputPorts {
+    pub ports: [Box<InputDevice>, ..2],
+    pub expansion: Option<Box<ExpansionDevice>>,
+    console: ConsoleType,
+    microphone: bool,
+    polled: bool,
+}
+
+impl InputPorts {
+    pub fn new() -> InputPorts {
+        InputPorts {
+            ports: [box Controller::new() as Box<InputDevice>, box Controller::new() as Box<InputDevice>],
+            expansion: None,
+            console: NesConsole,
+            microphone: false,
+            polled: false,
+        }
+    }
+
+    pub fn console(&self) -> ConsoleType {
+        self.console
+    }
+
+    pub fn set_console(&mut self, console: ConsoleType) {
+        self.console = console;
+    }
+
+    pub fn set_microphone(&mut self, loud: bool) {
+        self.microphone = loud;
+    }
+
+    /// Player 1 to 4, 0 based. Players 3 and 4 are on a Four Score, or on the expansion port
+    /// of a Famicom.
+    pub fn set_player_buttons(&mut self, player: uint, buttons: ButtonState) {
+        let port = player % PORT_COUNT;
+        let index = player / PORT_COUNT;
+
+        if index > 0 && self.console == FamicomConsole {
+            match self.expansion {
+                Some(ref mut device) => { device.set_buttons(port, buttons); }
+                None => { }
+            }
+        } else {
+            self.ports[port].set_buttons(index, buttons);
+        }
+    }
+
+    //whether the game read either port since the last call, frames where it didn't are lag frames
+    pub fn take_polled(&mut self) -> bool {
+        let polled = self.polled;
+        self.polled = false;
+        polled
+    }
+
+    //$4016
+    pub fn write(&mut self, val: u8) {
+        for device in self.ports.mut_iter() {
+            device.write_strobe(val);
+        }
+
+        if self.console == FamicomConsole {
+            match self.expansion {
+                Some(ref mut device) => { device.write(val & 0x07); }
+                None => { }
+            }
+        }
+    }
+
+    //$4016 for port 0, $4017 for port 1, without the open bus bits
+    pub fn read(&mut self, port: uint, ppu: &Ppu) -> u8 {
+        self.polled = true;
+
+        let data = self.ports[port].read(ppu);
+        if self.console == NesConsole {
+            return data & 0x1F;
+        }
+
+        let mut val = data & 0x01;
+        match self.expansion {
+            Some(ref mut device) => { val |= device.read(port) & 0x1E; }
+            None => { }
+        }
+        if port == 0 && self.microphone { val |= 0x04; }
+        val
+    }
+
+    /// # Default expansion device
+    ///
+    /// from http://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+    ///
+    /// Plugs in what the NES 2.0 header says the game expects. Devices this emulator doesn't
+    /// have keep the standard controllers, Famicom only devices still switch to FamicomConsole.
+    pub fn configure(&mut self, device: u8) {
+        match device {
+            0x02 => {
+                self.ports = [box FourScore::new(0) as Box<InputDevice>, box FourScore::new(1) as Box<InputDevice>];
+            }
+            0x03 => {
+                self.console = FamicomConsole;
+                self.expansion = Some(box HoriAdapter::new() as Box<ExpansionDevice>);
+            }
+            0x08 => { self.ports[1] = box Zapper::new() as Box<InputDevice>; }
+            0x09 => {
+                self.ports = [box Zapper::new() as Box<InputDevice>, box Zapper::new() as Box<InputDevice>];
+            }
+            0x0B | 0x0C => { self.ports[1] = box PowerPad::new() as Box<InputDevice>; }
+            0x0F => { self.ports[1] = box VausPaddle::new() as Box<InputDevice>; }
+            0x23 => {
+                self.console = FamicomConsole;
+                self.expansion = Some(box FamilyKeyboard::new() as Box<ExpansionDevice>);
+            }
+            //other Famicom expansion port devices
+            0x0A | 0x0D | 0x0E | 0x10 ... 0x18 | 0x1A ... 0x1E | 0x20 ... 0x22 => {
+                self.console = FamicomConsole;
+            }
+            _ => { }
+        }
+    }
+
+    //devices are saved by name, loading a state plugs the same ones back in
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for device in self.ports.iter() {
+            w.vec(device.name().as_bytes());
+            device.save_state(w);
+        }
+
+        match self.expansion {
+            Some(ref device) => {
+                w.bool(true);
+                w.vec(device.name().as_bytes());
+                device.save_state(w);
+            }
+            None => { w.bool(false); }
+        }
+
+        w.bool(self.console == FamicomConsole);
+        w.bool(self.microphone);
+        w.bool(self.polled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        for port in range(0u, PORT_COUNT) {
+            let name = String::from_utf8_lossy(r.vec().as_slice()).into_string();
+            if name.as_slice() != self.ports[port].name() {
+                self.ports[port] = match device_from_name(name.as_slice(), port) {
+                    Some(device) => device,
+                    None => { return Err(BadValue(format!("input device {}", name))); }
+                };
+            }
+            self.ports[port].load_state(r);
+        }
+
+        if r.bool() {
+            let name = String::from_utf8_lossy(r.vec().as_slice()).into_string();
+            let same = match self.expansion {
+                Some(ref device) => device.name() == name.as_slice(),
+                None => false,
+            };
+            if !same {
+                self.expansion = match expansion_from_name(name.as_slice()) {
+                    Some(device) => Some(device),
+                    None => { return Err(BadValue(format!("expansion device {}", name))); }
+                };
+            }
+            match self.expansion {
+                Some(ref mut device) => { device.load_state(r); }
+                None => { }
+            }
+        } else {
+            self.expansion = None;
+        }
+
+        self.console = if r.bool() { FamicomConsole } else { NesConsole };
+        self.microphone = r.bool();
+        self.polled = r.bool();
+        Ok(())
+    }
+}
+
+//adapter signatures are written the way games read them, first bit in bit 7, shift registers
+//here send bit 0 first
+pub fn signature_bits(signature: u8) -> u32 {
+    range(0u, 8).fold(0u32, |bits, bit| bits | (((signature >> (7 - bit)) & 0x01) as u32) << bit)
+}
+
+/// Builds a device by name for frontends: controller, zapper, fourscore, paddle or powerpad.
+/// The Four Score needs one half in each port.
+pub fn device_from_name(name: &str, port: uint) -> Option<Box<InputDevice>> {
+    match name {
+        "controller" => Some(box Controller::new() as Box<InputDevice>),
+        "zapper" => Some(box Zapper::new() as Box<InputDevice>),
+        "fourscore" => Some(box FourScore::new(port) as Box<InputDevice>),
+        "paddle" => Some(box VausPaddle::new() as Box<InputDevice>),
+        "powerpad" => Some(box PowerPad::new() as Box<InputDevice>),
+        _ => None,
+    }
+}
+
+/// Builds a Famicom expansion port device by name: hori or keyboard.
+pub fn expansion_from_name(name: &str) -> Option<Box<ExpansionDevice>> {
+    match name {
+        "hori" => Some(box HoriAdapter::new() as Box<ExpansionDevice>),
+        "keyboard" => Some(box FamilyKeyboard::new() as Box<ExpansionDevice>),
+        _ => None,
+    }
+}
+
+/// # Standard controller
+///
+/// from http://wiki.nesdev.com/w/index.php/Standard_controller
+///
+/// ## Output ($4016 write)
+///
+/// 7  bit  0
+/// ---- ----
+/// xxxx xxxS
+///         |
+///         +- Controller shift register strobe
+///
+/// While S is 1 the controllers keep reloading their shift registers from the buttons, so
+/// reads keep returning the state of A. Writing 0 latches the buttons and lets them be read
+/// out one bit at a time. The same strobe goes to both ports.
+///
+/// ## Input ($4016/$4017 read)
+///
+/// 7  bit  0
+/// ---- ----
+/// xxxx xxxD
+/// |||| ||||
+/// |||+-++++- Expansion port and Famicom microphone lines, 0 for a standard controller
+/// |||        (port 1 $4016, port 2 $4017)
+/// +++------- Open bus, the high byte of the address ($40)
+///            D: Serial controller data
+///
+/// Buttons come out in the order A, B, Select, Start, Up, Down, Left, Right. After the eighth
+/// read an official controller returns 1 until it's strobed again.
+
+pub static PORT_COUNT: uint = 2;
+
+//bits 5-7 of $4016/$4017 aren't driven and read back as the last value on the bus
+pub static OPEN_BUS: u8 = 0x40;
+
+bitflags!(
+    flags ButtonState: u8 {
+        static BUTTON_A         = 0b00000001,
+        static BUTTON_B         = 0b00000010,
+        static BUTTON_SELECT    = 0b00000100,
+        static BUTTON_START     = 0b00001000,
+        static BUTTON_UP        = 0b00010000,
+        static BUTTON_DOWN      = 0b00100000,
+        static BUTTON_LEFT      = 0b01000000,
+        static BUTTON_RIGHT     = 0b10000000
+    }
+)
+
+pub struct Controller {
+    buttons: ButtonState,
+    strobe: bool,
+    shift: u8,
+    reads: uint,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller {
+            buttons: ButtonState::empty(),
+            strobe: false,
+            shift: 0,
+            reads: 0,
+        }
+    }
+
+    pub fn buttons(&self) -> ButtonState {
+        self.buttons
+    }
+
+    fn reload(&mut self) {
+        self.shift = self.buttons.bits();
+        self.reads = 0;
+    }
+}
+
+impl InputDevice for Controller {
+    fn name(&self) -> &'static str {
+        "controller"
+    }
+
+    fn write_strobe(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe { self.reload(); }
+    }
+
+    //only bit 0 is driven
+    fn read(&mut self, _ppu: &Ppu) -> u8 {
+        if self.strobe {
+            return self.buttons.bits() & 0x01;
+        }
+
+        if self.reads >= 8 {
+            return 0x01;
+        }
+
+        let bit = self.shift & 0x01;
+        self.shift >>= 1;
+        self.reads += 1;
+        bit
+    }
+
+    //players 3 and 4 need a Four Score, a lone controller is only ever player 1 or 2
+    fn set_buttons(&mut self, index: uint, buttons: ButtonState) {
+        if index == 0 {
+            self.buttons = buttons;
+            if self.strobe { self.reload(); }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.buttons.bits());
+        w.bool(self.strobe);
+        w.u8(self.shift);
+        w.uint(self.reads);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.buttons = ButtonState::from_bits_truncate(r.u8());
+        self.strobe = r.bool();
+        self.shift = r.u8();
+        self.reads = r.uint();
+    }
+}
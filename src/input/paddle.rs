@@ -0,0 +1,81 @@
+use ppu::Ppu;
+use nes::state::{StateWriter, StateReader};
+
+use input::{InputDevice};
+
+/// # Arkanoid controller (Vaus)
+///
+/// from http://wiki.nesdev.com/w/index.php/Arkanoid_controller
+///
+/// NES version, on port 2:
+///
+/// 7  bit  0
+/// ---- ----
+/// xxxP FxxS
+///    | |
+///    | +---- Fire button (1: pressed)
+///    +------ Serial potentiometer data, inverted, most significant bit first
+///
+/// The strobe latches the knob position. Arkanoid's paddle works between roughly $62 and $F2,
+/// frontends set it with the x of set_pointer, 0 at the left end and 255 at the right.
+
+pub static PADDLE_MIN: u8 = 0x62;
+pub static PADDLE_MAX: u8 = 0xF2;
+
+pub struct VausPaddle {
+    position: u8,
+    fire: bool,
+    shift: u8,
+}
+
+impl VausPaddle {
+    pub fn new() -> VausPaddle {
+        VausPaddle {
+            position: PADDLE_MIN,
+            fire: false,
+            shift: 0,
+        }
+    }
+}
+
+impl InputDevice for VausPaddle {
+    fn name(&self) -> &'static str {
+        "paddle"
+    }
+
+    fn write_strobe(&mut self, val: u8) {
+        if val & 0x01 != 0 {
+            self.shift = !self.position;
+        }
+    }
+
+    fn read(&mut self, _ppu: &Ppu) -> u8 {
+        let data = if self.shift & 0x80 != 0 { 0x10 } else { 0x00 };
+        self.shift <<= 1;
+
+        let fire = if self.fire { 0x08 } else { 0x00 };
+        data | fire
+    }
+
+    fn set_pointer(&mut self, x: int, _y: int) {
+        let x = if x < 0 { 0 } else if x > 255 { 255 } else { x };
+        let range = (PADDLE_MAX - PADDLE_MIN) as int;
+        self.position = PADDLE_MIN + (x * range / 255) as u8;
+    }
+
+    fn set_trigger(&mut self, pressed: bool) {
+        self.fire = pressed;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.position);
+        w.bool(self.fire);
+        w.u8(self.shift);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.position = r.u8();
+        self.fire = r.bool();
+        self.shift = r.u8();
+    }
+}
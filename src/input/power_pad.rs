@@ -0,0 +1,103 @@
+use ppu::Ppu;
+use nes::state::{StateWriter, StateReader};
+
+use input::{InputDevice};
+
+/// # Power Pad
+///
+/// from http://wiki.nesdev.com/w/index.php/Power_Pad
+///
+/// 12 buttons read out over two serial lines after a strobe.
+///
+/// 7  bit  0
+/// ---- ----
+/// xxxH Lxxx
+///    | |
+///    | +---- Buttons 2, 1, 5, 9, 6, 10, 11, 7
+///    +------ Buttons 4, 3, 12, 8, then 1s
+///
+/// Side B numbering:
+///
+///  1  2  3  4
+///  5  6  7  8
+///  9 10 11 12
+
+static LOW_ORDER: [uint, ..8] = [2, 1, 5, 9, 6, 10, 11, 7];
+static HIGH_ORDER: [uint, ..4] = [4, 3, 12, 8];
+
+pub struct PowerPad {
+    pressed: u16,
+    strobe: bool,
+    low: u8,
+    high: u8,
+}
+
+impl PowerPad {
+    pub fn new() -> PowerPad {
+        PowerPad {
+            pressed: 0,
+            strobe: false,
+            low: 0,
+            high: 0,
+        }
+    }
+
+    fn is_pressed(&self, button: uint) -> bool {
+        self.pressed & (1 << (button - 1)) != 0
+    }
+
+    fn reload(&mut self) {
+        self.low = 0;
+        for (i, &button) in LOW_ORDER.iter().enumerate() {
+            if self.is_pressed(button) { self.low |= 1 << i; }
+        }
+
+        //the high line is padded with 1s after its four buttons
+        self.high = 0xF0;
+        for (i, &button) in HIGH_ORDER.iter().enumerate() {
+            if self.is_pressed(button) { self.high |= 1 << i; }
+        }
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn name(&self) -> &'static str {
+        "powerpad"
+    }
+
+    fn write_strobe(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe { self.reload(); }
+    }
+
+    fn read(&mut self, _ppu: &Ppu) -> u8 {
+        let low = if self.low & 0x01 != 0 { 0x08 } else { 0x00 };
+        let high = if self.high & 0x01 != 0 { 0x10 } else { 0x00 };
+
+        if !self.strobe {
+            self.low = (self.low >> 1) | 0x80;
+            self.high = (self.high >> 1) | 0x80;
+        }
+
+        low | high
+    }
+
+    fn set_pad(&mut self, pressed: u16) {
+        self.pressed = pressed & 0x0FFF;
+        if self.strobe { self.reload(); }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.pressed);
+        w.bool(self.strobe);
+        w.u8(self.low);
+        w.u8(self.high);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.pressed = r.u16() & 0x0FFF;
+        self.strobe = r.bool();
+        self.low = r.u8();
+        self.high = r.u8();
+    }
+}
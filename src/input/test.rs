@@ -0,0 +1,230 @@
+use nes::{CHR_ROM_BANK_SIZE};
+
+use ppu::Ppu;
+
+use input::{InputDevice, Controller, Zapper, FourScore, VausPaddle, PowerPad, ButtonState};
+use input::{InputPorts, ExpansionDevice, HoriAdapter, FamilyKeyboard, NesConsole, FamicomConsole};
+use input::keyboard::{key_index};
+use input::{BUTTON_A, BUTTON_B, BUTTON_START, BUTTON_RIGHT};
+use input::paddle::{PADDLE_MAX};
+
+fn get_ppu() -> Ppu {
+    Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]])
+}
+
+fn run_to_scanline(ppu: &mut Ppu, scanline: uint) {
+    while ppu.scanline() != scanline || ppu.dot() != 0 {
+        ppu.tick();
+    }
+}
+
+fn read_bits(device: &mut InputDevice, ppu: &Ppu, count: uint, mask: u8) -> Vec<u8> {
+    range(0u, count).map(|_| if device.read(ppu) & mask != 0 { 1 } else { 0 }).collect()
+}
+
+#[test]
+fn input_controller_shift_test() {
+    let ppu = get_ppu();
+    let mut controller = Controller::new();
+    controller.set_buttons(0, BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+
+    controller.write_strobe(0x01);
+    controller.write_strobe(0x00);
+    assert_eq!(read_bits(&mut controller, &ppu, 8, 0x01), vec![1, 0, 0, 1, 0, 0, 0, 1]);
+
+    //official controllers return 1 after the eighth read
+    assert_eq!(controller.read(&ppu), 0x01);
+    assert_eq!(controller.read(&ppu), 0x01);
+}
+
+#[test]
+fn input_controller_strobe_test() {
+    let ppu = get_ppu();
+    let mut controller = Controller::new();
+    controller.write_strobe(0x01);
+
+    //while strobe is high every read is the A button
+    assert_eq!(controller.read(&ppu), 0x00);
+    controller.set_buttons(0, BUTTON_A);
+    assert_eq!(controller.read(&ppu), 0x01);
+    assert_eq!(controller.read(&ppu), 0x01);
+
+    //buttons are latched when strobe goes low
+    controller.write_strobe(0x00);
+    controller.set_buttons(0, ButtonState::empty());
+    assert_eq!(read_bits(&mut controller, &ppu, 8, 0x01), vec![1, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn input_controller_players_test() {
+    let ppu = get_ppu();
+    let mut input = InputPorts::new();
+
+    //without a Four Score there's no player 3 to set
+    input.set_player_buttons(0, BUTTON_A);
+    input.set_player_buttons(2, BUTTON_B);
+
+    input.write(0x01);
+    input.write(0x00);
+    let port_1: Vec<u8> = range(0u, 8).map(|_| input.read(0, &ppu) & 0x01).collect();
+    assert_eq!(port_1, vec![1, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn input_zapper_test() {
+    let mut ppu = get_ppu();
+    let mut zapper = Zapper::new();
+    zapper.set_pointer(100, 50);
+
+    //black screen, no light
+    run_to_scanline(&mut ppu, 55);
+    assert_eq!(zapper.read(&ppu), 0x08);
+
+    //white backdrop, lit while the beam is just past the aimed spot
+    ppu.write_byte(0x3F00, 0x30);
+    run_to_scanline(&mut ppu, 50);
+    assert_eq!(zapper.read(&ppu), 0x08);
+    run_to_scanline(&mut ppu, 55);
+    assert_eq!(zapper.read(&ppu), 0x00);
+
+    run_to_scanline(&mut ppu, 100);
+    assert_eq!(zapper.read(&ppu), 0x08);
+
+    zapper.set_trigger(true);
+    assert_eq!(zapper.read(&ppu), 0x18);
+
+    zapper.set_pointer(-1, -1);
+    run_to_scanline(&mut ppu, 55);
+    assert_eq!(zapper.read(&ppu), 0x18);
+}
+
+#[test]
+fn input_four_score_test() {
+    let ppu = get_ppu();
+    let mut port_1 = FourScore::new(0);
+    let mut port_2 = FourScore::new(1);
+    port_1.set_buttons(0, BUTTON_A);
+    port_1.set_buttons(1, BUTTON_B);
+
+    port_1.write_strobe(0x01);
+    port_1.write_strobe(0x00);
+    port_2.write_strobe(0x01);
+    port_2.write_strobe(0x00);
+
+    let bits = read_bits(&mut port_1, &ppu, 24, 0x01);
+    assert_eq!(bits.slice(0, 8), [1, 0, 0, 0, 0, 0, 0, 0].as_slice());
+    assert_eq!(bits.slice(8, 16), [0, 1, 0, 0, 0, 0, 0, 0].as_slice());
+    assert_eq!(bits.slice(16, 24), [0, 0, 0, 1, 0, 0, 0, 0].as_slice());
+    assert_eq!(port_1.read(&ppu), 0x01);
+
+    let bits = read_bits(&mut port_2, &ppu, 24, 0x01);
+    assert_eq!(bits.slice(16, 24), [0, 0, 1, 0, 0, 0, 0, 0].as_slice());
+}
+
+#[test]
+fn input_paddle_test() {
+    let ppu = get_ppu();
+    let mut paddle = VausPaddle::new();
+    paddle.set_pointer(255, 0);
+    paddle.set_trigger(true);
+
+    paddle.write_strobe(0x01);
+    paddle.write_strobe(0x00);
+
+    //inverted, msb first
+    let bits = read_bits(&mut paddle, &ppu, 8, 0x10);
+    let position = bits.iter().fold(0u8, |val, &bit| (val << 1) | bit);
+    assert_eq!(!position, PADDLE_MAX);
+
+    assert_eq!(paddle.read(&ppu) & 0x08, 0x08);
+}
+
+#[test]
+fn input_power_pad_test() {
+    let ppu = get_ppu();
+    let mut pad = PowerPad::new();
+    pad.set_pad(1 << 0 | 1 << 3 | 1 << 11); //buttons 1, 4 and 12
+
+    pad.write_strobe(0x01);
+    pad.write_strobe(0x00);
+
+    //the high line reads 1 past its four buttons
+    let bits: Vec<u8> = range(0u, 8).map(|_| pad.read(&ppu) & 0x18).collect();
+    assert_eq!(bits, vec![0x10, 0x08, 0x10, 0x00, 0x10, 0x10, 0x10, 0x10]);
+}
+
+#[test]
+fn input_famicom_microphone_test() {
+    let ppu = get_ppu();
+    let mut input = InputPorts::new();
+    input.set_microphone(true);
+
+    //only Famicoms have the microphone
+    assert_eq!(input.read(0, &ppu) & 0x04, 0x00);
+
+    input.set_console(FamicomConsole);
+    assert_eq!(input.read(0, &ppu) & 0x04, 0x04);
+    assert_eq!(input.read(1, &ppu) & 0x04, 0x00);
+
+    input.set_console(NesConsole);
+    input.configure(0x23);
+    assert_eq!(input.console(), FamicomConsole);
+    assert!(input.expansion.is_some());
+}
+
+#[test]
+fn input_hori_adapter_test() {
+    let ppu = get_ppu();
+    let mut input = InputPorts::new();
+    input.set_console(FamicomConsole);
+    input.expansion = Some(box HoriAdapter::new() as Box<ExpansionDevice>);
+
+    input.set_player_buttons(0, BUTTON_B);
+    input.set_player_buttons(2, BUTTON_A); //player 3
+    input.set_player_buttons(3, BUTTON_START); //player 4
+
+    input.write(0x01);
+    input.write(0x00);
+
+    let port_1: Vec<u8> = range(0u, 24).map(|_| input.read(0, &ppu)).collect();
+    assert_eq!(port_1.slice(0, 3), [0x02, 0x01, 0x00].as_slice());
+    assert_eq!(port_1.slice(16, 24), [0, 0, 0x02, 0, 0, 0, 0, 0].as_slice());
+
+    let port_2: Vec<u8> = range(0u, 24).map(|_| input.read(1, &ppu) & 0x02).collect();
+    assert_eq!(port_2.slice(0, 8), [0, 0, 0, 0x02, 0, 0, 0, 0].as_slice());
+    assert_eq!(port_2.slice(16, 24), [0, 0, 0, 0x02, 0, 0, 0, 0].as_slice());
+}
+
+#[test]
+fn input_family_keyboard_test() {
+    let mut keyboard = FamilyKeyboard::new();
+    keyboard.set_key(key_index("RETURN").unwrap(), true);
+    keyboard.set_key(key_index("Q").unwrap(), true);
+
+    //disabled
+    keyboard.write(0x00);
+    assert_eq!(keyboard.read(1), 0x00);
+
+    //row 0, column 0: ] [ RETURN F8
+    keyboard.write(0x05);
+    keyboard.write(0x04);
+    assert_eq!(keyboard.read(1), 0x1E & !0x08);
+    assert_eq!(keyboard.read(0), 0x00);
+
+    //row 0, column 1, then on through row 7, column 0: CTR Q ESC F1
+    keyboard.write(0x06);
+    assert_eq!(keyboard.read(1), 0x1E);
+    for _ in range(0u, 6) {
+        keyboard.write(0x04);
+        keyboard.write(0x06);
+    }
+    keyboard.write(0x04);
+    assert_eq!(keyboard.read(1), 0x1E & !0x04);
+
+    //past the last row
+    for _ in range(0u, 2) {
+        keyboard.write(0x06);
+        keyboard.write(0x04);
+    }
+    assert_eq!(keyboard.read(1), 0x1E);
+}
@@ -0,0 +1,91 @@
+use ppu::{Ppu};
+use nes::state::{StateWriter, StateReader};
+
+use input::{InputDevice};
+
+/// # Zapper
+///
+/// from http://wiki.nesdev.com/w/index.php/Zapper
+///
+/// 7  bit  0
+/// ---- ----
+/// xxxT WxxS
+///    | |  |
+///    | |  +- Serial data, unused
+///    | +---- Light sensed at the current position (0: detected; 1: not detected)
+///    +------ Trigger (0: released or fully pulled; 1: half pulled)
+///
+/// The photodiode only reacts to the beam as it passes the spot the gun is aimed at, and stays
+/// lit for a little over 20 scanlines after that. Games wait for the frame they flashed white
+/// targets in and poll the port while it's being drawn. Here the light is sensed when the PPU
+/// has drawn the aimed pixel less than LIGHT_SCANLINES scanlines ago and it's bright enough.
+
+static LIGHT_SCANLINES: uint = 20;
+
+//sum of the color components, a bit above the system palette's mid grays
+static LIGHT_THRESHOLD: uint = 0x80 * 3;
+
+pub struct Zapper {
+    x: int,
+    y: int,
+    trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper {
+            x: -1,
+            y: -1,
+            trigger: false,
+        }
+    }
+
+    fn senses_light(&self, ppu: &Ppu) -> bool {
+        if self.x < 0 || self.y < 0 { return false; }
+
+        let (x, y) = (self.x as uint, self.y as uint);
+        let scanline = ppu.scanline();
+        let drawn = scanline > y || (scanline == y && ppu.dot() > x + 1);
+        if !drawn || scanline >= y + LIGHT_SCANLINES {
+            return false;
+        }
+
+        ppu.brightness(x, y) >= LIGHT_THRESHOLD
+    }
+}
+
+impl InputDevice for Zapper {
+    fn name(&self) -> &'static str {
+        "zapper"
+    }
+
+    fn write_strobe(&mut self, _val: u8) {
+    }
+
+    fn read(&mut self, ppu: &Ppu) -> u8 {
+        let light = if self.senses_light(ppu) { 0x00 } else { 0x08 };
+        let trigger = if self.trigger { 0x10 } else { 0x00 };
+        light | trigger
+    }
+
+    fn set_pointer(&mut self, x: int, y: int) {
+        self.x = x;
+        self.y = y;
+    }
+
+    fn set_trigger(&mut self, pressed: bool) {
+        self.trigger = pressed;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.x as u32);
+        w.u32(self.y as u32);
+        w.bool(self.trigger);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.x = r.u32() as i32 as int;
+        self.y = r.u32() as i32 as int;
+        self.trigger = r.bool();
+    }
+}
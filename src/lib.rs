@@ -2,12 +2,51 @@
 #![feature(phase, macro_rules, globs)]
 
 #[phase(plugin, link)] extern crate log;
+extern crate flate;
 
-pub use nes::{Nes};
+pub use nes::{Nes, FrameResult, Region, Ntsc, Pal, Dendy, STATE_SLOTS};
+pub use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+pub use nes::state::{StateError, StateResult};
+pub use nes::blargg::{run_test_rom, TestRomResult, TestRomStatus, TestRomPassed, TestRomFailed, TestRomTimedOut};
+pub use nes::golden::{render_frames, compare_png, compare_hash};
+pub use nes::golden::{GoldenError, GoldenResult, GoldenMissing, SizeMismatch, PixelMismatch, HashMismatch};
+pub use cpu::disasm::{Disassembly, disassemble, disassemble_all};
+pub use cpu::isa::{Instruction, Instr, AddressMode};
+pub use cpu::CpuState;
+pub use debugger::{Debugger, Breakpoint, BreakpointKind, ExecBreak, WatchBreak, OpcodeBreak};
+pub use debugger::{StopReason, BreakpointHit, WatchpointHit, StepDone, StepLimit};
+pub use debugger::{Access, AccessKind, ReadAccess, WriteAccess, ExecAccess, Bus, CpuBus, PpuBus};
+pub use debugger::condition::Condition;
+pub use debugger::command::{Command, Quit, parse_command, parse_command_with, execute_command};
+pub use debugger::gdb::{GdbStub, GdbAction, GdbReply, GdbContinue, GdbDetach};
+pub use debugger::gdb::serve_gdb;
+pub use debugger::trace::{TraceLogger, TraceFormat, NestestFormat, CompactFormat};
+pub use debugger::cdl::{CodeDataLog, CDL_CODE, CDL_DATA, CDL_BANK_MASK, CDL_INDIRECT_CODE, CDL_INDIRECT_DATA};
+pub use debugger::cdl::{CDL_PCM, CDL_CHR_RENDERED, CDL_CHR_READ};
+pub use debugger::symbols::{Symbols, Symbol, SourceLine};
+pub use debugger::export::{AsmStyle, Ca65Style, Asm6Style};
+pub use debugger::callstack::{CallStack, CallFrame, CallKind, JsrCall, NmiCall, IrqCall, BrkCall};
+pub use debugger::callstack::{Return, Returned, RtsJump, Untracked};
+pub use debugger::profiler::{Profiler, RoutineProfile};
+pub use apu::{PcmFormat, Wav, RawPcm};
+pub use nsf::{Nsf, NsfPlayer};
+pub use movie::{Movie, MovieFrame, MovieSession, MovieMode, Recording, Playing, Finished};
+pub use movie::checkpoint::{Checkpoints, Divergence};
+pub use movie::script::{InputScript};
+pub use input::{InputDevice, Controller, Zapper, FourScore, VausPaddle, PowerPad, device_from_name};
+pub use input::{ExpansionDevice, HoriAdapter, FamilyKeyboard, ConsoleType, NesConsole, FamicomConsole};
+pub use input::{ButtonState, BUTTON_A, BUTTON_B, BUTTON_SELECT, BUTTON_START};
+pub use input::{BUTTON_UP, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT};
 
 mod nes;
 mod cpu;
 mod ppu;
+mod apu;
+mod mapper;
+mod nsf;
+mod input;
+mod movie;
+mod debugger;
 
 #[cfg(test)]
 mod test {
@@ -1,22 +1,341 @@
+extern crate getopts;
+extern crate sdl2;
 extern crate rustnes;
 
-use rustnes::Nes;
+use rustnes::{Nes, NsfPlayer, Wav, RawPcm, Region, Movie, Checkpoints, SCREEN_WIDTH, SCREEN_HEIGHT};
+use rustnes::{Debugger, Quit, parse_command_with, execute_command, serve_gdb};
+use rustnes::{ButtonState, BUTTON_A, BUTTON_B, BUTTON_SELECT, BUTTON_START};
+use rustnes::{BUTTON_UP, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT};
 
+use getopts::{optopt, optflag, optmulti, getopts, usage};
+
+use sdl2::video::{Window, PosCentered, OPENGL};
+use sdl2::render::{Renderer, DriverAuto, ACCELERATED, AccessStreaming};
+use sdl2::pixels::{RGB24};
+use sdl2::event::{poll_event, QuitEvent, KeyDownEvent, KeyUpEvent, NoEvent};
+use sdl2::keycode::{KeyCode, EscapeKey, ReturnKey, RShiftKey, ZKey, XKey};
+use sdl2::keycode::{UpKey, DownKey, LeftKey, RightKey, F5Key, F7Key, BackspaceKey};
+use sdl2::keycode::{Num0Key, Num1Key, Num2Key, Num3Key, Num4Key, Num5Key, Num6Key, Num7Key, Num8Key, Num9Key};
+use sdl2::timer::{get_ticks, delay};
+
+use std::io;
 use std::os;
 
+static DEFAULT_RECORD_FRAMES: uint = 60 * 60; //one minute
+
+static WINDOW_SCALE: uint = 2;
+static FRAME_MS: uint = 1000 / 60;
+
+//rewind keeps a state every other frame, in up to 32 MB
+static REWIND_MEGABYTES: uint = 32;
+static REWIND_INTERVAL: uint = 2;
+
 fn main() {
     let args: Vec<String> = os::args();
 
-    let filename = 
-        if args.len() > 1 { 
-            args[0].as_slice() 
-        } else {
-            "mario.nes"
+    let opts = [
+        optopt("", "wav", "run headless and record the APU output to a WAV file", "FILE"),
+        optopt("", "pcm", "run headless and record the APU output as raw 16 bit PCM", "FILE"),
+        optopt("", "frames", "number of frames to run, or to record (default 3600)", "N"),
+        optflag("", "stems", "also record every APU channel to its own file"),
+        optopt("", "track", "NSF track to record, 1 based, in NSFe playlist order (default: the file's starting song)", "N"),
+        optopt("", "seconds", "NSF track length, overrides the NSFe time chunk", "N"),
+        optopt("", "fade", "NSF fade out length in seconds, overrides the NSFe fade chunk", "N"),
+        optopt("", "region", "override the ROM's region: ntsc, pal or dendy", "REGION"),
+        optopt("", "movie", "play an FM2 movie", "FILE"),
+        optopt("", "write-checkpoints", "play the movie and write the state hashes of every frame", "FILE"),
+        optopt("", "verify-checkpoints", "play the movie and compare every frame against a checkpoint file", "FILE"),
+        optflag("", "debug", "start stopped at the reset vector with a debugger prompt on stdin"),
+        optmulti("", "symbols", "names for the debugger, an ld65 .dbg or FCEUX .nl file", "FILE"),
+        optopt("", "gdb", "start stopped and wait for a GDB remote protocol client on a local port", "PORT"),
+        optflag("h", "help", "print this help"),
+    ];
+
+    let matches = match getopts(args.tail(), opts) {
+        Ok(m) => { m }
+        Err(f) => { fail!(f.to_string()) }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("{}", usage(format!("Usage: {} [options] ROM", args[0]).as_slice(), opts));
+        if !matches.opt_present("h") { os::set_exit_status(1); }
+        return;
+    }
+
+    let path = Path::new(matches.free[0].as_slice());
+
+    let record = match (matches.opt_str("wav"), matches.opt_str("pcm")) {
+        (Some(file), _) => Some((file, Wav)),
+        (None, Some(file)) => Some((file, RawPcm)),
+        (None, None) => None,
+    };
+
+    let is_nsf = match path.extension_str() {
+        Some(extension) => extension == "nsf" || extension == "nsfe",
+        None => false,
+    };
+
+    if is_nsf {
+        let (file, format) = record.expect("NSF files can only be recorded, use --wav or --pcm");
+
+        let mut player = NsfPlayer::new(path);
+        let track = match matches.opt_str("track") {
+            Some(n) => {
+                let playlist = player.nsf().playlist();
+                match from_str::<uint>(n.as_slice()) {
+                    Some(n) if n >= 1 && n <= playlist.len() => playlist[n - 1],
+                    _ => { fail!("--track must be 1 to {}", playlist.len()) }
+                }
+            }
+            None => player.track(),
         };
 
-    let path = Path::new(filename);
+        let (mut length, mut fade) = player.nsf().track_length(track);
+        match matches.opt_str("seconds") {
+            Some(n) => { length = from_str::<uint>(n.as_slice()).expect("--seconds must be a number") * 1000; }
+            None => { }
+        }
+        match matches.opt_str("fade") {
+            Some(n) => { fade = from_str::<uint>(n.as_slice()).expect("--fade must be a number") * 1000; }
+            None => { }
+        }
+
+        match player.render_track(track, length, fade, &Path::new(file), format) {
+            Ok(()) => { }
+            Err(e) => { fail!("Recording failed: {}", e) }
+        }
+        return;
+    }
+
     let mut nes = Nes::new(path);
+    match matches.opt_str("region") {
+        Some(name) => {
+            nes.set_region(Region::from_name(name.as_slice()).expect("--region must be ntsc, pal or dendy"));
+        }
+        None => { }
+    }
     nes.reset();
 
-    nes.run();
+    match matches.opt_str("movie") {
+        Some(file) => {
+            let movie = Movie::open(&Path::new(file)).expect("Couldn't load the movie");
+
+            let write = matches.opt_str("write-checkpoints");
+            let verify = matches.opt_str("verify-checkpoints");
+            if write.is_none() && verify.is_none() {
+                nes.play_movie(movie).unwrap();
+            } else {
+                let checkpoints = nes.movie_checkpoints(movie).unwrap();
+                match write {
+                    Some(file) => { checkpoints.save(&Path::new(file)).unwrap(); }
+                    None => { }
+                }
+                match verify {
+                    Some(file) => {
+                        let expected = Checkpoints::open(&Path::new(file)).expect("Couldn't load the checkpoints");
+                        match expected.compare(&checkpoints) {
+                            Some(divergence) => { fail!("Movie diverged at {}", divergence); }
+                            None => { println!("{} frames match", checkpoints.len()); }
+                        }
+                    }
+                    None => { }
+                }
+                return;
+            }
+        }
+        None => { }
+    }
+
+    for file in matches.opt_strs("symbols").iter() {
+        match nes.load_symbols(&Path::new(file.as_slice())) {
+            Ok(_) => { }
+            Err(e) => { fail!("Couldn't load {}: {}", file, e) }
+        }
+    }
+
+    if matches.opt_present("debug") {
+        debug(&mut nes);
+        return;
+    }
+
+    match matches.opt_str("gdb") {
+        Some(port) => {
+            let port = from_str::<u16>(port.as_slice()).expect("--gdb must be a port number");
+            println!("Waiting for gdb on port {}", port);
+            match serve_gdb(&mut nes, port) {
+                Ok(()) => { }
+                Err(e) => { fail!("GDB session failed: {}", e) }
+            }
+            return;
+        }
+        None => { }
+    }
+
+    match record {
+        Some((file, format)) => {
+            let frames = match matches.opt_str("frames") {
+                Some(n) => from_str::<uint>(n.as_slice()).expect("--frames must be a number"),
+                None => DEFAULT_RECORD_FRAMES,
+            };
+
+            match nes.record_audio(frames, &Path::new(file), format, matches.opt_present("stems")) {
+                Ok(()) => { }
+                Err(e) => { fail!("Recording failed: {}", e) }
+            }
+        }
+        None => {
+            match matches.opt_str("frames") {
+                Some(n) => {
+                    let frames = from_str::<uint>(n.as_slice()).expect("--frames must be a number");
+                    for _ in range(0, frames) { nes.run_frame(); }
+                }
+                None => { play(&mut nes); }
+            }
+        }
+    }
+}
+
+/// # Keys
+///
+/// - Arrows - D-pad
+/// - Z, X - B, A
+/// - Right shift, Return - Select, Start
+/// - 0-9 - Pick the save state slot
+/// - F5, F7 - Save to and load from the slot
+/// - Backspace - Rewind while held
+/// - Escape - Quit
+fn play(nes: &mut Nes) {
+    sdl2::init(sdl2::INIT_VIDEO);
+
+    let (width, height) = (SCREEN_WIDTH * WINDOW_SCALE, SCREEN_HEIGHT * WINDOW_SCALE);
+    let window = match Window::new("rustnes", PosCentered, PosCentered, width as int, height as int, OPENGL) {
+        Ok(window) => window,
+        Err(e) => { fail!("Couldn't open a window: {}", e) }
+    };
+    let renderer = match Renderer::from_window(window, DriverAuto, ACCELERATED) {
+        Ok(renderer) => renderer,
+        Err(e) => { fail!("Couldn't create a renderer: {}", e) }
+    };
+    let texture = match renderer.create_texture(RGB24, AccessStreaming, SCREEN_WIDTH as int, SCREEN_HEIGHT as int) {
+        Ok(texture) => texture,
+        Err(e) => { fail!("Couldn't create a texture: {}", e) }
+    };
+
+    nes.enable_rewind(REWIND_MEGABYTES, REWIND_INTERVAL);
+
+    let mut buttons = ButtonState::empty();
+    let mut slot = 0u;
+    let mut rewinding = false;
+
+    'frames: loop {
+        let start = get_ticks();
+
+        loop {
+            match poll_event() {
+                QuitEvent(_) => { break 'frames; }
+                KeyDownEvent(_, _, EscapeKey, _, _) => { break 'frames; }
+                KeyDownEvent(_, _, F5Key, _, _) => {
+                    match nes.save_slot(slot) {
+                        Ok(()) => { println!("Saved slot {}", slot); }
+                        Err(e) => { println!("Couldn't save slot {}: {}", slot, e); }
+                    }
+                }
+                KeyDownEvent(_, _, F7Key, _, _) => {
+                    match nes.load_slot(slot) {
+                        Ok(()) => { println!("Loaded slot {}", slot); }
+                        Err(e) => { println!("Couldn't load slot {}: {}", slot, e); }
+                    }
+                }
+                KeyDownEvent(_, _, BackspaceKey, _, _) => { rewinding = true; }
+                KeyUpEvent(_, _, BackspaceKey, _, _) => { rewinding = false; }
+                KeyDownEvent(_, _, key, _, _) => {
+                    match slot_key(key) {
+                        Some(n) => { slot = n; }
+                        None => { buttons.insert(button_key(key)); }
+                    }
+                }
+                KeyUpEvent(_, _, key, _, _) => { buttons.remove(button_key(key)); }
+                NoEvent => { break; }
+                _ => { }
+            }
+        }
+
+        //the oldest kept frame stays on screen once the buffer runs out
+        if rewinding {
+            nes.rewind_frame();
+        } else {
+            nes.set_buttons(0, buttons);
+            nes.run_frame();
+        }
+
+        let _ = texture.update(None, nes.framebuffer_rgb().as_slice(), (SCREEN_WIDTH * 3) as int);
+        let _ = renderer.clear();
+        let _ = renderer.copy(&texture, None, None);
+        renderer.present();
+
+        let elapsed = get_ticks() - start;
+        if elapsed < FRAME_MS { delay(FRAME_MS - elapsed); }
+    }
+
+    sdl2::quit();
+}
+
+fn button_key(key: KeyCode) -> ButtonState {
+    match key {
+        UpKey => BUTTON_UP,
+        DownKey => BUTTON_DOWN,
+        LeftKey => BUTTON_LEFT,
+        RightKey => BUTTON_RIGHT,
+        ZKey => BUTTON_B,
+        XKey => BUTTON_A,
+        RShiftKey => BUTTON_SELECT,
+        ReturnKey => BUTTON_START,
+        _ => ButtonState::empty(),
+    }
+}
+
+fn slot_key(key: KeyCode) -> Option<uint> {
+    match key {
+        Num0Key => Some(0),
+        Num1Key => Some(1),
+        Num2Key => Some(2),
+        Num3Key => Some(3),
+        Num4Key => Some(4),
+        Num5Key => Some(5),
+        Num6Key => Some(6),
+        Num7Key => Some(7),
+        Num8Key => Some(8),
+        Num9Key => Some(9),
+        _ => None,
+    }
+}
+
+//debugger prompt, an empty line repeats the last command
+fn debug(nes: &mut Nes) {
+    nes.attach_debugger(Debugger::new());
+    println!("{}", nes.disassemble_pc());
+
+    let mut stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(rustnes) ");
+        io::stdio::flush();
+
+        let line = match stdin.read_line() {
+            Ok(line) => line,
+            Err(_) => { return; }
+        };
+        let line = if line.as_slice().trim().is_empty() { last.clone() } else { line.as_slice().trim().to_string() };
+        if line.is_empty() { continue; }
+
+        match parse_command_with(line.as_slice(), nes.symbols()) {
+            Ok(Quit) => { return; }
+            Ok(command) => {
+                let output = execute_command(nes, command);
+                if !output.is_empty() { println!("{}", output); }
+            }
+            Err(e) => { println!("{}", e); }
+        }
+        last = line;
+    }
 }
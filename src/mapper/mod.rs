@@ -0,0 +1,170 @@
+use nes::{PrgRom, PRG_ROM_BANK_SIZE, PRG_RAM_BANK_SIZE};
+use nes::{VAddr};
+use nes::state::{StateWriter, StateReader};
+
+use apu::{ExpansionAudio};
+use apu::expansion::{Vrc6, Vrc7, Namco163, Sunsoft5b, Mmc5Audio, FdsAudio};
+
+#[cfg(test)]
+mod test;
+
+/// # Mappers
+///
+/// from http://wiki.nesdev.com/w/index.php/Mapper
+///
+/// Everything on the CPU bus from $4020 up lives on the cartridge, so the CPU hands those
+/// reads and writes to the cartridge's mapper. This covers expansion ROM ($4020-$5FFF),
+/// SRAM ($6000-$7FFF) and PRG-ROM ($8000-$FFFF), along with any bank switching registers
+/// the board decodes in that range.
+pub trait Mapper {
+    fn prg_read(&mut self, virtual_address: VAddr) -> u8;
+    fn prg_write(&mut self, virtual_address: VAddr, val: u8);
+
+    //what prg_read would return, without any of its side effects, for debuggers and test
+    //harnesses
+    fn prg_peek(&self, virtual_address: VAddr) -> u8;
+
+    //changes what's at an address without bank switching or any other side effect of a write,
+    //including ROM so cheats and patches work. False where there's nothing to change
+    fn prg_poke(&mut self, virtual_address: VAddr, val: u8) -> bool;
+
+    //where an address is in the PRG-ROM image, None for anything that isn't PRG-ROM
+    fn prg_rom_offset(&self, virtual_address: VAddr) -> Option<uint>;
+
+    //bank and control registers by name, most of them can't be read back through the bus
+    fn registers(&self) -> Vec<(String, u8)> {
+        Vec::new()
+    }
+
+    //sound chips on the cartridge, handed to the APU when the CPU is built
+    fn audio_chips(&self) -> Vec<Box<ExpansionAudio>> {
+        Vec::new()
+    }
+
+    //RAM and bank registers for save states, ROM is never saved
+    fn save_state(&self, _writer: &mut StateWriter) {
+    }
+
+    fn load_state(&mut self, _reader: &mut StateReader) {
+    }
+}
+
+/// Builds the mapper for an iNES mapper number. Boards that aren't emulated yet run as NROM,
+/// but still get their sound chip.
+pub fn new_mapper(mapper_number: u8, prg_rom: PrgRom) -> Box<Mapper> {
+    if mapper_number != 0 && board_audio_chips(mapper_number).is_empty() {
+        error!("Mapper {} isn't supported, running it as NROM", mapper_number);
+    }
+    box Nrom::for_board(prg_rom, mapper_number) as Box<Mapper>
+}
+
+/// The sound chips on a board, by iNES mapper number
+///
+/// - 5 - MMC5
+/// - 19 - Namco 163
+/// - 20 - Famicom Disk System
+/// - 24 - VRC6a
+/// - 26 - VRC6b
+/// - 69 - Sunsoft FME-7/5B
+/// - 85 - VRC7
+pub fn board_audio_chips(mapper_number: u8) -> Vec<Box<ExpansionAudio>> {
+    match mapper_number {
+        5 => vec![box Mmc5Audio::new() as Box<ExpansionAudio>],
+        19 => vec![box Namco163::new() as Box<ExpansionAudio>],
+        20 => vec![box FdsAudio::new() as Box<ExpansionAudio>],
+        24 => vec![box Vrc6::new(false) as Box<ExpansionAudio>],
+        26 => vec![box Vrc6::new(true) as Box<ExpansionAudio>],
+        69 => vec![box Sunsoft5b::new() as Box<ExpansionAudio>],
+        85 => vec![box Vrc7::new() as Box<ExpansionAudio>],
+        _ => Vec::new(),
+    }
+}
+
+/// # NROM (mapper 0)
+///
+/// - $6000-$7FFF - PRG-RAM
+/// - $8000-$BFFF - First 16 KB of PRG-ROM
+/// - $C000-$FFFF - Last 16 KB of PRG-ROM, or a mirror of $8000-$BFFF for NROM-128
+pub struct Nrom {
+    prg_rom: PrgRom,
+    prg_ram: [u8, ..PRG_RAM_BANK_SIZE],
+
+    //the board this stands in for, see new_mapper
+    mapper_number: u8,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: PrgRom) -> Nrom {
+        Nrom::for_board(prg_rom, 0)
+    }
+
+    pub fn for_board(prg_rom: PrgRom, mapper_number: u8) -> Nrom {
+        Nrom {
+            prg_rom: prg_rom,
+            prg_ram: [0u8, ..PRG_RAM_BANK_SIZE],
+            mapper_number: mapper_number,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn prg_read(&mut self, virtual_address: VAddr) -> u8 {
+        self.prg_peek(virtual_address)
+    }
+
+    fn prg_peek(&self, virtual_address: VAddr) -> u8 {
+        if virtual_address < 0x6000 {
+            //TODO Expansion ROM
+            0x00
+        } else if virtual_address < 0x8000 {
+            self.prg_ram[(virtual_address & 0x1FFF) as uint]
+        } else {
+            let bank = ((virtual_address - 0x8000) as uint / PRG_ROM_BANK_SIZE) % self.prg_rom.len();
+            let address = (virtual_address & 0x3FFF) as uint;
+            self.prg_rom[bank][address]
+        }
+    }
+
+    fn prg_write(&mut self, virtual_address: VAddr, val: u8) {
+        if virtual_address < 0x6000 {
+            //TODO Expansion ROM
+        } else if virtual_address < 0x8000 {
+            self.prg_ram[(virtual_address & 0x1FFF) as uint] = val;
+        } else {
+            error!("Can't write to PRG-ROM");
+        }
+    }
+
+    fn prg_poke(&mut self, virtual_address: VAddr, val: u8) -> bool {
+        if virtual_address < 0x6000 {
+            false
+        } else if virtual_address < 0x8000 {
+            self.prg_ram[(virtual_address & 0x1FFF) as uint] = val;
+            true
+        } else {
+            let bank = ((virtual_address - 0x8000) as uint / PRG_ROM_BANK_SIZE) % self.prg_rom.len();
+            let address = (virtual_address & 0x3FFF) as uint;
+            self.prg_rom.get_mut(bank)[address] = val;
+            true
+        }
+    }
+
+    fn prg_rom_offset(&self, virtual_address: VAddr) -> Option<uint> {
+        if virtual_address < 0x8000 { return None; }
+
+        let bank = ((virtual_address - 0x8000) as uint / PRG_ROM_BANK_SIZE) % self.prg_rom.len();
+        Some(bank * PRG_ROM_BANK_SIZE + (virtual_address & 0x3FFF) as uint)
+    }
+
+    fn audio_chips(&self) -> Vec<Box<ExpansionAudio>> {
+        board_audio_chips(self.mapper_number)
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(self.prg_ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) {
+        reader.read_into(self.prg_ram);
+    }
+}
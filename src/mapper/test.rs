@@ -0,0 +1,65 @@
+use mapper::{Mapper, Nrom, new_mapper};
+
+use nes::PRG_ROM_BANK_SIZE;
+
+#[test]
+fn mapper_nrom_128_mirror_test() {
+    let mut prg_rom_bank = prg_rom_bank!(0xC5);
+    prg_rom_bank[0x0000] = 0xAA;
+    prg_rom_bank[0x3FFF] = 0xBB;
+
+    let mut nrom = Nrom::new(prg_rom!(prg_rom_bank));
+
+    assert_eq!(nrom.prg_read(0x8000), 0xAA);
+    assert_eq!(nrom.prg_read(0xBFFF), 0xBB);
+    assert_eq!(nrom.prg_read(0xC000), 0xAA);
+    assert_eq!(nrom.prg_read(0xFFFF), 0xBB);
+}
+
+#[test]
+fn mapper_nrom_256_test() {
+    let mut nrom = Nrom::new(prg_rom!(prg_rom_bank!(0xAA), prg_rom_bank!(0xBB)));
+
+    assert_eq!(nrom.prg_read(0x8000), 0xAA);
+    assert_eq!(nrom.prg_read(0xC000), 0xBB);
+
+    //writes to PRG-ROM are ignored
+    nrom.prg_write(0x8000, 0x00);
+    assert_eq!(nrom.prg_read(0x8000), 0xAA);
+}
+
+#[test]
+fn mapper_nrom_prg_ram_test() {
+    let mut nrom = Nrom::new(prg_rom!());
+
+    assert_eq!(nrom.prg_read(0x6000), 0x00);
+    nrom.prg_write(0x6000, 0xAA);
+    nrom.prg_write(0x7FFF, 0xBB);
+    assert_eq!(nrom.prg_read(0x6000), 0xAA);
+    assert_eq!(nrom.prg_read(0x7FFF), 0xBB);
+}
+
+#[test]
+fn mapper_nrom_poke_test() {
+    let mut nrom = Nrom::new(prg_rom!(prg_rom_bank!(0xAA)));
+
+    //pokes reach PRG-ROM, mirrors included
+    assert!(nrom.prg_poke(0xC000, 0x11));
+    assert_eq!(nrom.prg_peek(0x8000), 0x11);
+    assert!(nrom.prg_poke(0x7FFF, 0x22));
+    assert_eq!(nrom.prg_peek(0x7FFF), 0x22);
+    assert!(!nrom.prg_poke(0x5000, 0x33));
+    assert!(nrom.registers().is_empty());
+}
+
+#[test]
+fn mapper_board_audio_test() {
+    assert!(Nrom::new(prg_rom!()).audio_chips().is_empty());
+
+    let names = [(5u8, "mmc5"), (19, "n163"), (24, "vrc6"), (26, "vrc6"), (69, "s5b"), (85, "vrc7")];
+    for &(number, name) in names.iter() {
+        let chips = new_mapper(number, prg_rom!()).audio_chips();
+        assert_eq!(chips.len(), 1);
+        assert_eq!(chips[0].name(), name);
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::io::{File, IoResult};
+use std::num::{from_str_radix};
+
+use nes::state::{StateHashes};
+
+/// # Checkpoints
+///
+/// Hashes of every part of the console after each frame of a movie. Playing the movie again,
+/// on another build or another machine, and comparing the hashes shows whether emulation is
+/// still deterministic, and if it isn't, the first frame and the first part that went wrong.
+///
+/// The file is text, one frame per line after the header:
+///
+///     rustnes checkpoints 1
+///     1 CpuState=8E1F0C6A3D2B7701 ram=... VRam=... mapper=...
+///
+/// See Nes::state_hashes for the parts.
+
+static HEADER: &'static str = "rustnes checkpoints 1";
+
+pub struct Checkpoint {
+    pub frame: uint,
+    pub hashes: Vec<(String, u64)>,
+}
+
+pub struct Checkpoints {
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+/// Where two runs first differ.
+#[deriving(PartialEq)]
+pub struct Divergence {
+    pub frame: uint,
+    pub component: String,
+
+    /// None when a run stops short, or doesn't have the component
+    pub expected: Option<u64>,
+    pub actual: Option<u64>,
+}
+
+impl fmt::Show for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.expected, self.actual) {
+            (Some(expected), Some(actual)) => {
+                write!(f, "frame {}: {} differs, expected {:016X} but got {:016X}",
+                       self.frame, self.component, expected, actual)
+            }
+            (Some(_), None) => write!(f, "frame {}: {} is missing", self.frame, self.component),
+            (None, _) => write!(f, "frame {}: {} wasn't expected", self.frame, self.component),
+        }
+    }
+}
+
+impl Checkpoints {
+    pub fn new() -> Checkpoints {
+        Checkpoints {
+            checkpoints: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.checkpoints.len()
+    }
+
+    pub fn push(&mut self, frame: uint, hashes: StateHashes) {
+        self.checkpoints.push(Checkpoint {
+            frame: frame,
+            hashes: hashes.move_iter().map(|(name, hash)| (name.to_string(), hash)).collect(),
+        });
+    }
+
+    /// Compares a run against these checkpoints, the expected ones.
+    pub fn compare(&self, actual: &Checkpoints) -> Option<Divergence> {
+        for (expected, actual) in self.checkpoints.iter().zip(actual.checkpoints.iter()) {
+            match expected.compare(actual) {
+                Some(divergence) => { return Some(divergence); }
+                None => { }
+            }
+        }
+
+        if self.len() > actual.len() {
+            return Some(Divergence {
+                frame: self.checkpoints[actual.len()].frame,
+                component: "frame".to_string(),
+                expected: Some(self.checkpoints[actual.len()].frame as u64),
+                actual: None,
+            });
+        } else if self.len() < actual.len() {
+            return Some(Divergence {
+                frame: actual.checkpoints[self.len()].frame,
+                component: "frame".to_string(),
+                expected: None,
+                actual: Some(actual.checkpoints[self.len()].frame as u64),
+            });
+        }
+
+        None
+    }
+
+    pub fn parse(text: &str) -> Option<Checkpoints> {
+        let mut lines = text.lines();
+        if lines.next().map(|line| line.trim_right_chars('\r')) != Some(HEADER) {
+            error!("Not a checkpoint file");
+            return None;
+        }
+
+        let mut checkpoints = Checkpoints::new();
+        for line in lines {
+            let line = line.trim_right_chars('\r');
+            if line.is_empty() { continue; }
+
+            let mut fields = line.split(' ');
+            let frame = match fields.next().and_then(|frame| from_str::<uint>(frame)) {
+                Some(frame) => frame,
+                None => {
+                    error!("Bad checkpoint line: {}", line);
+                    return None;
+                }
+            };
+
+            let mut hashes = Vec::new();
+            for field in fields {
+                let hash = match field.find('=') {
+                    Some(i) => from_str_radix::<u64>(field.slice_from(i + 1), 16).map(|hash| (field.slice_to(i).to_string(), hash)),
+                    None => None,
+                };
+                match hash {
+                    Some(hash) => { hashes.push(hash); }
+                    None => {
+                        error!("Bad checkpoint hash on frame {}: {}", frame, field);
+                        return None;
+                    }
+                }
+            }
+
+            checkpoints.checkpoints.push(Checkpoint {
+                frame: frame,
+                hashes: hashes,
+            });
+        }
+
+        Some(checkpoints)
+    }
+
+    pub fn write(&self) -> String {
+        let mut text = String::new();
+        text.push_str(HEADER);
+        text.push_char('\n');
+
+        for checkpoint in self.checkpoints.iter() {
+            text.push_str(checkpoint.frame.to_string().as_slice());
+            for &(ref name, hash) in checkpoint.hashes.iter() {
+                text.push_str(format!(" {}={:016X}", name, hash).as_slice());
+            }
+            text.push_char('\n');
+        }
+
+        text
+    }
+
+    pub fn open(path: &Path) -> Option<Checkpoints> {
+        match File::open(path).read_to_string() {
+            Ok(text) => Checkpoints::parse(text.as_slice()),
+            Err(e) => {
+                error!("Can't read {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut file = try!(File::create(path));
+        file.write_str(self.write().as_slice())
+    }
+}
+
+impl Checkpoint {
+    fn compare(&self, actual: &Checkpoint) -> Option<Divergence> {
+        for &(ref name, expected) in self.hashes.iter() {
+            let hash = actual.hashes.iter().find(|&&(ref other, _)| other == name).map(|&(_, hash)| hash);
+            if hash != Some(expected) {
+                return Some(Divergence {
+                    frame: self.frame,
+                    component: name.clone(),
+                    expected: Some(expected),
+                    actual: hash,
+                });
+            }
+        }
+
+        //components the expected run didn't have
+        for &(ref name, hash) in actual.hashes.iter() {
+            if !self.hashes.iter().any(|&(ref other, _)| other == name) {
+                return Some(Divergence {
+                    frame: self.frame,
+                    component: name.clone(),
+                    expected: None,
+                    actual: Some(hash),
+                });
+            }
+        }
+
+        None
+    }
+}
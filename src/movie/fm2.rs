@@ -0,0 +1,220 @@
+use std::num::{from_str_radix};
+
+use movie::{Movie, MovieFrame, MovieCommands, MAX_PLAYERS};
+
+use input::{ButtonState, BUTTON_A, BUTTON_B, BUTTON_SELECT, BUTTON_START};
+use input::{BUTTON_UP, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT};
+
+/// # FM2
+///
+/// from http://www.fceux.com/web/help/fceux.html?fm2.html
+///
+/// A text file, a header of `key value` lines followed by one line per frame:
+///
+///     version 3
+///     emuVersion 22020
+///     rerecordCount 12
+///     palFlag 0
+///     romFilename mario
+///     romChecksum base64:...
+///     guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B
+///     fourscore 0
+///     port0 1
+///     port1 1
+///     port2 0
+///     comment author someone
+///     |0|R..U...A|........||
+///
+/// The first field of a frame holds the commands (1 soft reset, 2 power, 4 FDS disk insert,
+/// 8 FDS side select, 16 VS coin), then one field per controller and one for the Famicom
+/// expansion port. A controller field is RLDUTSBA, with '.' or ' ' for buttons that aren't
+/// held. With fourscore set there are four controller fields, otherwise port0 and port1 say
+/// what's plugged in, 0 for nothing and 1 for a gamepad.
+///
+/// Binary movies, and ports with anything other than gamepads, aren't supported.
+
+static VERSION: uint = 3;
+
+static SI_NONE: uint = 0;
+static SI_GAMEPAD: uint = 1;
+
+static NO_GUID: &'static str = "00000000-0000-0000-0000-000000000000";
+
+//in the order the letters appear, RLDUTSBA
+static BUTTON_CHARS: [(char, ButtonState), ..8] = [
+    ('R', BUTTON_RIGHT),
+    ('L', BUTTON_LEFT),
+    ('D', BUTTON_DOWN),
+    ('U', BUTTON_UP),
+    ('T', BUTTON_START),
+    ('S', BUTTON_SELECT),
+    ('B', BUTTON_B),
+    ('A', BUTTON_A),
+];
+
+pub fn parse(text: &str) -> Option<Movie> {
+    let mut movie = Movie::new();
+
+    for line in text.lines() {
+        let line = line.trim_right_chars('\r');
+
+        if line.starts_with("|") {
+            let players = if movie.four_score { MAX_PLAYERS } else { 2 };
+            match parse_frame(line, players, movie.gamepads) {
+                Some(frame) => { movie.frames.push(frame); }
+                None => {
+                    error!("Bad FM2 frame {}: {}", movie.frames.len(), line);
+                    return None;
+                }
+            }
+            continue;
+        }
+
+        let (key, value) = match line.find(' ') {
+            Some(i) => (line.slice_to(i), line.slice_from(i + 1)),
+            None => (line, ""),
+        };
+
+        match key {
+            "version" => {
+                if from_str::<uint>(value) != Some(VERSION) {
+                    error!("Unsupported FM2 version: {}", value);
+                    return None;
+                }
+            }
+            "binary" => {
+                if value != "0" {
+                    error!("Binary FM2 movies aren't supported");
+                    return None;
+                }
+            }
+            "rerecordCount" => { movie.rerecord_count = from_str(value).unwrap_or(0); }
+            "palFlag" => { movie.pal = value == "1"; }
+            "romFilename" => { movie.rom_filename = value.to_string(); }
+            "romChecksum" => { movie.rom_checksum = Some(value.to_string()); }
+            "guid" => { movie.guid = Some(value.to_string()); }
+            "fourscore" => { movie.four_score = value == "1"; }
+            "port0" | "port1" => {
+                let port = if key == "port0" { 0 } else { 1 };
+                let device = from_str(value).unwrap_or(SI_NONE);
+                movie.gamepads[port] = device == SI_GAMEPAD;
+                if device != SI_NONE && device != SI_GAMEPAD {
+                    error!("Unsupported FM2 {} device: {}", key, value);
+                    return None;
+                }
+            }
+            "comment" => { movie.comments.push(value.to_string()); }
+            "savestate" => {
+                match decode_hex(value) {
+                    Some(state) => { movie.start_state = Some(state); }
+                    None => {
+                        error!("Unsupported FM2 savestate, only hex states of ours load");
+                        return None;
+                    }
+                }
+            }
+            //emuVersion, microphone, port2, FDS, NewPPU, subtitle and anything newer
+            _ => { }
+        }
+    }
+
+    Some(movie)
+}
+
+fn parse_frame(line: &str, players: uint, gamepads: [bool, ..2]) -> Option<MovieFrame> {
+    let fields: Vec<&str> = line.split('|').collect();
+    //the line starts and ends with '|'
+    if fields.len() < players + 3 { return None; }
+
+    let mut frame = MovieFrame::new();
+    frame.commands = MovieCommands::from_bits_truncate(match from_str::<u8>(fields[1]) {
+        Some(commands) => commands,
+        None => { return None; }
+    });
+
+    for player in range(0, players) {
+        let field = fields[player + 2];
+        if players == 2 && !gamepads[player] { continue; }
+        frame.buttons[player] = match parse_buttons(field) {
+            Some(buttons) => buttons,
+            None => { return None; }
+        };
+    }
+
+    Some(frame)
+}
+
+fn parse_buttons(field: &str) -> Option<ButtonState> {
+    if field.char_len() != BUTTON_CHARS.len() { return None; }
+
+    let mut buttons = ButtonState::empty();
+    for (c, &(_, button)) in field.chars().zip(BUTTON_CHARS.iter()) {
+        if c != '.' && c != ' ' { buttons.insert(button); }
+    }
+    Some(buttons)
+}
+
+pub fn write(movie: &Movie) -> String {
+    let mut text = String::new();
+
+    text.push_str(format!("version {}\n", VERSION).as_slice());
+    text.push_str("emuVersion 0\n");
+    text.push_str(format!("rerecordCount {}\n", movie.rerecord_count).as_slice());
+    text.push_str(format!("palFlag {}\n", if movie.pal { 1u } else { 0 }).as_slice());
+    text.push_str(format!("romFilename {}\n", movie.rom_filename).as_slice());
+    match movie.rom_checksum {
+        Some(ref checksum) => { text.push_str(format!("romChecksum {}\n", checksum).as_slice()); }
+        None => { }
+    }
+    text.push_str(format!("guid {}\n", movie.guid.as_ref().map_or(NO_GUID, |guid| guid.as_slice())).as_slice());
+    text.push_str(format!("fourscore {}\n", if movie.four_score { 1u } else { 0 }).as_slice());
+    text.push_str("microphone 0\n");
+    let ports: Vec<uint> = movie.gamepads.iter()
+        .map(|&gamepad| if gamepad && !movie.four_score { SI_GAMEPAD } else { SI_NONE })
+        .collect();
+    text.push_str(format!("port0 {}\nport1 {}\nport2 0\n", ports[0], ports[1]).as_slice());
+    text.push_str("FDS 0\nNewPPU 0\n");
+    for comment in movie.comments.iter() {
+        text.push_str(format!("comment {}\n", comment).as_slice());
+    }
+    match movie.start_state {
+        Some(ref state) => { text.push_str(format!("savestate 0x{}\n", encode_hex(state.as_slice())).as_slice()); }
+        None => { }
+    }
+
+    for frame in movie.frames.iter() {
+        text.push_str(format!("|{}|", frame.commands.bits()).as_slice());
+        for player in range(0, movie.players()) {
+            for &(c, button) in BUTTON_CHARS.iter() {
+                text.push_char(if frame.buttons[player].contains(button) { c } else { '.' });
+            }
+            text.push_char('|');
+        }
+        text.push_str("|\n");
+    }
+
+    text
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes.iter() {
+        hex.push_str(format!("{:02X}", *byte).as_slice());
+    }
+    hex
+}
+
+//FCEUX also writes base64: states, those are its own format so there's no point decoding them
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.starts_with("0x") || text.len() % 2 != 0 { return None; }
+
+    let hex = text.slice_from(2);
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for i in range(0, hex.len() / 2) {
+        match from_str_radix::<u8>(hex.slice(i * 2, i * 2 + 2), 16) {
+            Some(byte) => { bytes.push(byte); }
+            None => { return None; }
+        }
+    }
+    Some(bytes)
+}
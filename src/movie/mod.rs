@@ -0,0 +1,179 @@
+use std::io::{File, IoResult};
+
+use input::{ButtonState};
+
+pub mod fm2;
+pub mod checkpoint;
+pub mod script;
+
+#[cfg(test)]
+mod test;
+
+/// # Movies
+///
+/// A movie is the controller input for every frame from a starting point, either power on or
+/// a save state. Played back on the same ROM it reproduces the run exactly, which makes it a
+/// bug report or a regression test.
+///
+/// Movies are kept in FCEUX's text format, see the fm2 module. The starting save state is one
+/// of ours, FCEUX can't load it and we can't load FCEUX's.
+
+pub static MAX_PLAYERS: uint = 4;
+
+bitflags!(
+    flags MovieCommands: u8 {
+        static SOFT_RESET   = 0b00000001,
+        static POWER        = 0b00000010,
+        static FDS_INSERT   = 0b00000100, //ignored
+        static FDS_SELECT   = 0b00001000, //ignored
+        static VS_COIN      = 0b00010000  //ignored
+    }
+)
+
+#[deriving(Clone, PartialEq)]
+pub struct MovieFrame {
+    /// Applied before the frame runs
+    pub commands: MovieCommands,
+
+    /// Players 1 to 4, see Nes::set_player_buttons
+    pub buttons: [ButtonState, ..MAX_PLAYERS],
+}
+
+impl MovieFrame {
+    pub fn new() -> MovieFrame {
+        MovieFrame {
+            commands: MovieCommands::empty(),
+            buttons: [ButtonState::empty(), ..MAX_PLAYERS],
+        }
+    }
+}
+
+#[deriving(Clone)]
+pub struct Movie {
+    pub rom_filename: String,
+    pub rom_checksum: Option<String>, //kept from imported movies, we don't compute it
+    pub guid: Option<String>,
+    pub pal: bool,
+    pub four_score: bool,
+    /// Without a Four Score, whether each port has a gamepad in it, it's empty otherwise
+    pub gamepads: [bool, ..2],
+    pub rerecord_count: uint,
+    pub comments: Vec<String>,
+
+    /// Starts from power on when there's no state
+    pub start_state: Option<Vec<u8>>,
+
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new() -> Movie {
+        Movie {
+            rom_filename: String::new(),
+            rom_checksum: None,
+            guid: None,
+            pal: false,
+            four_score: false,
+            gamepads: [true, true],
+            rerecord_count: 0,
+            comments: Vec::new(),
+            start_state: None,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.frames.len()
+    }
+
+    //players 3 and 4 only exist with a Four Score
+    pub fn players(&self) -> uint {
+        if self.four_score { 4 } else { 2 }
+    }
+
+    pub fn open(path: &Path) -> Option<Movie> {
+        let text = match File::open(path).read_to_string() {
+            Ok(text) => text,
+            Err(e) => {
+                error!("Can't read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        fm2::parse(text.as_slice())
+    }
+
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut file = try!(File::create(path));
+        file.write_str(fm2::write(self).as_slice())
+    }
+}
+
+#[deriving(PartialEq, Show)]
+pub enum MovieMode {
+    Recording,
+    Playing,
+    Finished,
+}
+
+/// A movie being recorded or played on a console, see Nes::start_recording and
+/// Nes::play_movie.
+pub struct MovieSession {
+    pub movie: Movie,
+    pub mode: MovieMode,
+    pos: uint,
+    commands: MovieCommands,
+}
+
+impl MovieSession {
+    pub fn recording(movie: Movie) -> MovieSession {
+        MovieSession {
+            movie: movie,
+            mode: Recording,
+            pos: 0,
+            commands: MovieCommands::empty(),
+        }
+    }
+
+    pub fn playing(movie: Movie) -> MovieSession {
+        let mode = if movie.frames.is_empty() { Finished } else { Playing };
+        MovieSession {
+            movie: movie,
+            mode: mode,
+            pos: 0,
+            commands: MovieCommands::empty(),
+        }
+    }
+
+    //frames recorded or played so far
+    pub fn pos(&self) -> uint {
+        self.pos
+    }
+
+    //recorded with the next frame
+    pub fn add_commands(&mut self, commands: MovieCommands) {
+        if self.mode == Recording { self.commands.insert(commands); }
+    }
+
+    /// Called before each frame. While recording it stores the frame's input, while playing
+    /// it returns the frame to apply.
+    pub fn next_frame(&mut self, buttons: [ButtonState, ..MAX_PLAYERS]) -> Option<MovieFrame> {
+        match self.mode {
+            Recording => {
+                self.movie.frames.push(MovieFrame {
+                    commands: self.commands,
+                    buttons: buttons,
+                });
+                self.commands = MovieCommands::empty();
+                self.pos += 1;
+                None
+            }
+            Playing => {
+                let frame = self.movie.frames[self.pos].clone();
+                self.pos += 1;
+                if self.pos == self.movie.frames.len() { self.mode = Finished; }
+                Some(frame)
+            }
+            Finished => None,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::ascii::StrAsciiExt;
+use std::io::{File};
+
+use nes::Nes;
+
+use movie::{MAX_PLAYERS};
+
+use input::{ButtonState, BUTTON_A, BUTTON_B, BUTTON_SELECT, BUTTON_START};
+use input::{BUTTON_UP, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT};
+
+/// # Input scripts
+///
+/// A hand written alternative to a movie for tests, one change of input per line:
+///
+///     # frame  buttons        player (1 to 4, default 1)
+///     60       start
+///     62       none
+///     120      right+b
+///     180      a              2
+///
+/// Buttons are held from their frame until the player's next line. Frames count from the
+/// first frame run with the script, so frame 0 is the first.
+
+static BUTTON_NAMES: [(&'static str, ButtonState), ..8] = [
+    ("a", BUTTON_A),
+    ("b", BUTTON_B),
+    ("select", BUTTON_SELECT),
+    ("start", BUTTON_START),
+    ("up", BUTTON_UP),
+    ("down", BUTTON_DOWN),
+    ("left", BUTTON_LEFT),
+    ("right", BUTTON_RIGHT),
+];
+
+pub struct InputEvent {
+    pub frame: uint,
+    pub player: uint,
+    pub buttons: ButtonState,
+}
+
+pub struct InputScript {
+    pub events: Vec<InputEvent>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Option<InputScript> {
+        let mut events = Vec::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(i) => line.slice_to(i),
+                None => line,
+            };
+            let fields: Vec<&str> = line.words().collect();
+            if fields.is_empty() { continue; }
+
+            let event = match fields.len() {
+                2 => InputScript::parse_event(fields[0], fields[1], "1"),
+                3 => InputScript::parse_event(fields[0], fields[1], fields[2]),
+                _ => None,
+            };
+
+            match event {
+                Some(event) => { events.push(event); }
+                None => {
+                    error!("Bad input script line {}: {}", n + 1, line);
+                    return None;
+                }
+            }
+        }
+
+        //stable, so lines for the same frame keep their order
+        events.sort_by(|a, b| a.frame.cmp(&b.frame));
+
+        Some(InputScript {
+            events: events,
+        })
+    }
+
+    pub fn open(path: &Path) -> Option<InputScript> {
+        match File::open(path).read_to_string() {
+            Ok(text) => InputScript::parse(text.as_slice()),
+            Err(e) => {
+                error!("Can't read {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn parse_event(frame: &str, buttons: &str, player: &str) -> Option<InputEvent> {
+        let frame = match from_str::<uint>(frame) { Some(frame) => frame, None => return None };
+        let player = match from_str::<uint>(player) {
+            Some(player) if player >= 1 && player <= MAX_PLAYERS => player - 1,
+            _ => return None,
+        };
+
+        let mut state = ButtonState::empty();
+        if buttons != "none" {
+            for name in buttons.split('+') {
+                let name = name.to_ascii_lower();
+                match BUTTON_NAMES.iter().find(|&&(button_name, _)| button_name == name.as_slice()) {
+                    Some(&(_, button)) => { state.insert(button); }
+                    None => { return None; }
+                }
+            }
+        }
+
+        Some(InputEvent {
+            frame: frame,
+            player: player,
+            buttons: state,
+        })
+    }
+
+    /// Sets the buttons that change on `frame`, call it before running each frame.
+    pub fn apply(&self, nes: &mut Nes, frame: uint) {
+        for event in self.events.iter().filter(|event| event.frame == frame) {
+            nes.set_player_buttons(event.player, event.buttons);
+        }
+    }
+
+    //the last frame with a change, the script has nothing more to do after it
+    pub fn last_frame(&self) -> uint {
+        self.events.last().map_or(0, |event| event.frame)
+    }
+}
@@ -0,0 +1,257 @@
+use movie::{Movie, MovieFrame, MovieSession, Recording, Playing, Finished, SOFT_RESET, POWER};
+use movie::fm2;
+use movie::checkpoint::{Checkpoints, Divergence};
+use movie::script::{InputScript};
+
+use nes::Nes;
+use nes::test::get_test_rom;
+
+use input::{ButtonState, BUTTON_A, BUTTON_B, BUTTON_START, BUTTON_RIGHT, BUTTON_UP};
+use input::{InputDevice, FourScore};
+
+static FM2_TEXT: &'static str = "version 3\r
+emuVersion 22020\r
+rerecordCount 7\r
+palFlag 0\r
+romFilename mario\r
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\r
+guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\r
+fourscore 0\r
+port0 1\r
+port1 0\r
+port2 0\r
+comment author someone\r
+|2|........|||\r
+|0|R..U...A|||\r
+|1|...T....|||\r
+";
+
+//strobes the controllers, then adds the A button of player 1 to $00, forever
+static MOVIE_TEST_PROGRAM: [u8, ..23] = [
+    0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16,
+    0x40, 0x29, 0x01, 0x18, 0x65, 0x00, 0x85, 0x00, 0x4C, 0x00, 0x80,
+];
+
+#[test]
+fn movie_fm2_parse_test() {
+    let movie = fm2::parse(FM2_TEXT).unwrap();
+
+    assert_eq!(movie.rom_filename.as_slice(), "mario");
+    assert_eq!(movie.rerecord_count, 7);
+    assert_eq!(movie.pal, false);
+    assert_eq!(movie.four_score, false);
+    assert_eq!(movie.comments, vec!["author someone".to_string()]);
+    assert!(movie.start_state.is_none());
+    assert_eq!(movie.len(), 3);
+
+    assert!(movie.frames[0].commands == POWER);
+    assert!(movie.frames[1].buttons[0] == BUTTON_RIGHT | BUTTON_UP | BUTTON_A);
+    assert!(movie.frames[1].buttons[1] == ButtonState::empty());
+    assert!(movie.frames[2].commands == SOFT_RESET);
+    assert!(movie.frames[2].buttons[0] == BUTTON_START);
+
+    assert!(fm2::parse("version 3\n|0|RL|........||\n").is_none());
+    assert!(fm2::parse("version 3\nport0 2\n").is_none());
+    assert!(fm2::parse("version 2\n").is_none());
+}
+
+#[test]
+fn movie_fm2_write_test() {
+    let mut movie = fm2::parse(FM2_TEXT).unwrap();
+    movie.start_state = Some(vec![0x00, 0xAB, 0xFF]);
+    movie.four_score = true;
+    movie.frames.get_mut(1).buttons[3] = BUTTON_A;
+
+    let text = fm2::write(&movie);
+    assert!(text.as_slice().contains("savestate 0x00ABFF\n"));
+    assert!(text.as_slice().contains("|0|R..U...A|........|........|.......A||\n"));
+
+    let parsed = fm2::parse(text.as_slice()).unwrap();
+    assert_eq!(parsed.start_state, movie.start_state);
+    assert_eq!(parsed.rom_checksum, movie.rom_checksum);
+    assert!(parsed.frames == movie.frames);
+}
+
+#[test]
+fn movie_session_test() {
+    let none = [ButtonState::empty(), ..4];
+
+    let mut session = MovieSession::recording(Movie::new());
+    session.add_commands(SOFT_RESET);
+    assert!(session.next_frame([BUTTON_A, ButtonState::empty(), ButtonState::empty(), ButtonState::empty()]).is_none());
+    assert!(session.next_frame(none).is_none());
+    assert_eq!(session.mode, Recording);
+    assert_eq!(session.movie.len(), 2);
+    assert!(session.movie.frames[0].commands == SOFT_RESET);
+    assert!(session.movie.frames[1].commands.is_empty());
+
+    let mut session = MovieSession::playing(session.movie);
+    assert_eq!(session.mode, Playing);
+    assert!(session.next_frame(none).unwrap().buttons[0] == BUTTON_A);
+    assert!(session.next_frame(none).is_some());
+    assert_eq!(session.mode, Finished);
+    assert!(session.next_frame(none).is_none());
+    assert_eq!(session.pos(), 2);
+}
+
+#[test]
+fn movie_playback_test() {
+    let rom = get_test_rom(&MOVIE_TEST_PROGRAM);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    nes.run_frame();
+
+    nes.start_recording(true);
+    assert_eq!(nes.frame_count(), 0);
+    for frame in range(0u, 20) {
+        nes.set_buttons(0, if frame % 3 == 0 { BUTTON_A } else { ButtonState::empty() });
+        nes.run_frame();
+    }
+    let recorded = nes.save_state();
+    let movie = nes.stop_movie().unwrap();
+    assert_eq!(movie.len(), 20);
+
+    //through FM2 and back, on a console that's been running with other input
+    let movie = fm2::parse(fm2::write(&movie).as_slice()).unwrap();
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    nes.set_buttons(0, BUTTON_A);
+    for _ in range(0u, 5) { nes.run_frame(); }
+
+    nes.play_movie(movie.clone()).unwrap();
+    for _ in range(0u, 20) { nes.run_frame(); }
+    assert_eq!(nes.movie().unwrap().mode, Finished);
+    assert!(nes.save_state() == recorded);
+
+    //different input ends up somewhere else
+    let mut idle = movie.clone();
+    for frame in idle.frames.mut_iter() {
+        frame.buttons[0] = ButtonState::empty();
+    }
+    nes.play_movie(idle).unwrap();
+    for _ in range(0u, 20) { nes.run_frame(); }
+    assert!(nes.save_state() != recorded);
+}
+
+#[test]
+fn movie_four_player_test() {
+    let rom = get_test_rom(&MOVIE_TEST_PROGRAM);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    for port in range(0u, 2) {
+        nes.set_input_device(port, box FourScore::new(port) as Box<InputDevice>);
+    }
+    nes.reset();
+
+    nes.start_recording(true);
+    for frame in range(0u, 10) {
+        for player in range(0u, 4) {
+            let held = (frame + player) % 4 == 0;
+            nes.set_player_buttons(player, if held { BUTTON_A } else { ButtonState::empty() });
+        }
+        nes.run_frame();
+    }
+    let recorded = nes.save_state();
+    let movie = nes.stop_movie().unwrap();
+    assert!(movie.four_score);
+    assert_eq!(movie.players(), 4);
+    assert!(movie.frames[3].buttons[1] == BUTTON_A);
+    assert!(movie.frames[2].buttons[2] == BUTTON_A);
+    assert!(movie.frames[1].buttons[3] == BUTTON_A);
+
+    let parsed = fm2::parse(fm2::write(&movie).as_slice()).unwrap();
+    assert!(parsed.four_score);
+    assert!(parsed.frames == movie.frames);
+
+    //playing it plugs in the Four Score, players 3 and 4 are part of its state
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    nes.play_movie(parsed).unwrap();
+    assert_eq!(nes.input_device(1).name(), "fourscore");
+    for _ in range(0u, 10) { nes.run_frame(); }
+    assert!(nes.save_state() == recorded);
+}
+
+fn movie_with_a_on(frames: &[uint]) -> Movie {
+    let mut movie = Movie::new();
+    for frame in range(0u, 20) {
+        let mut movie_frame = MovieFrame::new();
+        if frames.contains(&frame) { movie_frame.buttons[0] = BUTTON_A; }
+        movie.frames.push(movie_frame);
+    }
+    movie
+}
+
+#[test]
+fn movie_checkpoint_test() {
+    let rom = get_test_rom(&MOVIE_TEST_PROGRAM);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+
+    let expected = nes.movie_checkpoints(movie_with_a_on(&[2, 5])).unwrap();
+    assert_eq!(expected.len(), 20);
+    assert_eq!(expected.checkpoints[0].frame, 1);
+    let (ref first, _) = expected.checkpoints[0].hashes[0];
+    assert_eq!(first.as_slice(), "CpuState");
+
+    //the same movie on a fresh console, through a checkpoint file
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    let actual = nes.movie_checkpoints(movie_with_a_on(&[2, 5])).unwrap();
+    let parsed = Checkpoints::parse(actual.write().as_slice()).unwrap();
+    assert_eq!(expected.compare(&parsed), None);
+
+    //an extra press on movie frame 9 shows up after the 10th frame
+    let actual = nes.movie_checkpoints(movie_with_a_on(&[2, 5, 9])).unwrap();
+    let divergence = expected.compare(&actual).unwrap();
+    assert_eq!(divergence.frame, 10);
+    assert!(divergence.component.as_slice() == "CpuState" || divergence.component.as_slice() == "ram");
+
+    let mut short = Checkpoints::parse(expected.write().as_slice()).unwrap();
+    short.checkpoints.pop();
+    assert_eq!(expected.compare(&short), Some(Divergence {
+        frame: 20,
+        component: "frame".to_string(),
+        expected: Some(20),
+        actual: None,
+    }));
+
+    assert!(Checkpoints::parse("rustnes checkpoints 1\n1 CpuState=XYZ\n").is_none());
+    assert!(Checkpoints::parse("checkpoints\n").is_none());
+}
+
+#[test]
+fn movie_input_script_test() {
+    let script = InputScript::parse("# frame buttons player\n\
+                                     120 right+B\n\
+                                     \n\
+                                     60 start  # press start\n\
+                                     62 none\n\
+                                     120 A 2\n").unwrap();
+
+    assert_eq!(script.events.len(), 4);
+    assert_eq!(script.events[0].frame, 60);
+    assert!(script.events[0].buttons == BUTTON_START);
+    assert!(script.events[1].buttons.is_empty());
+    assert!(script.events[2].buttons == BUTTON_RIGHT | BUTTON_B);
+    assert_eq!(script.events[3].player, 1);
+    assert_eq!(script.last_frame(), 120);
+
+    assert!(InputScript::parse("60 jump\n").is_none());
+    assert!(InputScript::parse("60 a 5\n").is_none());
+    assert!(InputScript::parse("soon a\n").is_none());
+
+    //scripted input drives the console like set_buttons would
+    let rom = get_test_rom(&MOVIE_TEST_PROGRAM);
+    let script = InputScript::parse("2 a\n3 none\n").unwrap();
+    let mut scripted = Nes::from_bytes(rom.as_slice());
+    let mut manual = Nes::from_bytes(rom.as_slice());
+    scripted.reset();
+    manual.reset();
+    for frame in range(0u, 5) {
+        script.apply(&mut scripted, frame);
+        scripted.run_frame();
+
+        if frame == 2 { manual.set_buttons(0, BUTTON_A); }
+        if frame == 3 { manual.set_buttons(0, ButtonState::empty()); }
+        manual.run_frame();
+    }
+    assert!(scripted.ram() == manual.ram());
+}
@@ -0,0 +1,105 @@
+use nes::{Nes, VAddr};
+
+/// # Test ROM status protocol
+///
+/// from http://wiki.nesdev.com/w/index.php/Emulator_tests
+///
+/// blargg's newer test ROMs (instr_test-v5, ppu_vbl_nmi, apu_test, cpu_interrupts_v2, ...)
+/// report through PRG-RAM so they can be run without looking at the screen:
+///
+/// - $6000      - Status, $80 while running, $81 when the ROM wants the reset button pressed
+///                (at least 100 ms later), otherwise the final result, 0 for passed
+/// - $6001-6003 - DE B0 61, the status is only meaningful once these are written
+/// - $6004      - NUL terminated text, what the ROM printed on screen
+///
+/// A ROM that never writes the signature doesn't use the protocol.
+
+static STATUS_ADDR: VAddr = 0x6000;
+static SIGNATURE_ADDR: VAddr = 0x6001;
+static TEXT_ADDR: VAddr = 0x6004;
+static TEXT_END: VAddr = 0x8000;
+
+static SIGNATURE: [u8, ..3] = [0xDE, 0xB0, 0x61];
+
+static STATUS_RUNNING: u8 = 0x80;
+static STATUS_NEEDS_RESET: u8 = 0x81;
+
+//100 ms and a bit, at 50 Hz
+static RESET_DELAY_FRAMES: uint = 6;
+
+#[deriving(PartialEq, Show)]
+pub enum TestRomStatus {
+    TestRomPassed,
+    TestRomFailed(u8),
+
+    /// Still running, or never wrote the signature, when the frames ran out
+    TestRomTimedOut,
+}
+
+pub struct TestRomResult {
+    pub status: TestRomStatus,
+
+    /// What the ROM printed, empty without the signature
+    pub message: String,
+
+    pub frames: uint,
+}
+
+impl TestRomResult {
+    pub fn passed(&self) -> bool {
+        self.status == TestRomPassed
+    }
+}
+
+/// Runs a reset console until the ROM reports a final status or `max_frames` have passed,
+/// pressing reset whenever the ROM asks for it.
+pub fn run_test_rom(nes: &mut Nes, max_frames: uint) -> TestRomResult {
+    let mut reset_frame = None;
+
+    for frame in range(1, max_frames + 1) {
+        nes.run_frame();
+        if !has_signature(nes) { continue; }
+
+        let status = nes.peek(STATUS_ADDR);
+        if status == STATUS_RUNNING {
+            reset_frame = None;
+        } else if status == STATUS_NEEDS_RESET {
+            match reset_frame {
+                None => { reset_frame = Some(frame + RESET_DELAY_FRAMES); }
+                Some(reset) if frame >= reset => {
+                    nes.reset();
+                    reset_frame = None;
+                }
+                Some(_) => { }
+            }
+        } else {
+            return TestRomResult {
+                status: if status == 0 { TestRomPassed } else { TestRomFailed(status) },
+                message: read_message(nes),
+                frames: frame,
+            };
+        }
+    }
+
+    TestRomResult {
+        status: TestRomTimedOut,
+        message: if has_signature(nes) { read_message(nes) } else { String::new() },
+        frames: max_frames,
+    }
+}
+
+fn has_signature(nes: &Nes) -> bool {
+    range(0, SIGNATURE.len()).all(|i| nes.peek(SIGNATURE_ADDR + i as VAddr) == SIGNATURE[i])
+}
+
+fn read_message(nes: &Nes) -> String {
+    let mut bytes = Vec::new();
+    let mut addr = TEXT_ADDR;
+    while addr < TEXT_END {
+        let byte = nes.peek(addr);
+        if byte == 0 { break; }
+        bytes.push(byte);
+        addr += 1;
+    }
+    String::from_utf8_lossy(bytes.as_slice()).into_string()
+}
@@ -0,0 +1,88 @@
+/// # Master clock
+///
+/// from http://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+///
+/// The CPU and PPU are both fed from one crystal through their own dividers. On NTSC the
+/// master clock is 21.477272 MHz, the CPU divides it by 12 and the PPU by 4, so the PPU runs
+/// exactly 3 dots per CPU cycle. On PAL it's 26.601712 MHz divided by 16 and 5, 3.2 dots per
+/// cycle.
+///
+/// The CPU runs ahead a whole instruction at a time, then the PPU catches up to the same
+/// point on the master clock. An instruction that reads or writes a PPU register first
+/// catches the PPU up to the cycle of the access, so $2002 polling sees vblank start on the
+/// right dot. The APU is clocked along with the CPU, cycle by cycle.
+///
+/// The dividers for each region are in the region module.
+
+use nes::region::{Region, Ntsc};
+use nes::state::{StateWriter, StateReader};
+
+pub struct MasterClock {
+    cpu_divider: uint,
+    ppu_divider: uint,
+
+    //master clock cycles the CPU and PPU have each reached
+    cpu_clock: u64,
+    ppu_clock: u64,
+}
+
+impl MasterClock {
+    pub fn new(cpu_divider: uint, ppu_divider: uint) -> MasterClock {
+        MasterClock {
+            cpu_divider: cpu_divider,
+            ppu_divider: ppu_divider,
+            cpu_clock: 0,
+            ppu_clock: 0,
+        }
+    }
+
+    pub fn ntsc() -> MasterClock {
+        MasterClock::for_region(Ntsc)
+    }
+
+    pub fn for_region(region: Region) -> MasterClock {
+        MasterClock::new(region.cpu_divider(), region.ppu_divider())
+    }
+
+    //master clock cycles since power on
+    pub fn cycles(&self) -> u64 {
+        self.cpu_clock
+    }
+
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu_clock / self.cpu_divider as u64
+    }
+
+    /// Moves the CPU ahead and returns the number of PPU dots needed to catch up with it.
+    pub fn advance_cpu(&mut self, cycles: uint) -> uint {
+        self.cpu_clock += (cycles * self.cpu_divider) as u64;
+        let cpu_clock = self.cpu_clock;
+        self.ppu_dots_to(cpu_clock)
+    }
+
+    /// The PPU dots needed to catch up with the CPU `cycles` into the instruction it's
+    /// running. The CPU only moves on with advance_cpu, which then leaves out these dots.
+    pub fn sync_ppu(&mut self, cycles: uint) -> uint {
+        let target = self.cpu_clock + (cycles * self.cpu_divider) as u64;
+        self.ppu_dots_to(target)
+    }
+
+    fn ppu_dots_to(&mut self, target: u64) -> uint {
+        let mut dots = 0;
+        while self.ppu_clock + self.ppu_divider as u64 <= target {
+            self.ppu_clock += self.ppu_divider as u64;
+            dots += 1;
+        }
+        dots
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.cpu_clock);
+        w.u64(self.ppu_clock);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.cpu_clock = r.u64();
+        self.ppu_clock = r.u64();
+    }
+}
@@ -0,0 +1,123 @@
+use std::os;
+
+use nes::Nes;
+use nes::state;
+use ppu::png;
+use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use movie::script::InputScript;
+
+/// # Golden screenshots
+///
+/// Regression tests for the PPU: run a ROM for some frames, optionally with scripted input,
+/// and compare the screen against a PNG that was checked as correct by a person, either pixel
+/// for pixel or by the FNV-1a hash of the RGB bytes.
+///
+/// When the PNG comparison fails, three images are written next to the golden one:
+///
+/// - `<name>.actual.png`   - what was rendered
+/// - `<name>.expected.png` - the golden image, re-encoded
+/// - `<name>.diff.png`     - pixels that differ in red, the rest dimmed
+///
+/// Running the tests with RUSTNES_BLESS set writes missing goldens instead of failing. The
+/// goldens are only as good as whoever looked at them, so check them before committing.
+
+static BLESS_VAR: &'static str = "RUSTNES_BLESS";
+
+static DIFF_COLOR: [u8, ..3] = [0xFF, 0x00, 0x00];
+
+#[deriving(PartialEq, Show)]
+pub enum GoldenError {
+    /// The golden PNG doesn't exist or couldn't be decoded
+    GoldenMissing(String),
+
+    /// The golden PNG isn't 256x240, its width and height
+    SizeMismatch(uint, uint),
+
+    /// How many pixels differ, and the position of the first one
+    PixelMismatch(uint, (uint, uint)),
+
+    /// The expected and actual hash
+    HashMismatch(u64, u64),
+}
+
+pub type GoldenResult = Result<(), GoldenError>;
+
+/// Runs `frames` frames, applying the script before each one, and returns the screen in RGB.
+pub fn render_frames(nes: &mut Nes, frames: uint, script: Option<&InputScript>) -> Vec<u8> {
+    for frame in range(0, frames) {
+        match script {
+            Some(script) => { script.apply(nes, frame); }
+            None => { }
+        }
+        nes.run_frame();
+    }
+
+    nes.framebuffer_rgb()
+}
+
+pub fn hash(rgb: &[u8]) -> u64 {
+    state::hash(rgb)
+}
+
+pub fn compare_hash(rgb: &[u8], expected: u64) -> GoldenResult {
+    let actual = hash(rgb);
+    if actual == expected { Ok(()) } else { Err(HashMismatch(expected, actual)) }
+}
+
+/// Compares a 256x240 RGB screen against the golden PNG, writing the actual, expected and diff
+/// images when they differ.
+pub fn compare_png(rgb: &[u8], golden: &Path) -> GoldenResult {
+    let (width, height, expected) = match png::read(golden) {
+        Some(image) => image,
+        None => {
+            if os::getenv(BLESS_VAR).is_some() {
+                write_image(golden, rgb);
+                return Ok(());
+            }
+            write_image(&output_path(golden, "actual"), rgb);
+            return Err(GoldenMissing(golden.display().to_string()));
+        }
+    };
+
+    if width != SCREEN_WIDTH || height != SCREEN_HEIGHT {
+        write_image(&output_path(golden, "actual"), rgb);
+        return Err(SizeMismatch(width, height));
+    }
+
+    let mut diff = Vec::with_capacity(rgb.len());
+    let mut count = 0;
+    let mut first = None;
+
+    for (i, (actual, expected)) in rgb.chunks(3).zip(expected.as_slice().chunks(3)).enumerate() {
+        if actual == expected {
+            diff.push_all(&[actual[0] / 4, actual[1] / 4, actual[2] / 4]);
+        } else {
+            diff.push_all(&DIFF_COLOR);
+            count += 1;
+            if first.is_none() { first = Some((i % SCREEN_WIDTH, i / SCREEN_WIDTH)); }
+        }
+    }
+
+    match first {
+        Some(position) => {
+            write_image(&output_path(golden, "actual"), rgb);
+            write_image(&output_path(golden, "expected"), expected.as_slice());
+            write_image(&output_path(golden, "diff"), diff.as_slice());
+            Err(PixelMismatch(count, position))
+        }
+        None => Ok(()),
+    }
+}
+
+/// `golden.png` becomes `golden.<kind>.png`
+pub fn output_path(golden: &Path, kind: &str) -> Path {
+    let stem = golden.filestem_str().unwrap_or("golden");
+    golden.with_filename(format!("{}.{}.png", stem, kind))
+}
+
+fn write_image(path: &Path, rgb: &[u8]) {
+    match png::write(path, SCREEN_WIDTH, SCREEN_HEIGHT, rgb) {
+        Ok(()) => { }
+        Err(e) => { error!("Can't write {}: {}", path.display(), e); }
+    }
+}
@@ -1,17 +1,52 @@
 #![macro_escape]
 
-use std::io::File;
+use std::io::{File, BufReader, IoResult, IoError, InvalidInput};
 use std::mem;
 
-use cpu::Cpu;
+use cpu::{Cpu, CpuState};
+use cpu::disasm::Disassembly;
 
-use ppu::Ppu;
+use debugger::{Debugger, StopReason, BreakpointHit, WatchpointHit};
+use debugger::trace::TraceLogger;
+use debugger::cdl::CodeDataLog;
+use debugger::callstack::CallStack;
+use debugger::profiler::Profiler;
+use debugger::symbols::Symbols;
+use debugger::export;
+use debugger::export::AsmStyle;
+
+use mapper::{new_mapper};
+
+use ppu::{Ppu, SCREEN_WIDTH, SCREEN_HEIGHT};
+use ppu::png;
+
+use apu::{PcmWriter, PcmFormat, RawPcm};
+
+use self::clock::{MasterClock};
+
+use self::state::{StateWriter, StateReader, SaveState, StateResult, StateHashes};
+use self::state::{RomMismatch, BadValue, NoSlotPath, IoFailed};
+
+use self::rewind::{RewindBuffer};
+
+pub use self::region::{Region, Ntsc, Pal, Dendy};
+
+use input::{InputDevice, ExpansionDevice, ButtonState, ConsoleType, FamicomConsole, PORT_COUNT};
+use input::{Controller, FourScore, HoriAdapter};
+
+use movie::{Movie, MovieSession, MovieFrame, MovieCommands, MAX_PLAYERS, SOFT_RESET, POWER};
+use movie::checkpoint::{Checkpoints};
+
+pub mod clock;
+pub mod region;
+pub mod state;
+pub mod rewind;
+pub mod blargg;
+pub mod golden;
 
 #[cfg(test)]
 pub mod test;
 
-static CYCLES_PER_SCANLINE: int = 113;
-
 pub static PRG_ROM_BANK_SIZE: uint = 0x4000; //16 KB
 type PrgRomBank = [u8, ..PRG_ROM_BANK_SIZE];
 pub type PrgRom = Vec<PrgRomBank>;
@@ -20,11 +55,12 @@ pub static CHR_ROM_BANK_SIZE: uint = 0x2000; //8 KB
 type ChrRomBank = [u8, ..CHR_ROM_BANK_SIZE];
 pub type ChrRom = Vec<ChrRomBank>;
 
-//currently unused, not sure what it does
-static PRG_RAM_BANK_SIZE: uint = 0x2000; //8 KB
+pub static PRG_RAM_BANK_SIZE: uint = 0x2000; //8 KB
 type PrgRamBank = [u8, ..PRG_RAM_BANK_SIZE];
 type PrgRam = Vec<PrgRamBank>;
 
+pub static INES_HEADER_SIZE: uint = 0x10;
+
 //currently unused, not sure what it does
 static TRAINER_SIZE: uint = 512;
 type Trainer = [u8, ..TRAINER_SIZE];
@@ -32,8 +68,51 @@ type Trainer = [u8, ..TRAINER_SIZE];
 //VAddr represents an NES virtual address
 pub type VAddr = u16;
 
+//numbered save state slots, see Nes::save_slot
+pub static STATE_SLOTS: uint = 10;
+
+/// What a frame of emulation produced, see Nes::run_frame.
+pub struct FrameResult<'a> {
+    /// System palette indices, see ppu::SCREEN_WIDTH and ppu::SCREEN_HEIGHT
+    pub framebuffer: &'a [u8],
+
+    /// Mixed APU output since the last frame, at the APU's sample rate
+    pub samples: Vec<f32>,
+
+    /// Frames run since power on, counting this one
+    pub frame: uint,
+
+    /// Set when the game didn't read the controllers during the frame
+    pub lag: bool,
+
+    /// Why the debugger stopped, the frame isn't finished unless it stopped on the last
+    /// instruction. The next run_frame carries on from there.
+    pub stop: Option<StopReason>,
+}
+
 pub struct Nes {
-    rom_path: Path,
+    rom_path: Option<Path>,
+    rom: Vec<u8>,
+    rom_hash: u64,
+    prg_rom_len: uint,
+    chr_rom_len: uint,
+
+    region: Region,
+
+    frame_count: uint,
+    lag: bool,
+
+    rewind: Option<RewindBuffer>,
+
+    //the buttons last set for each player, for movie recording
+    buttons: [ButtonState, ..MAX_PLAYERS],
+    movie: Option<MovieSession>,
+
+    debugger: Option<Debugger>,
+    symbols: Symbols,
+
+    //set when the debugger stopped part way through a frame
+    mid_frame: bool,
 
     //components
     cpu: Cpu,
@@ -41,47 +120,966 @@ pub struct Nes {
 
 impl Nes {
     pub fn new(rom_path: Path) -> Nes {
+        Nes::open(rom_path).unwrap()
+    }
+
+    /// Loads an iNES file, an Err if it can't be read or isn't a whole iNES image.
+    pub fn open(rom_path: Path) -> IoResult<Nes> {
         info!("Rom Path: {}", rom_path.display());
-        
-        let (rom_header, prg_rom, chr_rom) = Nes::read_rom(&rom_path);
+
+        let bytes = try!(File::open(&rom_path).read_to_end());
+        if !Nes::is_ines(bytes.as_slice()) {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "not an iNES ROM",
+                detail: Some(rom_path.display().to_string()),
+            });
+        }
+
+        let mut nes = Nes::from_bytes(bytes.as_slice());
+        nes.rom_path = Some(rom_path);
+        Ok(nes)
+    }
+
+    //a valid header and all the banks it says there are
+    fn is_ines(bytes: &[u8]) -> bool {
+        if bytes.len() < INES_HEADER_SIZE { return false; }
+
+        let mut buf = [0u8, ..INES_HEADER_SIZE];
+        for (i, &byte) in bytes.slice_to(INES_HEADER_SIZE).iter().enumerate() { buf[i] = byte; }
+        match RomHeader::new(&buf) {
+            Some(header) => {
+                bytes.len() >= INES_HEADER_SIZE + header.prg_rom_count as uint * PRG_ROM_BANK_SIZE +
+                    header.chr_rom_count as uint * CHR_ROM_BANK_SIZE
+            }
+            None => false,
+        }
+    }
+
+    /// Loads an iNES image that's already in memory
+    pub fn from_bytes(bytes: &[u8]) -> Nes {
+        let mut reader = BufReader::new(bytes);
+        let mut nes = Nes::from_reader(&mut reader);
+        nes.rom = bytes.to_vec();
+        nes
+    }
+
+    fn from_reader<R: Reader>(reader: &mut R) -> Nes {
+        let (rom_header, prg_rom, chr_rom) = Nes::read_rom(reader);
+        let rom_hash = Nes::hash_rom(&prg_rom, &chr_rom);
+        let prg_rom_len = prg_rom.len() * PRG_ROM_BANK_SIZE;
+        let chr_rom_len = chr_rom.len() * CHR_ROM_BANK_SIZE;
 
         //TODO Get things like horizontal/vertical scrolling here
 
         let ppu = Ppu::new(chr_rom);
 
-        let cpu = Cpu::new(prg_rom, ppu);
+        let mapper = new_mapper(rom_header.mapper_number(), prg_rom);
 
-        Nes { 
-            rom_path: rom_path,
+        let mut cpu = Cpu::new(mapper, ppu);
 
-            cpu: cpu, 
+        match rom_header.default_expansion_device() {
+            Some(device) => { cpu.input.configure(device); }
+            None => { }
         }
+
+        let mut nes = Nes { 
+            rom_path: None,
+            rom: Vec::new(),
+            rom_hash: rom_hash,
+            prg_rom_len: prg_rom_len,
+            chr_rom_len: chr_rom_len,
+
+            region: Ntsc,
+
+            frame_count: 0,
+            lag: false,
+
+            rewind: None,
+
+            buttons: [ButtonState::empty(), ..MAX_PLAYERS],
+            movie: None,
+
+            debugger: None,
+            symbols: Symbols::new(),
+            mid_frame: false,
+
+            cpu: cpu, 
+        };
+
+        nes.set_region(rom_header.region());
+        nes
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Overrides the region picked from the ROM header. Best done before the first frame, the
+    /// PPU carries on from its current scanline.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.cpu.clock = MasterClock::for_region(region);
+        self.cpu.ppu.set_region(region);
+        self.cpu.apu.set_region(region);
     }
 
     pub fn reset(&mut self) {
         self.cpu.reset();
+        self.add_movie_commands(SOFT_RESET);
+    }
+
+    /// Turns the console off and on again, everything but the region and the input devices
+    /// starts over, frame count included.
+    pub fn power_cycle(&mut self) {
+        self.power_on();
+        self.add_movie_commands(POWER);
+    }
+
+    fn power_on(&mut self) {
+        let mut nes = Nes::from_bytes(self.rom.as_slice());
+        nes.set_region(self.region);
+        mem::swap(&mut nes.cpu.input, &mut self.cpu.input);
+        mem::swap(&mut nes.cpu.trace, &mut self.cpu.trace);
+        mem::swap(&mut nes.cpu.cdl, &mut self.cpu.cdl);
+        mem::swap(&mut nes.cpu.call_stack, &mut self.cpu.call_stack);
+        mem::swap(&mut nes.cpu.profiler, &mut self.cpu.profiler);
+        mem::swap(&mut nes.cpu.ppu.chr_log, &mut self.cpu.ppu.chr_log);
+
+        self.cpu = nes.cpu;
+        self.frame_count = 0;
+        self.lag = false;
+        self.mid_frame = false;
+        let logging = self.debugger.is_some();
+        self.set_access_logging(logging);
+        match self.rewind {
+            Some(ref mut rewind) => { rewind.clear(); }
+            None => { }
+        }
+        self.clear_call_stacks();
+
+        self.cpu.reset();
+    }
+
+    /// Sets the buttons held on the standard controller in `port`, 0 for $4016 and 1 for
+    /// $4017. They're latched the next time the game strobes the controllers.
+    pub fn set_buttons(&mut self, port: uint, buttons: ButtonState) {
+        self.input_device(port).set_buttons(0, buttons);
+        self.buttons[port] = buttons;
+    }
+
+    /// Sets the buttons of player 1 to 4. Players 3 and 4 only exist with a Four Score, or
+    /// on a Famicom with a 4 player adapter.
+    pub fn set_player_buttons(&mut self, player: uint, buttons: ButtonState) {
+        self.cpu.input.set_player_buttons(player, buttons);
+        if player < MAX_PLAYERS { self.buttons[player] = buttons; }
+    }
+
+    /// Swaps the device plugged into `port`, see input::device_from_name.
+    pub fn set_input_device(&mut self, port: uint, device: Box<InputDevice>) {
+        if port >= PORT_COUNT { fail!("No controller port {}", port); }
+        self.cpu.input.ports[port] = device;
+    }
+
+    pub fn input_device<'a>(&'a mut self, port: uint) -> &'a mut InputDevice {
+        if port >= PORT_COUNT { fail!("No controller port {}", port); }
+        &mut *self.cpu.input.ports[port]
+    }
+
+    /// Famicom mode changes how $4016/$4017 are read, see input::InputPorts. It's picked from
+    /// the NES 2.0 default expansion device when the ROM has one.
+    pub fn set_console(&mut self, console: ConsoleType) {
+        self.cpu.input.set_console(console);
+    }
+
+    pub fn set_expansion_device(&mut self, device: Option<Box<ExpansionDevice>>) {
+        self.cpu.input.expansion = device;
+    }
+
+    pub fn set_microphone(&mut self, loud: bool) {
+        self.cpu.input.set_microphone(loud);
     }
 
     pub fn run(&mut self) {
-        let mut cycleCount = CYCLES_PER_SCANLINE;
-        let mut scanline: uint = 0;
-        
         loop {
-            self.cpu.run_cycles(&mut cycleCount);
-            info!("After run_cycles");
-            self.cpu.ppu.do_scanline(scanline);
-            scanline += 1;
-            if scanline == 262 { scanline = 0; }
+            self.run_frame();
+        }
+    }
+
+    /// Runs until the PPU finishes the current frame.
+    pub fn run_frame<'a>(&'a mut self) -> FrameResult<'a> {
+        if !self.mid_frame {
+            self.movie_frame();
+        }
+
+        let frame = self.frame_count;
+        let mut stop = None;
+        while self.frame_count == frame && stop.is_none() {
+            stop = self.debug_step();
+        }
+
+        self.mid_frame = self.frame_count == frame;
+        if !self.mid_frame {
+            self.capture_rewind();
+            if stop.is_none() {
+                stop = match self.debugger {
+                    Some(ref mut debugger) => debugger.frame_done(),
+                    None => None,
+                };
+            }
+        }
+
+        FrameResult {
+            framebuffer: self.cpu.ppu.framebuffer(),
+            samples: self.cpu.apu.take_samples(),
+            frame: self.frame_count,
+            lag: self.lag,
+            stop: stop,
+        }
+    }
+
+    /// Checks breakpoints around every instruction from now on, see the debugger module. The
+    /// call stack is followed while it's attached.
+    pub fn attach_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+        self.set_access_logging(true);
+        if self.cpu.call_stack.is_none() {
+            self.cpu.call_stack = Some(CallStack::new());
+        }
+    }
+
+    pub fn detach_debugger(&mut self) -> Option<Debugger> {
+        self.set_access_logging(false);
+        self.cpu.call_stack = None;
+        self.debugger.take()
+    }
+
+    pub fn debugger<'a>(&'a mut self) -> Option<&'a mut Debugger> {
+        self.debugger.as_mut()
+    }
+
+    /// Runs until the debugger stops or `max_frames` frames have finished.
+    pub fn debug_continue(&mut self, max_frames: Option<uint>) -> Option<StopReason> {
+        let mut frames = 0;
+        while max_frames.map_or(true, |max| frames < max) {
+            let stop = self.run_frame().stop;
+            if stop.is_some() { return stop; }
+            frames += 1;
+        }
+        None
+    }
+
+    pub fn debug_step_into(&mut self) -> Option<StopReason> {
+        match self.debugger {
+            Some(ref mut debugger) => { debugger.step_into(); }
+            None => { return None; }
+        }
+        self.debug_continue(None)
+    }
+
+    pub fn debug_step_over(&mut self) -> Option<StopReason> {
+        match self.debugger {
+            Some(ref mut debugger) => { debugger.step_over(&self.cpu); }
+            None => { return None; }
+        }
+        self.debug_continue(None)
+    }
+
+    pub fn debug_step_out(&mut self) -> Option<StopReason> {
+        match self.debugger {
+            Some(ref mut debugger) => { debugger.step_out(&self.cpu); }
+            None => { return None; }
+        }
+        self.debug_continue(None)
+    }
+
+    /// The registers, see CpuState.
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    pub fn set_cpu_state(&mut self, state: CpuState) {
+        self.cpu.set_state(state);
+    }
+
+    /// Logs every instruction from now on, see debugger::trace. The logger gets the symbols
+    /// loaded so far.
+    pub fn start_trace(&mut self, trace: TraceLogger) {
+        let mut trace = trace;
+        trace.set_cycles(self.cpu.clock.cpu_cycles());
+        if !self.symbols.is_empty() {
+            trace.set_symbols(Some(self.symbols.clone()));
+        }
+        self.cpu.trace = Some(trace);
+    }
+
+    /// Detaches the logger, dropping it writes out anything it kept.
+    pub fn stop_trace(&mut self) -> Option<TraceLogger> {
+        self.cpu.trace.take()
+    }
+
+    pub fn trace<'a>(&'a mut self) -> Option<&'a mut TraceLogger> {
+        self.cpu.trace.as_mut()
+    }
+
+    /// Adds the symbols in an ld65 .dbg or FCEUX .nl file, see debugger::symbols. They're
+    /// used by the debugger commands, and by the trace logger when it's started after.
+    pub fn load_symbols(&mut self, path: &Path) -> IoResult<uint> {
+        self.symbols.load(path)
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols<'a>(&'a self) -> &'a Symbols {
+        &self.symbols
+    }
+
+    /// The name for an address in the bank mapped in there now, `main_loop+3`, and the source
+    /// line that made it, `main_loop+3 at main.s:42`, whichever are known.
+    pub fn describe_addr(&self, virtual_address: VAddr) -> Option<String> {
+        let bank = self.cpu.prg_bank(virtual_address);
+        let name = self.symbols.describe(virtual_address, bank);
+        let line = self.symbols.source_line(virtual_address, bank).map(|line| format!("{}:{}", line.file, line.line));
+        match (name, line) {
+            (Some(name), Some(line)) => Some(format!("{} at {}", name, line)),
+            (name, line) => name.or(line),
+        }
+    }
+
+    /// The subroutines and interrupt handlers the CPU is in, see debugger::callstack. Only
+    /// followed with a debugger attached, so calls made before then are missing.
+    pub fn call_stack<'a>(&'a self) -> Option<&'a CallStack> {
+        self.cpu.call_stack.as_ref()
+    }
+
+    /// Counts the cycles spent in every subroutine from now on, see debugger::profiler. The
+    /// frame budget comes from the region.
+    pub fn start_profiler(&mut self) {
+        self.cpu.profiler = Some(Profiler::new(self.region.cpu_cycles_per_frame()));
+    }
+
+    pub fn stop_profiler(&mut self) -> Option<Profiler> {
+        self.cpu.profiler.take()
+    }
+
+    pub fn profiler<'a>(&'a self) -> Option<&'a Profiler> {
+        self.cpu.profiler.as_ref()
+    }
+
+    /// The profiler's table with routines named from the symbols.
+    pub fn profile_report(&self) -> Option<String> {
+        let symbols = &self.symbols;
+        self.cpu.profiler.as_ref().map(|profiler| {
+            profiler.report(|entry, bank| symbols.describe(entry, bank))
+        })
+    }
+
+    //the calls in progress don't carry over a power cycle or a loaded state
+    fn clear_call_stacks(&mut self) {
+        match self.cpu.call_stack {
+            Some(ref mut call_stack) => { call_stack.clear(); }
+            None => { }
+        }
+        match self.cpu.profiler {
+            Some(ref mut profiler) => { profiler.clear_stack(); }
+            None => { }
+        }
+    }
+
+    /// An empty code/data log the size of this ROM.
+    pub fn new_cdl(&self) -> CodeDataLog {
+        CodeDataLog::new(self.prg_rom_len, self.chr_rom_len)
+    }
+
+    /// Records PRG-ROM and CHR-ROM usage from now on, see debugger::cdl. False if the log is
+    /// for a different sized ROM.
+    pub fn start_cdl(&mut self, cdl: CodeDataLog) -> bool {
+        if cdl.prg_len() != self.prg_rom_len || cdl.chr_len() != self.chr_rom_len { return false; }
+
+        let mut cdl = cdl;
+        self.cpu.ppu.chr_log = Some(cdl.take_chr());
+        self.cpu.cdl = Some(cdl);
+        true
+    }
+
+    pub fn stop_cdl(&mut self) -> Option<CodeDataLog> {
+        let mut cdl = self.cpu.cdl.take();
+        match (cdl.as_mut(), self.cpu.ppu.chr_log.take()) {
+            (Some(cdl), Some(chr)) => { cdl.put_chr(chr); }
+            _ => { }
+        }
+        cdl
+    }
+
+    /// A copy of the log so far, for saving while it keeps recording.
+    pub fn cdl(&self) -> Option<CodeDataLog> {
+        self.cpu.cdl.as_ref().map(|cdl| {
+            let mut cdl = cdl.clone();
+            cdl.put_chr(self.cpu.ppu.chr_log.clone().unwrap_or(Vec::new()));
+            cdl
+        })
+    }
+
+    fn set_access_logging(&mut self, enabled: bool) {
+        self.cpu.access_log.set_enabled(enabled);
+        self.cpu.ppu.access_log.set_enabled(enabled);
+    }
+
+    //one step with the debugger looking before and after, if there is one
+    fn debug_step(&mut self) -> Option<StopReason> {
+        if self.debugger.is_none() {
+            self.step();
+            return None;
+        }
+
+        let stop = match self.debugger {
+            Some(ref mut debugger) => debugger.before_instruction(&self.cpu),
+            None => None,
+        };
+
+        let stop = if stop.is_some() {
+            stop
+        } else {
+            self.step();
+
+            let mut accesses = self.cpu.access_log.take();
+            accesses.push_all(self.cpu.ppu.access_log.take().as_slice());
+            match self.debugger {
+                Some(ref mut debugger) => debugger.after_instruction(&self.cpu, accesses.as_slice()),
+                None => None,
+            }
+        };
+
+        //a trace in ring mode shows what led up to a breakpoint
+        match (stop, self.cpu.trace.as_mut()) {
+            (Some(BreakpointHit(..)), Some(trace)) | (Some(WatchpointHit(..)), Some(trace)) => { trace.dump(); }
+            _ => { }
+        }
+
+        stop
+    }
+
+    /// Starts keeping a state every `interval` frames for rewind_frame, in at most
+    /// `megabytes` of memory.
+    pub fn enable_rewind(&mut self, megabytes: uint, interval: uint) {
+        let mut rewind = RewindBuffer::new(megabytes * 1024 * 1024);
+        rewind.set_interval(interval);
+        self.rewind = Some(rewind);
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Goes back to the newest kept state from before the current frame, its picture is in
+    /// framebuffer() right away. Returns false once the buffer runs out. Holding a rewind key
+    /// means calling this once per frame instead of run_frame.
+    pub fn rewind_frame(&mut self) -> bool {
+        let frame = self.frame_count;
+        let state = match self.rewind {
+            Some(ref mut rewind) => rewind.pop_before(frame),
+            None => None,
+        };
 
-            cycleCount += CYCLES_PER_SCANLINE;
+        match state {
+            Some(state) => {
+                self.load_state(state.as_slice()).unwrap();
+                true
+            }
+            None => false,
         }
     }
 
-    fn read_rom(path: &Path) -> (RomHeader, PrgRom, ChrRom) {
-        let mut file = File::open(path).unwrap();
+    fn capture_rewind(&mut self) {
+        let capture = match self.rewind {
+            Some(ref rewind) => self.frame_count % rewind.interval() == 0,
+            None => false,
+        };
 
+        if capture {
+            let state = self.save_state();
+            let frame = self.frame_count;
+            self.rewind.as_mut().unwrap().push(frame, state);
+        }
+    }
+
+    /// Records the input of every frame from here on. From power on the console is power
+    /// cycled first, otherwise the movie starts from a save state of the current frame.
+    ///
+    /// Four players are recorded with a Four Score, or a Hori adapter on a Famicom. Ports with
+    /// anything but a standard controller in them are recorded as empty.
+    pub fn start_recording(&mut self, from_power_on: bool) {
+        let mut movie = Movie::new();
+        movie.pal = self.region != Ntsc;
+
+        {
+            let input = &self.cpu.input;
+            let hori = match input.expansion {
+                Some(ref device) => device.name() == "hori",
+                None => false,
+            };
+            movie.four_score = input.ports.iter().all(|device| device.name() == "fourscore") ||
+                (hori && input.console() == FamicomConsole);
+            for port in range(0u, PORT_COUNT) {
+                let name = input.ports[port].name();
+                movie.gamepads[port] = movie.four_score || name == "controller";
+                if !movie.gamepads[port] {
+                    error!("Only controllers are recorded, the {} in port {} isn't", name, port);
+                }
+            }
+        }
+
+        movie.rom_filename = self.rom_path.as_ref()
+            .and_then(|path| path.filestem_str())
+            .unwrap_or("").to_string();
+
+        if from_power_on {
+            self.power_on();
+        } else {
+            movie.start_state = Some(self.save_state());
+        }
+
+        self.movie = Some(MovieSession::recording(movie));
+    }
+
+    /// Starts from the movie's save state, or power on, and takes the input for the following
+    /// frames from the movie. Whatever is set with set_buttons is ignored until it runs out.
+    /// The ports get what the movie was recorded with, a Four Score for 4 players, or a 4
+    /// player adapter on a Famicom.
+    pub fn play_movie(&mut self, movie: Movie) -> StateResult<()> {
+        self.movie = None;
+
+        //Dendy movies look like PAL ones
+        if movie.pal != (self.region != Ntsc) {
+            self.set_region(if movie.pal { Pal } else { Ntsc });
+        }
+
+        match movie.start_state {
+            Some(ref state) => { try!(self.load_state(state.as_slice())); }
+            None => { self.power_on(); }
+        }
+        self.plug_in_movie_devices(&movie);
+
+        self.movie = Some(MovieSession::playing(movie));
+        Ok(())
+    }
+
+    //ports without a gamepad in the movie keep whatever is plugged in
+    fn plug_in_movie_devices(&mut self, movie: &Movie) {
+        let input = &mut self.cpu.input;
+        let famicom = input.console() == FamicomConsole;
+        let four_score_ports = movie.four_score && !famicom;
+
+        if movie.four_score && famicom {
+            let hori = match input.expansion {
+                Some(ref device) => device.name() == "hori",
+                None => false,
+            };
+            if !hori { input.expansion = Some(box HoriAdapter::new() as Box<ExpansionDevice>); }
+        }
+
+        for port in range(0u, PORT_COUNT) {
+            let name = input.ports[port].name();
+            if four_score_ports && name != "fourscore" {
+                input.ports[port] = box FourScore::new(port) as Box<InputDevice>;
+            } else if !four_score_ports && movie.gamepads[port] && name != "controller" {
+                input.ports[port] = box Controller::new() as Box<InputDevice>;
+            }
+        }
+    }
+
+    /// Plays a whole movie and hashes the console after every frame, see
+    /// movie::checkpoint. Leaves the console where the movie ends.
+    pub fn movie_checkpoints(&mut self, movie: Movie) -> StateResult<Checkpoints> {
+        let frames = movie.len();
+        try!(self.play_movie(movie));
+
+        let mut checkpoints = Checkpoints::new();
+        for frame in range(1, frames + 1) {
+            self.run_frame();
+            checkpoints.push(frame, self.state_hashes());
+        }
+
+        Ok(checkpoints)
+    }
+
+    /// A hash of each part of the console: "CpuState", "ram", "PpuRegisters", "VRam",
+    /// "SprRam", "palette_ram", "framebuffer", "Apu", "mapper" and "clock".
+    pub fn state_hashes(&self) -> StateHashes {
+        let mut hashes = Vec::new();
+        self.cpu.state_hashes(&mut hashes);
+
+        let mut clock = StateWriter::new();
+        clock.uint(self.frame_count);
+        clock.bool(self.lag);
+        self.cpu.clock.save_state(&mut clock);
+        hashes.push(("clock", state::hash(clock.into_bytes().as_slice())));
+
+        hashes
+    }
+
+    pub fn movie<'a>(&'a self) -> Option<&'a MovieSession> {
+        self.movie.as_ref()
+    }
+
+    /// Stops recording or playback and hands back the movie.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|session| session.movie)
+    }
+
+    fn add_movie_commands(&mut self, commands: MovieCommands) {
+        match self.movie {
+            Some(ref mut session) => { session.add_commands(commands); }
+            None => { }
+        }
+    }
+
+    fn movie_frame(&mut self) {
+        let buttons = self.buttons;
+        let frame = match self.movie {
+            Some(ref mut session) => session.next_frame(buttons).map(|frame| (frame, session.movie.players())),
+            None => None,
+        };
+
+        match frame {
+            Some((frame, players)) => { self.apply_movie_frame(&frame, players); }
+            None => { }
+        }
+    }
+
+    fn apply_movie_frame(&mut self, frame: &MovieFrame, players: uint) {
+        if frame.commands.contains(POWER) {
+            self.power_on();
+        } else if frame.commands.contains(SOFT_RESET) {
+            self.cpu.reset();
+        }
+
+        for player in range(0, players) {
+            self.cpu.input.set_player_buttons(player, frame.buttons[player]);
+            self.buttons[player] = frame.buttons[player];
+        }
+    }
+
+    /// Runs one CPU instruction and returns the cycles it took.
+    pub fn step_instruction(&mut self) -> uint {
+        self.step()
+    }
+
+    /// Runs whole instructions until at least `cycles` CPU cycles have passed and returns the
+    /// number that did.
+    pub fn step_cycles(&mut self, cycles: uint) -> uint {
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            elapsed += self.step();
+        }
+        elapsed
+    }
+
+    pub fn framebuffer<'a>(&'a self) -> &'a [u8] {
+        self.cpu.ppu.framebuffer()
+    }
+
+    /// The framebuffer in RGB, 3 bytes per pixel.
+    pub fn framebuffer_rgb(&self) -> Vec<u8> {
+        self.cpu.ppu.rgb_framebuffer()
+    }
+
+    //FNV-1a of the framebuffer's palette indices
+    pub fn framebuffer_hash(&self) -> u64 {
+        state::hash(self.framebuffer())
+    }
+
+    /// Writes the framebuffer to a PNG.
+    pub fn screenshot(&self, path: &Path) -> IoResult<()> {
+        png::write(path, SCREEN_WIDTH, SCREEN_HEIGHT, self.framebuffer_rgb().as_slice())
+    }
+
+    /// Reads the CPU bus without side effects, see Cpu::peek_byte.
+    pub fn peek(&self, virtual_address: VAddr) -> u8 {
+        self.cpu.peek_byte(virtual_address)
+    }
+
+    /// Writes the CPU bus without side effects, see Cpu::poke_byte. False if it can't.
+    pub fn poke(&mut self, virtual_address: VAddr, val: u8) -> bool {
+        self.cpu.poke_byte(virtual_address, val)
+    }
+
+    /// Reads the PPU bus without side effects, pattern tables, name tables and palettes.
+    pub fn peek_ppu(&self, virtual_address: VAddr) -> u8 {
+        self.cpu.ppu.peek_byte(virtual_address)
+    }
+
+    pub fn poke_ppu(&mut self, virtual_address: VAddr, val: u8) -> bool {
+        self.cpu.ppu.poke_byte(virtual_address, val)
+    }
+
+    /// Sprite RAM.
+    pub fn peek_oam(&self, address: u8) -> u8 {
+        self.cpu.ppu.peek_oam(address)
+    }
+
+    pub fn poke_oam(&mut self, address: u8, val: u8) {
+        self.cpu.ppu.poke_oam(address, val);
+    }
+
+    /// Palette RAM, `index` is the offset from $3F00 and wraps every 32 bytes.
+    pub fn peek_palette(&self, index: u8) -> u8 {
+        self.cpu.ppu.peek_byte(0x3F00 | (index & 0x1F) as VAddr)
+    }
+
+    pub fn poke_palette(&mut self, index: u8, val: u8) {
+        self.cpu.ppu.poke_byte(0x3F00 | (index & 0x1F) as VAddr, val);
+    }
+
+    /// The mapper's bank and control registers by name.
+    pub fn mapper_registers(&self) -> Vec<(String, u8)> {
+        self.cpu.mapper_registers()
+    }
+
+    /// Disassembles the instruction at `virtual_address`, see cpu::disasm.
+    pub fn disassemble(&self, virtual_address: VAddr) -> Disassembly {
+        self.cpu.disassemble(virtual_address)
+    }
+
+    /// PRG-ROM as ca65 or asm6 source, one for every 16 KB bank, going by the code/data log
+    /// if one is recording. See debugger::export.
+    pub fn export_disassembly(&self, symbols: &Symbols, style: AsmStyle) -> Vec<String> {
+        let prg = self.rom.slice(INES_HEADER_SIZE, INES_HEADER_SIZE + self.prg_rom_len);
+        let usage = self.cpu.cdl.as_ref().map(|cdl| cdl.prg_usage());
+        export::export(prg, usage, symbols, style)
+    }
+
+    /// The instruction at PC with the addresses and values it's about to use, like nestest.log.
+    pub fn disassemble_pc(&self) -> String {
+        let line = self.cpu.disassemble(self.cpu.pc());
+        self.cpu.annotate_symbols(&line, &self.symbols)
+    }
+
+    /// The 16 KB PRG-ROM bank mapped in at an address, None outside PRG-ROM.
+    pub fn prg_bank(&self, virtual_address: VAddr) -> Option<uint> {
+        self.cpu.prg_bank(virtual_address)
+    }
+
+    /// The instruction's text with names for its operand where there are symbols.
+    pub fn symbolic_text(&self, line: &Disassembly) -> String {
+        self.cpu.symbolic_text(line, &self.symbols)
+    }
+
+    //the 2 KB of CPU RAM at $0000-$07FF
+    pub fn ram<'a>(&'a self) -> &'a [u8] {
+        self.cpu.ram()
+    }
+
+    /// APU output since the last call, or since the last run_frame.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.apu.take_samples()
+    }
+
+    pub fn frame_count(&self) -> uint {
+        self.frame_count
+    }
+
+    //CPU cycles since power on
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu.clock.cpu_cycles()
+    }
+
+    fn step(&mut self) -> uint {
+        let cycles = self.cpu.step();
+
+        let dots = self.cpu.clock.advance_cpu(cycles);
+        for _ in range(0, dots) {
+            self.cpu.ppu.tick();
+        }
+
+        if self.cpu.ppu.frame() != self.frame_count {
+            self.frame_count = self.cpu.ppu.frame();
+            self.lag = !self.cpu.input.take_polled();
+            match self.cpu.profiler {
+                Some(ref mut profiler) => { profiler.end_frame(); }
+                None => { }
+            }
+        }
+
+        cycles
+    }
+
+    /// Captures the whole console in a save state, see nes::state for the format. The devices
+    /// in the controller ports are saved too, loading the state plugs them back in.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        state::write_header(&mut writer, self.rom_hash);
+
+        let mut nes = StateWriter::new();
+        nes.u8(match self.region { Ntsc => 0, Pal => 1, Dendy => 2 });
+        nes.uint(self.frame_count);
+        nes.bool(self.lag);
+        self.cpu.clock.save_state(&mut nes);
+        writer.chunk(b"NES ", nes.into_bytes().as_slice());
+
+        let mut cpu = StateWriter::new();
+        self.cpu.save_state(&mut cpu);
+        writer.chunk(b"CPU ", cpu.into_bytes().as_slice());
+
+        let mut ppu = StateWriter::new();
+        self.cpu.ppu.save_state(&mut ppu);
+        writer.chunk(b"PPU ", ppu.into_bytes().as_slice());
+
+        let mut apu = StateWriter::new();
+        self.cpu.apu.save_state(&mut apu);
+        writer.chunk(b"APU ", apu.into_bytes().as_slice());
+
+        let mut mapper = StateWriter::new();
+        self.cpu.save_mapper_state(&mut mapper);
+        writer.chunk(b"MAPR", mapper.into_bytes().as_slice());
+
+        let mut input = StateWriter::new();
+        self.cpu.input.save_state(&mut input);
+        writer.chunk(b"INPT", input.into_bytes().as_slice());
+
+        writer.into_bytes()
+    }
+
+    /// Restores a state from save_state. It has to come from the same ROM, and the console is
+    /// left alone if the state can't be loaded.
+    pub fn load_state(&mut self, bytes: &[u8]) -> StateResult<()> {
+        let state = try!(SaveState::parse(bytes));
+        if state.rom_hash != self.rom_hash { return Err(RomMismatch); }
+
+        let mut nes = try!(state.chunk("NES "));
+        let mut cpu = try!(state.chunk("CPU "));
+        let mut ppu = try!(state.chunk("PPU "));
+        let mut apu = try!(state.chunk("APU "));
+        let mut mapper = try!(state.chunk("MAPR"));
+        let mut input = try!(state.chunk("INPT"));
+
+        let region = match nes.u8() {
+            0 => Ntsc,
+            1 => Pal,
+            2 => Dendy,
+            n => { return Err(BadValue(format!("region {}", n))); }
+        };
+
+        //a truncated chunk is only noticed halfway through loading it
+        let backup = self.save_state();
+
+        self.set_region(region);
+        self.frame_count = nes.uint();
+        self.lag = nes.bool();
+        self.cpu.clock.load_state(&mut nes);
+        self.cpu.load_state(&mut cpu);
+        self.cpu.ppu.load_state(&mut ppu);
+        self.cpu.apu.load_state(&mut apu);
+        self.cpu.load_mapper_state(&mut mapper);
+        let input_result = self.cpu.input.load_state(&mut input);
+
+        let result = nes.finish()
+            .and(cpu.finish())
+            .and(ppu.finish())
+            .and(apu.finish())
+            .and(mapper.finish())
+            .and(input_result)
+            .and(input.finish());
+
+        if result.is_err() {
+            self.load_state(backup.as_slice()).unwrap();
+        }
+        self.clear_call_stacks();
+        result
+    }
+
+    /// Where save state `slot` lives, next to the ROM, e.g. `mario.nes` keeps slot 1 in
+    /// `mario.nes.ss1`. Consoles loaded from_bytes have no slots.
+    pub fn state_slot_path(&self, slot: uint) -> Option<Path> {
+        if slot >= STATE_SLOTS { fail!("No save state slot {}", slot); }
+
+        self.rom_path.as_ref().map(|path| {
+            let filename = path.filename_str().unwrap_or("rom");
+            path.with_filename(format!("{}.ss{}", filename, slot))
+        })
+    }
+
+    pub fn save_slot(&self, slot: uint) -> StateResult<()> {
+        let path = try!(self.state_slot_path(slot).ok_or(NoSlotPath));
+        let mut file = try!(File::create(&path).map_err(|e| IoFailed(e)));
+        file.write(self.save_state().as_slice()).map_err(|e| IoFailed(e))
+    }
+
+    pub fn load_slot(&mut self, slot: uint) -> StateResult<()> {
+        let path = try!(self.state_slot_path(slot).ok_or(NoSlotPath));
+        let mut file = try!(File::open(&path).map_err(|e| IoFailed(e)));
+        let bytes = try!(file.read_to_end().map_err(|e| IoFailed(e)));
+        self.load_state(bytes.as_slice())
+    }
+
+    /// Runs for the given number of frames and writes the mixed APU output to `path`.
+    ///
+    /// With `stems` set, every channel is also written on its own next to `path`, e.g.
+    /// `song.wav` gets `song.pulse1.wav`, `song.pulse2.wav`, `song.triangle.wav`,
+    /// `song.noise.wav` and `song.dmc.wav`, plus one file per expansion sound chip.
+    pub fn record_audio(&mut self, frames: uint, path: &Path, format: PcmFormat, stems: bool) -> IoResult<()> {
+        let sample_rate = self.cpu.apu.sample_rate();
+
+        let mut writer = try!(PcmWriter::create(path, format, sample_rate, 1));
+
+        let mut stem_writers = Vec::new();
+        if stems {
+            for name in self.cpu.apu.stem_names().iter() {
+                let stem_path = Nes::stem_path(path, *name, format);
+                stem_writers.push(try!(PcmWriter::create(&stem_path, format, sample_rate, 1)));
+            }
+        }
+
+        self.cpu.apu.set_record_stems(stems);
+        self.cpu.apu.take_samples();
+
+        for _ in range(0, frames) {
+            let samples = self.run_frame().samples;
+            try!(writer.write_samples(samples.as_slice()));
+
+            if stems {
+                let stem_samples = self.cpu.apu.take_stems();
+                for (stem_writer, samples) in stem_writers.mut_iter().zip(stem_samples.iter()) {
+                    try!(stem_writer.write_samples(samples.as_slice()));
+                }
+            }
+        }
+
+        self.cpu.apu.set_record_stems(false);
+
+        for stem_writer in stem_writers.move_iter() {
+            try!(stem_writer.finish());
+        }
+        writer.finish()
+    }
+
+    fn stem_path(path: &Path, name: &str, format: PcmFormat) -> Path {
+        let stem = path.filestem_str().unwrap_or("audio");
+        let extension = match path.extension_str() {
+            Some(extension) => extension,
+            None => if format == RawPcm { "raw" } else { "wav" },
+        };
+
+        path.with_filename(format!("{}.{}.{}", stem, name, extension))
+    }
+
+    //identifies the ROM in save states
+    fn hash_rom(prg_rom: &PrgRom, chr_rom: &ChrRom) -> u64 {
+        let mut hash = state::FNV_OFFSET_BASIS;
+        for bank in prg_rom.iter() {
+            hash = state::fnv_hash(bank.as_slice(), hash);
+        }
+        for bank in chr_rom.iter() {
+            hash = state::fnv_hash(bank.as_slice(), hash);
+        }
+        hash
+    }
+
+    fn read_rom<R: Reader>(file: &mut R) -> (RomHeader, PrgRom, ChrRom) {
         //get the header info
-        let mut buf = [0u8, ..0x10];
+        let mut buf = [0u8, ..INES_HEADER_SIZE];
         file.read(buf);
         let header = RomHeader::new(&buf).expect("Bad header");
 
@@ -149,6 +1147,20 @@ impl Nes {
 ///   ||  ++- TV system (0: NTSC; 2: PAL; 1/3: dual compatible)
 ///   |+----- SRAM in CPU $6000-$7FFF is 0: present; 1: not present
 ///   +------ 0: Board has no bus conflicts; 1: Board has bus conflicts
+///
+/// ## Flags 12 (NES 2.0)
+///
+/// 76543210
+///       ||
+///       ++- CPU/PPU timing (0: NTSC; 1: PAL; 2: multiple region; 3: Dendy)
+///
+/// ## Flags 15 (NES 2.0)
+///
+/// from http://wiki.nesdev.com/w/index.php/NES_2.0
+///
+/// 76543210
+///   ||||||
+///   ++++++- Default expansion device, see InputPorts::configure
 #[packed]
 struct RomHeader {
     identifier: [u8, ..4], // NES^
@@ -159,7 +1171,7 @@ struct RomHeader {
     pub prg_ram_count: u8, // in 8KB, minimum 8KB for compat
     flags_9: u8,
     flags_10: u8,
-    pub zeros: [u8, ..5],
+    pub zeros: [u8, ..5], // flags 11-15 in NES 2.0
 }
 
 
@@ -182,7 +1194,7 @@ impl RomHeader {
         static MSDOS_EOF: u8 = 0x1a;
 
         if self.identifier != ['N' as u8, 'E' as u8, 'S' as u8, MSDOS_EOF] { return false; }
-        if self.zeros != [0u8, ..5] { return false; }
+        if !self.is_nes2() && self.zeros != [0u8, ..5] { return false; }
 
         true
     }
@@ -195,6 +1207,36 @@ impl RomHeader {
         RomHeader::is_flag_set(self.flags_6, 1 << 1)
     }
 
+    //flags 6 and 7, NES 2.0 bits 8-11 in flags 8 are left out
+    pub fn mapper_number(&self) -> u8 {
+        (self.flags_7 & 0xF0) | (self.flags_6 >> 4)
+    }
+
+    pub fn is_nes2(&self) -> bool {
+        self.flags_7 & 0x0C == 0x08
+    }
+
+    //NES 2.0 flags 12, or flags 9 in iNES. Multiple region games get NTSC.
+    pub fn region(&self) -> Region {
+        if self.is_nes2() {
+            match self.zeros[1] & 0x03 {
+                1 => Pal,
+                3 => Dendy,
+                _ => Ntsc,
+            }
+        } else if RomHeader::is_flag_set(self.flags_9, 1 << 0) {
+            Pal
+        } else {
+            Ntsc
+        }
+    }
+
+    //flags 15, 0 is unspecified
+    pub fn default_expansion_device(&self) -> Option<u8> {
+        let device = self.zeros[4] & 0x3F;
+        if self.is_nes2() && device != 0 { Some(device) } else { None }
+    }
+
     fn is_flag_set(flags: u8, flag: u8) -> bool {
         flags & flag != 0
     }
@@ -0,0 +1,94 @@
+use ppu::{DOTS_PER_SCANLINE};
+
+/// # Regions
+///
+/// from http://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+///
+///                     NTSC          PAL           Dendy
+/// Master clock        21.477272 MHz 26.601712 MHz 26.601712 MHz
+/// CPU divider         12            16            15
+/// PPU divider         4             5             5
+/// PPU dots per cycle  3             3.2           3
+/// Scanlines           262           312           312
+/// Vblank scanline     241           241           291
+/// Frame rate          60.0988 Hz    50.0070 Hz    50.0070 Hz
+///
+/// Only NTSC skips a dot on odd frames. Dendy is a Famiclone with a PAL-like PPU on an
+/// NTSC-like CPU, it keeps the NTSC APU tables where PAL has its own.
+#[deriving(PartialEq, Show)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn master_clock_rate(&self) -> f64 {
+        match *self {
+            Ntsc => 21477272.0,
+            Pal | Dendy => 26601712.0,
+        }
+    }
+
+    pub fn cpu_divider(&self) -> uint {
+        match *self {
+            Ntsc => 12,
+            Pal => 16,
+            Dendy => 15,
+        }
+    }
+
+    pub fn ppu_divider(&self) -> uint {
+        match *self {
+            Ntsc => 4,
+            Pal | Dendy => 5,
+        }
+    }
+
+    pub fn cpu_clock_rate(&self) -> f64 {
+        self.master_clock_rate() / (self.cpu_divider() as f64)
+    }
+
+    pub fn scanlines(&self) -> uint {
+        match *self {
+            Ntsc => 262,
+            Pal | Dendy => 312,
+        }
+    }
+
+    pub fn vblank_scanline(&self) -> uint {
+        match *self {
+            Ntsc | Pal => 241,
+            Dendy => 291,
+        }
+    }
+
+    pub fn skips_odd_dot(&self) -> bool {
+        *self == Ntsc
+    }
+
+    //whether the APU uses the PAL noise, DMC and frame counter tables
+    pub fn pal_apu(&self) -> bool {
+        *self == Pal
+    }
+
+    //ignores the odd frame dot
+    pub fn frame_rate(&self) -> f64 {
+        self.cpu_clock_rate() / self.cpu_cycles_per_frame()
+    }
+
+    //29780.67 on NTSC, ignores the odd frame dot
+    pub fn cpu_cycles_per_frame(&self) -> f64 {
+        let dots = (self.scanlines() * DOTS_PER_SCANLINE) as f64;
+        dots * (self.ppu_divider() as f64) / (self.cpu_divider() as f64)
+    }
+
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name {
+            "ntsc" => Some(Ntsc),
+            "pal" => Some(Pal),
+            "dendy" => Some(Dendy),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::{RingBuf, Deque};
+
+/// # Rewind
+///
+/// Keeps recent save states so the emulation can run backwards a frame at a time.
+///
+/// A state is a bit over 80 KB, mostly the framebuffer, so they're stored in groups. The first
+/// state of a group is kept whole as the keyframe, the rest are XORed against the keyframe and
+/// run length encoded, which leaves little more than what changed on screen since. When the
+/// buffer grows past its cap the oldest group is dropped.
+///
+/// Deltas are a list of runs:
+///
+/// - varint - Bytes that match the keyframe
+/// - varint - Length of the literal that follows
+/// - Literal, XORed with the keyframe
+///
+/// where a varint is 7 bits per byte, least significant first, with bit 7 set on all but the
+/// last byte.
+
+pub static DEFAULT_REWIND_MB: uint = 64;
+pub static DEFAULT_KEYFRAME_INTERVAL: uint = 30;
+
+struct Group {
+    keyframe: Vec<u8>,
+    keyframe_frame: uint,
+    deltas: Vec<(uint, Vec<u8>)>,
+    bytes: uint,
+}
+
+pub struct RewindBuffer {
+    capacity: uint,
+    interval: uint,
+    keyframe_interval: uint,
+    groups: RingBuf<Group>,
+    used: uint,
+}
+
+impl RewindBuffer {
+    /// `capacity` is in bytes, see Nes::enable_rewind for the megabyte version.
+    pub fn new(capacity: uint) -> RewindBuffer {
+        RewindBuffer {
+            capacity: capacity,
+            interval: 1,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            groups: RingBuf::new(),
+            used: 0,
+        }
+    }
+
+    //capture a state every `interval` frames
+    pub fn interval(&self) -> uint {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: uint) {
+        self.interval = if interval == 0 { 1 } else { interval };
+    }
+
+    //states per group, counting the keyframe
+    pub fn set_keyframe_interval(&mut self, keyframe_interval: uint) {
+        self.keyframe_interval = if keyframe_interval == 0 { 1 } else { keyframe_interval };
+    }
+
+    //bytes held, roughly
+    pub fn used(&self) -> uint {
+        self.used
+    }
+
+    pub fn len(&self) -> uint {
+        self.groups.iter().fold(0, |len, group| len + 1 + group.deltas.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.used = 0;
+    }
+
+    /// Adds the state captured at the end of `frame`.
+    pub fn push(&mut self, frame: uint, state: Vec<u8>) {
+        let new_group = match self.groups.back() {
+            Some(group) => {
+                group.deltas.len() + 1 >= self.keyframe_interval ||
+                    group.keyframe.len() != state.len()
+            }
+            None => true,
+        };
+
+        if new_group {
+            self.used += state.len();
+            self.groups.push_back(Group {
+                bytes: state.len(),
+                keyframe: state,
+                keyframe_frame: frame,
+                deltas: Vec::new(),
+            });
+        } else {
+            let group = self.groups.back_mut().unwrap();
+            let delta = encode_delta(group.keyframe.as_slice(), state.as_slice());
+            self.used += delta.len();
+            group.bytes += delta.len();
+            group.deltas.push((frame, delta));
+        }
+
+        //the newest group always stays, even when it's over the cap on its own
+        while self.used > self.capacity && self.groups.len() > 1 {
+            let group = self.groups.pop_front().unwrap();
+            self.used -= group.bytes;
+        }
+    }
+
+    /// Removes the newest state and returns it with the frame it was captured at.
+    pub fn pop(&mut self) -> Option<(uint, Vec<u8>)> {
+        let (state, empty) = match self.groups.back_mut() {
+            Some(group) => {
+                match group.deltas.pop() {
+                    Some((frame, delta)) => {
+                        self.used -= delta.len();
+                        group.bytes -= delta.len();
+                        let state = apply_delta(group.keyframe.as_slice(), delta.as_slice());
+                        (Some((frame, state)), false)
+                    }
+                    None => (Some((group.keyframe_frame, group.keyframe.clone())), true),
+                }
+            }
+            None => (None, false),
+        };
+
+        if empty {
+            let group = self.groups.pop_back().unwrap();
+            self.used -= group.bytes;
+        }
+        state
+    }
+
+    /// Drops states from `frame` on and returns the newest one before it.
+    pub fn pop_before(&mut self, frame: uint) -> Option<Vec<u8>> {
+        loop {
+            match self.pop() {
+                Some((state_frame, state)) => {
+                    if state_frame < frame { return Some(state); }
+                }
+                None => { return None; }
+            }
+        }
+    }
+}
+
+pub fn encode_delta(keyframe: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut pos = 0;
+
+    while pos < state.len() {
+        let start = pos;
+        while pos < state.len() && state[pos] == keyframe[pos] { pos += 1; }
+        if pos == state.len() { break; }
+        let skip = pos - start;
+
+        //short matching stretches are cheaper to keep in the literal than to start a new run
+        let literal_start = pos;
+        let mut matching = 0;
+        while pos < state.len() && matching < 4 {
+            if state[pos] == keyframe[pos] { matching += 1; } else { matching = 0; }
+            pos += 1;
+        }
+        pos -= matching;
+
+        write_varint(&mut delta, skip);
+        write_varint(&mut delta, pos - literal_start);
+        for i in range(literal_start, pos) {
+            delta.push(state[i] ^ keyframe[i]);
+        }
+    }
+
+    delta
+}
+
+pub fn apply_delta(keyframe: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = keyframe.to_vec();
+    let mut pos = 0;
+    let mut read = 0;
+
+    while read < delta.len() {
+        pos += read_varint(delta, &mut read);
+        let len = read_varint(delta, &mut read);
+        for _ in range(0, len) {
+            *state.get_mut(pos) ^= delta[read];
+            pos += 1;
+            read += 1;
+        }
+    }
+
+    state
+}
+
+fn write_varint(bytes: &mut Vec<u8>, val: uint) {
+    let mut val = val;
+    while val >= 0x80 {
+        bytes.push((val & 0x7F) as u8 | 0x80);
+        val >>= 7;
+    }
+    bytes.push(val as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut uint) -> uint {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        val |= (byte & 0x7F) as uint << shift;
+        if byte & 0x80 == 0 { return val; }
+        shift += 7;
+    }
+}
@@ -0,0 +1,278 @@
+use std::io::IoError;
+use std::mem;
+
+/// # Save states
+///
+/// A save state is a header followed by one chunk per component, a reader skips the chunks it
+/// doesn't know. Changing what goes in a chunk means bumping STATE_VERSION.
+///
+/// Header:
+///
+/// - 4 bytes - "RNST"
+/// - u32     - Format version, see STATE_VERSION
+/// - u64     - FNV-1a hash of the ROM's PRG-ROM and CHR-ROM, states only load on the same ROM
+///
+/// Chunks:
+///
+/// - 4 bytes - Tag, e.g. "CPU "
+/// - u32     - Length of the data
+/// - data
+///
+/// Everything is little endian. Each component writes and reads its own chunk with
+/// StateWriter and StateReader, a reader that runs past the end of its chunk returns zeros and
+/// the load fails with Truncated.
+///
+/// Only states of the current version load, there's no converting older ones.
+///
+/// - 1 - First version
+/// - 2 - The PPU's OAM address and I/O latch in "PPU "
+/// - 3 - Expansion sound chips in "APU ", input devices in "INPT"
+/// - 4 - The PPU's VRAM address, scroll and $2007 read buffer in "PPU "
+
+pub static STATE_MAGIC: &'static [u8] = b"RNST";
+pub static STATE_VERSION: u32 = 4;
+
+static HEADER_SIZE: uint = 16;
+
+#[deriving(PartialEq, Show)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    RomMismatch,
+    Truncated,
+    MissingChunk(String),
+    BadValue(String),
+    NoSlotPath,
+    IoFailed(IoError),
+}
+
+pub type StateResult<T> = Result<T, StateError>;
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            bytes: Vec::new(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(if val { 1 } else { 0 });
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.u8(val as u8);
+        self.u8((val >> 8) as u8);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.u16(val as u16);
+        self.u16((val >> 16) as u16);
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.u32(val as u32);
+        self.u32((val >> 32) as u32);
+    }
+
+    pub fn uint(&mut self, val: uint) {
+        self.u64(val as u64);
+    }
+
+    pub fn f32(&mut self, val: f32) {
+        let bits: u32 = unsafe { mem::transmute(val) };
+        self.u32(bits);
+    }
+
+    pub fn f64(&mut self, val: f64) {
+        let bits: u64 = unsafe { mem::transmute(val) };
+        self.u64(bits);
+    }
+
+    //fixed size data, the reader has to know the length
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.bytes.push_all(val);
+    }
+
+    //variable size data, prefixed with its length
+    pub fn vec(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.bytes(val);
+    }
+
+    pub fn chunk(&mut self, tag: &[u8], data: &[u8]) {
+        self.bytes(tag);
+        self.vec(data);
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    pos: uint,
+    truncated: bool,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            bytes: bytes,
+            pos: 0,
+            truncated: false,
+        }
+    }
+
+    //Truncated if anything was read past the end
+    pub fn finish(&self) -> StateResult<()> {
+        if self.truncated { Err(Truncated) } else { Ok(()) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        if self.pos < self.bytes.len() {
+            let val = self.bytes[self.pos];
+            self.pos += 1;
+            val
+        } else {
+            self.truncated = true;
+            0
+        }
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        let low = self.u8() as u16;
+        low | (self.u8() as u16) << 8
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        let low = self.u16() as u32;
+        low | (self.u16() as u32) << 16
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        let low = self.u32() as u64;
+        low | (self.u32() as u64) << 32
+    }
+
+    pub fn uint(&mut self) -> uint {
+        self.u64() as uint
+    }
+
+    pub fn f32(&mut self) -> f32 {
+        let bits = self.u32();
+        unsafe { mem::transmute(bits) }
+    }
+
+    pub fn f64(&mut self) -> f64 {
+        let bits = self.u64();
+        unsafe { mem::transmute(bits) }
+    }
+
+    pub fn bytes(&mut self, len: uint) -> &'a [u8] {
+        if self.pos + len <= self.bytes.len() {
+            let val = self.bytes.slice(self.pos, self.pos + len);
+            self.pos += len;
+            val
+        } else {
+            self.truncated = true;
+            self.pos = self.bytes.len();
+            &[]
+        }
+    }
+
+    //fills `buf` completely, leaving it alone if the data runs out
+    pub fn read_into(&mut self, buf: &mut [u8]) {
+        let bytes = self.bytes(buf.len());
+        if bytes.len() == buf.len() {
+            buf.copy_from(bytes);
+        }
+    }
+
+    pub fn vec(&mut self) -> Vec<u8> {
+        let len = self.u32() as uint;
+        self.bytes(len).to_vec()
+    }
+}
+
+/// A parsed state, its chunks are looked up by tag. Later chunks with the same tag are ignored.
+pub struct SaveState<'a> {
+    pub version: u32,
+    pub rom_hash: u64,
+    chunks: Vec<(String, &'a [u8])>,
+}
+
+impl<'a> SaveState<'a> {
+    pub fn parse(bytes: &'a [u8]) -> StateResult<SaveState<'a>> {
+        if bytes.len() < HEADER_SIZE { return Err(Truncated); }
+        if !bytes.starts_with(STATE_MAGIC) { return Err(BadMagic); }
+
+        let mut reader = StateReader::new(bytes.slice_from(4));
+        let version = reader.u32();
+        let rom_hash = reader.u64();
+        if version != STATE_VERSION { return Err(UnsupportedVersion(version)); }
+
+        let mut chunks = Vec::new();
+        while !reader.is_empty() {
+            let tag = String::from_utf8_lossy(reader.bytes(4)).into_string();
+            let len = reader.u32() as uint;
+            let data = reader.bytes(len);
+            try!(reader.finish());
+            chunks.push((tag, data));
+        }
+
+        Ok(SaveState {
+            version: version,
+            rom_hash: rom_hash,
+            chunks: chunks,
+        })
+    }
+
+    pub fn chunk(&self, tag: &str) -> StateResult<StateReader<'a>> {
+        match self.chunks.iter().find(|&&(ref name, _)| name.as_slice() == tag) {
+            Some(&(_, data)) => Ok(StateReader::new(data)),
+            None => Err(MissingChunk(tag.to_string())),
+        }
+    }
+}
+
+pub fn write_header(writer: &mut StateWriter, rom_hash: u64) {
+    writer.bytes(STATE_MAGIC);
+    writer.u32(STATE_VERSION);
+    writer.u64(rom_hash);
+}
+
+pub type StateHashes = Vec<(&'static str, u64)>;
+
+pub fn hash(bytes: &[u8]) -> u64 {
+    fnv_hash(bytes, FNV_OFFSET_BASIS)
+}
+
+/// FNV-1a, 64 bit
+pub fn fnv_hash(bytes: &[u8], hash: u64) -> u64 {
+    let mut hash = hash;
+    for &byte in bytes.iter() {
+        hash ^= byte as u64;
+        hash *= FNV_PRIME;
+    }
+    hash
+}
+
+pub static FNV_OFFSET_BASIS: u64 = 0xCBF29CE484222325;
+static FNV_PRIME: u64 = 0x100000001B3;
@@ -1,12 +1,35 @@
 #![macro_escape]
 
 use nes::{
+    Nes,
+    Ntsc,
+    Pal,
+    Dendy,
     PRG_ROM_BANK_SIZE,
+    CHR_ROM_BANK_SIZE,
     PrgRomBank,
     PrgRom,
     RomHeader,
 };
 
+use nes::clock::{MasterClock};
+use nes::state::{StateWriter, BadMagic, RomMismatch, Truncated, UnsupportedVersion};
+use nes::rewind::{RewindBuffer, encode_delta, apply_delta};
+use nes::blargg::{run_test_rom, TestRomPassed, TestRomFailed, TestRomTimedOut};
+use nes::golden::{render_frames, compare_png, compare_hash, output_path, hash};
+use nes::golden::{GoldenMissing, PixelMismatch, HashMismatch};
+
+use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT, DOTS_PER_SCANLINE};
+use ppu::png;
+
+use apu::{ExpansionAudio};
+use apu::expansion::{Vrc6};
+
+use input::{BUTTON_A};
+
+use std::io::{File, TempDir};
+use std::os;
+
 static MSDOS_EOF: u8 = 0x1a;
 
 static TEST_ROM_HEADER: [u8, ..16] = [ 
@@ -94,3 +117,554 @@ fn nes_rom_header_is_valid_test() {
     let bad_hdr = rom_header!();
     assert_eq!(bad_hdr.is_valid(), false);
 }
+
+#[test]
+fn nes_open_test() {
+    let dir = TempDir::new("rustnes_open").unwrap();
+    let rom = get_test_rom(&[0xEA]);
+
+    let path = dir.path().join("test.nes");
+    File::create(&path).write(rom.as_slice()).unwrap();
+    assert!(Nes::open(path).is_ok());
+
+    //not a ROM, and a ROM cut short
+    let path = dir.path().join("text.nes");
+    File::create(&path).write(b"NES is the console").unwrap();
+    assert!(Nes::open(path).is_err());
+    let path = dir.path().join("short.nes");
+    File::create(&path).write(rom.slice_to(rom.len() - 1)).unwrap();
+    assert!(Nes::open(path).is_err());
+    assert!(Nes::open(dir.path().join("missing.nes")).is_err());
+}
+
+#[test]
+fn nes_rom_header_nes2_test() {
+    let mut bytes = TEST_ROM_HEADER;
+
+    //iNES headers don't have a default expansion device
+    bytes[15] = 0x23;
+    assert!(RomHeader::new(&bytes).is_none());
+
+    bytes[7] = 0x48;
+    let hdr = RomHeader::new(&bytes).unwrap();
+    assert!(hdr.is_nes2());
+    assert_eq!(hdr.default_expansion_device(), Some(0x23));
+
+    bytes[15] = 0x00;
+    let hdr = RomHeader::new(&bytes).unwrap();
+    assert_eq!(hdr.default_expansion_device(), None);
+}
+
+//NROM-256 image with `program` at $8000 and every vector pointing at it
+pub fn get_test_rom(program: &[u8]) -> Vec<u8> {
+    let mut rom = TEST_ROM_HEADER.to_vec();
+    *rom.get_mut(4) = 0x02;
+    *rom.get_mut(5) = 0x01;
+    *rom.get_mut(6) = 0x00;
+    *rom.get_mut(7) = 0x00;
+
+    let mut prg_rom = Vec::from_elem(2 * PRG_ROM_BANK_SIZE, 0xEAu8);
+    for (i, &byte) in program.iter().enumerate() {
+        *prg_rom.get_mut(i) = byte;
+    }
+    for i in range(0u, 3) {
+        *prg_rom.get_mut(0x7FFA + i * 2) = 0x00;
+        *prg_rom.get_mut(0x7FFB + i * 2) = 0x80;
+    }
+
+    rom.push_all(prg_rom.as_slice());
+    rom.push_all(Vec::from_elem(CHR_ROM_BANK_SIZE, 0u8).as_slice());
+    rom
+}
+
+#[test]
+fn nes_run_frame_test() {
+    //JMP $8000
+    let rom = get_test_rom(&[0x4C, 0x00, 0x80]);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+
+    {
+        let result = nes.run_frame();
+        assert_eq!(result.frame, 1);
+        assert_eq!(result.framebuffer.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert!(result.samples.len() >= 720 && result.samples.len() <= 740);
+        assert!(result.lag);
+    }
+
+    assert_eq!(nes.run_frame().frame, 2);
+    assert_eq!(nes.frame_count(), 2);
+}
+
+#[test]
+fn nes_lag_frame_test() {
+    //LDA $4016, JMP $8000
+    let rom = get_test_rom(&[0xAD, 0x16, 0x40, 0x4C, 0x00, 0x80]);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+
+    assert!(!nes.run_frame().lag);
+}
+
+#[test]
+fn nes_step_test() {
+    //NOP
+    let rom = get_test_rom(&[0xEA]);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+
+    assert_eq!(nes.step_instruction(), 2);
+    assert_eq!(nes.step_cycles(5), 6);
+    assert_eq!(nes.frame_count(), 0);
+}
+
+#[test]
+fn nes_master_clock_test() {
+    let mut clock = MasterClock::ntsc();
+    assert_eq!(clock.advance_cpu(1), 3);
+    assert_eq!(clock.advance_cpu(7), 21);
+
+    //a register access 3 cycles in runs those dots early
+    assert_eq!(clock.sync_ppu(3), 9);
+    assert_eq!(clock.advance_cpu(4), 3);
+
+    //PAL dividers, 3.2 dots per cycle
+    let mut clock = MasterClock::new(16, 5);
+    assert_eq!(clock.advance_cpu(1), 3);
+    assert_eq!(clock.advance_cpu(1), 3);
+    assert_eq!(clock.advance_cpu(3), 10);
+    assert_eq!(clock.cpu_cycles(), 5);
+}
+
+//the dot the PPU draws next, counted from the top of the frame
+fn get_ppu_position(nes: &Nes) -> uint {
+    nes.cpu.ppu.scanline() * DOTS_PER_SCANLINE + nes.cpu.ppu.dot()
+}
+
+//runs the LDA $2002 at $8000 and returns what it read
+fn poll_ppu_status(nes: &mut Nes) -> u8 {
+    let mut state = nes.cpu_state();
+    state.PC = 0x8000;
+    nes.set_cpu_state(state);
+    nes.step_instruction();
+    nes.cpu_state().A
+}
+
+#[test]
+fn nes_ppu_register_sync_test() {
+    //LDA $2002, then NOPs
+    let rom = get_test_rom(&[0xAD, 0x02, 0x20]);
+    let vblank = Ntsc.vblank_scanline() * DOTS_PER_SCANLINE + 1;
+
+    //the read is on the 4th cycle, so vblank starting in the 9 dots before it is seen, and
+    //the read clears it
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    while get_ppu_position(&nes) < vblank - 8 { nes.step_instruction(); }
+    assert_eq!(poll_ppu_status(&mut nes) & 0x80, 0x80);
+    assert_eq!(nes.peek(0x2002) & 0x80, 0x00);
+
+    //any earlier and it starts after the read
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    while get_ppu_position(&nes) < vblank - 14 { nes.step_instruction(); }
+    assert_eq!(poll_ppu_status(&mut nes) & 0x80, 0x00);
+    nes.step_instruction();
+    assert_eq!(nes.peek(0x2002) & 0x80, 0x80);
+}
+
+#[test]
+fn nes_region_test() {
+    let mut bytes = TEST_ROM_HEADER;
+    assert_eq!(RomHeader::new(&bytes).unwrap().region(), Ntsc);
+
+    bytes[9] = 0x01;
+    assert_eq!(RomHeader::new(&bytes).unwrap().region(), Pal);
+
+    //NES 2.0 ignores flags 9
+    bytes[7] = 0x08;
+    bytes[12] = 0x03;
+    assert_eq!(RomHeader::new(&bytes).unwrap().region(), Dendy);
+    bytes[12] = 0x02;
+    assert_eq!(RomHeader::new(&bytes).unwrap().region(), Ntsc);
+
+    assert!((Ntsc.frame_rate() - 60.0988).abs() < 0.001);
+    assert!((Pal.frame_rate() - 50.0070).abs() < 0.001);
+    assert!((Dendy.frame_rate() - 50.0070).abs() < 0.001);
+}
+
+#[test]
+fn nes_pal_frame_test() {
+    //JMP $8000
+    let rom = get_test_rom(&[0x4C, 0x00, 0x80]);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.set_region(Pal);
+    nes.reset();
+
+    //312 scanlines at 3.2 dots per cycle
+    nes.run_frame();
+    let cycles = nes.cpu_cycles();
+    assert!(cycles >= 33247 && cycles <= 33251);
+
+    let samples = nes.run_frame().samples.len();
+    assert!(samples >= 880 && samples <= 884);
+}
+
+//LDA #$01, STA $4015, LDA #$BF, STA $4000, then forever INC $00 and play it on pulse 1
+static STATE_TEST_PROGRAM: [u8, ..20] = [
+    0xA9, 0x01, 0x8D, 0x15, 0x40, 0xA9, 0xBF, 0x8D, 0x00, 0x40,
+    0xE6, 0x00, 0xA5, 0x00, 0x8D, 0x02, 0x40, 0x4C, 0x0A, 0x80,
+];
+
+fn run_frames(nes: &mut Nes, frames: uint) -> Vec<f32> {
+    let mut samples = Vec::new();
+    for _ in range(0, frames) {
+        samples.push_all(nes.run_frame().samples.as_slice());
+    }
+    samples
+}
+
+#[test]
+fn nes_save_state_round_trip_test() {
+    let rom = get_test_rom(&STATE_TEST_PROGRAM);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+
+    run_frames(&mut nes, 10);
+    let state = nes.save_state();
+
+    let samples = run_frames(&mut nes, 5);
+    let framebuffer = nes.framebuffer().to_vec();
+    let after = nes.save_state();
+
+    assert_eq!(nes.load_state(state.as_slice()), Ok(()));
+    assert_eq!(nes.frame_count(), 10);
+
+    assert!(run_frames(&mut nes, 5) == samples);
+    assert!(nes.framebuffer() == framebuffer.as_slice());
+    assert!(nes.save_state() == after);
+}
+
+#[test]
+fn nes_save_state_reject_test() {
+    let rom = get_test_rom(&STATE_TEST_PROGRAM);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    run_frames(&mut nes, 2);
+    let state = nes.save_state();
+
+    //unknown chunks are skipped
+    let mut extended = state.clone();
+    let mut writer = StateWriter::new();
+    writer.chunk(b"XTRA", &[1, 2, 3]);
+    extended.push_all(writer.into_bytes().as_slice());
+    assert_eq!(nes.load_state(extended.as_slice()), Ok(()));
+
+    let other_rom = get_test_rom(&[0x4C, 0x00, 0x80]);
+    let mut other = Nes::from_bytes(other_rom.as_slice());
+    assert_eq!(other.load_state(state.as_slice()), Err(RomMismatch));
+
+    let mut bad_magic = state.clone();
+    *bad_magic.get_mut(0) = 'X' as u8;
+    assert_eq!(nes.load_state(bad_magic.as_slice()), Err(BadMagic));
+
+    let mut newer = state.clone();
+    *newer.get_mut(4) = 0xFF;
+    assert_eq!(nes.load_state(newer.as_slice()), Err(UnsupportedVersion(0xFF)));
+
+    let mut older = state.clone();
+    *older.get_mut(4) = 0x01;
+    assert_eq!(nes.load_state(older.as_slice()), Err(UnsupportedVersion(1)));
+
+    //a failed load leaves the console alone
+    run_frames(&mut nes, 1);
+    let before = nes.save_state();
+    assert_eq!(nes.load_state(state.slice_to(state.len() - 10)), Err(Truncated));
+    assert!(nes.save_state() == before);
+}
+
+#[test]
+fn nes_save_state_devices_test() {
+    let rom = get_test_rom(&STATE_TEST_PROGRAM);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.cpu.input.configure(0x02);
+    nes.cpu.apu.register_expansion(box Vrc6::new(false) as Box<ExpansionAudio>);
+    nes.reset();
+    run_frames(&mut nes, 1);
+
+    nes.cpu.apu.expansion_write(0x9000, 0x8F);
+    nes.cpu.apu.expansion_write(0x9002, 0x80);
+
+    //part way through reading out the Four Score
+    nes.cpu.input.set_player_buttons(2, BUTTON_A);
+    nes.cpu.input.write(1);
+    nes.cpu.input.write(0);
+    nes.cpu.input.read(0, &nes.cpu.ppu);
+    let state = nes.save_state();
+
+    //the console it's loaded into has the standard controllers
+    let mut other = Nes::from_bytes(rom.as_slice());
+    other.cpu.apu.register_expansion(box Vrc6::new(false) as Box<ExpansionAudio>);
+    assert_eq!(other.load_state(state.as_slice()), Ok(()));
+    assert_eq!(other.cpu.input.ports[0].name(), "fourscore");
+    assert_eq!(other.cpu.input.ports[1].name(), "fourscore");
+    assert!(other.save_state() == state);
+
+    for _ in range(0u, 24) {
+        assert_eq!(other.cpu.input.read(0, &other.cpu.ppu), nes.cpu.input.read(0, &nes.cpu.ppu));
+    }
+}
+
+#[test]
+fn nes_rewind_delta_test() {
+    let keyframe = Vec::from_fn(1000, |i| i as u8);
+    let mut state = keyframe.clone();
+    *state.get_mut(3) = 0xFF;
+    *state.get_mut(5) = 0xFF;
+    *state.get_mut(999) = 0x00;
+
+    let delta = encode_delta(keyframe.as_slice(), state.as_slice());
+    assert!(delta.len() < 16);
+    assert!(apply_delta(keyframe.as_slice(), delta.as_slice()) == state);
+    assert!(encode_delta(keyframe.as_slice(), keyframe.as_slice()).is_empty());
+
+    //the oldest group goes once the cap is reached, the newest state comes back first
+    let mut rewind = RewindBuffer::new(2500);
+    rewind.set_keyframe_interval(2);
+    for frame in range(0u, 6) {
+        let mut state = keyframe.clone();
+        *state.get_mut(0) = frame as u8;
+        rewind.push(frame, state);
+    }
+    assert_eq!(rewind.len(), 4);
+    assert!(rewind.used() <= 2500);
+
+    let (frame, state) = rewind.pop().unwrap();
+    assert_eq!(frame, 5);
+    assert_eq!(state[0], 5);
+    assert!(rewind.pop_before(4).unwrap()[0] == 3);
+    assert!(rewind.pop_before(2).is_none());
+    assert!(rewind.is_empty());
+}
+
+#[test]
+fn nes_rewind_test() {
+    let rom = get_test_rom(&STATE_TEST_PROGRAM);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    nes.enable_rewind(1, 1);
+
+    run_frames(&mut nes, 12);
+    let state = nes.save_state();
+    run_frames(&mut nes, 8);
+
+    for _ in range(0u, 8) {
+        assert!(nes.rewind_frame());
+    }
+    assert_eq!(nes.frame_count(), 12);
+    assert!(nes.save_state() == state);
+
+    //running forward again keeps capturing, the rewound states are gone
+    run_frames(&mut nes, 1);
+    assert!(nes.rewind_frame());
+    assert_eq!(nes.frame_count(), 11);
+}
+
+//asks for a reset the first time through, then passes with "OK"
+static BLARGG_TEST_PROGRAM: [u8, ..49] = [
+    0xAD, 0x10, 0x00,       //LDA $0010
+    0xD0, 0x1A,             //BNE $801F
+    0xEE, 0x10, 0x00,       //INC $0010
+    0xA9, 0xDE, 0x8D, 0x01, 0x60,
+    0xA9, 0xB0, 0x8D, 0x02, 0x60,
+    0xA9, 0x61, 0x8D, 0x03, 0x60,
+    0xA9, 0x81, 0x8D, 0x00, 0x60,
+    0x4C, 0x1C, 0x80,       //JMP $801C
+    0xA9, 0x4F, 0x8D, 0x04, 0x60,
+    0xA9, 0x4B, 0x8D, 0x05, 0x60,
+    0xA9, 0x00, 0x8D, 0x00, 0x60,
+    0x4C, 0x2E, 0x80,       //JMP $802E
+];
+
+#[test]
+fn nes_blargg_protocol_test() {
+    let rom = get_test_rom(&BLARGG_TEST_PROGRAM);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+
+    let result = run_test_rom(&mut nes, 60);
+    assert_eq!(result.status, TestRomPassed);
+    assert_eq!(result.message.as_slice(), "OK");
+    assert_eq!(result.frames, 8);
+
+    //without the signature it runs out of frames
+    let rom = get_test_rom(&[0x4C, 0x00, 0x80]);
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    let result = run_test_rom(&mut nes, 10);
+    assert_eq!(result.status, TestRomTimedOut);
+    assert!(result.message.is_empty());
+
+    //the status is the error code
+    let mut program = BLARGG_TEST_PROGRAM.to_vec();
+    *program.get_mut(42) = 0x03;
+    let rom = get_test_rom(program.as_slice());
+    let mut nes = Nes::from_bytes(rom.as_slice());
+    nes.reset();
+    assert_eq!(run_test_rom(&mut nes, 60).status, TestRomFailed(3));
+}
+
+//long enough for the slowest of them, apu_test takes around 40 seconds
+static BLARGG_MAX_FRAMES: uint = 60 * 60;
+
+//with RUSTNES_TEST_ROMS set a ROM missing from test_roms fails its test, otherwise it's
+//skipped, see test_roms/todo.md
+static TEST_ROMS_VAR: &'static str = "RUSTNES_TEST_ROMS";
+
+fn run_blargg_rom(name: &str) {
+    let path = Path::new("test_roms").join(name);
+    if !path.exists() && os::getenv(TEST_ROMS_VAR).is_none() {
+        println!("skipped {}, it isn't in test_roms", name);
+        return;
+    }
+    let bytes = match File::open(&path).read_to_end() {
+        Ok(bytes) => bytes,
+        Err(e) => { fail!("{} isn't in test_roms: {}", name, e); }
+    };
+
+    let mut header = [0u8, ..0x10];
+    for (i, &byte) in bytes.iter().take(0x10).enumerate() { header[i] = byte; }
+    match RomHeader::new(&header) {
+        Some(ref header) if header.mapper_number() == 0 => { }
+        Some(ref header) => { fail!("{} needs mapper {}, only NROM is emulated", name, header.mapper_number()); }
+        None => { fail!("{} isn't an iNES ROM", name); }
+    }
+
+    let mut nes = Nes::new(path);
+    nes.reset();
+
+    let result = run_test_rom(&mut nes, BLARGG_MAX_FRAMES);
+    if !result.passed() {
+        fail!("{}: {} after {} frames\n{}", name, result.status, result.frames, result.message);
+    }
+}
+
+macro_rules! blargg_test(
+    ($name:ident, $rom:expr) => (
+        #[test]
+        fn $name() {
+            run_blargg_rom($rom);
+        }
+    );
+)
+
+//the single test ROMs, the combined ones are MMC1
+blargg_test!(nes_blargg_instr_basics_test, "instr_test-v5/rom_singles/01-basics.nes")
+blargg_test!(nes_blargg_instr_implied_test, "instr_test-v5/rom_singles/02-implied.nes")
+blargg_test!(nes_blargg_instr_immediate_test, "instr_test-v5/rom_singles/03-immediate.nes")
+blargg_test!(nes_blargg_instr_zero_page_test, "instr_test-v5/rom_singles/04-zero_page.nes")
+blargg_test!(nes_blargg_instr_zp_xy_test, "instr_test-v5/rom_singles/05-zp_xy.nes")
+blargg_test!(nes_blargg_instr_absolute_test, "instr_test-v5/rom_singles/06-absolute.nes")
+blargg_test!(nes_blargg_instr_abs_xy_test, "instr_test-v5/rom_singles/07-abs_xy.nes")
+blargg_test!(nes_blargg_instr_ind_x_test, "instr_test-v5/rom_singles/08-ind_x.nes")
+blargg_test!(nes_blargg_instr_ind_y_test, "instr_test-v5/rom_singles/09-ind_y.nes")
+blargg_test!(nes_blargg_instr_branches_test, "instr_test-v5/rom_singles/10-branches.nes")
+blargg_test!(nes_blargg_instr_stack_test, "instr_test-v5/rom_singles/11-stack.nes")
+blargg_test!(nes_blargg_instr_jmp_jsr_test, "instr_test-v5/rom_singles/12-jmp_jsr.nes")
+blargg_test!(nes_blargg_instr_rts_test, "instr_test-v5/rom_singles/13-rts.nes")
+blargg_test!(nes_blargg_instr_rti_test, "instr_test-v5/rom_singles/14-rti.nes")
+blargg_test!(nes_blargg_instr_brk_test, "instr_test-v5/rom_singles/15-brk.nes")
+blargg_test!(nes_blargg_instr_special_test, "instr_test-v5/rom_singles/16-special.nes")
+blargg_test!(nes_blargg_apu_len_ctr_test, "apu_test/rom_singles/1-len_ctr.nes")
+blargg_test!(nes_blargg_apu_len_table_test, "apu_test/rom_singles/2-len_table.nes")
+blargg_test!(nes_blargg_apu_irq_flag_test, "apu_test/rom_singles/3-irq_flag.nes")
+blargg_test!(nes_blargg_cpu_cli_latency_test, "cpu_interrupts_v2/rom_singles/1-cli_latency.nes")
+blargg_test!(nes_blargg_ppu_vbl_basics_test, "ppu_vbl_nmi/rom_singles/01-vbl_basics.nes")
+
+//JMP $8000, the screen stays the backdrop
+static GOLDEN_TEST_PROGRAM: [u8, ..3] = [0x4C, 0x00, 0x80];
+
+static GOLDEN_FRAMES: uint = 2;
+
+#[test]
+fn nes_golden_backdrop_test() {
+    let mut nes = Nes::from_bytes(get_test_rom(&GOLDEN_TEST_PROGRAM).as_slice());
+    nes.reset();
+
+    let rgb = render_frames(&mut nes, GOLDEN_FRAMES, None);
+    match compare_png(rgb.as_slice(), &Path::new("test_roms/golden/backdrop.png")) {
+        Ok(()) => { }
+        Err(e) => { fail!("backdrop.png: {}", e); }
+    }
+}
+
+#[test]
+fn nes_golden_mismatch_test() {
+    let mut nes = Nes::from_bytes(get_test_rom(&GOLDEN_TEST_PROGRAM).as_slice());
+    nes.reset();
+    let rgb = render_frames(&mut nes, GOLDEN_FRAMES, None);
+
+    let dir = TempDir::new("rustnes_golden").unwrap();
+    let golden = dir.path().join("screen.png");
+
+    assert_eq!(compare_png(rgb.as_slice(), &golden), Err(GoldenMissing(golden.display().to_string())));
+    assert!(output_path(&golden, "actual").exists());
+
+    let mut expected = rgb.clone();
+    let pixel = (10 * SCREEN_WIDTH + 20) * 3;
+    *expected.get_mut(pixel) = expected[pixel] ^ 0xFF;
+    png::write(&golden, SCREEN_WIDTH, SCREEN_HEIGHT, expected.as_slice()).unwrap();
+
+    assert_eq!(compare_png(rgb.as_slice(), &golden), Err(PixelMismatch(1, (20, 10))));
+    assert!(output_path(&golden, "expected").exists());
+
+    let (_, _, diff) = png::read(&output_path(&golden, "diff")).unwrap();
+    assert!(diff.slice(pixel, pixel + 3) == &[0xFF, 0x00, 0x00]);
+    assert!(diff.slice(0, 3) == &[rgb[0] / 4, rgb[1] / 4, rgb[2] / 4]);
+
+    png::write(&golden, SCREEN_WIDTH, SCREEN_HEIGHT, rgb.as_slice()).unwrap();
+    assert_eq!(compare_png(rgb.as_slice(), &golden), Ok(()));
+
+    assert_eq!(compare_hash(rgb.as_slice(), hash(rgb.as_slice())), Ok(()));
+    assert_eq!(compare_hash(rgb.as_slice(), 0), Err(HashMismatch(0, hash(rgb.as_slice()))));
+}
+
+#[test]
+fn nes_peek_poke_test() {
+    //JMP $8000
+    let mut nes = Nes::from_bytes(get_test_rom(&[0x4C, 0x00, 0x80]).as_slice());
+    nes.reset();
+
+    //peeking $2002 leaves vblank set
+    while nes.peek(0x2002) & 0x80 == 0 { nes.step_instruction(); }
+    assert_eq!(nes.peek(0x2002) & 0x80, 0x80);
+    assert_eq!(nes.peek(0x200A) & 0x80, 0x80);
+
+    //poking $2000 doesn't raise NMI even in vblank
+    assert!(nes.poke(0x2000, 0x80));
+    assert_eq!(nes.peek(0x2000), 0x80);
+    assert!(!nes.cpu.ppu.take_nmi());
+    assert!(!nes.poke(0x2002, 0x00));
+
+    //RAM mirrors, PRG-RAM and PRG-ROM
+    assert!(nes.poke(0x0801, 0xAA));
+    assert_eq!(nes.peek(0x0001), 0xAA);
+    assert!(nes.poke(0x6000, 0xBB));
+    assert_eq!(nes.peek(0x6000), 0xBB);
+    assert!(nes.poke(0x8001, 0x04));
+    assert_eq!(nes.disassemble_pc().as_slice(), "JMP $8004");
+    assert!(!nes.poke(0x4016, 0x01));
+
+    assert!(nes.poke_ppu(0x2400, 0x12));
+    assert_eq!(nes.peek_ppu(0x2000), 0x12);
+    assert_eq!(nes.peek_ppu(0x6000), 0x12);
+
+    nes.poke_palette(0x10, 0x21);
+    assert_eq!(nes.peek_palette(0x00), 0x21);
+    assert_eq!(nes.peek_ppu(0x3F20), 0x21);
+
+    nes.poke_oam(0xFF, 0x34);
+    assert_eq!(nes.peek_oam(0xFF), 0x34);
+
+    assert!(nes.mapper_registers().is_empty());
+}
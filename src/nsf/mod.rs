@@ -0,0 +1,531 @@
+use std::io::{File, IoResult};
+
+use nes::{CHR_ROM_BANK_SIZE, PRG_RAM_BANK_SIZE};
+use nes::{VAddr};
+use nes::{Region, Ntsc, Pal};
+
+use cpu::Cpu;
+
+use ppu::Ppu;
+
+use apu::{PcmWriter, PcmFormat, DEFAULT_SAMPLE_RATE};
+use apu::{ExpansionAudio};
+use apu::expansion::{Vrc6, Vrc7, FdsAudio, Mmc5Audio, Namco163, Sunsoft5b};
+
+use mapper::{Mapper};
+
+pub mod nsfe;
+
+#[cfg(test)]
+mod test;
+
+/// # NSF
+///
+/// from http://wiki.nesdev.com/w/index.php/NSF
+///
+///  offset  size  field
+///  $000    5     "NESM", $1A
+///  $005    1     Version
+///  $006    1     Total songs
+///  $007    1     Starting song (1 based)
+///  $008    2     Load address
+///  $00A    2     Init address
+///  $00C    2     Play address
+///  $00E    32    Song name
+///  $02E    32    Artist
+///  $04E    32    Copyright
+///  $06E    2     NTSC play speed, in 1/1000000 second ticks
+///  $070    8     Bankswitch init values
+///  $078    2     PAL play speed, in 1/1000000 second ticks
+///  $07A    1     PAL/NTSC bits
+///  $07B    1     Extra sound chip support
+///  $07C    1     NSF2 flags
+///  $07D    3     Program data length, 0 if not specified
+///  $080          Program data
+///
+/// ## PAL/NTSC bits
+///
+/// 76543210
+/// ||||||||
+/// ||||||+- 0: NTSC; 1: PAL
+/// |||||+-- 1: dual PAL/NTSC
+/// ++++++--- Reserved, set to zero
+///
+/// ## Extra sound chip support
+///
+/// 76543210
+/// ||||||||
+/// |||||||+- VRC6
+/// ||||||+-- VRC7
+/// |||||+--- FDS
+/// ||||+---- MMC5
+/// |||+----- Namco 163
+/// ||+------ Sunsoft 5B
+/// ++------- Reserved, set to zero
+///
+/// # Playing
+///
+/// To start a song the player clears RAM, silences the APU, sets up the banks, and calls INIT
+/// with the song number (0 based) in A and 0 for NTSC or 1 for PAL in X. After INIT returns,
+/// PLAY is called at the header's play speed. There is no PPU, so nothing else drives the
+/// timing.
+///
+/// A routine that's still running when the next call is due carries on in the next frame
+/// instead, PLAY isn't called again until it returns.
+
+static NSF_HEADER_SIZE: uint = 0x80;
+static NSF_BANK_SIZE: uint = 0x1000; //4 KB
+
+pub static DEFAULT_NTSC_SPEED: u16 = 16639; //~60.1 Hz
+pub static DEFAULT_PAL_SPEED: u16 = 19997; //~50 Hz
+
+//INIT gets a generous budget since some drivers decompress or build tables there
+static INIT_MAX_CYCLES: uint = 1789773; //one second
+
+pub static DEFAULT_TRACK_LENGTH: uint = 150000; //in ms
+pub static DEFAULT_TRACK_FADE: uint = 8000; //in ms
+
+bitflags!(
+    flags ExpansionChips: u8 {
+        static VRC6_CHIP    = 0b00000001,
+        static VRC7_CHIP    = 0b00000010,
+        static FDS_CHIP     = 0b00000100,
+        static MMC5_CHIP    = 0b00001000,
+        static N163_CHIP    = 0b00010000,
+        static S5B_CHIP     = 0b00100000
+    }
+)
+
+/// Metadata from the NSF header, or from NSFe's auth, tlbl, time, fade and plst chunks.
+#[deriving(Clone, Show)]
+pub struct NsfMetadata {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    pub track_names: Vec<String>,
+    pub track_times: Vec<Option<uint>>, //in ms
+    pub track_fades: Vec<Option<uint>>, //in ms
+    pub playlist: Vec<u8>,
+}
+
+impl NsfMetadata {
+    pub fn new() -> NsfMetadata {
+        NsfMetadata {
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: String::new(),
+            track_names: Vec::new(),
+            track_times: Vec::new(),
+            track_fades: Vec::new(),
+            playlist: Vec::new(),
+        }
+    }
+}
+
+pub struct Nsf {
+    pub load_addr: VAddr,
+    pub init_addr: VAddr,
+    pub play_addr: VAddr,
+    pub total_songs: uint,
+    pub starting_song: uint, //0 based
+    pub bank_init: [u8, ..8],
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    pub pal: bool,
+    pub dual: bool,
+    pub chips: ExpansionChips,
+    pub data: Vec<u8>,
+    pub metadata: NsfMetadata,
+}
+
+impl Nsf {
+    /// Parses either an NSF or an NSFe image.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Nsf> {
+        if bytes.starts_with(b"NESM\x1a") {
+            Nsf::parse_nsf(bytes)
+        } else if bytes.starts_with(b"NSFE") {
+            nsfe::parse(bytes)
+        } else {
+            None
+        }
+    }
+
+    fn parse_nsf(bytes: &[u8]) -> Option<Nsf> {
+        if bytes.len() < NSF_HEADER_SIZE { return None; }
+
+        let mut bank_init = [0u8, ..8];
+        for i in range(0u, 8) {
+            bank_init[i] = bytes[0x70 + i];
+        }
+
+        let data_length = read_u24(bytes, 0x7D);
+        let data =
+            if data_length == 0 || NSF_HEADER_SIZE + data_length > bytes.len() {
+                bytes.slice_from(NSF_HEADER_SIZE).to_vec()
+            } else {
+                bytes.slice(NSF_HEADER_SIZE, NSF_HEADER_SIZE + data_length).to_vec()
+            };
+
+        let mut metadata = NsfMetadata::new();
+        metadata.title = c_string(bytes.slice(0x0E, 0x2E));
+        metadata.artist = c_string(bytes.slice(0x2E, 0x4E));
+        metadata.copyright = c_string(bytes.slice(0x4E, 0x6E));
+
+        let nsf = Nsf {
+            load_addr: read_u16(bytes, 0x08),
+            init_addr: read_u16(bytes, 0x0A),
+            play_addr: read_u16(bytes, 0x0C),
+            total_songs: bytes[0x06] as uint,
+            starting_song: if bytes[0x07] > 0 { bytes[0x07] as uint - 1 } else { 0 },
+            bank_init: bank_init,
+            ntsc_speed: read_u16(bytes, 0x6E),
+            pal_speed: read_u16(bytes, 0x78),
+            pal: bytes[0x7A] & 0x01 != 0,
+            dual: bytes[0x7A] & 0x02 != 0,
+            chips: ExpansionChips::from_bits_truncate(bytes[0x7B]),
+            data: data,
+            metadata: metadata,
+        };
+
+        if nsf.is_valid() { Some(nsf) } else { None }
+    }
+
+    fn is_valid(&self) -> bool {
+        if self.total_songs == 0 { return false; }
+        if self.data.is_empty() { return false; }
+        if !self.is_bankswitched() && self.load_addr < 0x8000 { return false; }
+
+        true
+    }
+
+    /// Any nonzero bankswitch init value means the tune uses $5FF8-$5FFF.
+    pub fn is_bankswitched(&self) -> bool {
+        self.bank_init.iter().any(|&bank| bank != 0)
+    }
+
+    //PAL only tunes get PAL timing, dual and NTSC tunes play at NTSC rate
+    pub fn plays_pal(&self) -> bool {
+        self.pal && !self.dual
+    }
+
+    pub fn region(&self) -> Region {
+        if self.plays_pal() { Pal } else { Ntsc }
+    }
+
+    //in CPU cycles
+    pub fn play_period(&self) -> f64 {
+        let speed =
+            if self.plays_pal() {
+                if self.pal_speed == 0 { DEFAULT_PAL_SPEED } else { self.pal_speed }
+            } else {
+                if self.ntsc_speed == 0 { DEFAULT_NTSC_SPEED } else { self.ntsc_speed }
+            };
+
+        (speed as f64) * self.region().cpu_clock_rate() / 1000000.0
+    }
+
+    pub fn track_name(&self, track: uint) -> Option<String> {
+        if track < self.metadata.track_names.len() {
+            Some(self.metadata.track_names[track].clone())
+        } else {
+            None
+        }
+    }
+
+    /// The tracks (0 based) in the order they're meant to be played, NSFe's plst chunk if
+    /// there is one, otherwise all of them. Entries past the last track are left out.
+    pub fn playlist(&self) -> Vec<uint> {
+        if self.metadata.playlist.is_empty() {
+            range(0, self.total_songs).collect()
+        } else {
+            self.metadata.playlist.iter()
+                .map(|&track| track as uint)
+                .filter(|&track| track < self.total_songs)
+                .collect()
+        }
+    }
+
+    //length and fade for a track in ms, NSFe time/fade chunks win over the defaults
+    pub fn track_length(&self, track: uint) -> (uint, uint) {
+        let time = if track < self.metadata.track_times.len() { self.metadata.track_times[track] } else { None };
+        let fade = if track < self.metadata.track_fades.len() { self.metadata.track_fades[track] } else { None };
+
+        (time.unwrap_or(DEFAULT_TRACK_LENGTH), fade.unwrap_or(DEFAULT_TRACK_FADE))
+    }
+}
+
+/// # NSF mapper
+///
+/// - $5FF8-$5FFF - Bank select for $8000-$8FFF through $F000-$FFFF
+/// - $6000-$7FFF - RAM
+/// - $8000-$FFFF - Program data in 4 KB banks
+///
+/// With bankswitching the data is padded by (load address & $0FFF) so bank 0 starts on a 4 KB
+/// boundary. Without it the data is just placed at the load address.
+pub struct NsfMapper {
+    data: Vec<u8>,
+    banks: [u8, ..8],
+    bankswitched: bool,
+    prg_ram: [u8, ..PRG_RAM_BANK_SIZE],
+    chips: ExpansionChips,
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &Nsf) -> NsfMapper {
+        let bankswitched = nsf.is_bankswitched();
+
+        let padding =
+            if bankswitched { (nsf.load_addr & 0x0FFF) as uint }
+            else { (nsf.load_addr - 0x8000) as uint };
+
+        let mut data = Vec::from_elem(padding, 0u8);
+        data.push_all(nsf.data.as_slice());
+
+        NsfMapper {
+            data: data,
+            banks: if bankswitched { nsf.bank_init } else { [0, 1, 2, 3, 4, 5, 6, 7] },
+            bankswitched: bankswitched,
+            prg_ram: [0u8, ..PRG_RAM_BANK_SIZE],
+            chips: nsf.chips,
+        }
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn prg_read(&mut self, virtual_address: VAddr) -> u8 {
+        self.prg_peek(virtual_address)
+    }
+
+    fn prg_peek(&self, virtual_address: VAddr) -> u8 {
+        if virtual_address < 0x6000 {
+            0x00
+        } else if virtual_address < 0x8000 {
+            self.prg_ram[(virtual_address & 0x1FFF) as uint]
+        } else {
+            let bank = self.banks[((virtual_address - 0x8000) >> 12) as uint] as uint;
+            let address = bank * NSF_BANK_SIZE + (virtual_address & 0x0FFF) as uint;
+            if address < self.data.len() { self.data[address] } else { 0x00 }
+        }
+    }
+
+    fn prg_write(&mut self, virtual_address: VAddr, val: u8) {
+        if virtual_address >= 0x5FF8 && virtual_address < 0x6000 {
+            if self.bankswitched {
+                self.banks[(virtual_address - 0x5FF8) as uint] = val;
+            }
+        } else if virtual_address >= 0x6000 && virtual_address < 0x8000 {
+            self.prg_ram[(virtual_address & 0x1FFF) as uint] = val;
+        }
+    }
+
+    //the bank registers only change the banks, so poking them is the same as writing them
+    fn prg_poke(&mut self, virtual_address: VAddr, val: u8) -> bool {
+        if virtual_address < 0x5FF8 {
+            false
+        } else if virtual_address < 0x6000 {
+            self.prg_write(virtual_address, val);
+            self.bankswitched
+        } else if virtual_address < 0x8000 {
+            self.prg_write(virtual_address, val);
+            true
+        } else {
+            let bank = self.banks[((virtual_address - 0x8000) >> 12) as uint] as uint;
+            let address = bank * NSF_BANK_SIZE + (virtual_address & 0x0FFF) as uint;
+            if address < self.data.len() {
+                *self.data.get_mut(address) = val;
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    //the NSF data stands in for PRG-ROM
+    fn prg_rom_offset(&self, virtual_address: VAddr) -> Option<uint> {
+        if virtual_address < 0x8000 { return None; }
+
+        let bank = self.banks[((virtual_address - 0x8000) >> 12) as uint] as uint;
+        let address = bank * NSF_BANK_SIZE + (virtual_address & 0x0FFF) as uint;
+        if address < self.data.len() { Some(address) } else { None }
+    }
+
+    fn registers(&self) -> Vec<(String, u8)> {
+        range(0u, 8).map(|i| (format!("${:04X}", 0x5FF8 + i), self.banks[i])).collect()
+    }
+
+    //NSF rips use the VRC6a register layout
+    fn audio_chips(&self) -> Vec<Box<ExpansionAudio>> {
+        let mut chips = Vec::new();
+        if self.chips.contains(VRC6_CHIP) { chips.push(box Vrc6::new(false) as Box<ExpansionAudio>); }
+        if self.chips.contains(VRC7_CHIP) { chips.push(box Vrc7::new() as Box<ExpansionAudio>); }
+        if self.chips.contains(FDS_CHIP) { chips.push(box FdsAudio::new() as Box<ExpansionAudio>); }
+        if self.chips.contains(MMC5_CHIP) { chips.push(box Mmc5Audio::new() as Box<ExpansionAudio>); }
+        if self.chips.contains(N163_CHIP) { chips.push(box Namco163::new() as Box<ExpansionAudio>); }
+        if self.chips.contains(S5B_CHIP) { chips.push(box Sunsoft5b::new() as Box<ExpansionAudio>); }
+        chips
+    }
+}
+
+pub struct NsfPlayer {
+    nsf: Nsf,
+    cpu: Cpu,
+    track: uint,
+    sample_rate: uint,
+    play_period: f64,
+    play_remainder: f64,
+
+    //INIT or PLAY didn't return within its cycles
+    running: bool,
+
+    //cycles the last instruction of a frame ran past its end, they come off the next frame
+    overrun: uint,
+}
+
+impl NsfPlayer {
+    pub fn new(path: Path) -> NsfPlayer {
+        info!("Nsf Path: {}", path.display());
+
+        let bytes = File::open(&path).read_to_end().unwrap();
+        let nsf = Nsf::from_bytes(bytes.as_slice()).expect("Bad NSF");
+
+        NsfPlayer::from_nsf(nsf)
+    }
+
+    pub fn from_nsf(nsf: Nsf) -> NsfPlayer {
+        let track = nsf.starting_song;
+        let play_period = nsf.play_period();
+        let cpu = NsfPlayer::new_cpu(&nsf);
+
+        let mut player = NsfPlayer {
+            nsf: nsf,
+            cpu: cpu,
+            track: track,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            play_period: play_period,
+            play_remainder: 0.0,
+            running: false,
+            overrun: 0,
+        };
+        player.select_track(track);
+        player
+    }
+
+    fn new_cpu(nsf: &Nsf) -> Cpu {
+        let mapper = box NsfMapper::new(nsf) as Box<Mapper>;
+        let mut cpu = Cpu::new(mapper, Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]));
+        cpu.apu.set_region(nsf.region());
+        cpu
+    }
+
+    pub fn nsf<'a>(&'a self) -> &'a Nsf {
+        &self.nsf
+    }
+
+    pub fn track(&self) -> uint {
+        self.track
+    }
+
+    pub fn track_count(&self) -> uint {
+        self.nsf.total_songs
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: uint) {
+        self.sample_rate = sample_rate;
+        self.cpu.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Starts `track` (0 based) from a freshly powered up machine and runs its INIT routine.
+    pub fn select_track(&mut self, track: uint) {
+        self.track = track;
+        self.cpu = NsfPlayer::new_cpu(&self.nsf);
+        self.cpu.apu.set_sample_rate(self.sample_rate);
+        self.play_remainder = 0.0;
+        self.overrun = 0;
+
+        for addr in range(0x4000u16, 0x4014) {
+            self.cpu.write_byte(addr, 0x00);
+        }
+        self.cpu.write_byte(0x4015, 0x00);
+        self.cpu.write_byte(0x4015, 0x0F);
+        self.cpu.write_byte(0x4017, 0x40);
+
+        let region = if self.nsf.plays_pal() { 1 } else { 0 };
+        let init_addr = self.nsf.init_addr;
+        self.cpu.call_subroutine(init_addr, track as u8, region, INIT_MAX_CYCLES);
+        self.running = !self.cpu.subroutine_returned();
+    }
+
+    /// Calls PLAY once, then idles the APU until the next call is due. If INIT or PLAY hasn't
+    /// returned yet it runs on instead.
+    pub fn play_frame(&mut self) {
+        let period = self.play_period + self.play_remainder;
+        let cycles = period as uint;
+        self.play_remainder = period - (cycles as f64);
+        let cycles = if self.overrun < cycles { cycles - self.overrun } else { 0 };
+
+        let used =
+            if self.running {
+                self.cpu.resume_subroutine(cycles)
+            } else {
+                let play_addr = self.nsf.play_addr;
+                self.cpu.call_subroutine(play_addr, 0, 0, cycles)
+            };
+        self.running = !self.cpu.subroutine_returned();
+        if used < cycles {
+            self.cpu.step_apu(cycles - used);
+            self.overrun = 0;
+        } else {
+            self.overrun = used - cycles;
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.apu.take_samples()
+    }
+
+    /// Renders `track` for `length` ms, fading out linearly over the last `fade` ms.
+    pub fn render_track(&mut self, track: uint, length: uint, fade: uint, path: &Path, format: PcmFormat) -> IoResult<()> {
+        self.select_track(track);
+
+        let total_samples = length * self.sample_rate / 1000;
+        let fade_samples = fade * self.sample_rate / 1000;
+        let fade_start = if fade_samples < total_samples { total_samples - fade_samples } else { 0 };
+
+        let mut writer = try!(PcmWriter::create(path, format, self.sample_rate, 1));
+
+        let mut written: uint = 0;
+        while written < total_samples {
+            self.play_frame();
+
+            let mut samples = self.take_samples();
+            samples.truncate(total_samples - written);
+            for (i, sample) in samples.mut_iter().enumerate() {
+                let pos = written + i;
+                if pos >= fade_start {
+                    *sample *= ((total_samples - pos) as f32) / ((total_samples - fade_start) as f32);
+                }
+            }
+
+            try!(writer.write_samples(samples.as_slice()));
+            written += samples.len();
+        }
+
+        writer.finish()
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: uint) -> u16 {
+    (bytes[offset + 1] as u16) << 8 | (bytes[offset] as u16)
+}
+
+fn read_u24(bytes: &[u8], offset: uint) -> uint {
+    (bytes[offset + 2] as uint) << 16 | (bytes[offset + 1] as uint) << 8 | (bytes[offset] as uint)
+}
+
+//reads a NUL terminated (or padded) string
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(bytes.slice_to(end)).into_string()
+}
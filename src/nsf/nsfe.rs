@@ -0,0 +1,137 @@
+use nsf::{Nsf, NsfMetadata, ExpansionChips};
+use nsf::{read_u16, c_string};
+
+/// # NSFe
+///
+/// from http://wiki.nesdev.com/w/index.php/NSFe
+///
+/// An NSFe file is "NSFE" followed by chunks, each one a 4 byte little endian length, a 4 byte
+/// id and then the chunk data. Chunks whose id starts with an upper case letter are required
+/// to be understood, unknown lower case chunks can be skipped.
+///
+/// - INFO - load, init and play address, PAL/NTSC bits, sound chips, song count, first song
+/// - DATA - program data
+/// - BANK - bankswitch init values
+/// - RATE - NTSC, PAL and Dendy play speed
+/// - NEND - end of file
+/// - auth - NUL terminated game title, artist, copyright and ripper
+/// - tlbl - NUL terminated track names
+/// - time - signed 4 byte track lengths in ms, negative for unknown
+/// - fade - signed 4 byte fade lengths in ms, negative for unknown
+/// - plst - playlist of track numbers, see Nsf::playlist
+
+pub fn parse(bytes: &[u8]) -> Option<Nsf> {
+    if !bytes.starts_with(b"NSFE") { return None; }
+
+    let mut nsf = Nsf {
+        load_addr: 0,
+        init_addr: 0,
+        play_addr: 0,
+        total_songs: 1,
+        starting_song: 0,
+        bank_init: [0u8, ..8],
+        ntsc_speed: 0,
+        pal_speed: 0,
+        pal: false,
+        dual: false,
+        chips: ExpansionChips::empty(),
+        data: Vec::new(),
+        metadata: NsfMetadata::new(),
+    };
+
+    let mut has_info = false;
+    let mut pos: uint = 4;
+
+    while pos + 8 <= bytes.len() {
+        let length = read_u32(bytes, pos) as uint;
+        let id = String::from_utf8_lossy(bytes.slice(pos + 4, pos + 8)).into_string();
+        pos += 8;
+
+        if pos + length > bytes.len() { return None; }
+        let chunk = bytes.slice(pos, pos + length);
+        pos += length;
+
+        match id.as_slice() {
+            "INFO" => {
+                if chunk.len() < 8 { return None; }
+                nsf.load_addr = read_u16(chunk, 0);
+                nsf.init_addr = read_u16(chunk, 2);
+                nsf.play_addr = read_u16(chunk, 4);
+                nsf.pal = chunk[6] & 0x01 != 0;
+                nsf.dual = chunk[6] & 0x02 != 0;
+                nsf.chips = ExpansionChips::from_bits_truncate(chunk[7]);
+                if chunk.len() > 8 { nsf.total_songs = chunk[8] as uint; }
+                if chunk.len() > 9 { nsf.starting_song = chunk[9] as uint; }
+                has_info = true;
+            }
+            "DATA" => {
+                nsf.data = chunk.to_vec();
+            }
+            "BANK" => {
+                for (i, &bank) in chunk.iter().take(8).enumerate() {
+                    nsf.bank_init[i] = bank;
+                }
+            }
+            "RATE" => {
+                if chunk.len() >= 2 { nsf.ntsc_speed = read_u16(chunk, 0); }
+                if chunk.len() >= 4 { nsf.pal_speed = read_u16(chunk, 2); }
+            }
+            "NEND" => { break; }
+            "auth" => {
+                let mut strings = split_strings(chunk).move_iter();
+                nsf.metadata.title = strings.next().unwrap_or(String::new());
+                nsf.metadata.artist = strings.next().unwrap_or(String::new());
+                nsf.metadata.copyright = strings.next().unwrap_or(String::new());
+                nsf.metadata.ripper = strings.next().unwrap_or(String::new());
+            }
+            "tlbl" => {
+                nsf.metadata.track_names = split_strings(chunk);
+            }
+            "time" => {
+                nsf.metadata.track_times = read_times(chunk);
+            }
+            "fade" => {
+                nsf.metadata.track_fades = read_times(chunk);
+            }
+            "plst" => {
+                nsf.metadata.playlist = chunk.to_vec();
+            }
+            _ => {
+                if id.as_slice().chars().next().map_or(false, |c| c.is_uppercase()) {
+                    error!("Unknown required NSFe chunk: {}", id);
+                    return None;
+                }
+            }
+        }
+    }
+
+    if !has_info { return None; }
+    if nsf.starting_song >= nsf.total_songs { nsf.starting_song = 0; }
+
+    if nsf.is_valid() { Some(nsf) } else { None }
+}
+
+fn read_u32(bytes: &[u8], offset: uint) -> u32 {
+    (bytes[offset + 3] as u32) << 24 | (bytes[offset + 2] as u32) << 16 |
+    (bytes[offset + 1] as u32) << 8 | (bytes[offset] as u32)
+}
+
+fn split_strings(chunk: &[u8]) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut rest = chunk;
+
+    while !rest.is_empty() {
+        let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        strings.push(c_string(rest.slice_to(end)));
+        rest = if end < rest.len() { rest.slice_from(end + 1) } else { rest.slice_from(end) };
+    }
+
+    strings
+}
+
+fn read_times(chunk: &[u8]) -> Vec<Option<uint>> {
+    range(0, chunk.len() / 4).map(|i| {
+        let time = read_u32(chunk, i * 4) as i32;
+        if time < 0 { None } else { Some(time as uint) }
+    }).collect()
+}
@@ -0,0 +1,204 @@
+use mapper::Mapper;
+use apu::ExpansionAudio;
+
+use nsf::{Nsf, NsfMapper, NsfPlayer, DEFAULT_TRACK_LENGTH, DEFAULT_TRACK_FADE};
+use nsf::{VRC6_CHIP, N163_CHIP};
+
+fn get_nsf_bytes(load_addr: u16, bank_init: [u8, ..8], data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::from_elem(0x80, 0u8);
+    bytes.push_all(data);
+
+    {
+        let header = bytes.as_mut_slice();
+        header[0x00] = 'N' as u8;
+        header[0x01] = 'E' as u8;
+        header[0x02] = 'S' as u8;
+        header[0x03] = 'M' as u8;
+        header[0x04] = 0x1A;
+        header[0x05] = 0x01;
+        header[0x06] = 0x03; //3 songs
+        header[0x07] = 0x02; //start at song 2
+        header[0x08] = (load_addr & 0xFF) as u8;
+        header[0x09] = (load_addr >> 8) as u8;
+        header[0x0A] = 0x00; //INIT $8000
+        header[0x0B] = 0x80;
+        header[0x0C] = 0x03; //PLAY $8003
+        header[0x0D] = 0x80;
+        header[0x0E] = 'S' as u8;
+        header[0x0F] = 'o' as u8;
+        header[0x10] = 'n' as u8;
+        header[0x11] = 'g' as u8;
+        for i in range(0u, 8) {
+            header[0x70 + i] = bank_init[i];
+        }
+        header[0x7B] = 0x11; //VRC6 and Namco 163
+    }
+
+    bytes
+}
+
+fn push_chunk(bytes: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+    let length = data.len();
+    bytes.push_all(&[length as u8, (length >> 8) as u8, (length >> 16) as u8, (length >> 24) as u8]);
+    bytes.push_all(id);
+    bytes.push_all(data);
+}
+
+fn get_nsfe_bytes() -> Vec<u8> {
+    get_nsfe_bytes_with_info(&[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0x00, 0x00, 0x02, 0x01])
+}
+
+fn get_nsfe_bytes_with_info(info: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.push_all(b"NSFE");
+
+    push_chunk(&mut bytes, b"INFO", info);
+    push_chunk(&mut bytes, b"DATA", &[0x60, 0x60, 0x60, 0x60]);
+    push_chunk(&mut bytes, b"tlbl", b"Title\x00Ending\x00");
+    push_chunk(&mut bytes, b"time", &[0x10, 0x27, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]); //10000 ms, unknown
+    push_chunk(&mut bytes, b"plst", &[0x01, 0x00, 0x05]);
+    push_chunk(&mut bytes, b"NEND", &[]);
+
+    bytes
+}
+
+#[test]
+fn nsf_header_test() {
+    let bytes = get_nsf_bytes(0x8000, [0u8, ..8], &[0x60, 0x60, 0x60, 0x60]);
+    let nsf = Nsf::from_bytes(bytes.as_slice()).unwrap();
+
+    assert_eq!(nsf.load_addr, 0x8000);
+    assert_eq!(nsf.init_addr, 0x8000);
+    assert_eq!(nsf.play_addr, 0x8003);
+    assert_eq!(nsf.total_songs, 3);
+    assert_eq!(nsf.starting_song, 1);
+    assert_eq!(nsf.metadata.title.as_slice(), "Song");
+    assert!(nsf.chips == VRC6_CHIP | N163_CHIP);
+    assert_eq!(nsf.is_bankswitched(), false);
+    assert_eq!(nsf.data.len(), 4);
+
+    let bad_bytes = [0u8, ..0x84];
+    assert!(Nsf::from_bytes(bad_bytes.as_slice()).is_none());
+}
+
+#[test]
+fn nsf_mapper_test() {
+    let bytes = get_nsf_bytes(0x8010, [0u8, ..8], &[0xAA, 0xBB]);
+    let nsf = Nsf::from_bytes(bytes.as_slice()).unwrap();
+    let mut mapper = NsfMapper::new(&nsf);
+
+    assert_eq!(mapper.prg_read(0x8010), 0xAA);
+    assert_eq!(mapper.prg_read(0x8011), 0xBB);
+    assert_eq!(mapper.prg_read(0x8012), 0x00);
+
+    //bank switching is ignored without bankswitch init values
+    mapper.prg_write(0x5FF8, 0x01);
+    assert_eq!(mapper.prg_read(0x8010), 0xAA);
+
+    mapper.prg_write(0x6000, 0xCC);
+    assert_eq!(mapper.prg_read(0x6000), 0xCC);
+
+    let chips = mapper.audio_chips();
+    assert_eq!(chips.len(), 2);
+    assert_eq!(chips[0].name(), "vrc6");
+    assert_eq!(chips[1].name(), "n163");
+}
+
+#[test]
+fn nsf_mapper_bankswitch_test() {
+    let mut data = Vec::from_elem(0x2000 - 0x10, 0u8);
+    *data.get_mut(0x0000) = 0xAA; //bank 0, $x010
+    *data.get_mut(0x1000 - 0x10) = 0xBB; //bank 1, $x000
+
+    let bytes = get_nsf_bytes(0x8010, [0, 1, 0, 0, 0, 0, 0, 1], data.as_slice());
+    let nsf = Nsf::from_bytes(bytes.as_slice()).unwrap();
+    assert!(nsf.is_bankswitched());
+
+    let mut mapper = NsfMapper::new(&nsf);
+    assert_eq!(mapper.prg_read(0x8010), 0xAA);
+    assert_eq!(mapper.prg_read(0x9000), 0xBB);
+    assert_eq!(mapper.prg_read(0xF000), 0xBB);
+
+    mapper.prg_write(0x5FF8, 0x01);
+    assert_eq!(mapper.prg_read(0x8000), 0xBB);
+
+    //poking through a bank changes the data in it
+    assert!(mapper.prg_poke(0x9000, 0xCC));
+    assert_eq!(mapper.prg_peek(0x8000), 0xCC);
+    assert!(mapper.prg_poke(0x5FFF, 0x00));
+    assert_eq!(mapper.registers()[7], ("$5FFF".to_string(), 0x00));
+    assert_eq!(mapper.registers()[0], ("$5FF8".to_string(), 0x01));
+}
+
+#[test]
+fn nsfe_test() {
+    let bytes = get_nsfe_bytes();
+    let nsf = Nsf::from_bytes(bytes.as_slice()).unwrap();
+
+    assert_eq!(nsf.load_addr, 0x8000);
+    assert_eq!(nsf.play_addr, 0x8003);
+    assert_eq!(nsf.total_songs, 2);
+    assert_eq!(nsf.starting_song, 1);
+    assert_eq!(nsf.data.len(), 4);
+
+    assert_eq!(nsf.track_name(0), Some("Title".to_string()));
+    assert_eq!(nsf.track_name(1), Some("Ending".to_string()));
+    assert_eq!(nsf.track_name(2), None);
+
+    assert_eq!(nsf.track_length(0), (10000, DEFAULT_TRACK_FADE));
+    assert_eq!(nsf.track_length(1), (DEFAULT_TRACK_LENGTH, DEFAULT_TRACK_FADE));
+
+    //there's no track 5
+    assert_eq!(nsf.playlist(), vec![1, 0]);
+    let nsf = Nsf::from_bytes(get_nsf_bytes(0x8000, [0u8, ..8], &[0x60]).as_slice()).unwrap();
+    assert_eq!(nsf.playlist(), vec![0, 1, 2]);
+}
+
+#[test]
+fn nsfe_invalid_test() {
+    //load address below $8000 without bankswitching
+    let bytes = get_nsfe_bytes_with_info(&[0x00, 0x70, 0x00, 0x80, 0x03, 0x80, 0x00, 0x00, 0x02, 0x01]);
+    assert!(Nsf::from_bytes(bytes.as_slice()).is_none());
+
+    //no songs
+    let bytes = get_nsfe_bytes_with_info(&[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0x00, 0x00, 0x00, 0x00]);
+    assert!(Nsf::from_bytes(bytes.as_slice()).is_none());
+}
+
+#[test]
+fn nsf_player_play_overrun_test() {
+    //INIT returns straight away, PLAY never does
+    let bytes = get_nsf_bytes(0x8000, [0u8, ..8], &[0x60, 0x60, 0x60, 0x4C, 0x03, 0x80]);
+    let nsf = Nsf::from_bytes(bytes.as_slice()).unwrap();
+    let mut player = NsfPlayer::from_nsf(nsf);
+    player.select_track(0);
+    assert!(!player.running);
+    let sp = player.cpu.state().S;
+
+    //the first PLAY is left running and carried on with, not called again
+    player.play_frame();
+    assert!(player.running);
+    assert_eq!(player.cpu.state().S, sp - 2);
+    for _ in range(0u, 10) {
+        player.play_frame();
+    }
+    assert!(player.running);
+    assert_eq!(player.cpu.state().S, sp - 2);
+}
+
+#[test]
+fn nsf_player_frame_carry_test() {
+    //PLAY is a 3 cycle JMP to itself
+    let bytes = get_nsf_bytes(0x8000, [0u8, ..8], &[0x60, 0x60, 0x60, 0x4C, 0x03, 0x80]);
+    let nsf = Nsf::from_bytes(bytes.as_slice()).unwrap();
+    let mut player = NsfPlayer::from_nsf(nsf);
+    player.play_period = 10.0;
+
+    //12 cycles in a 10 cycle frame leaves the next one 8, which takes 9
+    player.play_frame();
+    assert_eq!(player.overrun, 2);
+    player.play_frame();
+    assert_eq!(player.overrun, 1);
+    player.play_frame();
+    assert_eq!(player.overrun, 0);
+}
@@ -2,6 +2,14 @@ use std::mem;
 
 use nes::{ChrRom, CHR_ROM_BANK_SIZE};
 use nes::{VAddr};
+use nes::{Region, Ntsc};
+use nes::state;
+use nes::state::{StateWriter, StateReader, StateHashes};
+
+use debugger::{AccessLog, PpuBus, ReadAccess, WriteAccess};
+use debugger::cdl::{CDL_CHR_RENDERED, CDL_CHR_READ};
+
+pub mod png;
 
 #[cfg(test)]
 pub mod test;
@@ -106,20 +114,30 @@ pub mod test;
 ///
 /// - Holds the VAddr in SprRam to access on the next write to $2004
 ///
-/// $2004 - SprRam I/O Register - Write Only
+/// $2004 - SprRam I/O Register - Read/Write
+///
+/// - Reads or writes a byte of SprRam at the VAddr indicated by $2003, writes move it on by
+///   one
 ///
-/// - Writes a byte to SprRam at the VAddr indicated by $2003
+/// Reading a write only register gives the I/O latch, the last value written to or read from
+/// any of them. It fades to 0 if it isn't refreshed.
 ///
 /// $2005 - VRAM Address Register 1 - Write Only
 ///
+/// - Two writes, the x scroll and then the y scroll, see Scrolling
+///
 /// $2006 - VRAM Address Register 2 - Write Only
 ///
+/// - Two writes, the high byte of the VRAM address and then the low byte
+///
 /// $2007 - VRAM I/O Register - Read/Write
 ///
 /// - Reads or writes a byte from VRAM at the current address.
 ///
 /// TODO
-/// DMA Register ($4014) and Joypad I/O Registers ($4016 and $4017)
+/// DMA Register ($4014)
+///
+/// The Joypad I/O Registers ($4016 and $4017) are in the input module
 ///
 
 
@@ -324,19 +342,66 @@ struct AttrTable<'a> {
 
 
 
+
+/// # Scrolling
+///
+/// from http://wiki.nesdev.com/w/index.php/PPU_scrolling
+///
+/// $2005 and $2006 share a write latch, the first write goes to the x scroll or the address'
+/// high byte and the second to the y scroll or the low byte. Reading $2002 resets it. Both
+/// fill in the temporary address t, the second $2006 write copies it to the address v that
+/// $2007 reads and writes through.
+///
+/// t and v are 15 bits, fine y (14-12), name table (11-10), coarse y (9-5) and coarse x (4-0).
+/// Fine x is kept on its own.
 
 struct PpuRegisters {
+    ppu_ctrl: u8,
+    ppu_mask: u8,
     ppu_status: PpuStatus,
+    vram_address: VAddr,
+    temp_address: VAddr,
+    fine_x: u8,
+    write_latch: bool,
+    read_buffer: u8,
+    oam_address: u8,
+
+    //the I/O bus between the CPU and the PPU holds the last value written or read, it's what
+    //reads of the write only registers see, until it fades
+    io_latch: u8,
+    io_latch_frame: uint,
 }
 
 impl PpuRegisters {
     pub fn new() -> PpuRegisters {
         PpuRegisters {
+            ppu_ctrl: 0,
+            ppu_mask: 0,
             ppu_status: PpuStatus::new(),
+            vram_address: 0,
+            temp_address: 0,
+            fine_x: 0,
+            write_latch: false,
+            read_buffer: 0,
+            oam_address: 0,
+            io_latch: 0,
+            io_latch_frame: 0,
         }
     }
 }
 
+//the I/O latch fades to 0 around 600 ms after it was last refreshed
+static IO_LATCH_DECAY_FRAMES: uint = 36;
+
+static CTRL_NAME_TABLE: u8       = 0b00000011;
+static CTRL_VRAM_INCREMENT: u8   = 0b00000100;
+static CTRL_SPRITE_TABLE: u8     = 0b00001000;
+static CTRL_BACKGROUND_TABLE: u8 = 0b00010000;
+static CTRL_SPRITE_SIZE: u8      = 0b00100000;
+static CTRL_NMI_FLAG: u8         = 0b10000000;
+static MASK_SHOW_BACKGROUND: u8  = 0b00001000;
+static MASK_SHOW_SPRITES: u8     = 0b00010000;
+
 //TODO least significant bits
 struct PpuStatus {
     sprite_overflow: bool,
@@ -361,10 +426,51 @@ impl PpuStatus {
     }
 }
 
+/// # Framebuffer
+///
+/// One byte per pixel, each an index into the system palette (see SYSTEM_PALETTE), row by
+/// row from the top left. Frontends turn them into colors with `Ppu::rgb`.
+///
+/// TODO Only the backdrop color ($3F00) is drawn so far
+///
+/// # Frame timing
+///
+/// from http://wiki.nesdev.com/w/index.php/PPU_frame_timing
+///
+/// The PPU draws one pixel per dot, 341 dots per scanline. Scanlines 0-239 are visible, 240
+/// is idle, vblank starts on dot 1 of 241 (raising NMI if $2000 bit 7 is set) and ends on
+/// dot 1 of the pre-render line, 261. With rendering enabled the pre-render line is one dot
+/// shorter on odd frames.
+///
+/// PAL and Dendy frames are 312 scanlines, see nes::Region for where vblank starts.
+
+pub static SCREEN_WIDTH: uint = 256;
+pub static SCREEN_HEIGHT: uint = 240;
+
+pub static DOTS_PER_SCANLINE: uint = 341;
+
+static PALETTE_RAM_SIZE: uint = 0x20;
+
 pub struct Ppu {
     vram: VRam,
     spr_ram: SprRam,
+    palette_ram: [u8, ..PALETTE_RAM_SIZE],
     registers: PpuRegisters,
+
+    framebuffer: Vec<u8>,
+    vblank_scanline: uint,
+    pre_render_scanline: uint,
+    skip_odd_dot: bool,
+    scanline: uint,
+    dot: uint,
+    frame: uint,
+    nmi_pending: bool,
+
+    /// PPU bus accesses for the debugger's watchpoints
+    pub access_log: AccessLog,
+
+    /// The CHR-ROM half of the code/data logger while it's recording, see Nes::start_cdl
+    pub chr_log: Option<Vec<u8>>,
 }
 
 impl Ppu {
@@ -375,25 +481,404 @@ impl Ppu {
         Ppu {
             vram: vram,
             spr_ram: spr_ram,
+            palette_ram: [0u8, ..PALETTE_RAM_SIZE],
             registers: PpuRegisters::new(),
+
+            framebuffer: Vec::from_elem(SCREEN_WIDTH * SCREEN_HEIGHT, 0u8),
+            vblank_scanline: Ntsc.vblank_scanline(),
+            pre_render_scanline: Ntsc.scanlines() - 1,
+            skip_odd_dot: Ntsc.skips_odd_dot(),
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            nmi_pending: false,
+            access_log: AccessLog::new(),
+            chr_log: None,
         }
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.vblank_scanline = region.vblank_scanline();
+        self.pre_render_scanline = region.scanlines() - 1;
+        self.skip_odd_dot = region.skips_odd_dot();
+    }
+
+    pub fn framebuffer<'a>(&'a self) -> &'a [u8] {
+        self.framebuffer.as_slice()
+    }
+
+    //the scanline and dot the PPU draws next
+    pub fn scanline(&self) -> uint {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> uint {
+        self.dot
+    }
+
+    //frames finished since power on
+    pub fn frame(&self) -> uint {
+        self.frame
+    }
+
+    pub fn rendering_enabled(&self) -> bool {
+        self.registers.ppu_mask & (MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES) != 0
+    }
+
+    //NMI is edge triggered, the CPU takes it once
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
+
+    pub fn rgb(palette_index: u8) -> [u8, ..3] {
+        SYSTEM_PALETTE[(palette_index as uint) % SYSTEM_PALETTE_SIZE]
+    }
+
+    //the framebuffer as 3 bytes per pixel
+    pub fn rgb_framebuffer(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.framebuffer.len() * 3);
+        for &index in self.framebuffer.iter() {
+            rgb.push_all(&Ppu::rgb(index));
+        }
+        rgb
+    }
+
+    //sum of the color components of a pixel, 0 to 765
+    pub fn brightness(&self, x: uint, y: uint) -> uint {
+        if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT { return 0; }
+
+        let color = Ppu::rgb(self.framebuffer[y * SCREEN_WIDTH + x]);
+        color.iter().fold(0, |sum, &c| sum + c as uint)
+    }
+
+    //$2000-$2007 as the CPU reads them, reads refresh the I/O latch and the write only
+    //registers give what's in it
+    pub fn read_register(&mut self, register: uint) -> u8 {
+        let val = match register {
+            2 => { (self.read_ppu_status() & 0xE0) | (self.io_latch() & 0x1F) }
+            4 => { self.spr_ram.buf[self.registers.oam_address as uint] }
+            7 => {
+                //palette entries are 6 bits
+                let palette = self.registers.vram_address & 0x3FFF >= 0x3F00;
+                let val = self.read_ppu_data();
+                if palette { (val & 0x3F) | (self.io_latch() & 0xC0) } else { val }
+            }
+            _ => { self.io_latch() }
+        };
+        self.set_io_latch(val);
+
+        val
+    }
+
+    pub fn write_register(&mut self, register: uint, val: u8) {
+        self.set_io_latch(val);
+        match register {
+            0 => { self.write_ppu_ctrl(val); }
+            1 => { self.write_ppu_mask(val); }
+            2 => { error!("PPU Status Register ($2002) is Read Only"); }
+            3 => { self.registers.oam_address = val; }
+            4 => { self.write_oam_data(val); }
+            5 => { self.write_ppu_scroll(val); }
+            6 => { self.write_ppu_addr(val); }
+            7 => { self.write_ppu_data(val); }
+            _ => { }
+        }
+    }
+
+    fn io_latch(&self) -> u8 {
+        if self.frame >= self.registers.io_latch_frame + IO_LATCH_DECAY_FRAMES {
+            0x00
+        } else {
+            self.registers.io_latch
+        }
+    }
+
+    fn set_io_latch(&mut self, val: u8) {
+        self.registers.io_latch = val;
+        self.registers.io_latch_frame = self.frame;
+    }
+
+    //$2000
+    pub fn write_ppu_ctrl(&mut self, val: u8) {
+        //turning NMI on during vblank raises one right away
+        let nmi_was_enabled = self.registers.ppu_ctrl & CTRL_NMI_FLAG != 0;
+        self.registers.ppu_ctrl = val;
+        self.registers.temp_address = (self.registers.temp_address & 0x73FF) |
+            ((val & CTRL_NAME_TABLE) as VAddr << 10);
+        if !nmi_was_enabled && val & CTRL_NMI_FLAG != 0 && self.registers.ppu_status.v_blank {
+            self.nmi_pending = true;
+        }
+    }
+
+    //$2001
+    pub fn write_ppu_mask(&mut self, val: u8) {
+        self.registers.ppu_mask = val;
+    }
+
     //$2002
     pub fn read_ppu_status(&mut self) -> u8 {
         let reg = self.registers.ppu_status.read();
         self.registers.ppu_status.v_blank = false;
+        self.registers.write_latch = false;
 
         reg
     }
 
-    pub fn do_scanline(&mut self, scanline: uint) {
-        info!("Scanline: {}", scanline);
-        if scanline == 240 { self.registers.ppu_status.v_blank = true; }
-        if scanline == 260 { 
-            self.registers.ppu_status.v_blank = false;
-            self.registers.ppu_status.sprite_zero_hit = false;
-            self.registers.ppu_status.sprite_overflow = false;
+    //$2005, x scroll then y scroll
+    pub fn write_ppu_scroll(&mut self, val: u8) {
+        let val = val as VAddr;
+        if !self.registers.write_latch {
+            self.registers.temp_address = (self.registers.temp_address & 0x7FE0) | (val >> 3);
+            self.registers.fine_x = (val & 0x07) as u8;
+        } else {
+            self.registers.temp_address = (self.registers.temp_address & 0x0C1F) |
+                ((val & 0x07) << 12) | ((val & 0xF8) << 2);
+        }
+        self.registers.write_latch = !self.registers.write_latch;
+    }
+
+    //$2006, high byte then low byte
+    pub fn write_ppu_addr(&mut self, val: u8) {
+        let val = val as VAddr;
+        if !self.registers.write_latch {
+            self.registers.temp_address = (self.registers.temp_address & 0x00FF) | ((val & 0x3F) << 8);
+        } else {
+            self.registers.temp_address = (self.registers.temp_address & 0x7F00) | val;
+            self.registers.vram_address = self.registers.temp_address;
+        }
+        self.registers.write_latch = !self.registers.write_latch;
+    }
+
+    //$2007, reads below the palettes give what the previous read fetched. Reading a palette
+    //gives it right away and fetches the name table underneath it instead
+    pub fn read_ppu_data(&mut self) -> u8 {
+        let address = self.registers.vram_address & 0x3FFF;
+        let val = if address >= 0x3F00 {
+            self.registers.read_buffer = self.peek_byte(address - 0x1000);
+            self.read_byte(address)
+        } else {
+            let fetched = self.read_byte(address);
+            mem::replace(&mut self.registers.read_buffer, fetched)
+        };
+        self.increment_vram_address();
+
+        val
+    }
+
+    //$2004, the address moves on after a write but not a read
+    fn write_oam_data(&mut self, val: u8) {
+        let address = self.registers.oam_address;
+        self.spr_ram.buf[address as uint] = val;
+        self.registers.oam_address = address + 1;
+    }
+
+    pub fn write_ppu_data(&mut self, val: u8) {
+        let address = self.registers.vram_address & 0x3FFF;
+        self.write_byte(address, val);
+        self.increment_vram_address();
+    }
+
+    //by 1 or 32 after each $2007 access, depending on $2000
+    fn increment_vram_address(&mut self) {
+        let increment = if self.registers.ppu_ctrl & CTRL_VRAM_INCREMENT != 0 { 32 } else { 1 };
+        self.registers.vram_address = (self.registers.vram_address + increment) & 0x7FFF;
+    }
+
+    //$2000-$2007 without side effects, so reading $2002 leaves vblank alone and $2007 leaves
+    //the address and read buffer alone. $2000, $2001 and $2003 give what was last written to
+    //them, $2005 and $2006 the I/O latch
+    pub fn peek_register(&self, register: uint) -> u8 {
+        match register {
+            0 => { self.registers.ppu_ctrl }
+            1 => { self.registers.ppu_mask }
+            2 => { self.registers.ppu_status.read() }
+            3 => { self.registers.oam_address }
+            4 => { self.spr_ram.buf[self.registers.oam_address as uint] }
+            5 | 6 => { self.io_latch() }
+            7 => {
+                let address = self.registers.vram_address & 0x3FFF;
+                if address >= 0x3F00 { self.peek_byte(address) } else { self.registers.read_buffer }
+            }
+            _ => { 0x00 }
+        }
+    }
+
+    //sets $2000, $2001 and $2003 without raising NMI, false for the rest
+    pub fn poke_register(&mut self, register: uint, val: u8) -> bool {
+        match register {
+            0 => { self.registers.ppu_ctrl = val; true }
+            1 => { self.registers.ppu_mask = val; true }
+            3 => { self.registers.oam_address = val; true }
+            _ => { false }
+        }
+    }
+
+    //sprite RAM, as the CPU sees it through $2003/$2004
+    pub fn peek_oam(&self, address: u8) -> u8 {
+        self.spr_ram.buf[address as uint]
+    }
+
+    pub fn poke_oam(&mut self, address: u8, val: u8) {
+        self.spr_ram.buf[address as uint] = val;
+    }
+
+    //the pattern fetches and VRAM address updates of a rendering scanline, from
+    //http://wiki.nesdev.com/w/index.php/PPU_rendering
+    //TODO draw what's fetched, the attribute fetches go with that
+    fn render_fetches(&mut self) {
+        let dot = self.dot;
+        if (dot >= 1 && dot <= 256) || (dot >= 321 && dot <= 336) {
+            match dot % 8 {
+                5 => {
+                    let address = self.background_pattern_address();
+                    self.log_pattern_fetch(address);
+                }
+                7 => {
+                    let address = self.background_pattern_address();
+                    self.log_pattern_fetch(address + 8);
+                }
+                0 => { self.increment_coarse_x(); }
+                _ => { }
+            }
+            if dot == 256 { self.increment_y(); }
+        } else if dot >= 257 && dot <= 320 {
+            if dot == 257 {
+                self.registers.vram_address = (self.registers.vram_address & 0x7BE0) |
+                    (self.registers.temp_address & 0x041F);
+            }
+            if self.scanline == self.pre_render_scanline && dot >= 280 && dot <= 304 {
+                self.registers.vram_address = (self.registers.vram_address & 0x041F) |
+                    (self.registers.temp_address & 0x7BE0);
+            }
+
+            let plane = match (dot - 257) % 8 { 4 => 0, 6 => 8, _ => { return; } };
+            match self.sprite_pattern_address((dot - 257) / 8) {
+                Some(address) => { self.log_pattern_fetch(address + plane); }
+                None => { }
+            }
+        }
+    }
+
+    //low plane of the background tile at v, the high plane is 8 bytes after it
+    fn background_pattern_address(&self) -> VAddr {
+        let vram_address = self.registers.vram_address;
+        let tile = self.peek_byte(0x2000 | (vram_address & 0x0FFF)) as VAddr;
+        let table = if self.registers.ppu_ctrl & CTRL_BACKGROUND_TABLE != 0 { 0x1000 } else { 0x0000 };
+        table | (tile << 4) | ((vram_address >> 12) & 0x07)
+    }
+
+    //low plane of the sprite in a slot, the slots are the first eight sprites on the next
+    //line. Empty slots fetch nothing
+    //TODO sprite overflow
+    fn sprite_pattern_address(&self, slot: uint) -> Option<VAddr> {
+        if self.scanline >= SCREEN_HEIGHT { return None; }
+
+        let tall = self.registers.ppu_ctrl & CTRL_SPRITE_SIZE != 0;
+        let height = if tall { 16 } else { 8 };
+        let mut found = 0;
+        for i in range(0, SPR_RAM_SIZE / 4) {
+            let spr = self.spr_ram.spr(i);
+            let y = spr.y() as uint;
+            if self.scanline < y || self.scanline - y >= height { continue; }
+            if found < slot {
+                found += 1;
+                continue;
+            }
+
+            let row = if spr.v_flip() { height - 1 - (self.scanline - y) } else { self.scanline - y };
+            let (table, tile) = if tall {
+                ((spr.idx() as VAddr & 0x01) << 12, (spr.idx() as VAddr & 0xFE) + (row / 8) as VAddr)
+            } else {
+                let table = if self.registers.ppu_ctrl & CTRL_SPRITE_TABLE != 0 { 0x1000 } else { 0x0000 };
+                (table, spr.idx() as VAddr)
+            };
+            return Some(table | (tile << 4) | (row % 8) as VAddr);
+        }
+        None
+    }
+
+    //rendering doesn't go in the access log, PPU bus watchpoints are for $2007, but the
+    //code/data logger marks the CHR-ROM as drawn
+    fn log_pattern_fetch(&mut self, address: VAddr) {
+        let address = address as uint;
+        match self.chr_log {
+            Some(ref mut chr_log) if address < chr_log.len() => { *chr_log.get_mut(address) |= CDL_CHR_RENDERED; }
+            _ => { }
+        }
+    }
+
+    //coarse x wraps into the next name table
+    fn increment_coarse_x(&mut self) {
+        let vram_address = self.registers.vram_address;
+        self.registers.vram_address = if vram_address & 0x001F == 31 {
+            (vram_address & !0x001F) ^ 0x0400
+        } else {
+            vram_address + 1
+        };
+    }
+
+    //fine y, then coarse y, which wraps into the next name table after row 29
+    fn increment_y(&mut self) {
+        let vram_address = self.registers.vram_address;
+        if vram_address & 0x7000 != 0x7000 {
+            self.registers.vram_address = vram_address + 0x1000;
+            return;
+        }
+
+        let mut vram_address = vram_address & !0x7000;
+        let coarse_y = (vram_address & 0x03E0) >> 5;
+        let coarse_y = if coarse_y == 29 {
+            vram_address ^= 0x0800;
+            0
+        } else if coarse_y == 31 {
+            0
+        } else {
+            coarse_y + 1
+        };
+        self.registers.vram_address = (vram_address & !0x03E0) | (coarse_y << 5);
+    }
+
+    //runs one dot
+    pub fn tick(&mut self) {
+        if self.scanline < SCREEN_HEIGHT && self.dot >= 1 && self.dot <= SCREEN_WIDTH {
+            let backdrop = self.palette_ram[0] & 0x3F;
+            *self.framebuffer.get_mut(self.scanline * SCREEN_WIDTH + self.dot - 1) = backdrop;
+        }
+
+        if self.rendering_enabled() &&
+            (self.scanline < SCREEN_HEIGHT || self.scanline == self.pre_render_scanline) {
+            self.render_fetches();
+        }
+
+        if self.dot == 1 {
+            if self.scanline == self.vblank_scanline {
+                self.registers.ppu_status.v_blank = true;
+                if self.registers.ppu_ctrl & CTRL_NMI_FLAG != 0 { self.nmi_pending = true; }
+            } else if self.scanline == self.pre_render_scanline {
+                self.registers.ppu_status.v_blank = false;
+                self.registers.ppu_status.sprite_zero_hit = false;
+                self.registers.ppu_status.sprite_overflow = false;
+            }
+        }
+
+        self.dot += 1;
+
+        //odd frames skip the last dot of the pre-render line
+        if self.skip_odd_dot && self.scanline == self.pre_render_scanline &&
+            self.dot == DOTS_PER_SCANLINE - 1 && self.frame % 2 == 1 && self.rendering_enabled() {
+            self.dot += 1;
+        }
+
+        if self.dot >= DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > self.pre_render_scanline {
+                info!("Frame: {}", self.frame);
+                self.scanline = 0;
+                self.frame += 1;
+            }
         }
     }
 
@@ -433,19 +918,137 @@ impl Ppu {
 /// |_ _ _ _ _ _ _ _ _ _| $1000 | Pattern Tables |
 /// | Pattern Table 0   |       |                |
 /// |___________________| $0000 |________________|
-    pub fn read_byte(&self, virtual_address: VAddr) -> u8 {
-        if virtual_address < 0x2000 {
-            self.vram[virtual_address]
-        } else {
-            0
+    pub fn read_byte(&mut self, virtual_address: VAddr) -> u8 {
+        let val = self.peek_byte(virtual_address);
+        self.access_log.record(PpuBus, virtual_address, val, ReadAccess);
+
+        let address = (virtual_address & 0x3FFF) as uint;
+        match self.chr_log {
+            Some(ref mut chr_log) if address < chr_log.len() => { *chr_log.get_mut(address) |= CDL_CHR_READ; }
+            _ => { }
         }
+
+        val
     }
 
     pub fn write_byte(&mut self, virtual_address: VAddr, val: u8) {
+        self.access_log.record(PpuBus, virtual_address, val, WriteAccess);
+        self.poke_byte(virtual_address, val);
+    }
+
+    //Read a byte from the PPU bus without logging it, for debuggers and tools
+    pub fn peek_byte(&self, virtual_address: VAddr) -> u8 {
+        match Ppu::bus_address(virtual_address) {
+            Some(address) => { self.vram[address] }
+            None if virtual_address & 0x3FFF >= 0x3F00 => {
+                self.palette_ram[Ppu::palette_address(virtual_address)]
+            }
+            None => { 0x00 }
+        }
+    }
+
+    //Write a byte to the PPU bus without logging it, false where there's nothing to write
+    pub fn poke_byte(&mut self, virtual_address: VAddr, val: u8) -> bool {
+        match Ppu::bus_address(virtual_address) {
+            Some(address) => {
+                self.vram[address] = val;
+                true
+            }
+            None if virtual_address & 0x3FFF >= 0x3F00 => {
+                self.palette_ram[Ppu::palette_address(virtual_address)] = val & 0x3F;
+                true
+            }
+            None => { false }
+        }
+    }
+
+    //where an address below the palettes lives in VRAM, there's only the one name table so
+    //all four mirror it
+    //TODO name table mirroring
+    fn bus_address(virtual_address: VAddr) -> Option<VAddr> {
+        let virtual_address = virtual_address & 0x3FFF; //Mirrored after 0x4000
         if virtual_address < 0x2000 {
-            self.vram[virtual_address] = val;
+            Some(virtual_address)
+        } else if virtual_address < 0x3F00 {
+            Some(0x2000 | (virtual_address & 0x03FF))
+        } else {
+            None
         }
     }
+
+    //$3F20-$3FFF mirror $3F00-$3F1F, and $3F10/$3F14/$3F18/$3F1C mirror $3F00/$3F04/$3F08/$3F0C
+    fn palette_address(virtual_address: VAddr) -> uint {
+        let address = (virtual_address & 0x001F) as uint;
+        if address & 0x13 == 0x10 { address & 0x0F } else { address }
+    }
+
+    //the region timing isn't saved, it comes from the console the state is loaded into
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(self.vram.buf);
+        w.bytes(self.spr_ram.buf);
+        w.bytes(self.palette_ram);
+        self.save_registers(w);
+        w.bytes(self.framebuffer.as_slice());
+        w.uint(self.scanline);
+        w.uint(self.dot);
+        w.uint(self.frame);
+        w.bool(self.nmi_pending);
+    }
+
+    fn save_registers(&self, w: &mut StateWriter) {
+        w.u8(self.registers.ppu_ctrl);
+        w.u8(self.registers.ppu_mask);
+        w.u8(self.registers.ppu_status.read());
+        w.u16(self.registers.vram_address);
+        w.u16(self.registers.temp_address);
+        w.u8(self.registers.fine_x);
+        w.bool(self.registers.write_latch);
+        w.u8(self.registers.read_buffer);
+        w.u8(self.registers.oam_address);
+        w.u8(self.registers.io_latch);
+        w.uint(self.registers.io_latch_frame);
+    }
+
+    //see Nes::state_hashes, the timing goes with the registers
+    pub fn state_hashes(&self, hashes: &mut StateHashes) {
+        let mut registers = StateWriter::new();
+        self.save_registers(&mut registers);
+        registers.uint(self.scanline);
+        registers.uint(self.dot);
+        registers.uint(self.frame);
+        registers.bool(self.nmi_pending);
+
+        hashes.push(("PpuRegisters", state::hash(registers.into_bytes().as_slice())));
+        hashes.push(("VRam", state::hash(self.vram.buf)));
+        hashes.push(("SprRam", state::hash(self.spr_ram.buf)));
+        hashes.push(("palette_ram", state::hash(self.palette_ram)));
+        hashes.push(("framebuffer", state::hash(self.framebuffer.as_slice())));
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        r.read_into(self.vram.buf);
+        r.read_into(self.spr_ram.buf);
+        r.read_into(self.palette_ram);
+        self.registers.ppu_ctrl = r.u8();
+        self.registers.ppu_mask = r.u8();
+        let status = r.u8();
+        self.registers.ppu_status.sprite_overflow = status & 0b00100000 != 0;
+        self.registers.ppu_status.sprite_zero_hit = status & 0b01000000 != 0;
+        self.registers.ppu_status.v_blank = status & 0b10000000 != 0;
+        self.registers.vram_address = r.u16();
+        self.registers.temp_address = r.u16();
+        self.registers.fine_x = r.u8();
+        self.registers.write_latch = r.bool();
+        self.registers.read_buffer = r.u8();
+        self.registers.oam_address = r.u8();
+        self.registers.io_latch = r.u8();
+        self.registers.io_latch_frame = r.uint();
+        r.read_into(self.framebuffer.as_mut_slice());
+        self.scanline = r.uint();
+        self.dot = r.uint();
+        self.frame = r.uint();
+        self.nmi_pending = r.bool();
+    }
 }
 
 
@@ -0,0 +1,227 @@
+use std::io::{File, IoResult};
+
+use flate::inflate_bytes_zlib;
+
+/// # PNG
+///
+/// from http://www.w3.org/TR/PNG/
+///
+/// Writes 8 bit RGB images, enough for screenshots. A PNG is a signature followed by chunks,
+/// each a big endian length, a 4 byte type, the data and a CRC-32 of the type and data:
+///
+/// - IHDR - width, height, bit depth 8, color type 2 (RGB), compression, filter and interlace 0
+/// - IDAT - zlib stream of the rows, each row starting with its filter type, always 0 here
+/// - IEND - empty
+///
+/// The zlib stream uses stored deflate blocks, the files are bigger than they could be but
+/// there's no compressor to get wrong.
+///
+/// Reading is for golden screenshots made by other tools, so it takes 8 bit RGB, RGBA and
+/// palette images with any filters and real deflate, inflated with the flate crate. Interlaced
+/// images and other bit depths aren't supported.
+
+static SIGNATURE: [u8, ..8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+//deflate stored blocks hold up to 65535 bytes
+static MAX_STORED_BLOCK: uint = 0xFFFF;
+
+/// `rgb` is 3 bytes per pixel, row by row from the top left.
+pub fn encode(width: uint, height: uint, rgb: &[u8]) -> Vec<u8> {
+    assert_eq!(rgb.len(), width * height * 3);
+
+    let mut png = SIGNATURE.to_vec();
+
+    let mut ihdr = Vec::new();
+    push_u32(&mut ihdr, width as u32);
+    push_u32(&mut ihdr, height as u32);
+    ihdr.push_all(&[8, 2, 0, 0, 0]);
+    push_chunk(&mut png, b"IHDR", ihdr.as_slice());
+
+    let mut rows = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks(width * 3) {
+        rows.push(0); //no filter
+        rows.push_all(row);
+    }
+    push_chunk(&mut png, b"IDAT", zlib_stored(rows.as_slice()).as_slice());
+
+    push_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+pub fn write(path: &Path, width: uint, height: uint, rgb: &[u8]) -> IoResult<()> {
+    let mut file = try!(File::create(path));
+    file.write(encode(width, height, rgb).as_slice())
+}
+
+/// Returns the width, height and 3 bytes per pixel, alpha is dropped.
+pub fn decode(bytes: &[u8]) -> Option<(uint, uint, Vec<u8>)> {
+    if !bytes.starts_with(&SIGNATURE) { return None; }
+
+    let mut width = 0;
+    let mut height = 0;
+    let mut color_type = 0;
+    let mut palette = Vec::new();
+    let mut idat = Vec::new();
+
+    let mut pos = SIGNATURE.len();
+    while pos + 12 <= bytes.len() {
+        let len = read_u32(bytes, pos) as uint;
+        let kind = bytes.slice(pos + 4, pos + 8);
+        if pos + 12 + len > bytes.len() { return None; }
+        let data = bytes.slice(pos + 8, pos + 8 + len);
+        pos += 12 + len;
+
+        if kind == b"IHDR" {
+            if len != 13 { return None; }
+            width = read_u32(data, 0) as uint;
+            height = read_u32(data, 4) as uint;
+            color_type = data[9];
+            //bit depth 8, deflate, adaptive filtering, no interlacing
+            if data[8] != 8 || data[10] != 0 || data[11] != 0 || data[12] != 0 {
+                error!("Unsupported PNG: bit depth {}, interlace {}", data[8], data[12]);
+                return None;
+            }
+        } else if kind == b"PLTE" {
+            palette = data.to_vec();
+        } else if kind == b"IDAT" {
+            idat.push_all(data);
+        } else if kind == b"IEND" {
+            break;
+        }
+    }
+
+    let bpp = match color_type {
+        2 => 3,
+        3 => 1,
+        6 => 4,
+        _ => {
+            error!("Unsupported PNG color type {}", color_type);
+            return None;
+        }
+    };
+
+    let inflated = match inflate_bytes_zlib(idat.as_slice()) {
+        Some(inflated) => inflated,
+        None => { return None; }
+    };
+    let raw = inflated.as_slice();
+
+    let stride = width * bpp;
+    if raw.len() < (stride + 1) * height { return None; }
+
+    let mut pixels = Vec::from_elem(stride * height, 0u8);
+    for y in range(0, height) {
+        let filter = raw[y * (stride + 1)];
+        let line = raw.slice(y * (stride + 1) + 1, (y + 1) * (stride + 1));
+        for x in range(0, stride) {
+            let a = if x >= bpp { pixels[y * stride + x - bpp] } else { 0 };
+            let b = if y > 0 { pixels[(y - 1) * stride + x] } else { 0 };
+            let c = if x >= bpp && y > 0 { pixels[(y - 1) * stride + x - bpp] } else { 0 };
+
+            let predictor = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as uint + b as uint) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => { return None; }
+            };
+            *pixels.get_mut(y * stride + x) = line[x] + predictor;
+        }
+    }
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for pixel in pixels.as_slice().chunks(bpp) {
+        if color_type == 3 {
+            let index = pixel[0] as uint * 3;
+            if index + 3 > palette.len() { return None; }
+            rgb.push_all(palette.slice(index, index + 3));
+        } else {
+            rgb.push_all(pixel.slice_to(3));
+        }
+    }
+
+    Some((width, height, rgb))
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as int + b as int - c as int;
+    let pa = (p - a as int).abs();
+    let pb = (p - b as int).abs();
+    let pc = (p - c as int).abs();
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+fn read_u32(bytes: &[u8], offset: uint) -> u32 {
+    (bytes[offset] as u32) << 24 | (bytes[offset + 1] as u32) << 16 |
+    (bytes[offset + 2] as u32) << 8 | (bytes[offset + 3] as u32)
+}
+
+pub fn read(path: &Path) -> Option<(uint, uint, Vec<u8>)> {
+    match File::open(path).read_to_end() {
+        Ok(bytes) => decode(bytes.as_slice()),
+        Err(e) => {
+            error!("Can't read {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut zlib = vec![0x78, 0x01]; //deflate, 32K window, no preset dictionary
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if data.is_empty() {
+        zlib.push_all(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    loop {
+        let block = match blocks.next() {
+            Some(block) => block,
+            None => break,
+        };
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+
+        zlib.push(if last { 0x01 } else { 0x00 });
+        zlib.push_all(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        zlib.push_all(block);
+    }
+
+    push_u32(&mut zlib, adler32(data));
+    zlib
+}
+
+fn push_chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    push_u32(png, data.len() as u32);
+
+    let start = png.len();
+    png.push_all(kind);
+    png.push_all(data);
+    let crc = crc32(png.slice_from(start));
+    push_u32(png, crc);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, val: u32) {
+    bytes.push_all(&[(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]);
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in bytes.iter() {
+        crc ^= byte as u32;
+        for _ in range(0u, 8) {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub fn adler32(bytes: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in bytes.iter() {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
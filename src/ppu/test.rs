@@ -2,8 +2,13 @@ use std::iter;
 use std::mem;
 
 use nes::VAddr;
+use nes::CHR_ROM_BANK_SIZE;
 
+use ppu::png;
 use ppu::{
+    Ppu,
+    SCREEN_WIDTH,
+    DOTS_PER_SCANLINE,
     Spr,
     SprRam,
     SPR_RAM_SIZE,
@@ -44,3 +49,161 @@ fn ppu_spr_ram_test() {
     assert_eq!(spr.h_flip(), true);
     assert_eq!(spr.v_flip(), true);
 }
+
+#[test]
+fn ppu_palette_framebuffer_test() {
+    let mut ppu = Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]);
+
+    //$3F10 is the backdrop too, and palette RAM repeats every 32 bytes
+    ppu.write_byte(0x3F10, 0x21);
+    assert_eq!(ppu.read_byte(0x3F00), 0x21);
+    assert_eq!(ppu.read_byte(0x3F20), 0x21);
+    ppu.write_byte(0x3F11, 0x15);
+    assert_eq!(ppu.read_byte(0x3F01), 0x00);
+
+    for _ in range(0, 11 * DOTS_PER_SCANLINE) { ppu.tick(); }
+    assert_eq!(ppu.scanline(), 11);
+    assert_eq!(ppu.framebuffer()[10 * SCREEN_WIDTH], 0x21);
+    assert_eq!(ppu.framebuffer()[11 * SCREEN_WIDTH], 0x00);
+    assert_eq!(ppu.brightness(0, 10), 0x3F + 0xBF + 0xFF);
+}
+
+#[test]
+fn ppu_vram_address_test() {
+    let mut ppu = Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]);
+
+    //$2002 resets the write latch, and $2000 bit 2 steps down a column
+    ppu.write_ppu_addr(0x3F);
+    ppu.read_ppu_status();
+    ppu.write_ppu_addr(0x20);
+    ppu.write_ppu_addr(0x00);
+    ppu.write_ppu_ctrl(0x04);
+    ppu.write_ppu_data(0x11);
+    ppu.write_ppu_data(0x22);
+    assert_eq!(ppu.peek_byte(0x2000), 0x11);
+    assert_eq!(ppu.peek_byte(0x2020), 0x22);
+
+    //reads come through the buffer, except for the palettes
+    ppu.write_ppu_ctrl(0x00);
+    ppu.write_ppu_addr(0x20);
+    ppu.write_ppu_addr(0x00);
+    assert_eq!(ppu.read_ppu_data(), 0x00);
+    assert_eq!(ppu.peek_register(7), 0x11);
+    assert_eq!(ppu.read_ppu_data(), 0x11);
+    ppu.write_byte(0x3F00, 0x21);
+    ppu.write_ppu_addr(0x3F);
+    ppu.write_ppu_addr(0x00);
+    assert_eq!(ppu.read_ppu_data(), 0x21);
+}
+
+#[test]
+fn ppu_io_latch_test() {
+    let mut ppu = Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]);
+
+    //the write only registers read back the last value on the bus, $2002 its low bits
+    ppu.write_register(5, 0x5A);
+    assert_eq!(ppu.read_register(0), 0x5A);
+    assert_eq!(ppu.read_register(6), 0x5A);
+    assert_eq!(ppu.read_register(2), 0x1A);
+
+    ppu.write_register(3, 0x10);
+    ppu.write_register(4, 0x77);
+    assert_eq!(ppu.peek_oam(0x10), 0x77);
+    ppu.write_register(3, 0x10);
+    assert_eq!(ppu.read_register(4), 0x77);
+    assert_eq!(ppu.read_register(1), 0x77);
+
+    //and it fades when nothing refreshes it
+    while ppu.frame() < 40 { ppu.tick(); }
+    assert_eq!(ppu.read_register(1), 0x00);
+}
+
+#[test]
+fn ppu_frame_timing_test() {
+    let mut ppu = Ppu::new(vec![[0u8, ..CHR_ROM_BANK_SIZE]]);
+    ppu.write_ppu_ctrl(0x80);
+
+    //vblank and NMI on dot 1 of scanline 241
+    for _ in range(0, 241 * DOTS_PER_SCANLINE + 1) { ppu.tick(); }
+    assert_eq!(ppu.take_nmi(), false);
+    ppu.tick();
+    assert_eq!(ppu.take_nmi(), true);
+    assert_eq!(ppu.take_nmi(), false);
+    assert_eq!(ppu.read_ppu_status() & 0x80, 0x80);
+    assert_eq!(ppu.read_ppu_status() & 0x80, 0x00);
+
+    //even frames are 262 full scanlines
+    while ppu.frame() == 0 { ppu.tick(); }
+    assert_eq!((ppu.scanline(), ppu.dot()), (0, 0));
+
+    //odd frames with rendering on are one dot shorter
+    ppu.write_ppu_mask(0x08);
+    for _ in range(0, 262 * DOTS_PER_SCANLINE - 1) { ppu.tick(); }
+    assert_eq!(ppu.frame(), 2);
+    assert_eq!((ppu.scanline(), ppu.dot()), (0, 0));
+
+    //even frames aren't
+    for _ in range(0, 262 * DOTS_PER_SCANLINE - 1) { ppu.tick(); }
+    assert_eq!(ppu.frame(), 2);
+    ppu.tick();
+    assert_eq!(ppu.frame(), 3);
+}
+
+#[test]
+fn ppu_png_test() {
+    assert_eq!(png::crc32(b"IEND"), 0xAE426082);
+    assert_eq!(png::adler32(b"Wikipedia"), 0x11E60398);
+
+    let rgb = [0xFFu8, 0x00, 0x00, 0x00, 0xFF, 0x00];
+    let bytes = png::encode(2, 1, &rgb);
+    assert!(bytes.starts_with(&[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]));
+    assert!(bytes.slice(12, 16) == b"IHDR");
+    assert!(bytes.slice(16, 24) == &[0, 0, 0, 2, 0, 0, 0, 1]);
+    assert!(bytes.ends_with(&[0, 0, 0, 0, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82]));
+
+    //one stored block holding the filter byte and the row
+    let idat = bytes.slice(33 + 8, bytes.len() - 12 - 4);
+    assert!(idat == &[0x78, 0x01, 0x01, 0x07, 0x00, 0xF8, 0xFF, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00,
+                      0x07, 0xFF, 0x01, 0xFF]);
+}
+
+//2x2 RGBA, zlib compressed, rows filtered with sub and paeth
+static PNG_RGBA: [u8, ..82] = [
+    0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x08, 0x06, 0x00, 0x00, 0x00, 0x72, 0xB6, 0x0D,
+    0x24, 0x00, 0x00, 0x00, 0x19, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x63, 0xE4, 0x12, 0x91, 0xFB,
+    0xCF, 0xCA, 0xCA, 0xCA, 0xC0, 0xC2, 0xC4, 0xC4, 0xC4, 0x08, 0x64, 0xFC, 0x07, 0x00, 0x13, 0xF5,
+    0x02, 0x65, 0x6B, 0xA9, 0x0C, 0x38, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42,
+    0x60, 0x82];
+
+//3x1 with a 3 color palette
+static PNG_PALETTE: [u8, ..90] = [
+    0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x08, 0x03, 0x00, 0x00, 0x00, 0x2C, 0x3E, 0xE4,
+    0x86, 0x00, 0x00, 0x00, 0x09, 0x50, 0x4C, 0x54, 0x45, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+    0x08, 0x09, 0x25, 0x85, 0x56, 0xF0, 0x00, 0x00, 0x00, 0x0C, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA,
+    0x63, 0x60, 0x62, 0x60, 0x04, 0x00, 0x00, 0x0B, 0x00, 0x04, 0x6A, 0x68, 0x1D, 0x20, 0x00, 0x00,
+    0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82];
+
+#[test]
+fn ppu_png_decode_test() {
+    let rgb: Vec<u8> = range(0u, 300).map(|i| (i * 7) as u8).collect();
+    let (width, height, decoded) = png::decode(png::encode(10, 10, rgb.as_slice()).as_slice()).unwrap();
+    assert_eq!((width, height), (10, 10));
+    assert!(decoded == rgb);
+
+    let (width, height, decoded) = png::decode(PNG_RGBA.as_slice()).unwrap();
+    assert_eq!((width, height), (2, 2));
+    assert!(decoded.as_slice() == &[10, 20, 30, 15, 25, 35, 12, 22, 32, 20, 30, 40]);
+
+    let (width, height, decoded) = png::decode(PNG_PALETTE.as_slice()).unwrap();
+    assert_eq!((width, height), (3, 1));
+    assert!(decoded.as_slice() == &[7, 8, 9, 1, 2, 3, 7, 8, 9]);
+
+    assert!(png::decode(PNG_RGBA.slice_to(60)).is_none());
+
+    //a deflate block of the reserved type 3
+    let mut corrupt = PNG_RGBA.to_vec();
+    *corrupt.get_mut(43) = 0x07;
+    assert!(png::decode(corrupt.as_slice()).is_none());
+}